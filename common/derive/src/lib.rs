@@ -0,0 +1,234 @@
+//! Derive macros for the `Serializable` and `Deserializable` traits.
+//!
+//! The generated implementations refer to the traits through the `ekiden_common`
+//! crate. Crates which only depend on `ekiden-core` can change this with the
+//! `#[serializable(crate = "ekiden_core")]` attribute.
+extern crate proc_macro;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use quote::Tokens;
+use syn::{Data, DeriveInput, Fields, GenericParam, Ident, Lit, Meta, NestedMeta, Path};
+
+/// Derive `Serializable` for a struct or an enum.
+#[proc_macro_derive(Serializable, attributes(serializable))]
+pub fn derive_serializable(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("Failed to parse derive input");
+    let krate = get_crate_path(&input);
+    let name = &input.ident;
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let (pattern, writes) = write_fields(&krate, quote!(#name), &data.fields);
+            quote! {
+                let #pattern = *self;
+                #(#writes)*
+            }
+        }
+        Data::Enum(ref data) => {
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let index = index as u32;
+                let variant_name = &variant.ident;
+                let (pattern, writes) =
+                    write_fields(&krate, quote!(#name::#variant_name), &variant.fields);
+
+                quote! {
+                    #pattern => {
+                        length += #krate::serializer::Serializable::write_to(&#index, writer)?;
+                        #(#writes)*
+                    }
+                }
+            });
+
+            quote! {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => panic!("Serializable cannot be derived for unions"),
+    };
+
+    let trait_path = quote!(#krate::serializer::Serializable);
+    let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
+    let where_clause = get_where_clause(&input, &trait_path);
+
+    let expanded = quote! {
+        impl #impl_generics #trait_path for #name #ty_generics #where_clause {
+            #[allow(unused_mut, unused_variables)]
+            fn write_to(
+                &self,
+                writer: &mut ::std::io::Write
+            ) -> #krate::error::Result<usize> {
+                let mut length = 0;
+                #body
+
+                Ok(length)
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+/// Derive `Deserializable` for a struct or an enum.
+#[proc_macro_derive(Deserializable, attributes(serializable))]
+pub fn derive_deserializable(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("Failed to parse derive input");
+    let krate = get_crate_path(&input);
+    let name = &input.ident;
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let value = read_fields(&krate, quote!(#name), &data.fields);
+            quote! {
+                Ok(#value)
+            }
+        }
+        Data::Enum(ref data) => {
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let index = index as u32;
+                let variant_name = &variant.ident;
+                let value = read_fields(&krate, quote!(#name::#variant_name), &variant.fields);
+
+                quote! {
+                    #index => Ok(#value),
+                }
+            });
+
+            quote! {
+                let index: u32 = #krate::serializer::Deserializable::read_from(reader)?;
+                match index {
+                    #(#arms)*
+                    _ => Err(#krate::error::Error::new("Unknown enum variant")),
+                }
+            }
+        }
+        Data::Union(_) => panic!("Deserializable cannot be derived for unions"),
+    };
+
+    let trait_path = quote!(#krate::serializer::Deserializable);
+    let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
+    let where_clause = get_where_clause(&input, &trait_path);
+
+    let expanded = quote! {
+        impl #impl_generics #trait_path for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn read_from(reader: &mut ::std::io::Read) -> #krate::error::Result<Self> {
+                #body
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+/// Path of the crate which provides the serializer, as configured by the
+/// `#[serializable(crate = "...")]` attribute.
+fn get_crate_path(input: &DeriveInput) -> Path {
+    let mut path = "::ekiden_common".to_owned();
+    for attribute in &input.attrs {
+        let list = match attribute.interpret_meta() {
+            Some(Meta::List(list)) => list,
+            _ => continue,
+        };
+        if list.ident != "serializable" {
+            continue;
+        }
+
+        for item in list.nested {
+            match item {
+                NestedMeta::Meta(Meta::NameValue(ref name_value))
+                    if name_value.ident == "crate" =>
+                {
+                    match name_value.lit {
+                        Lit::Str(ref value) => path = format!("::{}", value.value()),
+                        _ => panic!("Crate path must be a string"),
+                    }
+                }
+                _ => panic!("Unknown serializable attribute"),
+            }
+        }
+    }
+
+    syn::parse_str(&path).expect("Malformed crate path")
+}
+
+/// Where clause of the implementation, which requires all type parameters to implement
+/// the derived trait.
+fn get_where_clause(input: &DeriveInput, trait_path: &Tokens) -> Tokens {
+    let predicates: Vec<Tokens> = match input.generics.where_clause {
+        Some(ref where_clause) => where_clause
+            .predicates
+            .iter()
+            .map(|predicate| quote!(#predicate))
+            .collect(),
+        None => vec![],
+    };
+    let bounds = input.generics.params.iter().filter_map(|param| match *param {
+        GenericParam::Type(ref param) => {
+            let ident = &param.ident;
+            Some(quote!(#ident: #trait_path))
+        }
+        _ => None,
+    });
+
+    quote! {
+        where #(#predicates,)* #(#bounds,)*
+    }
+}
+
+/// Names under which the fields are bound in patterns.
+fn get_bindings(fields: &Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| match field.ident {
+            Some(ident) => ident,
+            None => Ident::from(format!("field{}", index)),
+        })
+        .collect()
+}
+
+/// Pattern which binds references to all fields of a struct or an enum variant, and
+/// the statements which write the fields in declaration order.
+fn write_fields(krate: &Path, path: Tokens, fields: &Fields) -> (Tokens, Vec<Tokens>) {
+    let bindings = get_bindings(fields);
+    let writes = bindings
+        .iter()
+        .map(|binding| {
+            quote! {
+                length += #krate::serializer::Serializable::write_to(#binding, writer)?;
+            }
+        })
+        .collect();
+
+    let pattern = match *fields {
+        Fields::Named(_) => quote!(#path { #(ref #bindings),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(ref #bindings),* )),
+        Fields::Unit => quote!(#path),
+    };
+
+    (pattern, writes)
+}
+
+/// Expression which constructs a struct or an enum variant by reading its fields in
+/// declaration order.
+fn read_fields(krate: &Path, path: Tokens, fields: &Fields) -> Tokens {
+    let reads = fields.iter().map(|_| {
+        quote! {
+            #krate::serializer::Deserializable::read_from(reader)?
+        }
+    });
+
+    match *fields {
+        Fields::Named(_) => {
+            let names = fields.iter().map(|field| field.ident.unwrap());
+            quote!(#path { #(#names: #reads),* })
+        }
+        Fields::Unnamed(_) => quote!(#path ( #(#reads),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
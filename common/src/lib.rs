@@ -1,3 +1,5 @@
+#![feature(use_extern_macros)]
+
 #[cfg(not(target_env = "sgx"))]
 extern crate rand;
 
@@ -7,6 +9,8 @@ extern crate sgx_trts;
 extern crate byteorder;
 extern crate protobuf;
 
+extern crate ekiden_common_derive;
+
 pub mod error;
 pub mod random;
 #[macro_use]
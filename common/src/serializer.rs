@@ -1,11 +1,27 @@
 //! Serialization and deserialization.
+//!
+//! The encoding is deterministic, so the same value is always encoded the same way:
+//!
+//! * Integers and floats are encoded in little-endian byte order.
+//! * Booleans are encoded as a single byte (1 for true, 0 for false).
+//! * Strings are encoded as their length (little-endian u32) followed by their bytes.
+//! * Vectors are encoded as their length (little-endian u32) followed by their elements.
+//! * Options are encoded as a tag byte (0 for none, 1 for some) followed by the value.
+//! * Tuples are encoded as their elements in order.
+//!
+//! Implementations for structs and enums can be derived with `#[derive(Serializable,
+//! Deserializable)]`. Structs are encoded as their fields in declaration order, while
+//! enums are encoded as the index of the variant in declaration order (little-endian
+//! u32), followed by the fields of the variant.
 use std::io::{Cursor, Read, Write};
 use std::str;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use protobuf::well_known_types::Empty;
 
-use super::error::Result;
+pub use ekiden_common_derive::{Deserializable, Serializable};
+
+use super::error::{Error, Result};
 
 /// A serializer for a specific data type.
 pub trait Serializable {
@@ -28,6 +44,23 @@ pub trait Serializable {
     /// the input stream (e.g., they should assume that there may be multiple
     /// fields in the stream).
     fn write_to(&self, writer: &mut Write) -> Result<usize>;
+
+    /// Write the elements of a vector of this type into given writer.
+    ///
+    /// Returns the number of bytes written. This is used by the implementation for
+    /// vectors, so that vectors of bytes can be written at once.
+    #[doc(hidden)]
+    fn write_elements_to(elements: &[Self], writer: &mut Write) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        let mut length = 0;
+        for element in elements {
+            length += element.write_to(writer)?;
+        }
+
+        Ok(length)
+    }
 }
 
 /// A deserializer for a specific data type.
@@ -51,6 +84,23 @@ pub trait Deserializable {
     fn read_from(reader: &mut Read) -> Result<Self>
     where
         Self: Sized;
+
+    /// Read the given number of elements of a vector of this type from reader.
+    ///
+    /// This is used by the implementation for vectors, so that vectors of bytes can be
+    /// read at once.
+    #[doc(hidden)]
+    fn read_elements_from(count: usize, reader: &mut Read) -> Result<Vec<Self>>
+    where
+        Self: Sized,
+    {
+        let mut elements = vec![];
+        for _ in 0..count {
+            elements.push(Self::read_from(reader)?);
+        }
+
+        Ok(elements)
+    }
 }
 
 impl Serializable for str {
@@ -81,25 +131,104 @@ impl Deserializable for String {
     }
 }
 
-impl Serializable for Vec<u8> {
+impl<T: Serializable> Serializable for Vec<T> {
     fn write_to(&self, writer: &mut Write) -> Result<usize> {
-        // Encode bytes as length (little-endian u32) + value.
+        // Encode vector as length (little-endian u32) + elements.
         writer.write_u32::<LittleEndian>(self.len() as u32)?;
-        writer.write(self)?;
-        Ok(4 + self.len())
+        Ok(4 + T::write_elements_to(self, writer)?)
     }
 }
 
-impl Deserializable for Vec<u8> {
+impl<T: Deserializable> Deserializable for Vec<T> {
     fn read_from(reader: &mut Read) -> Result<Self> {
-        // Decode bytes as length (little-endian u32) + value.
+        // Decode vector as length (little-endian u32) + elements.
         let length = reader.read_u32::<LittleEndian>()?;
-        let mut buffer = vec![0; length as usize];
-        reader.read_exact(&mut buffer)?;
-        Ok(buffer)
+        T::read_elements_from(length as usize, reader)
+    }
+}
+
+impl<T: Serializable> Serializable for Option<T> {
+    fn write_to(&self, writer: &mut Write) -> Result<usize> {
+        // Encode option as a tag byte (0 for none, 1 for some) + value.
+        match *self {
+            Some(ref value) => {
+                writer.write_u8(1)?;
+                Ok(1 + value.write_to(writer)?)
+            }
+            None => {
+                writer.write_u8(0)?;
+                Ok(1)
+            }
+        }
+    }
+}
+
+impl<T: Deserializable> Deserializable for Option<T> {
+    fn read_from(reader: &mut Read) -> Result<Self> {
+        // Decode option as a tag byte (0 for none, 1 for some) + value.
+        match reader.read_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::read_from(reader)?)),
+            _ => Err(Error::new("Malformed option tag")),
+        }
+    }
+}
+
+impl<T: Serializable> Serializable for Box<T> {
+    fn write_to(&self, writer: &mut Write) -> Result<usize> {
+        // Encode box as its contents.
+        (**self).write_to(writer)
     }
 }
 
+impl<T: Deserializable> Deserializable for Box<T> {
+    fn read_from(reader: &mut Read) -> Result<Self> {
+        // Decode box as its contents.
+        Ok(Box::new(T::read_from(reader)?))
+    }
+}
+
+impl Serializable for () {
+    fn write_to(&self, _writer: &mut Write) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Deserializable for () {
+    fn read_from(_reader: &mut Read) -> Result<Self> {
+        Ok(())
+    }
+}
+
+// Serializability for tuples, encoded as their elements in order.
+macro_rules! impl_serializable_tuple {
+    ($($name:ident : $index:tt),+) => {
+        impl<$($name: Serializable),+> Serializable for ($($name,)+) {
+            fn write_to(&self, writer: &mut Write) -> Result<usize> {
+                let mut length = 0;
+                $(
+                    length += self.$index.write_to(writer)?;
+                )+
+
+                Ok(length)
+            }
+        }
+
+        impl<$($name: Deserializable),+> Deserializable for ($($name,)+) {
+            fn read_from(reader: &mut Read) -> Result<Self> {
+                Ok(($($name::read_from(reader)?,)+))
+            }
+        }
+    }
+}
+
+impl_serializable_tuple!(A: 0);
+impl_serializable_tuple!(A: 0, B: 1);
+impl_serializable_tuple!(A: 0, B: 1, C: 2);
+impl_serializable_tuple!(A: 0, B: 1, C: 2, D: 3);
+impl_serializable_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_serializable_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+
 // Serializability for numeric types.
 macro_rules! impl_serializable_numeric {
     ($num_type:ty, $reader:ident, $writer:ident, 1) => {
@@ -133,7 +262,32 @@ macro_rules! impl_serializable_numeric {
     }
 }
 
-impl_serializable_numeric!(u8, read_u8, write_u8, 1);
+impl Serializable for u8 {
+    fn write_to(&self, writer: &mut Write) -> Result<usize> {
+        writer.write_u8(*self)?;
+        Ok(1)
+    }
+
+    fn write_elements_to(elements: &[Self], writer: &mut Write) -> Result<usize> {
+        // Encode bytes as they are.
+        writer.write_all(elements)?;
+        Ok(elements.len())
+    }
+}
+
+impl Deserializable for u8 {
+    fn read_from(reader: &mut Read) -> Result<Self> {
+        Ok(reader.read_u8()?)
+    }
+
+    fn read_elements_from(count: usize, reader: &mut Read) -> Result<Vec<Self>> {
+        // Decode bytes as they are.
+        let mut buffer = vec![0; count];
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
 impl_serializable_numeric!(u16, read_u16, write_u16, 2);
 impl_serializable_numeric!(u32, read_u32, write_u32, 4);
 impl_serializable_numeric!(u64, read_u64, write_u64, 8);
@@ -152,8 +306,13 @@ impl Serializable for bool {
 }
 
 impl Deserializable for bool {
+    /// Decode a boolean, where any non-zero byte is true.
+    ///
+    /// Earlier versions decoded booleans inverted, so stored values read back as their
+    /// negation. The encoding did not change, so stored booleans need no migration, but
+    /// contracts which compensated for the inversion must be updated.
     fn read_from(reader: &mut Read) -> Result<Self> {
-        Ok(reader.read_u8()? == 0)
+        Ok(reader.read_u8()? != 0)
     }
 }
 
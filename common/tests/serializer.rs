@@ -0,0 +1,140 @@
+#![feature(use_extern_macros)]
+
+extern crate ekiden_common;
+
+use std::fmt::Debug;
+
+use ekiden_common::serializer::{Deserializable, Serializable};
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+struct Unit;
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+struct Tuple(u8, String);
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+struct Named {
+    id: u32,
+    name: String,
+    tags: Vec<String>,
+    parent: Option<Box<Named>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+enum Shape {
+    Empty,
+    Circle(u64),
+    Rectangle { width: u64, height: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+struct Pair<A, B>
+where
+    A: Clone,
+{
+    first: A,
+    second: B,
+}
+
+#[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// Check that a value is encoded as expected and decoded back to the same value.
+fn check<T>(value: T, expected: Vec<u8>)
+where
+    T: Serializable + Deserializable + Debug + PartialEq,
+{
+    let encoded = value.write().unwrap();
+    assert_eq!(encoded, expected);
+    assert_eq!(T::read(&encoded).unwrap(), value);
+}
+
+#[test]
+fn test_bool() {
+    check(true, vec![1]);
+    check(false, vec![0]);
+}
+
+#[test]
+fn test_bytes() {
+    // Vectors of bytes are encoded as their length followed by the bytes themselves.
+    check(vec![1u8, 2, 3], vec![3, 0, 0, 0, 1, 2, 3]);
+    check(Vec::<u8>::new(), vec![0, 0, 0, 0]);
+    check(vec![vec![7u8], vec![]], vec![2, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0]);
+
+    // Truncated bytes are rejected.
+    assert!(Vec::<u8>::read(&vec![3, 0, 0, 0, 1, 2]).is_err());
+}
+
+#[test]
+fn test_derive_struct() {
+    check(Unit, vec![]);
+    check(Tuple(1, "a".to_owned()), vec![1, 1, 0, 0, 0, b'a']);
+
+    // Fields are encoded in declaration order.
+    let child = Named {
+        id: 2,
+        name: "b".to_owned(),
+        tags: vec!["x".to_owned()],
+        parent: Some(Box::new(Named {
+            id: 1,
+            name: "a".to_owned(),
+            tags: vec![],
+            parent: None,
+        })),
+    };
+    check(
+        child,
+        vec![
+            2, 0, 0, 0, 1, 0, 0, 0, b'b', 1, 0, 0, 0, 1, 0, 0, 0, b'x', 1, 1, 0, 0, 0, 1, 0,
+            0, 0, b'a', 0, 0, 0, 0, 0,
+        ],
+    );
+
+    // Truncated structs are rejected.
+    assert!(Tuple::read(&vec![1, 1, 0, 0, 0]).is_err());
+}
+
+#[test]
+fn test_derive_enum() {
+    // Variants are encoded as their index in declaration order, followed by their fields.
+    check(Shape::Empty, vec![0, 0, 0, 0]);
+    check(Shape::Circle(5), vec![1, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]);
+    check(
+        Shape::Rectangle {
+            width: 2,
+            height: 3,
+        },
+        vec![2, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0],
+    );
+
+    // Unknown variants are rejected.
+    assert!(Shape::read(&vec![3, 0, 0, 0]).is_err());
+}
+
+#[test]
+fn test_derive_generics() {
+    check(
+        Pair {
+            first: 1u8,
+            second: "a".to_owned(),
+        },
+        vec![1, 1, 0, 0, 0, b'a'],
+    );
+    check(
+        Pair {
+            first: Shape::Circle(1),
+            second: (true, 2u16),
+        },
+        vec![1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0],
+    );
+
+    check::<Either<u8, String>>(Either::Left(1), vec![0, 0, 0, 0, 1]);
+    check::<Either<u8, String>>(
+        Either::Right("a".to_owned()),
+        vec![1, 0, 0, 0, 1, 0, 0, 0, b'a'],
+    );
+}
@@ -29,6 +29,7 @@ mod ias;
 mod instrumentation;
 mod handlers;
 mod server;
+mod state_cache;
 
 use std::path::Path;
 use std::thread;
@@ -137,6 +138,23 @@ fn main() {
                 .default_value("identity.pb")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .help("Fold diffs into a checkpoint when requested by consensus (enable on a single compute node)"),
+        )
+        .arg(
+            Arg::with_name("state-cache-dir")
+                .long("state-cache-dir")
+                .help("Directory for persisting the cached contract state across restarts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("paged-state-dir")
+                .long("paged-state-dir")
+                .help("Directory for storing paged contract state (must be shared by all compute nodes of the contract)")
+                .takes_value(true),
+        )
         .get_matches();
 
     let port = value_t!(matches, "port", u16).unwrap_or(9001);
@@ -186,6 +204,9 @@ fn main() {
         value_t!(matches, "max-batch-timeout", u64).unwrap_or(1000) * 1_000_000,
         ias,
         matches.value_of("identity-file").unwrap_or("identity.pb"),
+        matches.is_present("checkpoint"),
+        matches.value_of("state-cache-dir"),
+        matches.value_of("paged-state-dir"),
     )));
     let num_threads = value_t!(matches, "grpc-threads", usize).unwrap();
     server.http.set_cpu_pool_threads(num_threads);
@@ -13,19 +13,26 @@ use time;
 use std;
 use std::error::Error as StdError;
 use std::fmt::Write;
-use std::sync::Mutex;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 
 use ekiden_compute_api::{CallContractRequest, CallContractResponse, Compute};
 use ekiden_consensus_api::{self, Consensus, ConsensusClient};
 use ekiden_core::enclave::api::IdentityProof;
+use ekiden_core::enclave::consensus_hash;
 use ekiden_core::enclave::quote;
 use ekiden_core::error::{Error, Result};
 use ekiden_core::rpc::api;
 use ekiden_untrusted::{Enclave, EnclaveDb, EnclaveIdentity, EnclaveRpc};
+use ekiden_untrusted::db::{set_key_value_store, FileKeyValueStore};
 
 use super::ias::IAS;
 use super::instrumentation;
+use super::state_cache::StateCache;
+
+/// Maximum number of times a batch is executed again when its state update was rejected
+/// because another compute node updated the state first.
+const MAX_STALE_STATE_RETRIES: usize = 3;
 
 /// This struct describes a call sent to the worker thread.
 struct QueuedRequest {
@@ -48,25 +55,41 @@ struct QueuedResponse<'a> {
 struct CachedStateInitialized {
     encrypted_state: Vec<u8>,
     height: u64,
+    /// Consensus hash of the state.
+    state_hash: Vec<u8>,
 }
 
 struct ComputeServerWorker {
     /// Consensus client.
     consensus: Option<ConsensusClient>,
+    /// Consensus node host, used for subscribing to state updates.
+    consensus_host: String,
+    /// Consensus node port, used for subscribing to state updates.
+    consensus_port: u16,
+    /// State updates received from the consensus subscription. None if not subscribed.
+    state_updates: Option<Receiver<ekiden_consensus_api::GetDiffsResponse>>,
     /// Contract running in an enclave.
     contract: Enclave,
     /// Enclave identity proof.
-    #[allow(dead_code)]
     identity_proof: IdentityProof,
+    /// Identifier of the contract's state in consensus (MRENCLAVE of the enclave).
+    contract_id: Vec<u8>,
     /// Cached state reconstituted from checkpoint and diffs. None if
     /// cache or state is uninitialized.
     cached_state: Option<CachedStateInitialized>,
+    /// Persistent copy of the cached state. None if not configured.
+    state_cache: Option<StateCache>,
     /// Instrumentation objects.
     ins: instrumentation::WorkerMetrics,
     /// Maximum batch size.
     max_batch_size: usize,
     /// Maximum batch timeout.
     max_batch_timeout: u64,
+    /// True if this node folds diffs into checkpoints when consensus requests it.
+    checkpoint: bool,
+    /// True if the state must be fetched from consensus for the next batch instead of
+    /// relying on the subscription, e.g., after a state update was rejected as stale.
+    poll_state: bool,
 }
 
 impl ComputeServerWorker {
@@ -78,16 +101,36 @@ impl ComputeServerWorker {
         max_batch_timeout: u64,
         ias: &IAS,
         saved_identity_path: &str,
+        checkpoint: bool,
+        state_cache_dir: Option<&str>,
     ) -> Self {
-        let (contract, identity_proof) =
+        let (contract, identity_proof, contract_id) =
             Self::create_contract(contract_filename, ias, saved_identity_path);
-        ComputeServerWorker {
+        let state_cache = match state_cache_dir {
+            Some(dir) if !cfg!(feature = "no_cache") => match StateCache::open(dir, &contract_id) {
+                Ok(state_cache) => Some(state_cache),
+                Err(error) => {
+                    eprintln!("compute: failed to open state cache {:?}", error);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let mut worker = ComputeServerWorker {
             contract,
             identity_proof,
+            contract_id,
             cached_state: None,
+            state_cache,
             ins: instrumentation::WorkerMetrics::new(),
             max_batch_size: max_batch_size,
             max_batch_timeout: max_batch_timeout,
+            checkpoint: checkpoint,
+            poll_state: false,
+            consensus_host: consensus_host.to_owned(),
+            consensus_port: consensus_port,
+            state_updates: None,
             // Connect to consensus node
             // TODO: Use TLS client.
             consensus: match ConsensusClient::new_plain(
@@ -104,7 +147,10 @@ impl ComputeServerWorker {
                     None
                 }
             },
-        }
+        };
+
+        worker.load_cached_state();
+        worker
     }
 
     /// Create an instance of the contract.
@@ -112,7 +158,7 @@ impl ComputeServerWorker {
         contract_filename: &str,
         ias: &IAS,
         saved_identity_path: &str,
-    ) -> (Enclave, IdentityProof) {
+    ) -> (Enclave, IdentityProof, Vec<u8>) {
         // TODO: Handle contract initialization errors.
         let contract = Enclave::new(contract_filename).unwrap();
 
@@ -130,7 +176,7 @@ impl ComputeServerWorker {
 
         println!("Loaded contract with MRENCLAVE: {}", mr_enclave);
 
-        (contract, identity_proof)
+        (contract, identity_proof, iai.mr_enclave.to_vec())
     }
 
     #[cfg(not(feature = "no_cache"))]
@@ -145,10 +191,19 @@ impl ComputeServerWorker {
         self.cached_state = Some(CachedStateInitialized {
             encrypted_state: checkpoint.get_payload().to_vec(),
             height: checkpoint.get_height(),
+            state_hash: vec![],
         });
         Ok(())
     }
 
+    fn set_cached_state_hash(&mut self, state_hash: &[u8]) -> Result<()> {
+        let csi = self.cached_state.as_mut().ok_or(Error::new(
+            "set_cached_state_hash called with uninitialized cached state",
+        ))?;
+        csi.state_hash = state_hash.to_vec();
+        Ok(())
+    }
+
     fn advance_cached_state(&mut self, diffs: &[Vec<u8>]) -> Result<Vec<u8>> {
         #[cfg(feature = "no_diffs")]
         assert!(
@@ -168,71 +223,486 @@ impl ComputeServerWorker {
         Ok(csi.encrypted_state.clone())
     }
 
-    fn call_contract_batch_fallible<'a>(
+    /// Load the cached state from the state cache and bring it up to date.
+    ///
+    /// The cache is discarded if it can't be validated against consensus, in which case
+    /// the state is fetched from scratch on the next batch.
+    fn load_cached_state(&mut self) {
+        let cached = match self.state_cache.as_mut().map(|state_cache| state_cache.load()) {
+            Some(Ok(Some(cached))) => cached,
+            Some(Ok(None)) | None => return,
+            Some(Err(error)) => {
+                eprintln!("compute: failed to load state cache {:?}", error);
+                self.clear_cached_state();
+                return;
+            }
+        };
+
+        self.cached_state = Some(CachedStateInitialized {
+            encrypted_state: cached.encrypted_state,
+            height: cached.height,
+            state_hash: cached.state_hash,
+        });
+
+        match self.validate_cached_state() {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("compute: cached state does not match consensus, discarding it");
+                self.clear_cached_state();
+            }
+            Err(error) => {
+                eprintln!("compute: failed to validate cached state {:?}", error);
+                self.clear_cached_state();
+            }
+        }
+    }
+
+    /// Check that the cached state is part of the consensus state and apply the diffs
+    /// that are missing from it.
+    ///
+    /// The consensus hash of the checkpoint and the diffs up to the cached height must
+    /// match the hash of the cached state. Returns false if it does not, or if the
+    /// cached state is not between the current checkpoint and the current state, e.g.,
+    /// because the state was checkpointed or reset since it was cached.
+    fn validate_cached_state(&mut self) -> Result<bool> {
+        let (height, state_hash) = match self.cached_state.as_ref() {
+            Some(csi) => (csi.height, csi.state_hash.clone()),
+            None => return Ok(false),
+        };
+
+        let (_, consensus_response, _) = self.consensus
+            .as_ref()
+            .ok_or(Error::new("No consensus client"))?
+            .get(grpc::RequestOptions::new(), {
+                let mut consensus_request = ekiden_consensus_api::GetRequest::new();
+                consensus_request.set_contract_id(self.contract_id.clone());
+                consensus_request
+            })
+            .wait()?;
+
+        let checkpoint = consensus_response.get_checkpoint();
+        let diffs = consensus_response.get_diffs();
+        if height < checkpoint.get_height()
+            || height > checkpoint.get_height() + diffs.len() as u64
+        {
+            return Ok(false);
+        }
+
+        let cached_diffs = (height - checkpoint.get_height()) as usize;
+        let cached_state_hash = consensus_hash::contract_state_hash(
+            checkpoint.get_height(),
+            checkpoint.get_payload(),
+            &diffs[..cached_diffs],
+        );
+        if cached_state_hash != state_hash {
+            return Ok(false);
+        }
+
+        self.advance_cached_state(&diffs[cached_diffs..])?;
+        self.set_cached_state_hash(consensus_response.get_state_hash())?;
+        self.store_cached_state();
+        self.checkpoint_if_due(consensus_response.get_checkpoint_due());
+
+        Ok(true)
+    }
+
+    /// Persist the cached state to the state cache, if configured.
+    ///
+    /// The state cache is only an optimization, so errors are only reported.
+    fn store_cached_state(&mut self) {
+        let csi = match self.cached_state.as_ref() {
+            Some(csi) => csi,
+            None => return,
+        };
+
+        if let Some(ref mut state_cache) = self.state_cache {
+            if let Err(error) = state_cache.store(&csi.encrypted_state, csi.height, &csi.state_hash)
+            {
+                eprintln!("compute: failed to store cached state {:?}", error);
+            }
+        }
+    }
+
+    /// Discard the cached state and remove it from the state cache.
+    fn clear_cached_state(&mut self) {
+        self.cached_state = None;
+        if let Some(state_cache) = self.state_cache.as_mut() {
+            if let Err(error) = state_cache.clear() {
+                eprintln!("compute: failed to clear state cache {:?}", error);
+            }
+        }
+    }
+
+    /// Apply a state update from consensus to the cached state.
+    ///
+    /// The update may overlap with the cached state, in which case only the diffs that
+    /// are not yet applied are. Returns false if the update does not reach back to the
+    /// cached state or is inconsistent, in which case the full state must be fetched.
+    fn apply_state_update(
         &mut self,
-        request_batch: &'a mut [QueuedRequest],
-    ) -> Result<Vec<QueuedResponse<'a>>> {
-        // Get state updates from consensus
-        let encrypted_state_opt = if self.consensus.is_some() {
-            let _consensus_get_timer = self.ins.consensus_get_time.start_timer();
-
-            #[cfg(not(feature = "no_cache"))]
-            let cached_state_height = self.get_cached_state_height();
-            #[cfg(feature = "no_cache")]
-            let cached_state_height = None;
-
-            match cached_state_height {
-                Some(height) => {
-                    let (_, consensus_response, _) = self.consensus
-                        .as_ref()
-                        .unwrap()
-                        .get_diffs(grpc::RequestOptions::new(), {
-                            let mut consensus_request =
-                                ekiden_consensus_api::GetDiffsRequest::new();
-                            consensus_request.set_since_height(height);
-                            consensus_request
-                        })
-                        .wait()?;
-                    if consensus_response.has_checkpoint() {
-                        self.set_cached_state(consensus_response.get_checkpoint())?;
+        update: &ekiden_consensus_api::GetDiffsResponse,
+    ) -> Result<bool> {
+        // Ignore updates which are older than the cached state.
+        if let Some(ref csi) = self.cached_state {
+            if update.get_height() < csi.height {
+                return Ok(true);
+            }
+        }
+
+        if update.has_checkpoint() {
+            self.set_cached_state(update.get_checkpoint())?;
+        }
+
+        let cached_state_height = match self.cached_state.as_ref() {
+            Some(csi) => csi.height,
+            None => return Ok(false),
+        };
+        let diffs = update.get_diffs();
+        let diffs_height = match update.get_height().checked_sub(diffs.len() as u64) {
+            Some(diffs_height) => diffs_height,
+            None => return Ok(false),
+        };
+        if cached_state_height < diffs_height {
+            return Ok(false);
+        }
+
+        self.advance_cached_state(&diffs[(cached_state_height - diffs_height) as usize..])?;
+        self.set_cached_state_hash(update.get_state_hash())?;
+        self.store_cached_state();
+        self.checkpoint_if_due(update.get_checkpoint_due());
+
+        Ok(true)
+    }
+
+    /// Subscribe to committed state updates from consensus.
+    ///
+    /// Updates are received in a background thread, so that fetching the state does
+    /// not need a round trip to consensus.
+    fn subscribe(&mut self) {
+        let since_height = self.cached_state.as_ref().map_or(0, |csi| csi.height);
+        let consensus_host = self.consensus_host.clone();
+        let consensus_port = self.consensus_port;
+        let contract_id = self.contract_id.clone();
+
+        let (update_sender, update_receiver) = channel();
+        std::thread::spawn(move || {
+            let consensus = match ConsensusClient::new_plain(
+                &consensus_host,
+                consensus_port,
+                Default::default(),
+            ) {
+                Ok(client) => client,
+                _ => return,
+            };
+
+            let mut consensus_request = ekiden_consensus_api::SubscribeRequest::new();
+            consensus_request.set_contract_id(contract_id);
+            consensus_request.set_since_height(since_height);
+            for update in consensus
+                .subscribe(grpc::RequestOptions::new(), consensus_request)
+                .wait_drop_metadata()
+            {
+                match update {
+                    Ok(update) => {
+                        if update_sender.send(update).is_err() {
+                            // Worker is no longer interested in updates.
+                            return;
+                        }
                     }
-                    Some(self.advance_cached_state(consensus_response.get_diffs())?)
-                }
-                None => {
-                    if let Ok((_, consensus_response, _)) = self.consensus
-                        .as_ref()
-                        .unwrap()
-                        .get(
-                            grpc::RequestOptions::new(),
-                            ekiden_consensus_api::GetRequest::new(),
-                        )
-                        .wait()
-                    {
-                        self.set_cached_state(consensus_response.get_checkpoint())?;
-                        Some(self.advance_cached_state(consensus_response.get_diffs())?)
-                    } else {
-                        // We should bail if there was an error other
-                        // than the state not being initialized. But
-                        // don't go fixing this. There's another
-                        // resolution planned in #95.
-                        None
+                    Err(error) => {
+                        eprintln!("compute: state subscription failed {:?}", error);
+                        return;
                     }
                 }
             }
+        });
+
+        self.state_updates = Some(update_receiver);
+    }
+
+    /// Apply all state updates received from the consensus subscription.
+    ///
+    /// Returns false if the cached state may not be current, in which case the state
+    /// must be fetched from consensus.
+    fn receive_state_updates(&mut self) -> Result<bool> {
+        if self.state_updates.is_none() {
+            self.subscribe();
+            return Ok(false);
+        }
+
+        loop {
+            let update = match self.state_updates.as_ref().unwrap().try_recv() {
+                Ok(update) => update,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // Subscription failed, subscribe again on the next fetch.
+                    self.state_updates = None;
+                    return Ok(false);
+                }
+            };
+
+            if !self.apply_state_update(&update)? {
+                // Updates were missed, so subscribe again from the current state.
+                self.state_updates = None;
+                return Ok(false);
+            }
+        }
+
+        Ok(self.cached_state.is_some())
+    }
+
+    /// Fetch the current state from consensus and update the cached state.
+    ///
+    /// Unless `poll` is set, the cached state is kept current by the consensus
+    /// subscription and consensus is only queried if the subscription is not available.
+    ///
+    /// Returns the current encrypted state or None if there is no state.
+    fn fetch_state(&mut self, poll: bool) -> Result<Option<Vec<u8>>> {
+        if self.consensus.is_none() {
+            return Ok(None);
+        }
+
+        if !poll && !cfg!(feature = "no_cache") && self.receive_state_updates()? {
+            let csi = self.cached_state.as_ref().unwrap();
+            return Ok(Some(csi.encrypted_state.clone()));
+        }
+
+        let _consensus_get_timer = self.ins.consensus_get_time.start_timer();
+
+        #[cfg(not(feature = "no_cache"))]
+        let cached_state_height = self.get_cached_state_height();
+        #[cfg(feature = "no_cache")]
+        let cached_state_height = None;
+
+        if let Some(height) = cached_state_height {
+            let (_, consensus_response, _) = self.consensus
+                .as_ref()
+                .unwrap()
+                .get_diffs(grpc::RequestOptions::new(), {
+                    let mut consensus_request = ekiden_consensus_api::GetDiffsRequest::new();
+                    consensus_request.set_contract_id(self.contract_id.clone());
+                    consensus_request.set_since_height(height);
+                    consensus_request
+                })
+                .wait()?;
+            if self.apply_state_update(&consensus_response)? {
+                let csi = self.cached_state.as_ref().unwrap();
+                return Ok(Some(csi.encrypted_state.clone()));
+            }
+
+            // The update does not apply to the cached state, so fetch the full state.
+            self.clear_cached_state();
+        }
+
+        if let Ok((_, consensus_response, _)) = self.consensus
+            .as_ref()
+            .unwrap()
+            .get(grpc::RequestOptions::new(), {
+                let mut consensus_request = ekiden_consensus_api::GetRequest::new();
+                consensus_request.set_contract_id(self.contract_id.clone());
+                consensus_request
+            })
+            .wait()
+        {
+            self.set_cached_state(consensus_response.get_checkpoint())?;
+            let encrypted_state = self.advance_cached_state(consensus_response.get_diffs())?;
+            self.set_cached_state_hash(consensus_response.get_state_hash())?;
+            self.store_cached_state();
+            self.checkpoint_if_due(consensus_response.get_checkpoint_due());
+            Ok(Some(encrypted_state))
         } else {
-            None
+            // We should bail if there was an error other
+            // than the state not being initialized. But
+            // don't go fixing this. There's another
+            // resolution planned in #95.
+            Ok(None)
+        }
+    }
+
+    /// Submit a checkpoint if consensus requested one and this node is designated to
+    /// produce checkpoints.
+    ///
+    /// Failing to checkpoint does not affect the current batch, so errors are only
+    /// reported.
+    fn checkpoint_if_due(&mut self, checkpoint_due: bool) {
+        if !self.checkpoint || !checkpoint_due {
+            return;
+        }
+
+        if let Err(error) = self.submit_checkpoint() {
+            eprintln!("compute: failed to submit checkpoint {:?}", error);
+        }
+    }
+
+    /// Submit a new checkpoint folded from the current consensus state.
+    ///
+    /// The enclave folds the diffs on top of the last checkpoint itself and binds the
+    /// signature to the consensus hash of its input, so the checkpoint is fetched from
+    /// consensus instead of using the cached state.
+    fn submit_checkpoint(&mut self) -> Result<()> {
+        let (_, consensus_response, _) = self.consensus
+            .as_ref()
+            .unwrap()
+            .get(grpc::RequestOptions::new(), {
+                let mut consensus_request = ekiden_consensus_api::GetRequest::new();
+                consensus_request.set_contract_id(self.contract_id.clone());
+                consensus_request
+            })
+            .wait()?;
+
+        let diffs = consensus_response.get_diffs();
+        if diffs.is_empty() {
+            return Ok(());
+        }
+
+        let checkpoint_height = consensus_response.get_checkpoint().get_height();
+        let height = checkpoint_height + diffs.len() as u64;
+        let (state, signature) = self.contract.db_state_checkpoint(
+            &consensus_response.get_checkpoint().get_payload().to_vec(),
+            checkpoint_height,
+            diffs,
+        )?;
+
+        let _consensus_set_timer = self.ins.consensus_set_time.start_timer();
+        let result = self.consensus
+            .as_ref()
+            .unwrap()
+            .checkpoint(grpc::RequestOptions::new(), {
+                let mut checkpoint_req = ekiden_consensus_api::CheckpointRequest::new();
+                checkpoint_req.set_payload(state.clone());
+                checkpoint_req.set_base_height(height);
+                checkpoint_req.set_base_state_hash(consensus_response.get_state_hash().to_vec());
+                checkpoint_req.set_identity_proof(self.identity_proof.clone());
+                checkpoint_req.set_contract_id(self.contract_id.clone());
+                checkpoint_req.set_signature(signature);
+                checkpoint_req
+            })
+            .wait();
+
+        match result {
+            Ok((_, checkpoint_response, _)) => {
+                self.cached_state = Some(CachedStateInitialized {
+                    encrypted_state: state,
+                    height,
+                    state_hash: checkpoint_response.get_state_hash().to_vec(),
+                });
+                self.store_cached_state();
+                Ok(())
+            }
+            // Another node updated the state first. Consensus will request the
+            // checkpoint again.
+            Err(grpc::Error::GrpcMessage(ref error))
+                if error.grpc_status == ekiden_consensus_api::GRPC_STATUS_STALE_STATE as i32 =>
+            {
+                Ok(())
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Submit the state produced by the last contract call to consensus.
+    ///
+    /// If `use_diff` is set, only the changes made to the state are submitted as a diff.
+    /// Otherwise the full state replaces the consensus state.
+    ///
+    /// Returns false if consensus rejected the update because it was not computed on the
+    /// current state.
+    fn submit_state(&mut self, use_diff: bool) -> Result<bool> {
+        // State updates are computed on the cached state and produce the next height.
+        let (base_height, base_state_hash) = match self.cached_state.as_ref() {
+            Some(csi) => (csi.height, csi.state_hash.clone()),
+            None => (0, vec![]),
         };
 
-        #[cfg(not(feature = "no_diffs"))]
-        let orig_encrypted_state_opt = encrypted_state_opt.clone();
-        #[cfg(feature = "no_diffs")]
-        let orig_encrypted_state_opt = None;
+        let result = if use_diff {
+            // Check if any changes were made. In case no changes were made, this means that
+            // no request caused a state update and thus no state update is required.
+            let (diff_res, diff_signature) = self.contract
+                .db_state_get_diff(base_height + 1, &base_state_hash)?;
+            if diff_res.is_empty() {
+                return Ok(true);
+            }
+
+            let _consensus_set_timer = self.ins.consensus_set_time.start_timer();
+            self.consensus
+                .as_ref()
+                .unwrap()
+                .add_diff(grpc::RequestOptions::new(), {
+                    let mut add_diff_req = ekiden_consensus_api::AddDiffRequest::new();
+                    add_diff_req.set_payload(diff_res);
+                    add_diff_req.set_base_height(base_height);
+                    add_diff_req.set_base_state_hash(base_state_hash);
+                    add_diff_req.set_identity_proof(self.identity_proof.clone());
+                    add_diff_req.set_contract_id(self.contract_id.clone());
+                    add_diff_req.set_signature(diff_signature);
+                    add_diff_req
+                })
+                .wait()
+                .map(|_| ())
+        } else {
+            // Check if any state was produced. In case no state was produced, this means
+            // that no request caused a state update and thus no state update is required.
+            let (encrypted_state, state_signature) = self.contract
+                .db_state_get(base_height + 1, &base_state_hash)?;
+            if encrypted_state.is_empty() {
+                return Ok(true);
+            }
+
+            let mut consensus_replace_request = ekiden_consensus_api::ReplaceRequest::new();
+            consensus_replace_request.set_payload(encrypted_state);
+            consensus_replace_request.set_base_height(base_height);
+            consensus_replace_request.set_base_state_hash(base_state_hash);
+            consensus_replace_request.set_identity_proof(self.identity_proof.clone());
+            consensus_replace_request.set_contract_id(self.contract_id.clone());
+            consensus_replace_request.set_signature(state_signature);
+
+            let _consensus_set_timer = self.ins.consensus_set_time.start_timer();
+            self.consensus
+                .as_ref()
+                .unwrap()
+                .replace(grpc::RequestOptions::new(), consensus_replace_request)
+                .wait()
+                .map(|_| ())
+        };
+
+        match result {
+            Ok(()) => Ok(true),
+            Err(grpc::Error::GrpcMessage(ref error))
+                if error.grpc_status == ekiden_consensus_api::GRPC_STATUS_STALE_STATE as i32 =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Call the contract with a batch of requests on the current state.
+    ///
+    /// If `poll` is set, the current state is fetched from consensus instead of relying
+    /// on the subscription. If `retry` is set, the contract executes the requests of its
+    /// last batch again instead, as requests made over a secure channel cannot be opened
+    /// twice. Returns None if the resulting state update was not computed on the current
+    /// state.
+    fn execute_batch(
+        &mut self,
+        request_batch: &[QueuedRequest],
+        poll: bool,
+        retry: bool,
+    ) -> Result<Option<api::EnclaveResponse>> {
+        // Get state updates from consensus
+        let encrypted_state_opt = self.fetch_state(poll)?;
+
+        // Diffs are relative to the current state, so the state must be replaced if there
+        // is none yet.
+        let use_diff = !cfg!(feature = "no_diffs") && encrypted_state_opt.is_some();
 
         // Call contract with batch of requests.
         let mut enclave_request = api::EnclaveRequest::new();
 
         // Prepare batch of requests.
-        {
+        if retry {
+            enclave_request.set_retry(true);
+        } else {
             let client_requests = enclave_request.mut_client_request();
             for ref queued_request in request_batch.iter() {
                 // TODO: Why doesn't enclave request contain bytes directly?
@@ -263,6 +733,38 @@ impl ComputeServerWorker {
             ));
         }
 
+        if !self.submit_state(use_diff)? {
+            return Ok(None);
+        }
+
+        Ok(Some(enclave_response))
+    }
+
+    fn call_contract_batch_fallible<'a>(
+        &mut self,
+        request_batch: &'a mut [QueuedRequest],
+    ) -> Result<Vec<QueuedResponse<'a>>> {
+        let mut poll = self.poll_state;
+        let mut retries = 0;
+        let enclave_response = loop {
+            let retry = retries > 0;
+            if let Some(enclave_response) = self.execute_batch(request_batch, poll, retry)? {
+                self.poll_state = false;
+                break enclave_response;
+            }
+
+            // Another compute node updated the state while we were executing the batch,
+            // so our state update was rejected. Execute the batch again on the current
+            // state. The subscription may lag behind the update that made ours stale, so
+            // poll consensus for the state.
+            self.poll_state = true;
+            poll = true;
+            retries += 1;
+            if retries > MAX_STALE_STATE_RETRIES {
+                return Err(Error::new("State update was rejected as stale too many times"));
+            }
+        };
+
         let mut response_batch = vec![];
         for (index, queued_request) in request_batch.iter_mut().enumerate() {
             let mut response = CallContractResponse::new();
@@ -276,39 +778,6 @@ impl ComputeServerWorker {
             });
         }
 
-        // Check if any state was produced. In case no state was produced, this means that
-        // no request caused a state update and thus no state update is required.
-        let encrypted_state = self.contract.db_state_get()?;
-        if !encrypted_state.is_empty() {
-            let _consensus_set_timer = self.ins.consensus_set_time.start_timer();
-            match orig_encrypted_state_opt {
-                Some(orig_encrypted_state) => {
-                    let diff_res = self.contract
-                        .db_state_diff(&orig_encrypted_state, &encrypted_state)?;
-
-                    self.consensus
-                        .as_ref()
-                        .unwrap()
-                        .add_diff(grpc::RequestOptions::new(), {
-                            let mut add_diff_req = ekiden_consensus_api::AddDiffRequest::new();
-                            add_diff_req.set_payload(diff_res);
-                            add_diff_req
-                        })
-                        .wait()?;
-                }
-                None => {
-                    let mut consensus_replace_request = ekiden_consensus_api::ReplaceRequest::new();
-                    consensus_replace_request.set_payload(encrypted_state);
-
-                    self.consensus
-                        .as_ref()
-                        .unwrap()
-                        .replace(grpc::RequestOptions::new(), consensus_replace_request)
-                        .wait()?;
-                }
-            }
-        }
-
         Ok(response_batch)
     }
 
@@ -403,10 +872,21 @@ impl ComputeServerImpl {
         max_batch_timeout: u64,
         ias: IAS,
         saved_identity_path: &str,
+        checkpoint: bool,
+        state_cache_dir: Option<&str>,
+        paged_state_dir: Option<&str>,
     ) -> Self {
+        // Paged contract state must be stored before the contract is loaded, so that
+        // it is shared with the other compute nodes and survives restarts.
+        if let Some(dir) = paged_state_dir {
+            let store = FileKeyValueStore::open(dir).expect("Failed to open paged state store");
+            set_key_value_store(Arc::new(store));
+        }
+
         let contract_filename_owned = String::from(contract_filename);
         let consensus_host_owned = String::from(consensus_host);
         let saved_identity_path_owned = String::from(saved_identity_path);
+        let state_cache_dir_owned = state_cache_dir.map(String::from);
 
         let (request_sender, request_receiver) = channel();
         // move request_receiver
@@ -419,6 +899,8 @@ impl ComputeServerImpl {
                 max_batch_timeout,
                 &ias,
                 &saved_identity_path_owned,
+                checkpoint,
+                state_cache_dir_owned.as_ref().map(String::as_str),
             ).work(request_receiver);
         });
 
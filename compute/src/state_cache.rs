@@ -0,0 +1,226 @@
+//! Persistent on-disk cache of the contract state.
+//!
+//! The cached state is stored in a file named after the contract identifier. It holds
+//! the format version, the height of the state, its consensus hash and the encrypted
+//! state itself, encoded with the common serializer. This allows a restarted compute
+//! node to only fetch the diffs it is missing from consensus.
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ekiden_core::error::{Error, Result};
+use ekiden_core::serializer::{Deserializable, Serializable};
+
+/// Version of the cache file format.
+const CACHE_VERSION: u32 = 1;
+/// Extension of the cache file.
+const CACHE_FILE_EXTENSION: &'static str = "state";
+/// Extension of the temporary file used while writing the cache.
+const CACHE_TEMP_FILE_EXTENSION: &'static str = "state.tmp";
+
+/// State loaded from the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedState {
+    /// Encrypted contract state.
+    pub encrypted_state: Vec<u8>,
+    /// Height of the state.
+    pub height: u64,
+    /// Consensus hash of the state.
+    pub state_hash: Vec<u8>,
+}
+
+pub struct StateCache {
+    /// Cache file.
+    path: PathBuf,
+    /// Temporary file used while writing the cache.
+    temp_path: PathBuf,
+    /// Height and hash of the state that was last stored, used to skip redundant writes.
+    stored: Option<(u64, Vec<u8>)>,
+}
+
+impl StateCache {
+    /// Open the cache for a contract in the given directory, creating the directory if
+    /// it does not exist.
+    pub fn open<P: AsRef<Path>>(dir: P, contract_id: &[u8]) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut name = String::new();
+        for &byte in contract_id {
+            write!(&mut name, "{:02x}", byte).unwrap();
+        }
+
+        let dir = dir.as_ref();
+        Ok(StateCache {
+            path: dir.join(&name).with_extension(CACHE_FILE_EXTENSION),
+            temp_path: dir.join(&name).with_extension(CACHE_TEMP_FILE_EXTENSION),
+            stored: None,
+        })
+    }
+
+    /// Load the cached state.
+    ///
+    /// Returns None if nothing is cached.
+    pub fn load(&mut self) -> Result<Option<CachedState>> {
+        let mut buffer = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut buffer)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let (version, height, state_hash, encrypted_state): (u32, u64, Vec<u8>, Vec<u8>) =
+            Deserializable::read(&buffer)?;
+        if version != CACHE_VERSION {
+            return Err(Error::new(format!(
+                "Unsupported state cache version {} (expected {})",
+                version, CACHE_VERSION
+            )));
+        }
+
+        self.stored = Some((height, state_hash.clone()));
+
+        Ok(Some(CachedState {
+            encrypted_state,
+            height,
+            state_hash,
+        }))
+    }
+
+    /// Atomically replace the cached state.
+    ///
+    /// Nothing is written if the state at the same height and with the same hash is
+    /// already cached.
+    pub fn store(&mut self, encrypted_state: &[u8], height: u64, state_hash: &[u8]) -> Result<()> {
+        if let Some((stored_height, ref stored_hash)) = self.stored {
+            if stored_height == height && &stored_hash[..] == state_hash {
+                return Ok(());
+            }
+        }
+
+        let state = (
+            CACHE_VERSION,
+            height,
+            state_hash.to_vec(),
+            encrypted_state.to_vec(),
+        ).write()?;
+
+        {
+            let mut file = File::create(&self.temp_path)?;
+            file.write_all(&state)?;
+            file.sync_all()?;
+        }
+        fs::rename(&self.temp_path, &self.path)?;
+
+        self.stored = Some((height, state_hash.to_vec()));
+
+        Ok(())
+    }
+
+    /// Remove the cached state.
+    pub fn clear(&mut self) -> Result<()> {
+        self.stored = None;
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use ekiden_core::serializer::Serializable;
+
+    use super::{CachedState, StateCache, CACHE_VERSION};
+
+    static TEST_DIR_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Path of a fresh directory for a test.
+    fn test_dir() -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "ekiden-compute-state-cache-{}-{}",
+            process::id(),
+            TEST_DIR_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_store_load() {
+        let dir = test_dir();
+
+        let mut cache = StateCache::open(&dir, &[0xab; 32]).unwrap();
+        assert_eq!(cache.load().unwrap(), None);
+
+        cache.store(b"state", 3, b"hash").unwrap();
+        cache.store(b"newer state", 4, b"newer hash").unwrap();
+
+        // A newly opened cache sees the latest state.
+        let mut cache = StateCache::open(&dir, &[0xab; 32]).unwrap();
+        assert_eq!(
+            cache.load().unwrap(),
+            Some(CachedState {
+                encrypted_state: b"newer state".to_vec(),
+                height: 4,
+                state_hash: b"newer hash".to_vec(),
+            })
+        );
+
+        // Other contracts are cached separately.
+        let mut other = StateCache::open(&dir, &[0xcd; 32]).unwrap();
+        assert_eq!(other.load().unwrap(), None);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.load().unwrap(), None);
+        cache.clear().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_redundant() {
+        let dir = test_dir();
+        let path = dir.join("ab".repeat(32)).with_extension("state");
+
+        let mut cache = StateCache::open(&dir, &[0xab; 32]).unwrap();
+        cache.store(b"state", 3, b"hash").unwrap();
+
+        // Storing the same height and hash again does not write the cache.
+        fs::remove_file(&path).unwrap();
+        cache.store(b"state", 3, b"hash").unwrap();
+        assert!(!path.exists());
+
+        cache.store(b"state", 3, b"other hash").unwrap();
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let dir = test_dir();
+        let path = dir.join("ab".repeat(32)).with_extension("state");
+        let mut cache = StateCache::open(&dir, &[0xab; 32]).unwrap();
+
+        // Truncated cache.
+        fs::write(&path, b"foo").unwrap();
+        assert!(cache.load().is_err());
+
+        // Unsupported version.
+        let state = (CACHE_VERSION + 1, 3u64, b"hash".to_vec(), b"state".to_vec())
+            .write()
+            .unwrap();
+        fs::write(&path, state).unwrap();
+        assert!(cache.load().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
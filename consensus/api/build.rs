@@ -4,11 +4,15 @@ extern crate protoc_rust_grpc;
 fn main() {
     // Generate module file.
     // Must be done first to create src/generated directory
-    ekiden_tools::generate_mod("src/generated", &["consensus", "consensus_grpc"]);
+    ekiden_tools::generate_mod_with_imports(
+        "src/generated",
+        &["ekiden_enclave_common::generated::enclave_identity"],
+        &["consensus", "consensus_grpc"],
+    );
 
     protoc_rust_grpc::run(protoc_rust_grpc::Args {
         out_dir: "src/generated/",
-        includes: &[],
+        includes: &["src/", "../../enclave/common/src/"],
         input: &["src/consensus.proto"],
         rust_protobuf: true,
     }).expect("protoc-rust-grpc");
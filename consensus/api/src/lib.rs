@@ -2,7 +2,14 @@ extern crate grpc;
 extern crate protobuf;
 extern crate tls_api;
 
+extern crate ekiden_enclave_common;
+
 mod generated;
 
 pub use generated::consensus::*;
 pub use generated::consensus_grpc::*;
+
+/// gRPC status returned when a state update was not computed on the current state.
+///
+/// The compute node should fetch the current state and compute the update again.
+pub const GRPC_STATUS_STALE_STATE: grpc::GrpcStatus = grpc::GrpcStatus::Aborted;
@@ -21,6 +21,9 @@ fn spawn_client_server() -> consensus_grpc::ConsensusClient {
         grpc_port: 9002,
         no_tendermint: true,
         artificial_delay: 100,
+        storage_path: None,
+        trusted_enclaves: vec![],
+        checkpoint_policy: lib::CheckpointPolicy::default(),
     };
     let client_port = config.grpc_port;
     let _server_handle = thread::spawn(move || {
@@ -1,10 +1,14 @@
 extern crate ekiden_tools;
+extern crate protoc_rust;
 extern crate protoc_rust_grpc;
 
 fn main() {
     // Generate module file.
     // Must be done first to create src/generated directory
-    ekiden_tools::generate_mod("src/generated", &["tendermint", "tendermint_grpc"]);
+    ekiden_tools::generate_mod(
+        "src/generated",
+        &["tendermint", "tendermint_grpc", "storage"],
+    );
 
     protoc_rust_grpc::run(protoc_rust_grpc::Args {
         out_dir: "src/generated/",
@@ -12,4 +16,10 @@ fn main() {
         input: &["src/tendermint.proto"],
         rust_protobuf: true,
     }).expect("protoc-rust-grpc");
+
+    protoc_rust::run(protoc_rust::Args {
+        out_dir: "src/generated/",
+        includes: &[],
+        input: &["src/storage.proto"],
+    }).expect("protoc-rust");
 }
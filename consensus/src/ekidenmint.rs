@@ -5,14 +5,34 @@
 // https://github.com/tendermint/basecoin/
 use abci::application::Application;
 use abci::types;
-use protobuf;
+use byteorder::{LittleEndian, WriteBytesExt};
+use protobuf::Message;
 use std;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
-use ekiden_consensus_api::StoredTx;
+use ekiden_enclave_common::quote::MrEnclave;
 
+use errors::Error;
 use state;
 
+/// ABCI code for transactions that were not computed on the current state.
+pub const CODE_STALE_STATE: types::CodeType = types::CodeType::BadNonce;
+
+/// Get the ABCI code for a rejected transaction.
+///
+/// Stale state is reported with a distinct code so that compute nodes know to fetch
+/// the current state and try again.
+pub fn error_code(
+    error: &(std::error::Error + 'static),
+    default: types::CodeType,
+) -> types::CodeType {
+    match error.downcast_ref::<Error>() {
+        Some(&Error::StaleStateError(_)) => CODE_STALE_STATE,
+        _ => default,
+    }
+}
+
 //#[derive(Copy, Clone)]
 #[derive(Clone)]
 pub struct Ekidenmint {
@@ -25,51 +45,109 @@ impl Ekidenmint {
     }
 
     pub fn deliver_tx_fallible(&self, tx: &[u8]) -> Result<(), Box<std::error::Error>> {
-        state::State::check_tx(tx)?;
-        let mut stored: StoredTx = protobuf::parse_from_bytes(tx)?;
-        // Set the state
+        // Check and set the state
         let mut s = self.state.lock().unwrap();
-        if stored.has_replace() {
-            let current_height = match s.everything.as_ref() {
-                Some(si) => si.checkpoint_height + si.diffs.len() as u64,
-                None => 0,
-            };
-            s.everything = Some(state::StateInitialized {
-                checkpoint: stored.take_replace(),
-                checkpoint_height: current_height + 1,
-                diffs: Vec::new(),
+        s.deliver_tx(tx)
+    }
+
+    pub fn commit_fallible(&self) -> Result<Vec<u8>, Box<std::error::Error>> {
+        let mut s = self.state.lock().unwrap();
+        s.commit()
+    }
+
+    /// Handle a query for the given path.
+    ///
+    /// Supported paths are:
+    ///
+    /// * `/checkpoint?contract=C` returns the latest checkpoint of contract `C` as a
+    ///   serialized `Checkpoint`.
+    /// * `/diffs?contract=C&since=H` returns the diffs of contract `C` since height `H` as a
+    ///   serialized `GetDiffsResponse`, the same as the `GetDiffs` gRPC call.
+    /// * `/height?contract=C` returns the height of the state of contract `C` as a
+    ///   little-endian `u64`.
+    /// * `/hash` returns the hash of the state of all contracts. With a `contract=C`
+    ///   parameter, it returns the hash of the state of contract `C`.
+    ///
+    /// Contracts are identified by the hex-encoded MRENCLAVE of their enclave.
+    ///
+    /// Queries only reflect the state as of the last committed block, so that the result
+    /// matches the height reported in the response.
+    pub fn query_fallible(&self, path: &str) -> Result<Vec<u8>, Box<std::error::Error>> {
+        let (route, params) = match path.find('?') {
+            Some(index) => (&path[..index], &path[index + 1..]),
+            None => (path, ""),
+        };
+
+        let mut contract_id = None;
+        let mut since_height = None;
+        for param in params.split('&') {
+            let mut param = param.splitn(2, '=');
+            match param.next() {
+                Some("contract") => {
+                    let mr_enclave = MrEnclave::from_str(param.next().unwrap_or(""))
+                        .map_err(|_| "Malformed contract parameter")?;
+                    contract_id = Some(mr_enclave.0.to_vec());
+                }
+                Some("since") => {
+                    since_height = Some(param.next().unwrap_or("").parse::<u64>()?);
+                }
+                _ => {}
+            }
+        }
+
+        let s = self.state.lock().unwrap();
+        if route == "/hash" {
+            return Ok(match contract_id {
+                Some(contract_id) => match s.get_committed(&contract_id) {
+                    Some(si) => si.hash(),
+                    None => Vec::new(),
+                },
+                None => s.app_hash.clone(),
             });
-            Ok(())
-        } else if stored.has_diff() {
-            let si = s.everything
-                .as_mut()
-                .ok_or::<Box<std::error::Error>>(From::from(
-                    "Can't add diff to uninitialized state.",
-                ))?;
-            si.diffs.push(stored.take_diff());
-            Ok(())
-        } else if stored.has_checkpoint() {
-            let si = s.everything
-                .as_mut()
-                .ok_or::<Box<std::error::Error>>(From::from(
-                    "Can't checkpoint uninitialized state.",
+        }
+
+        let contract_id = contract_id.ok_or::<Box<std::error::Error>>(From::from(
+            "Missing contract parameter",
+        ))?;
+        match route {
+            "/checkpoint" => match s.get_committed(&contract_id) {
+                Some(si) => Ok(si.get_checkpoint().write_to_bytes()?),
+                None => Err(From::from("State not initialized.")),
+            },
+            "/diffs" => {
+                let since_height = since_height.ok_or::<Box<std::error::Error>>(From::from(
+                    "Missing since parameter",
                 ))?;
-            si.checkpoint = stored.take_checkpoint();
-            si.checkpoint_height += si.diffs.len() as u64;
-            si.diffs.clear();
-            Ok(())
-        } else {
-            Err(From::from("Unrecognized StoredTx variant"))
+
+                match s.get_committed_diffs(&contract_id, since_height) {
+                    Some(response) => Ok(response.write_to_bytes()?),
+                    None => Err(From::from("State not initialized.")),
+                }
+            }
+            "/height" => {
+                let height_value = match s.get_committed(&contract_id) {
+                    Some(si) => si.height(),
+                    None => 0,
+                };
+                let mut height = Vec::new();
+                height.write_u64::<LittleEndian>(height_value)?;
+                Ok(height)
+            }
+            _ => Err(From::from(format!("Unknown query path: {}", route))),
         }
     }
 }
 
 impl Application for Ekidenmint {
     fn info(&self, _req: &types::RequestInfo) -> types::ResponseInfo {
-        // @todo - supposed to return information about app state
-        // https://github.com/tendermint/abci
+        // Report the last committed block so that Tendermint can replay any blocks
+        // that we are missing during the handshake.
         println!("info");
-        types::ResponseInfo::new()
+        let s = self.state.lock().unwrap();
+        let mut resp = types::ResponseInfo::new();
+        resp.set_last_block_height(s.last_block_height);
+        resp.set_last_block_app_hash(s.app_hash.clone());
+        return resp;
     }
 
     fn set_option(&self, req: &types::RequestSetOption) -> types::ResponseSetOption {
@@ -79,22 +157,32 @@ impl Application for Ekidenmint {
         types::ResponseSetOption::new()
     }
 
-    fn query(&self, _p: &types::RequestQuery) -> types::ResponseQuery {
-        // @todo - handle query requests
-        // https://github.com/tendermint/abci
-        println!("query");
-        types::ResponseQuery::new()
+    fn query(&self, p: &types::RequestQuery) -> types::ResponseQuery {
+        let mut resp = types::ResponseQuery::new();
+        match self.query_fallible(p.get_path()) {
+            Ok(value) => {
+                resp.set_code(types::CodeType::OK);
+                resp.set_value(value);
+            }
+            Err(e) => {
+                resp.set_code(types::CodeType::BaseInvalidInput);
+                resp.set_log(e.description().to_owned());
+            }
+        }
+        resp.set_height(self.state.lock().unwrap().last_block_height);
+        return resp;
     }
 
     fn check_tx(&self, p: &types::RequestCheckTx) -> types::ResponseCheckTx {
         let mut resp = types::ResponseCheckTx::new();
-        match state::State::check_tx(p.get_tx()) {
+        let s = self.state.lock().unwrap();
+        match s.check_tx(p.get_tx()) {
             Ok(_) => {
                 resp.set_code(types::CodeType::OK);
             }
             Err(error) => {
-                resp.set_code(types::CodeType::BaseInvalidInput);
-                resp.set_log(error);
+                resp.set_code(error_code(&*error, types::CodeType::BaseInvalidInput));
+                resp.set_log(error.description().to_owned());
             }
         }
         return resp;
@@ -121,7 +209,7 @@ impl Application for Ekidenmint {
                 resp.set_code(types::CodeType::OK);
             }
             Err(e) => {
-                resp.set_code(types::CodeType::BaseEncodingError);
+                resp.set_code(error_code(&*e, types::CodeType::BaseEncodingError));
                 resp.set_log(e.description().to_owned());
             }
         }
@@ -137,11 +225,17 @@ impl Application for Ekidenmint {
     fn commit(&self, _p: &types::RequestCommit) -> types::ResponseCommit {
         // RequestCommit is empty
         println!("commit");
-        let resp = types::ResponseCommit::new();
-        // @todo - respond with Merkle root hash of the application state in `data`
-        //resp.set_code(types::CodeType::OK);
-        //resp.set_data(String::from("test data").into_bytes());
-        //resp.set_log(String::from("test log"));
+        let mut resp = types::ResponseCommit::new();
+        match self.commit_fallible() {
+            Ok(app_hash) => {
+                resp.set_code(types::CodeType::OK);
+                resp.set_data(app_hash);
+            }
+            Err(e) => {
+                resp.set_code(types::CodeType::InternalError);
+                resp.set_log(e.description().to_owned());
+            }
+        }
         return resp;
     }
 
@@ -157,3 +251,182 @@ impl Application for Ekidenmint {
         types::ResponseFlush::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use abci::application::Application;
+    use abci::types;
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use protobuf;
+
+    use ekiden_consensus_api::{Checkpoint, GetDiffsResponse};
+    use ekiden_enclave_common::quote::MrEnclave;
+
+    use super::super::state::{CheckpointPolicy, State};
+    use super::super::test_utils::TestEnclave;
+    use super::Ekidenmint;
+
+    /// Deliver a transaction computed on the current state.
+    fn deliver_tx<F>(app: &Ekidenmint, state: &Arc<Mutex<State>>, tx: F)
+    where
+        F: FnOnce(&State) -> Vec<u8>,
+    {
+        let mut req = types::RequestDeliverTx::new();
+        req.set_tx(tx(&*state.lock().unwrap()));
+        assert_eq!(app.deliver_tx(&req).get_code(), types::CodeType::OK);
+    }
+
+    /// Deliver a transaction computed on the current state and commit it in its own block.
+    fn deliver<F>(app: &Ekidenmint, state: &Arc<Mutex<State>>, tx: F)
+    where
+        F: FnOnce(&State) -> Vec<u8>,
+    {
+        app.begin_block(&types::RequestBeginBlock::new());
+        deliver_tx(app, state, tx);
+        app.end_block(&types::RequestEndBlock::new());
+
+        let resp = app.commit(&types::RequestCommit::new());
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        assert_eq!(resp.get_data(), &state.lock().unwrap().app_hash[..]);
+    }
+
+    fn query(app: &Ekidenmint, path: &str) -> types::ResponseQuery {
+        let mut req = types::RequestQuery::new();
+        req.set_path(path.to_owned());
+        app.query(&req)
+    }
+
+    fn query_height(app: &Ekidenmint, contract: &str) -> u64 {
+        let resp = query(app, &format!("/height?contract={}", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        Cursor::new(resp.get_value()).read_u64::<LittleEndian>().unwrap()
+    }
+
+    #[test]
+    fn test_query() {
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+        let state = Arc::new(Mutex::new(State::new(
+            vec![enclave.mr_enclave.clone()],
+            CheckpointPolicy::default(),
+        )));
+        let app = Ekidenmint::new(Arc::clone(&state));
+        let contract = "01".repeat(32);
+
+        // Uninitialized state.
+        assert_eq!(query_height(&app, &contract), 0);
+        assert!(query(&app, "/hash").get_value().is_empty());
+        let path = format!("/checkpoint?contract={}", contract);
+        assert!(query(&app, &path).get_code() != types::CodeType::OK);
+        let path = format!("/diffs?contract={}&since=0", contract);
+        assert!(query(&app, &path).get_code() != types::CodeType::OK);
+
+        deliver(&app, &state, |state| enclave.replace_tx(state, b"helloworld"));
+        deliver(&app, &state, |state| enclave.diff_tx(state, b"diff1"));
+        deliver(&app, &state, |state| enclave.diff_tx(state, b"diff2"));
+
+        assert_eq!(query_height(&app, &contract), 3);
+        assert_eq!(query(&app, &format!("/height?contract={}", contract)).get_height(), 3);
+
+        let resp = query(&app, "/hash");
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        assert_eq!(resp.get_value(), &state.lock().unwrap().app_hash[..]);
+
+        let resp = query(&app, &format!("/hash?contract={}", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        assert_eq!(
+            resp.get_value(),
+            &state.lock().unwrap().contract_hash(&enclave.contract_id())[..]
+        );
+
+        let resp = query(&app, &format!("/checkpoint?contract={}", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        let checkpoint: Checkpoint = protobuf::parse_from_bytes(resp.get_value()).unwrap();
+        assert_eq!(checkpoint.get_payload(), b"helloworld");
+        assert_eq!(checkpoint.get_height(), 1);
+
+        let resp = query(&app, &format!("/diffs?contract={}&since=2", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        let diffs: GetDiffsResponse = protobuf::parse_from_bytes(resp.get_value()).unwrap();
+        assert!(!diffs.has_checkpoint());
+        assert_eq!(diffs.get_diffs(), &[b"diff2".to_vec()]);
+        assert_eq!(
+            diffs.get_state_hash(),
+            &state.lock().unwrap().contract_hash(&enclave.contract_id())[..]
+        );
+
+        // Diffs since before the checkpoint include the checkpoint.
+        let resp = query(&app, &format!("/diffs?since=0&contract={}", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        let diffs: GetDiffsResponse = protobuf::parse_from_bytes(resp.get_value()).unwrap();
+        assert_eq!(diffs.get_checkpoint().get_payload(), b"helloworld");
+        assert_eq!(diffs.get_diffs(), &[b"diff1".to_vec(), b"diff2".to_vec()]);
+
+        // Other contracts have no state.
+        assert_eq!(query_height(&app, &"02".repeat(32)), 0);
+
+        // Malformed queries.
+        let path = format!("/diffs?contract={}", contract);
+        assert!(query(&app, &path).get_code() != types::CodeType::OK);
+        let path = format!("/diffs?contract={}&since=foo", contract);
+        assert!(query(&app, &path).get_code() != types::CodeType::OK);
+        assert!(query(&app, "/height").get_code() != types::CodeType::OK);
+        assert!(query(&app, "/height?contract=01").get_code() != types::CodeType::OK);
+        assert!(query(&app, "/unknown").get_code() != types::CodeType::OK);
+    }
+
+    #[test]
+    fn test_query_uncommitted() {
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+        let state = Arc::new(Mutex::new(State::new(
+            vec![enclave.mr_enclave.clone()],
+            CheckpointPolicy::default(),
+        )));
+        let app = Ekidenmint::new(Arc::clone(&state));
+        let contract = "01".repeat(32);
+
+        // Transactions are not visible before the block is committed.
+        app.begin_block(&types::RequestBeginBlock::new());
+        deliver_tx(&app, &state, |state| enclave.replace_tx(state, b"helloworld"));
+        assert_eq!(query_height(&app, &contract), 0);
+        assert!(query(&app, "/hash").get_value().is_empty());
+        let path = format!("/hash?contract={}", contract);
+        assert!(query(&app, &path).get_value().is_empty());
+        let path = format!("/checkpoint?contract={}", contract);
+        assert!(query(&app, &path).get_code() != types::CodeType::OK);
+        app.end_block(&types::RequestEndBlock::new());
+        assert_eq!(app.commit(&types::RequestCommit::new()).get_code(), types::CodeType::OK);
+        assert_eq!(query_height(&app, &contract), 1);
+        let committed_hash = query(&app, &format!("/hash?contract={}", contract))
+            .get_value()
+            .to_vec();
+
+        // Changes to initialized state are not visible either.
+        app.begin_block(&types::RequestBeginBlock::new());
+        deliver_tx(&app, &state, |state| enclave.diff_tx(state, b"diff1"));
+        deliver_tx(&app, &state, |state| enclave.diff_tx(state, b"diff2"));
+
+        let resp = query(&app, &format!("/height?contract={}", contract));
+        assert_eq!(resp.get_height(), 1);
+        assert_eq!(query_height(&app, &contract), 1);
+        let resp = query(&app, &format!("/hash?contract={}", contract));
+        assert_eq!(resp.get_value(), &committed_hash[..]);
+        let resp = query(&app, &format!("/diffs?contract={}&since=1", contract));
+        assert_eq!(resp.get_code(), types::CodeType::OK);
+        let diffs: GetDiffsResponse = protobuf::parse_from_bytes(resp.get_value()).unwrap();
+        assert!(diffs.get_diffs().is_empty());
+        assert_eq!(diffs.get_height(), 1);
+
+        app.end_block(&types::RequestEndBlock::new());
+        assert_eq!(app.commit(&types::RequestCommit::new()).get_code(), types::CodeType::OK);
+
+        assert_eq!(query_height(&app, &contract), 3);
+        let resp = query(&app, &format!("/diffs?contract={}&since=1", contract));
+        let diffs: GetDiffsResponse = protobuf::parse_from_bytes(resp.get_value()).unwrap();
+        assert_eq!(diffs.get_diffs(), &[b"diff1".to_vec(), b"diff2".to_vec()]);
+        let resp = query(&app, "/hash");
+        assert_eq!(resp.get_value(), &state.lock().unwrap().app_hash[..]);
+    }
+}
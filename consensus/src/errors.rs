@@ -8,6 +8,9 @@ pub enum Error {
     HyperError(hyper::Error),
     HyperUriError(hyper::error::UriError),
     StringError(string::FromUtf8Error),
+    StorageError(String),
+    /// Transaction was not computed on the current state.
+    StaleStateError(String),
 }
 
 impl std::fmt::Display for Error {
@@ -22,6 +25,8 @@ impl std::error::Error for Error {
             &Error::HyperError(ref e) => e.description(),
             &Error::HyperUriError(ref e) => e.description(),
             &Error::StringError(ref e) => e.description(),
+            &Error::StorageError(ref message) => message,
+            &Error::StaleStateError(ref message) => message,
         }
     }
     fn cause(&self) -> Option<&std::error::Error> {
@@ -29,6 +34,8 @@ impl std::error::Error for Error {
             &Error::HyperError(ref e) => Some(e),
             &Error::HyperUriError(ref e) => Some(e),
             &Error::StringError(ref e) => Some(e),
+            &Error::StorageError(_) => None,
+            &Error::StaleStateError(_) => None,
         }
     }
 }
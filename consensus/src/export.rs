@@ -0,0 +1,304 @@
+//! Export and import of the consensus state.
+//!
+//! An export is a serialized [`StateExport`] containing the committed state of all
+//! contracts together with the block height and application state hash. It is used to
+//! back up the state of a node and to seed the state of a new node.
+//!
+//! Both operations work directly on a storage directory, so the consensus node using
+//! the directory must not be running.
+//!
+//! [`StateExport`]: super::generated::storage::StateExport
+use std;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use protobuf::{self, Message};
+
+use super::generated::storage::{Commit, StateExport};
+use super::state::State;
+use super::storage::{self, Storage};
+
+/// Version of the export format.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Export the committed state in a storage directory to a file.
+///
+/// Returns the height of the last committed block and the application state hash.
+pub fn export_state<P: AsRef<Path>, Q: AsRef<Path>>(
+    storage_path: P,
+    export_path: Q,
+) -> Result<(u64, Vec<u8>), Box<std::error::Error>> {
+    if !storage_path.as_ref().is_dir() {
+        return Err(From::from("Storage directory does not exist"));
+    }
+
+    // Retain all history found in storage. The node that imports the state prunes it
+    // to its own window on the next checkpoint. The storage is only read, so that
+    // uncommitted transactions in the log are left in place.
+    let recovered = Storage::read(storage_path, u64::max_value())?;
+
+    let mut export = StateExport::new();
+    export.set_version(EXPORT_VERSION);
+    {
+        let mut commit = Commit::new();
+        commit.set_block_height(recovered.last_block_height);
+        commit.set_app_hash(recovered.app_hash.clone());
+        export.set_last_commit(commit);
+    }
+    export.set_contracts(storage::contracts_to_snapshots(&recovered.contracts));
+
+    let mut file = File::create(export_path)?;
+    file.write_all(&export.write_to_bytes()?)?;
+    file.sync_all()?;
+
+    Ok((recovered.last_block_height, recovered.app_hash))
+}
+
+/// Import state from a file into a fresh storage directory.
+///
+/// The state is refused if it does not match the application state hash recorded in
+/// the export. If `reset_height` is set, the state is imported as of block height zero,
+/// so that it can be used as the genesis state of a new chain. Like a fresh chain, the
+/// node then reports height zero with the hash of its state, so the `app_hash` in the
+/// Tendermint genesis file must be set to the returned hash.
+///
+/// Returns the height of the last committed block and the application state hash.
+pub fn import_state<P: AsRef<Path>, Q: AsRef<Path>>(
+    export_path: P,
+    storage_path: Q,
+    reset_height: bool,
+) -> Result<(u64, Vec<u8>), Box<std::error::Error>> {
+    let mut buffer = Vec::new();
+    File::open(export_path)?.read_to_end(&mut buffer)?;
+
+    let mut export: StateExport = protobuf::parse_from_bytes(&buffer)?;
+    if export.get_version() != EXPORT_VERSION {
+        return Err(From::from(format!(
+            "Unsupported export version {} (expected {})",
+            export.get_version(),
+            EXPORT_VERSION
+        )));
+    }
+
+    let contracts = storage::contracts_from_snapshots(export.take_contracts().into_vec())?;
+    let app_hash = export.get_last_commit().get_app_hash().to_vec();
+    if State::compute_app_hash(&contracts) != app_hash {
+        return Err(From::from("Exported state does not match its hash"));
+    }
+
+    let block_height = if reset_height {
+        0
+    } else {
+        export.get_last_commit().get_block_height()
+    };
+
+    // Check without modifying the directory, as opening the storage discards any
+    // uncommitted transactions in the log.
+    let existing = Storage::read(&storage_path, 0)?;
+    if existing.last_block_height != 0 || !existing.contracts.is_empty() {
+        return Err(From::from("Storage directory already contains state"));
+    }
+
+    let (mut storage, _) = Storage::open(storage_path, 0)?;
+    storage.write_snapshot(&contracts, block_height, &app_hash)?;
+
+    Ok((block_height, app_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use abci::application::Application;
+    use abci::types;
+    use protobuf::{self, Message};
+
+    use ekiden_enclave_common::quote::MrEnclave;
+
+    use super::super::ekidenmint::Ekidenmint;
+    use super::super::generated::storage::StateExport;
+    use super::super::state::{CheckpointPolicy, State};
+    use super::super::test_utils::TestEnclave;
+    use super::{export_state, import_state, EXPORT_VERSION};
+
+    static TEST_DIR_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Create a fresh directory for a test.
+    fn test_dir() -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "ekiden-consensus-export-{}-{}",
+            process::id(),
+            TEST_DIR_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Open state that trusts the test enclave.
+    fn open(path: &PathBuf, enclave: &TestEnclave) -> State {
+        State::open(
+            path,
+            vec![enclave.mr_enclave.clone()],
+            CheckpointPolicy::default(),
+        ).unwrap()
+    }
+
+    /// Deliver a transaction computed on the current state and commit it in its own block.
+    fn deliver<F>(state: &mut State, tx: F)
+    where
+        F: FnOnce(&State) -> Vec<u8>,
+    {
+        let tx = tx(state);
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        let app_hash = {
+            let mut state = open(&dir.join("source"), &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+            state.app_hash.clone()
+        };
+
+        let export_path = dir.join("export");
+        assert_eq!(
+            export_state(dir.join("source"), &export_path).unwrap(),
+            (2, app_hash.clone())
+        );
+
+        // Import into a fresh node.
+        assert_eq!(
+            import_state(&export_path, dir.join("backup"), false).unwrap(),
+            (2, app_hash.clone())
+        );
+        let mut state = open(&dir.join("backup"), &enclave);
+        {
+            let si = &state.contracts[&enclave.contract_id()];
+            assert_eq!(si.checkpoint, b"helloworld");
+            assert_eq!(si.checkpoint_height, 1);
+            assert_eq!(si.diffs, vec![b"diff1".to_vec()]);
+        }
+        assert_eq!(state.last_block_height, 2);
+        assert_eq!(state.app_hash, app_hash);
+
+        // Imported state accepts further updates.
+        deliver(&mut state, |state| enclave.diff_tx(state, b"diff2"));
+        assert_eq!(state.height(&enclave.contract_id()), 3);
+        drop(state);
+
+        // Import as genesis state of a new chain.
+        assert_eq!(
+            import_state(&export_path, dir.join("genesis"), true).unwrap(),
+            (0, app_hash.clone())
+        );
+        let state = Arc::new(Mutex::new(open(&dir.join("genesis"), &enclave)));
+        let app = Ekidenmint::new(Arc::clone(&state));
+        let info = app.info(&types::RequestInfo::new());
+        assert_eq!(info.get_last_block_height(), 0);
+        assert_eq!(info.get_last_block_app_hash(), &app_hash[..]);
+        assert_eq!(state.lock().unwrap().state_hash, app_hash);
+
+        // The first block of the new chain is block one.
+        app.begin_block(&types::RequestBeginBlock::new());
+        let mut req = types::RequestDeliverTx::new();
+        req.set_tx(enclave.diff_tx(&*state.lock().unwrap(), b"diff2"));
+        assert_eq!(app.deliver_tx(&req).get_code(), types::CodeType::OK);
+        app.end_block(&types::RequestEndBlock::new());
+        assert_eq!(app.commit(&types::RequestCommit::new()).get_code(), types::CodeType::OK);
+        assert_eq!(state.lock().unwrap().last_block_height, 1);
+        drop(app);
+        drop(state);
+
+        // Existing state is not overwritten.
+        assert!(import_state(&export_path, dir.join("backup"), false).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_read_only() {
+        let dir = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        let app_hash = {
+            let mut state = open(&dir.join("source"), &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            let app_hash = state.app_hash.clone();
+
+            // Deliver a transaction without committing it.
+            let tx = enclave.diff_tx(&state, b"diff1");
+            state.deliver_tx(&tx).unwrap();
+            app_hash
+        };
+
+        let log_path = dir.join("source").join("log");
+        let log_length = fs::metadata(&log_path).unwrap().len();
+
+        // Only committed state is exported and the log is left untouched.
+        let export_path = dir.join("export");
+        assert_eq!(export_state(dir.join("source"), &export_path).unwrap(), (1, app_hash));
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), log_length);
+
+        // Importing into a directory with state does not modify it either.
+        assert!(import_state(&export_path, dir.join("source"), false).is_err());
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), log_length);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let dir = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        {
+            let mut state = open(&dir.join("source"), &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+        }
+
+        let export_path = dir.join("export");
+        export_state(dir.join("source"), &export_path).unwrap();
+        let export: StateExport = {
+            let buffer = fs::read(&export_path).unwrap();
+            protobuf::parse_from_bytes(&buffer).unwrap()
+        };
+
+        // Tampered state.
+        let mut tampered = export.clone();
+        tampered.mut_contracts()[0].set_checkpoint(b"tampered".to_vec());
+        fs::write(&export_path, tampered.write_to_bytes().unwrap()).unwrap();
+        assert!(import_state(&export_path, dir.join("tampered"), false).is_err());
+
+        // History going back further than height zero, which is not covered by the hash.
+        let mut inconsistent = export.clone();
+        inconsistent.mut_contracts()[0].set_history(protobuf::RepeatedField::from_vec(vec![
+            b"diff0".to_vec(),
+            b"diff1".to_vec(),
+        ]));
+        fs::write(&export_path, inconsistent.write_to_bytes().unwrap()).unwrap();
+        assert!(import_state(&export_path, dir.join("inconsistent"), false).is_err());
+
+        // Unsupported version.
+        let mut future = export.clone();
+        future.set_version(EXPORT_VERSION + 1);
+        fs::write(&export_path, future.write_to_bytes().unwrap()).unwrap();
+        assert!(import_state(&export_path, dir.join("future"), false).is_err());
+
+        // Missing storage directory.
+        assert!(export_state(dir.join("missing"), &export_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,29 @@
 extern crate abci;
+extern crate base64;
+extern crate byteorder;
+extern crate crc;
 extern crate futures;
 extern crate grpc;
 extern crate hyper;
 extern crate protobuf;
+extern crate sodalite;
 extern crate tls_api;
 extern crate tokio_core;
 extern crate tokio_proto;
 
 extern crate ekiden_consensus_api;
+extern crate ekiden_enclave_common;
 
 mod ekidenmint;
 mod errors;
+mod export;
 mod tendermint;
 pub mod generated;
 mod rpc;
 mod state;
+mod storage;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
@@ -23,15 +32,20 @@ use std::thread;
 use std::time;
 
 use abci::server::{AbciProto, AbciService};
+use abci::types::CodeType;
 use tokio_proto::TcpServer;
 
 use ekiden_consensus_api::ConsensusServer;
+use ekiden_enclave_common::quote::MrEnclave;
 use errors::Error;
 use generated::tendermint::ResponseBroadcastTx;
 use rpc::ConsensusServerImpl;
 use state::State;
 use tendermint::TendermintProxy;
 
+pub use export::{export_state, import_state};
+pub use state::CheckpointPolicy;
+
 #[derive(Debug)]
 pub struct Config {
     pub tendermint_host: String,
@@ -40,11 +54,30 @@ pub struct Config {
     pub grpc_port: u16,
     pub no_tendermint: bool,
     pub artificial_delay: u64,
+    pub storage_path: Option<String>,
+    /// MRENCLAVEs of compute enclaves that are allowed to update the state.
+    pub trusted_enclaves: Vec<MrEnclave>,
+    /// Policy for compacting diffs into checkpoints.
+    pub checkpoint_policy: CheckpointPolicy,
 }
 
 pub fn run(config: &Config) -> Result<(), Box<Error>> {
     // Create a shared State object and ekidenmint
-    let state = Arc::new(Mutex::new(State::new()));
+    let state = match config.storage_path {
+        Some(ref path) => match State::open(
+            path,
+            config.trusted_enclaves.clone(),
+            config.checkpoint_policy.clone(),
+        ) {
+            Ok(state) => state,
+            Err(error) => return Err(Box::new(Error::StorageError(error.to_string()))),
+        },
+        None => State::new(
+            config.trusted_enclaves.clone(),
+            config.checkpoint_policy.clone(),
+        ),
+    };
+    let state = Arc::new(Mutex::new(state));
     let delay = time::Duration::from_millis(config.artificial_delay);
 
     // Create new channel (gRPC broadcast => Tendermint/Ekidenmint).
@@ -66,8 +99,36 @@ pub fn run(config: &Config) -> Result<(), Box<Error>> {
         // Setup short circuit
         for req in receiver {
             thread::sleep(delay);
-            app.deliver_tx_fallible(&req.payload).unwrap();
-            req.response.send(Ok(ResponseBroadcastTx::new())).unwrap();
+            // Each transaction is committed as its own block.
+            let mut response = ResponseBroadcastTx::new();
+            let mut commit_error = None;
+            match app.deliver_tx_fallible(&req.payload) {
+                Ok(_) => {
+                    if let Err(error) = app.commit_fallible() {
+                        // The transaction may not have been stored, so it is reported as
+                        // failed and the node stops, as its state is no longer reliable.
+                        let deliver_tx = response.mut_deliver_tx();
+                        deliver_tx.set_code(CodeType::InternalError as u32);
+                        deliver_tx.set_log(error.description().to_owned());
+                        commit_error = Some(error);
+                    }
+                }
+                Err(error) => {
+                    let deliver_tx = response.mut_deliver_tx();
+                    deliver_tx.set_code(
+                        ekidenmint::error_code(&*error, CodeType::BaseEncodingError) as u32,
+                    );
+                    deliver_tx.set_log(error.description().to_owned());
+                }
+            }
+            req.response.send(Ok(response)).unwrap();
+
+            if let Some(error) = commit_error {
+                return Err(Box::new(Error::StorageError(format!(
+                    "Failed to commit block: {}",
+                    error
+                ))));
+            }
         }
         return Ok(());
     }
@@ -1,8 +1,13 @@
 #[macro_use]
 extern crate clap;
 extern crate ekiden_consensus;
+extern crate ekiden_enclave_common;
 
-use clap::{App, Arg};
+use std::fmt::Write;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use ekiden_enclave_common::quote::MrEnclave;
 
 fn main() {
     let matches = App::new("Ekiden Compute Node")
@@ -44,8 +49,91 @@ fn main() {
                 .takes_value(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("storage-path")
+                .long("storage-path")
+                .help("Directory for persisting consensus state (in memory only if not set)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trusted-enclave")
+                .long("trusted-enclave")
+                .value_name("MRENCLAVE")
+                .help("MRENCLAVE (in hex format) of a compute enclave allowed to update the state")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("checkpoint-max-diffs")
+                .long("checkpoint-max-diffs")
+                .help("Request a checkpoint after this many diffs (0 to disable)")
+                .takes_value(true)
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-max-diffs-size")
+                .long("checkpoint-max-diffs-size")
+                .help("Request a checkpoint after this many bytes of diffs (0 to disable)")
+                .takes_value(true)
+                .default_value("67108864"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-history")
+                .long("checkpoint-history")
+                .help("Number of diffs to retain after they are folded into a checkpoint")
+                .takes_value(true)
+                .default_value("100"),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the committed state of a stopped node to a file")
+                .arg(
+                    Arg::with_name("storage-path")
+                        .long("storage-path")
+                        .help("Storage directory of the node")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("File to write the exported state to")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Bootstrap the state of a fresh node from an exported file")
+                .arg(
+                    Arg::with_name("storage-path")
+                        .long("storage-path")
+                        .help("Storage directory of the node (must not contain any state)")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .help("File to read the exported state from")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("genesis")
+                        .long("genesis")
+                        .help("Import the state at block height zero, for starting a new chain"),
+                ),
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        ("export", Some(matches)) => return export(matches),
+        ("import", Some(matches)) => return import(matches),
+        _ => {}
+    }
+
     let config = ekiden_consensus::Config {
         tendermint_host: matches.value_of("tendermint-host").unwrap().to_string(),
         tendermint_port: value_t!(matches, "tendermint-port", u16).unwrap_or_else(|e| e.exit()),
@@ -54,14 +142,75 @@ fn main() {
         grpc_port: value_t!(matches, "grpc-port", u16).unwrap_or_else(|e| e.exit()),
         no_tendermint: { matches.occurrences_of("no-tendermint") > 0 },
         artificial_delay: value_t!(matches, "artificial-delay", u64).unwrap_or_else(|e| e.exit()),
+        storage_path: matches.value_of("storage-path").map(|path| path.to_string()),
+        trusted_enclaves: if matches.is_present("trusted-enclave") {
+            values_t!(matches, "trusted-enclave", MrEnclave).unwrap_or_else(|e| e.exit())
+        } else {
+            vec![]
+        },
+        checkpoint_policy: ekiden_consensus::CheckpointPolicy {
+            max_diffs: value_t!(matches, "checkpoint-max-diffs", u64)
+                .unwrap_or_else(|e| e.exit()),
+            max_diffs_size: value_t!(matches, "checkpoint-max-diffs-size", u64)
+                .unwrap_or_else(|e| e.exit()),
+            history_window: value_t!(matches, "checkpoint-history", u64)
+                .unwrap_or_else(|e| e.exit()),
+        },
     };
 
     println!(
         "Ekiden Consensus Node starting on port {} ... ",
         config.grpc_port
     );
+    if config.trusted_enclaves.is_empty() {
+        eprintln!("WARNING: No trusted enclaves configured. All state updates will be rejected.");
+    }
     if let Err(e) = ekiden_consensus::run(&config) {
         eprintln!("Application error: {}", e);
         std::process::exit(1);
     }
 }
+
+/// Format a hash in hex.
+fn to_hex(hash: &[u8]) -> String {
+    let mut hex = String::new();
+    for &byte in hash {
+        write!(&mut hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn export(matches: &ArgMatches) {
+    match ekiden_consensus::export_state(
+        matches.value_of("storage-path").unwrap(),
+        matches.value_of("output").unwrap(),
+    ) {
+        Ok((block_height, app_hash)) => println!(
+            "Exported state at block height {} with app hash {}",
+            block_height,
+            to_hex(&app_hash)
+        ),
+        Err(e) => {
+            eprintln!("Failed to export state: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import(matches: &ArgMatches) {
+    match ekiden_consensus::import_state(
+        matches.value_of("input").unwrap(),
+        matches.value_of("storage-path").unwrap(),
+        matches.is_present("genesis"),
+    ) {
+        Ok((block_height, app_hash)) => println!(
+            "Imported state at block height {} with app hash {}",
+            block_height,
+            to_hex(&app_hash)
+        ),
+        Err(e) => {
+            eprintln!("Failed to import state: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
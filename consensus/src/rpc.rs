@@ -1,11 +1,15 @@
 use std;
 use std::sync::{mpsc, Arc, Mutex};
 
+use futures::Stream;
 use grpc;
-use protobuf::{self, Message};
+use protobuf::Message;
 
 use ekiden_consensus_api::{self, Consensus};
 
+use super::ekidenmint::CODE_STALE_STATE;
+use super::errors::Error;
+use super::generated::tendermint::ResponseBroadcastTx;
 use super::state;
 
 use super::tendermint::BroadcastRequest;
@@ -29,14 +33,19 @@ impl ConsensusServerImpl {
 
     fn replace_fallible(
         &self,
-        payload: Vec<u8>,
+        mut req: ekiden_consensus_api::ReplaceRequest,
     ) -> Result<ekiden_consensus_api::ReplaceResponse, Box<std::error::Error>> {
         let mut stored = ekiden_consensus_api::StoredTx::new();
-        stored.set_replace(payload);
+        stored.set_replace(req.take_payload());
+        stored.set_base_height(req.get_base_height());
+        stored.set_base_state_hash(req.take_base_state_hash());
+        stored.set_identity_proof(req.take_identity_proof());
+        stored.set_signature(req.take_signature());
+        stored.set_contract_id(req.take_contract_id());
         let stored_bytes = stored.write_to_bytes()?;
 
-        // check attestation - early reject
-        state::State::check_tx(&stored_bytes)?;
+        // Check attestation and height - early reject.
+        self.state.lock().unwrap().check_tx(&stored_bytes)?;
 
         // Create a one-shot channel for response.
         let (tx, rx) = mpsc::channel();
@@ -47,21 +56,26 @@ impl ConsensusServerImpl {
 
         let broadcast_channel = self.broadcast_channel.lock().unwrap();
         broadcast_channel.send(req).unwrap();
-        rx.recv().unwrap()?;
+        check_broadcast_response(rx.recv().unwrap()?)?;
 
         Ok(ekiden_consensus_api::ReplaceResponse::new())
     }
 
     fn add_diff_fallible(
         &self,
-        payload: Vec<u8>,
+        mut req: ekiden_consensus_api::AddDiffRequest,
     ) -> Result<ekiden_consensus_api::AddDiffResponse, Box<std::error::Error>> {
         let mut stored = ekiden_consensus_api::StoredTx::new();
-        stored.set_diff(payload);
+        stored.set_diff(req.take_payload());
+        stored.set_base_height(req.get_base_height());
+        stored.set_base_state_hash(req.take_base_state_hash());
+        stored.set_identity_proof(req.take_identity_proof());
+        stored.set_signature(req.take_signature());
+        stored.set_contract_id(req.take_contract_id());
         let stored_bytes = stored.write_to_bytes()?;
 
-        // check attestation - early reject
-        state::State::check_tx(&stored_bytes)?;
+        // Check attestation and height - early reject.
+        self.state.lock().unwrap().check_tx(&stored_bytes)?;
 
         // Create a one-shot channel for response.
         let (tx, rx) = mpsc::channel();
@@ -72,30 +86,88 @@ impl ConsensusServerImpl {
 
         let broadcast_channel = self.broadcast_channel.lock().unwrap();
         broadcast_channel.send(req).unwrap();
-        rx.recv().unwrap()?;
+        check_broadcast_response(rx.recv().unwrap()?)?;
 
         Ok(ekiden_consensus_api::AddDiffResponse::new())
     }
+
+    fn checkpoint_fallible(
+        &self,
+        mut req: ekiden_consensus_api::CheckpointRequest,
+    ) -> Result<ekiden_consensus_api::CheckpointResponse, Box<std::error::Error>> {
+        let mut stored = ekiden_consensus_api::StoredTx::new();
+        let contract_id = req.get_contract_id().to_vec();
+        stored.set_checkpoint(req.take_payload());
+        stored.set_base_height(req.get_base_height());
+        stored.set_base_state_hash(req.take_base_state_hash());
+        stored.set_identity_proof(req.take_identity_proof());
+        stored.set_signature(req.take_signature());
+        stored.set_contract_id(req.take_contract_id());
+        let stored_bytes = stored.write_to_bytes()?;
+
+        // Check attestation and height - early reject.
+        self.state.lock().unwrap().check_tx(&stored_bytes)?;
+
+        // Create a one-shot channel for response.
+        let (tx, rx) = mpsc::channel();
+        let req = BroadcastRequest {
+            response: tx,
+            payload: stored_bytes,
+        };
+
+        let broadcast_channel = self.broadcast_channel.lock().unwrap();
+        broadcast_channel.send(req).unwrap();
+        check_broadcast_response(rx.recv().unwrap()?)?;
+
+        // The checkpoint changes the state hash, which the compute node needs for its
+        // next state update.
+        let mut response = ekiden_consensus_api::CheckpointResponse::new();
+        response.set_state_hash(self.state.lock().unwrap().contract_hash(&contract_id));
+
+        Ok(response)
+    }
+}
+
+/// Check that a broadcast transaction was accepted.
+fn check_broadcast_response(response: ResponseBroadcastTx) -> Result<(), Box<std::error::Error>> {
+    for &(code, log) in &[
+        (response.get_check_tx().get_code(), response.get_check_tx().get_log()),
+        (response.get_deliver_tx().get_code(), response.get_deliver_tx().get_log()),
+    ] {
+        if code == CODE_STALE_STATE as u32 {
+            return Err(Box::new(Error::StaleStateError(log.to_owned())));
+        } else if code != 0 {
+            return Err(From::from(format!("Transaction rejected ({}): {}", code, log)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Convert an error into a gRPC error.
+///
+/// Stale state errors get a distinct status so that compute nodes know to fetch the
+/// current state and try again.
+fn to_grpc_error(error: Box<std::error::Error>) -> grpc::Error {
+    match error.downcast_ref::<Error>() {
+        Some(&Error::StaleStateError(ref message)) => {
+            grpc::Error::GrpcMessage(grpc::GrpcMessageError {
+                grpc_status: ekiden_consensus_api::GRPC_STATUS_STALE_STATE as i32,
+                grpc_message: message.clone(),
+            })
+        }
+        _ => grpc::Error::Panic(error.description().to_owned()),
+    }
 }
 
 impl Consensus for ConsensusServerImpl {
     fn get(
         &self,
         _options: grpc::RequestOptions,
-        _req: ekiden_consensus_api::GetRequest,
+        req: ekiden_consensus_api::GetRequest,
     ) -> grpc::SingleResponse<ekiden_consensus_api::GetResponse> {
-        let s = self.state.lock().unwrap();
-        match s.everything {
-            Some(ref si) => {
-                let mut response = ekiden_consensus_api::GetResponse::new();
-                {
-                    let mut checkpoint = response.mut_checkpoint();
-                    checkpoint.set_payload(si.checkpoint.clone());
-                    checkpoint.set_height(si.checkpoint_height);
-                }
-                response.set_diffs(protobuf::RepeatedField::from_vec(si.diffs.clone()));
-                grpc::SingleResponse::completed(response)
-            }
+        match self.state.lock().unwrap().get(req.get_contract_id()) {
+            Some(response) => grpc::SingleResponse::completed(response),
             None => grpc::SingleResponse::err(grpc::Error::Other("State not initialized.")),
         }
     }
@@ -106,25 +178,8 @@ impl Consensus for ConsensusServerImpl {
         req: ekiden_consensus_api::GetDiffsRequest,
     ) -> grpc::SingleResponse<ekiden_consensus_api::GetDiffsResponse> {
         let s = self.state.lock().unwrap();
-        match s.everything {
-            Some(ref si) => {
-                let mut response = ekiden_consensus_api::GetDiffsResponse::new();
-                if si.checkpoint_height > req.get_since_height() {
-                    // We don't have diffs going back far enough.
-                    {
-                        let mut checkpoint = response.mut_checkpoint();
-                        checkpoint.set_payload(si.checkpoint.clone());
-                        checkpoint.set_height(si.checkpoint_height);
-                    }
-                    response.set_diffs(protobuf::RepeatedField::from_vec(si.diffs.clone()));
-                } else {
-                    let num_known = req.get_since_height() - si.checkpoint_height;
-                    response.set_diffs(protobuf::RepeatedField::from_vec(
-                        si.diffs[num_known as usize..].to_vec(),
-                    ));
-                }
-                grpc::SingleResponse::completed(response)
-            }
+        match s.get_diffs(req.get_contract_id(), req.get_since_height()) {
+            Some(response) => grpc::SingleResponse::completed(response),
             None => grpc::SingleResponse::err(grpc::Error::Other("State not initialized.")),
         }
     }
@@ -134,9 +189,9 @@ impl Consensus for ConsensusServerImpl {
         _options: grpc::RequestOptions,
         req: ekiden_consensus_api::ReplaceRequest,
     ) -> grpc::SingleResponse<ekiden_consensus_api::ReplaceResponse> {
-        match self.replace_fallible(req.get_payload().to_vec()) {
+        match self.replace_fallible(req) {
             Ok(res) => grpc::SingleResponse::completed(res),
-            Err(e) => grpc::SingleResponse::err(grpc::Error::Panic(e.description().to_owned())),
+            Err(e) => grpc::SingleResponse::err(to_grpc_error(e)),
         }
     }
 
@@ -145,9 +200,35 @@ impl Consensus for ConsensusServerImpl {
         _options: grpc::RequestOptions,
         req: ekiden_consensus_api::AddDiffRequest,
     ) -> grpc::SingleResponse<ekiden_consensus_api::AddDiffResponse> {
-        match self.add_diff_fallible(req.get_payload().to_vec()) {
+        match self.add_diff_fallible(req) {
             Ok(res) => grpc::SingleResponse::completed(res),
-            Err(e) => grpc::SingleResponse::err(grpc::Error::Panic(e.description().to_owned())),
+            Err(e) => grpc::SingleResponse::err(to_grpc_error(e)),
         }
     }
+
+    fn checkpoint(
+        &self,
+        _options: grpc::RequestOptions,
+        req: ekiden_consensus_api::CheckpointRequest,
+    ) -> grpc::SingleResponse<ekiden_consensus_api::CheckpointResponse> {
+        match self.checkpoint_fallible(req) {
+            Ok(res) => grpc::SingleResponse::completed(res),
+            Err(e) => grpc::SingleResponse::err(to_grpc_error(e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        _options: grpc::RequestOptions,
+        req: ekiden_consensus_api::SubscribeRequest,
+    ) -> grpc::StreamingResponse<ekiden_consensus_api::GetDiffsResponse> {
+        let updates = self.state
+            .lock()
+            .unwrap()
+            .subscribe(req.get_contract_id(), req.get_since_height());
+
+        grpc::StreamingResponse::no_metadata(
+            updates.map_err(|_| grpc::Error::Other("Subscription closed.")),
+        )
+    }
 }
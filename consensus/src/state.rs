@@ -1,21 +1,812 @@
+use std;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use futures::sync::mpsc;
+use protobuf;
+
+use ekiden_consensus_api::{Checkpoint, GetDiffsResponse, GetResponse, StoredTx};
+use ekiden_enclave_common::consensus_hash;
+use ekiden_enclave_common::quote::{self, MrEnclave};
+use ekiden_enclave_common::signature::{self, SIGNATURE_CONTEXT_STATE_CHECKPOINT,
+                                       SIGNATURE_CONTEXT_STATE_DIFF,
+                                       SIGNATURE_CONTEXT_STATE_REPLACE};
+
+use errors::Error;
+use storage::Storage;
+
+/// Length of the application state hash.
+pub const APP_HASH_LEN: usize = consensus_hash::HASH_LEN;
+
+/// Policy for compacting diffs into checkpoints.
+#[derive(Clone, Debug)]
+pub struct CheckpointPolicy {
+    /// Request a checkpoint once this many diffs were added since the last checkpoint.
+    /// Zero disables the limit.
+    pub max_diffs: u64,
+    /// Request a checkpoint once the diffs added since the last checkpoint exceed this
+    /// many bytes. Zero disables the limit.
+    pub max_diffs_size: u64,
+    /// Number of diffs folded into the last checkpoint that are retained, so that nodes
+    /// which are slightly behind can catch up without fetching the checkpoint.
+    pub history_window: u64,
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        CheckpointPolicy {
+            max_diffs: 1000,
+            max_diffs_size: 64 * 1024 * 1024,
+            history_window: 100,
+        }
+    }
+}
+
+/// State of a single contract.
+#[derive(Clone)]
 pub struct StateInitialized {
     pub checkpoint: Vec<u8>,
     pub checkpoint_height: u64,
     pub diffs: Vec<Vec<u8>>,
+    /// Retained diffs leading up to the checkpoint, in order. These are not part of
+    /// the state hash.
+    pub history: Vec<Vec<u8>>,
+}
+
+impl StateInitialized {
+    /// Height of the state, including all diffs.
+    pub fn height(&self) -> u64 {
+        self.checkpoint_height + self.diffs.len() as u64
+    }
+
+    /// Latest checkpoint.
+    pub fn get_checkpoint(&self) -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.set_payload(self.checkpoint.clone());
+        checkpoint.set_height(self.checkpoint_height);
+        checkpoint
+    }
+
+    /// Check if the diffs should be folded into a new checkpoint under the given policy.
+    pub fn checkpoint_due(&self, policy: &CheckpointPolicy) -> bool {
+        let diffs_size: u64 = self.diffs.iter().map(|diff| diff.len() as u64).sum();
+
+        (policy.max_diffs > 0 && self.diffs.len() as u64 >= policy.max_diffs)
+            || (policy.max_diffs_size > 0 && diffs_size >= policy.max_diffs_size)
+    }
+
+    /// Diffs that bring a state at the given height up to date.
+    ///
+    /// Returns None if the diffs going back that far are not retained, in which case
+    /// the checkpoint is needed as well.
+    pub fn diffs_since(&self, height: u64) -> Option<Vec<Vec<u8>>> {
+        // Imported states are validated, so the history never goes back further than
+        // height zero. Otherwise, just require the checkpoint.
+        let history_height = self.checkpoint_height
+            .checked_sub(self.history.len() as u64)?;
+        if height < history_height || height > self.height() {
+            None
+        } else if height < self.checkpoint_height {
+            let mut diffs = self.history[(height - history_height) as usize..].to_vec();
+            diffs.extend_from_slice(&self.diffs);
+            Some(diffs)
+        } else {
+            Some(self.diffs[(height - self.checkpoint_height) as usize..].to_vec())
+        }
+    }
+
+    /// Compute a deterministic hash of the state.
+    ///
+    /// The hash is the root of a binary Merkle tree where the first leaf covers
+    /// the checkpoint and its height and each subsequent leaf covers one diff, in
+    /// order.
+    pub fn hash(&self) -> Vec<u8> {
+        consensus_hash::contract_state_hash(self.checkpoint_height, &self.checkpoint, &self.diffs)
+    }
+}
+
+/// Subscriber to committed state updates of a contract.
+struct Subscriber {
+    sender: mpsc::UnboundedSender<GetDiffsResponse>,
+    /// Contract identifier.
+    contract_id: Vec<u8>,
+    /// Height of the state that the subscriber was last brought up to date with.
+    height: u64,
+    /// Hash of the state that the subscriber was last brought up to date with.
+    state_hash: Vec<u8>,
 }
 
 pub struct State {
-    pub everything: Option<StateInitialized>,
+    /// Initialized contract states, keyed by contract identifier. The identifier of a
+    /// contract is the MRENCLAVE of its enclave.
+    pub contracts: BTreeMap<Vec<u8>, StateInitialized>,
+    /// Height of the last committed block.
+    pub last_block_height: u64,
+    /// Application state hash as of the last committed block.
+    pub app_hash: Vec<u8>,
+    /// Hash of the current state, including delivered transactions that are not yet
+    /// committed.
+    pub state_hash: Vec<u8>,
+    /// Committed states of contracts which were changed by transactions delivered since
+    /// the last commit. None if the contract was not initialized.
+    committed_contracts: BTreeMap<Vec<u8>, Option<StateInitialized>>,
+    /// Durable storage backend. None if the state is only kept in memory.
+    storage: Option<Storage>,
+    /// True if a snapshot should be written on the next commit.
+    snapshot_pending: bool,
+    /// MRENCLAVEs of compute enclaves that are allowed to update the state.
+    trusted_enclaves: HashSet<MrEnclave>,
+    /// Policy for compacting diffs into checkpoints.
+    checkpoint_policy: CheckpointPolicy,
+    /// Subscribers to committed state updates.
+    subscribers: Vec<Subscriber>,
 }
 
 impl State {
-    pub fn new() -> Self {
-        State { everything: None }
+    pub fn new(trusted_enclaves: Vec<MrEnclave>, checkpoint_policy: CheckpointPolicy) -> Self {
+        State {
+            contracts: BTreeMap::new(),
+            last_block_height: 0,
+            app_hash: Vec::new(),
+            state_hash: Vec::new(),
+            committed_contracts: BTreeMap::new(),
+            storage: None,
+            snapshot_pending: false,
+            trusted_enclaves: trusted_enclaves.into_iter().collect(),
+            checkpoint_policy,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Open state persisted in the given storage directory.
+    ///
+    /// Any state that was committed to the directory is recovered. Transactions
+    /// that were delivered but not committed are discarded, as Tendermint will
+    /// replay them.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        trusted_enclaves: Vec<MrEnclave>,
+        checkpoint_policy: CheckpointPolicy,
+    ) -> Result<Self, Box<std::error::Error>> {
+        let (storage, recovered) = Storage::open(path, checkpoint_policy.history_window)?;
+
+        Ok(State {
+            contracts: recovered.contracts,
+            last_block_height: recovered.last_block_height,
+            state_hash: recovered.app_hash.clone(),
+            app_hash: recovered.app_hash,
+            committed_contracts: BTreeMap::new(),
+            storage: Some(storage),
+            snapshot_pending: false,
+            trusted_enclaves: trusted_enclaves.into_iter().collect(),
+            checkpoint_policy,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Height of the current state of a contract.
+    ///
+    /// Uninitialized state has height zero.
+    pub fn height(&self, contract_id: &[u8]) -> u64 {
+        match self.contracts.get(contract_id) {
+            Some(si) => si.height(),
+            None => 0,
+        }
+    }
+
+    /// Hash of the current state of a contract.
+    ///
+    /// Uninitialized state has an empty hash.
+    pub fn contract_hash(&self, contract_id: &[u8]) -> Vec<u8> {
+        match self.contracts.get(contract_id) {
+            Some(si) => si.hash(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Check if the diffs of a contract should be folded into a new checkpoint.
+    pub fn checkpoint_due(&self, contract_id: &[u8]) -> bool {
+        match self.contracts.get(contract_id) {
+            Some(si) => si.checkpoint_due(&self.checkpoint_policy),
+            None => false,
+        }
     }
 
-    pub fn check_tx(_tx: &[u8]) -> Result<(), String> {
-        // @todo - check attestations
-        // @todo - check that this was based off latest
+    /// Get the latest checkpoint and all diffs on top of it for a contract.
+    ///
+    /// Returns None if the state of the contract is not initialized.
+    pub fn get(&self, contract_id: &[u8]) -> Option<GetResponse> {
+        let si = self.contracts.get(contract_id)?;
+
+        let mut response = GetResponse::new();
+        response.set_checkpoint(si.get_checkpoint());
+        response.set_diffs(protobuf::RepeatedField::from_vec(si.diffs.clone()));
+        response.set_state_hash(si.hash());
+        response.set_checkpoint_due(si.checkpoint_due(&self.checkpoint_policy));
+        Some(response)
+    }
+
+    /// State of a contract as of the last committed block.
+    ///
+    /// Returns None if the committed state of the contract is not initialized.
+    pub fn get_committed(&self, contract_id: &[u8]) -> Option<&StateInitialized> {
+        match self.committed_contracts.get(contract_id) {
+            Some(committed) => committed.as_ref(),
+            None => self.contracts.get(contract_id),
+        }
+    }
+
+    /// Get the diffs of a contract since the given height.
+    ///
+    /// If the diffs going back that far are not retained, the checkpoint and all diffs
+    /// on top of it are returned instead. Returns None if the state of the contract is
+    /// not initialized.
+    pub fn get_diffs(&self, contract_id: &[u8], since_height: u64) -> Option<GetDiffsResponse> {
+        let si = self.contracts.get(contract_id)?;
+        Some(self.diffs_response(si, since_height))
+    }
+
+    /// Get the diffs of a contract since the given height as of the last committed block.
+    ///
+    /// See `get_diffs`.
+    pub fn get_committed_diffs(
+        &self,
+        contract_id: &[u8],
+        since_height: u64,
+    ) -> Option<GetDiffsResponse> {
+        let si = self.get_committed(contract_id)?;
+        Some(self.diffs_response(si, since_height))
+    }
+
+    fn diffs_response(&self, si: &StateInitialized, since_height: u64) -> GetDiffsResponse {
+        let mut response = GetDiffsResponse::new();
+        match si.diffs_since(since_height) {
+            Some(diffs) => {
+                response.set_diffs(protobuf::RepeatedField::from_vec(diffs));
+            }
+            None => {
+                response.set_checkpoint(si.get_checkpoint());
+                response.set_diffs(protobuf::RepeatedField::from_vec(si.diffs.clone()));
+            }
+        }
+        response.set_state_hash(si.hash());
+        response.set_checkpoint_due(si.checkpoint_due(&self.checkpoint_policy));
+        response.set_height(si.height());
+        response
+    }
+
+    /// Subscribe to committed state updates of a contract since the given height.
+    ///
+    /// Each update is the same as the response of `get_diffs` for the height of the
+    /// previous update, so it brings the state of the subscriber up to date.
+    pub fn subscribe(
+        &mut self,
+        contract_id: &[u8],
+        since_height: u64,
+    ) -> mpsc::UnboundedReceiver<GetDiffsResponse> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(Subscriber {
+            sender,
+            contract_id: contract_id.to_vec(),
+            height: since_height,
+            state_hash: Vec::new(),
+        });
+        self.notify_subscribers();
+
+        receiver
+    }
+
+    /// Send an update to all subscribers which are not up to date with the committed
+    /// state. Subscribers which went away are dropped.
+    fn notify_subscribers(&mut self) {
+        // Only committed state is sent.
+        if self.state_hash != self.app_hash {
+            return;
+        }
+
+        let subscribers = std::mem::replace(&mut self.subscribers, Vec::new());
+        for mut subscriber in subscribers {
+            if let Some(update) = self.get_diffs(&subscriber.contract_id, subscriber.height) {
+                if subscriber.state_hash != update.get_state_hash() {
+                    subscriber.height = update.get_height();
+                    subscriber.state_hash = update.get_state_hash().to_vec();
+                    if subscriber.sender.unbounded_send(update).is_err() {
+                        // Subscriber went away.
+                        continue;
+                    }
+                }
+            }
+            self.subscribers.push(subscriber);
+        }
+    }
+
+    /// Check that a transaction may be applied to the current state.
+    ///
+    /// Returns a `StaleStateError` if the transaction was not computed on the current
+    /// state of its contract.
+    pub fn check_tx(&self, tx: &[u8]) -> Result<(), Box<std::error::Error>> {
+        let stored: StoredTx = protobuf::parse_from_bytes(tx)?;
+        self.check_stored_tx(&stored)
+    }
+
+    /// Check that a decoded transaction may be applied to the current state.
+    ///
+    /// The transaction must be computed on the current state of its contract and must
+    /// be signed by an attested compute enclave running that contract, with a trusted
+    /// MRENCLAVE.
+    fn check_stored_tx(&self, stored: &StoredTx) -> Result<(), Box<std::error::Error>> {
+        let contract_id = stored.get_contract_id();
+        let height = self.height(contract_id);
+        if stored.get_base_height() != height
+            || stored.get_base_state_hash() != &self.contract_hash(contract_id)[..]
+        {
+            return Err(Box::new(Error::StaleStateError(format!(
+                "Transaction is not based on the current state (base height {}, current height {})",
+                stored.get_base_height(),
+                height
+            ))));
+        }
+
+        // The enclave signs the height of the state that the transaction produces.
+        let (context, payload, signed_height) = if stored.has_replace() {
+            (&SIGNATURE_CONTEXT_STATE_REPLACE, stored.get_replace(), height + 1)
+        } else if stored.has_diff() {
+            if !self.contracts.contains_key(contract_id) {
+                return Err(From::from("Can't add diff to uninitialized state."));
+            }
+            (&SIGNATURE_CONTEXT_STATE_DIFF, stored.get_diff(), height + 1)
+        } else if stored.has_checkpoint() {
+            if !self.contracts.contains_key(contract_id) {
+                return Err(From::from("Can't checkpoint uninitialized state."));
+            }
+            // A checkpoint is a full state at the current height.
+            (&SIGNATURE_CONTEXT_STATE_CHECKPOINT, stored.get_checkpoint(), height)
+        } else {
+            return Err(From::from("Unrecognized StoredTx variant"));
+        };
+
+        if !stored.has_identity_proof() || stored.get_signature().is_empty() {
+            return Err(From::from("Transaction is not signed"));
+        }
+
+        let authenticated = match quote::verify(stored.get_identity_proof()) {
+            Ok(authenticated) => authenticated,
+            Err(error) => {
+                return Err(From::from(format!(
+                    "Invalid enclave identity proof: {}",
+                    error
+                )))
+            }
+        };
+
+        if !self.trusted_enclaves.contains(&authenticated.mr_enclave) {
+            return Err(From::from("Transaction is not signed by a trusted enclave"));
+        }
+
+        if &authenticated.mr_enclave[..] != contract_id {
+            return Err(From::from("Transaction is not signed by the contract's enclave"));
+        }
+
+        match signature::verify(
+            &authenticated.identity.sign_key_pub,
+            context,
+            &signature::pack_state_update(signed_height, stored.get_base_state_hash(), payload),
+            stored.get_signature(),
+        ) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(From::from(format!(
+                "Invalid transaction signature: {}",
+                error
+            ))),
+        }
+    }
+
+    /// Apply a delivered transaction and write it through to storage.
+    ///
+    /// The transaction is checked again, as the state may have changed since it
+    /// was admitted. It only becomes durable once the block is committed.
+    pub fn deliver_tx(&mut self, tx: &[u8]) -> Result<(), Box<std::error::Error>> {
+        let stored: StoredTx = protobuf::parse_from_bytes(tx)?;
+        self.check_stored_tx(&stored)?;
+        let is_diff = stored.has_diff();
+
+        // Keep the committed state of the contract until the block is committed.
+        if !self.committed_contracts.contains_key(stored.get_contract_id()) {
+            let committed = self.contracts.get(stored.get_contract_id()).cloned();
+            self.committed_contracts.insert(stored.get_contract_id().to_vec(), committed);
+        }
+
+        // The transaction is logged before it is applied, so that the state never
+        // contains a transaction which would be missing after recovery.
+        if let Some(ref mut storage) = self.storage {
+            storage.append_tx(tx)?;
+        }
+
+        State::apply(&mut self.contracts, stored, self.checkpoint_policy.history_window)?;
+        self.state_hash = State::compute_app_hash(&self.contracts);
+
+        // Replacing or checkpointing the state removes all diffs, so this is a
+        // good time to write a snapshot and truncate the log.
+        if !is_diff {
+            self.snapshot_pending = true;
+        }
+
         Ok(())
     }
+
+    /// Commit all delivered transactions as the next block.
+    ///
+    /// Returns the new application state hash.
+    pub fn commit(&mut self) -> Result<Vec<u8>, Box<std::error::Error>> {
+        let block_height = self.last_block_height + 1;
+        let app_hash = self.state_hash.clone();
+
+        if let Some(ref mut storage) = self.storage {
+            storage.append_commit(block_height, &app_hash)?;
+
+            if self.snapshot_pending {
+                storage.write_snapshot(&self.contracts, block_height, &app_hash)?;
+            }
+        }
+
+        self.snapshot_pending = false;
+        self.committed_contracts.clear();
+        self.last_block_height = block_height;
+        self.app_hash = app_hash.clone();
+        self.notify_subscribers();
+
+        Ok(app_hash)
+    }
+
+    /// Compute application state hash.
+    ///
+    /// The hash is the root of a binary Merkle tree where each leaf covers the
+    /// identifier and the state hash of one contract, in identifier order. State
+    /// without any initialized contracts has an empty hash.
+    pub fn compute_app_hash(contracts: &BTreeMap<Vec<u8>, StateInitialized>) -> Vec<u8> {
+        if contracts.is_empty() {
+            return Vec::new();
+        }
+
+        let leaves = contracts
+            .iter()
+            .map(|(contract_id, si)| {
+                let mut leaf = vec![consensus_hash::MERKLE_LEAF_PREFIX];
+                leaf.write_u64::<LittleEndian>(contract_id.len() as u64).unwrap();
+                leaf.extend_from_slice(contract_id);
+                leaf.extend_from_slice(&si.hash());
+                consensus_hash::hash(&leaf)
+            })
+            .collect();
+
+        consensus_hash::merkle_root(leaves)
+    }
+
+    /// Apply a transaction to the state of its contract.
+    ///
+    /// A checkpoint retains up to `history_window` of the diffs it folds.
+    pub fn apply(
+        contracts: &mut BTreeMap<Vec<u8>, StateInitialized>,
+        mut stored: StoredTx,
+        history_window: u64,
+    ) -> Result<(), Box<std::error::Error>> {
+        let contract_id = stored.take_contract_id();
+
+        if stored.has_replace() {
+            let current_height = match contracts.get(&contract_id) {
+                Some(si) => si.height(),
+                None => 0,
+            };
+            contracts.insert(
+                contract_id,
+                StateInitialized {
+                    checkpoint: stored.take_replace(),
+                    checkpoint_height: current_height + 1,
+                    diffs: Vec::new(),
+                    history: Vec::new(),
+                },
+            );
+            Ok(())
+        } else if stored.has_diff() {
+            let si = contracts
+                .get_mut(&contract_id)
+                .ok_or::<Box<std::error::Error>>(From::from(
+                    "Can't add diff to uninitialized state.",
+                ))?;
+            si.diffs.push(stored.take_diff());
+            Ok(())
+        } else if stored.has_checkpoint() {
+            let si = contracts
+                .get_mut(&contract_id)
+                .ok_or::<Box<std::error::Error>>(From::from(
+                    "Can't checkpoint uninitialized state.",
+                ))?;
+            si.checkpoint = stored.take_checkpoint();
+            si.checkpoint_height += si.diffs.len() as u64;
+            si.history.extend(si.diffs.drain(..));
+
+            let history_window = std::cmp::min(history_window, si.history.len() as u64);
+            let pruned = si.history.len() - history_window as usize;
+            si.history.drain(..pruned);
+            Ok(())
+        } else {
+            Err(From::from("Unrecognized StoredTx variant"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+    use protobuf::{self, Message};
+
+    use ekiden_consensus_api::StoredTx;
+    use ekiden_enclave_common::quote::MrEnclave;
+
+    use super::super::errors::Error;
+    use super::super::test_utils::TestEnclave;
+    use super::{CheckpointPolicy, State, StateInitialized};
+
+    #[test]
+    fn test_state_hash() {
+        let mut state = StateInitialized {
+            checkpoint: b"checkpoint".to_vec(),
+            checkpoint_height: 1,
+            diffs: vec![],
+            history: vec![],
+        };
+        let checkpoint_hash = state.hash();
+        assert_eq!(checkpoint_hash.len(), super::APP_HASH_LEN);
+
+        // Hash must depend on the checkpoint height.
+        state.checkpoint_height = 2;
+        assert!(state.hash() != checkpoint_hash);
+        state.checkpoint_height = 1;
+        assert_eq!(state.hash(), checkpoint_hash);
+
+        // Hash must depend on the diffs and their order.
+        state.diffs = vec![b"diff1".to_vec(), b"diff2".to_vec(), b"diff3".to_vec()];
+        let diffs_hash = state.hash();
+        assert!(diffs_hash != checkpoint_hash);
+
+        state.diffs = vec![b"diff2".to_vec(), b"diff1".to_vec(), b"diff3".to_vec()];
+        assert!(state.hash() != diffs_hash);
+
+        // Hash must not depend on the retained history.
+        state.history = vec![b"diff0".to_vec()];
+        let history_hash = state.hash();
+        state.history = vec![];
+        assert_eq!(state.hash(), history_hash);
+    }
+
+    #[test]
+    fn test_checkpoint_due() {
+        let state = StateInitialized {
+            checkpoint: b"checkpoint".to_vec(),
+            checkpoint_height: 1,
+            diffs: vec![b"diff1".to_vec(), b"diff2".to_vec()],
+            history: vec![],
+        };
+
+        let mut policy = CheckpointPolicy {
+            max_diffs: 0,
+            max_diffs_size: 0,
+            history_window: 0,
+        };
+        assert!(!state.checkpoint_due(&policy));
+
+        policy.max_diffs = 3;
+        assert!(!state.checkpoint_due(&policy));
+        policy.max_diffs = 2;
+        assert!(state.checkpoint_due(&policy));
+
+        policy.max_diffs = 0;
+        policy.max_diffs_size = 11;
+        assert!(!state.checkpoint_due(&policy));
+        policy.max_diffs_size = 10;
+        assert!(state.checkpoint_due(&policy));
+    }
+
+    #[test]
+    fn test_checkpoint_history() {
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+        let mut state = State::new(
+            vec![enclave.mr_enclave.clone()],
+            CheckpointPolicy {
+                max_diffs: 3,
+                max_diffs_size: 0,
+                history_window: 2,
+            },
+        );
+
+        let contract_id = enclave.contract_id();
+
+        let tx = enclave.replace_tx(&state, b"helloworld");
+        state.deliver_tx(&tx).unwrap();
+        for diff in &[b"diff1", b"diff2", b"diff3"] {
+            let tx = enclave.diff_tx(&state, *diff);
+            state.deliver_tx(&tx).unwrap();
+        }
+        assert!(state.checkpoint_due(&contract_id));
+
+        let tx = enclave.checkpoint_tx(&state, b"folded");
+        state.deliver_tx(&tx).unwrap();
+        let tx = enclave.diff_tx(&state, b"diff4");
+        state.deliver_tx(&tx).unwrap();
+        assert!(!state.checkpoint_due(&contract_id));
+
+        let si = &state.contracts[&contract_id];
+        assert_eq!(si.checkpoint, b"folded");
+        assert_eq!(si.checkpoint_height, 4);
+        assert_eq!(si.history, vec![b"diff2".to_vec(), b"diff3".to_vec()]);
+        assert_eq!(si.height(), 5);
+
+        // Nodes within the history window only need diffs.
+        assert_eq!(
+            si.diffs_since(2),
+            Some(vec![b"diff2".to_vec(), b"diff3".to_vec(), b"diff4".to_vec()])
+        );
+        assert_eq!(si.diffs_since(4), Some(vec![b"diff4".to_vec()]));
+        assert_eq!(si.diffs_since(5), Some(vec![]));
+
+        // Nodes behind the history window or ahead of the state need the checkpoint.
+        assert_eq!(si.diffs_since(1), None);
+        assert_eq!(si.diffs_since(0), None);
+        assert_eq!(si.diffs_since(6), None);
+
+        // History going back further than height zero is never used.
+        let si = StateInitialized {
+            checkpoint: b"checkpoint".to_vec(),
+            checkpoint_height: 1,
+            diffs: vec![],
+            history: vec![b"diff0".to_vec(), b"diff1".to_vec()],
+        };
+        assert_eq!(si.diffs_since(0), None);
+        assert_eq!(si.diffs_since(1), None);
+    }
+
+    #[test]
+    fn test_check_tx() {
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+        let mut state = State::new(vec![enclave.mr_enclave.clone()], CheckpointPolicy::default());
+
+        // Unsigned transactions are rejected.
+        let mut unsigned = StoredTx::new();
+        unsigned.set_replace(b"helloworld".to_vec());
+        assert!(state.check_tx(&unsigned.write_to_bytes().unwrap()).is_err());
+
+        // Diffs and checkpoints of uninitialized state are rejected.
+        assert!(state.check_tx(&enclave.diff_tx(&state, b"diff1")).is_err());
+        assert!(state.check_tx(&enclave.checkpoint_tx(&state, b"helloworld")).is_err());
+
+        let tx = enclave.replace_tx(&state, b"helloworld");
+        assert!(state.check_tx(&tx).is_ok());
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+
+        // Transactions computed on an earlier state are rejected as stale.
+        for result in vec![state.check_tx(&tx), state.deliver_tx(&tx)] {
+            match result.unwrap_err().downcast_ref::<Error>() {
+                Some(&Error::StaleStateError(_)) => {}
+                _ => panic!("Expected a stale state error"),
+            }
+        }
+        assert!(state.check_tx(&enclave.diff_tx(&state, b"diff1")).is_ok());
+
+        // Base state hash must match.
+        let mut stale: StoredTx =
+            protobuf::parse_from_bytes(&enclave.diff_tx(&state, b"diff1")).unwrap();
+        stale.set_base_state_hash(vec![0; super::APP_HASH_LEN]);
+        assert!(state.check_tx(&stale.write_to_bytes().unwrap()).is_err());
+
+        // Payload must match the signature.
+        let mut forged: StoredTx =
+            protobuf::parse_from_bytes(&enclave.diff_tx(&state, b"diff1")).unwrap();
+        forged.set_diff(b"diff2".to_vec());
+        assert!(state.check_tx(&forged.write_to_bytes().unwrap()).is_err());
+
+        // A signed replacement cannot be replayed as a checkpoint of the state it produces.
+        let replace: StoredTx =
+            protobuf::parse_from_bytes(&enclave.replace_tx(&state, b"replaced")).unwrap();
+        state.deliver_tx(&enclave.diff_tx(&state, b"diff1")).unwrap();
+        state.commit().unwrap();
+        let mut replayed: StoredTx =
+            protobuf::parse_from_bytes(&enclave.checkpoint_tx(&state, b"replaced")).unwrap();
+        assert!(state.check_tx(&replayed.write_to_bytes().unwrap()).is_ok());
+        replayed.set_signature(replace.get_signature().to_vec());
+        assert!(state.check_tx(&replayed.write_to_bytes().unwrap()).is_err());
+
+        // Enclave must be trusted.
+        let untrusted = TestEnclave::new(MrEnclave([2; 32]));
+        assert!(state.check_tx(&untrusted.replace_tx(&state, b"helloworld")).is_err());
+    }
+
+    #[test]
+    fn test_contracts() {
+        let token = TestEnclave::new(MrEnclave([1; 32]));
+        let key_manager = TestEnclave::new(MrEnclave([2; 32]));
+        let mut state = State::new(
+            vec![token.mr_enclave.clone(), key_manager.mr_enclave.clone()],
+            CheckpointPolicy::default(),
+        );
+
+        let tx = token.replace_tx(&state, b"token");
+        state.deliver_tx(&tx).unwrap();
+        let token_hash = state.contract_hash(&token.contract_id());
+        let app_hash = state.state_hash.clone();
+
+        // Contracts are updated independently.
+        let key_manager_tx = key_manager.replace_tx(&state, b"key manager");
+        let tx = token.diff_tx(&state, b"diff1");
+        state.deliver_tx(&tx).unwrap();
+        state.deliver_tx(&key_manager_tx).unwrap();
+
+        assert_eq!(state.height(&token.contract_id()), 2);
+        assert_eq!(state.height(&key_manager.contract_id()), 1);
+        assert!(state.contract_hash(&token.contract_id()) != token_hash);
+        assert!(state.state_hash != app_hash);
+        assert_eq!(
+            state.contracts[&key_manager.contract_id()].checkpoint,
+            b"key manager"
+        );
+
+        // Enclaves may only update the state of their own contract.
+        let mut forged: StoredTx =
+            protobuf::parse_from_bytes(&key_manager.diff_tx(&state, b"diff1")).unwrap();
+        forged.set_contract_id(token.contract_id());
+        forged.set_base_height(state.height(&token.contract_id()));
+        forged.set_base_state_hash(state.contract_hash(&token.contract_id()));
+        assert!(state.check_tx(&forged.write_to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+        let mut state = State::new(vec![enclave.mr_enclave.clone()], CheckpointPolicy::default());
+
+        let mut updates = state.subscribe(&enclave.contract_id(), 0).wait();
+
+        let tx = enclave.replace_tx(&state, b"helloworld");
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+
+        let update = updates.next().unwrap().unwrap();
+        assert_eq!(update.get_checkpoint().get_payload(), b"helloworld");
+        assert_eq!(update.get_checkpoint().get_height(), 1);
+        assert!(update.get_diffs().is_empty());
+        assert_eq!(update.get_height(), 1);
+        assert_eq!(
+            update.get_state_hash(),
+            &state.contract_hash(&enclave.contract_id())[..]
+        );
+
+        let tx = enclave.diff_tx(&state, b"diff1");
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+
+        let update = updates.next().unwrap().unwrap();
+        assert!(!update.has_checkpoint());
+        assert_eq!(update.get_diffs(), &[b"diff1".to_vec()]);
+        assert_eq!(update.get_height(), 2);
+
+        // Uncommitted transactions are not sent to new subscribers.
+        let tx = enclave.diff_tx(&state, b"diff2");
+        state.deliver_tx(&tx).unwrap();
+        let mut late_updates = state.subscribe(&enclave.contract_id(), 1).wait();
+        state.commit().unwrap();
+
+        let update = updates.next().unwrap().unwrap();
+        assert_eq!(update.get_diffs(), &[b"diff2".to_vec()]);
+        let update = late_updates.next().unwrap().unwrap();
+        assert_eq!(update.get_diffs(), &[b"diff1".to_vec(), b"diff2".to_vec()]);
+        assert_eq!(update.get_height(), 3);
+
+        // Subscribers which went away are dropped.
+        drop(late_updates);
+        let tx = enclave.diff_tx(&state, b"diff3");
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+        assert_eq!(state.subscribers.len(), 1);
+    }
 }
@@ -0,0 +1,710 @@
+//! Durable on-disk storage for the consensus state.
+//!
+//! The storage consists of two files in the storage directory:
+//!
+//! * `snapshot` contains a full [`Snapshot`] of the state. It is replaced atomically
+//!   by writing a temporary file and renaming it over the old one.
+//! * `log` is an append-only log of delivered transactions and block commits which
+//!   have not yet been folded into the snapshot. Each record is framed as a
+//!   little-endian `u32` length, a little-endian `u32` CRC32 checksum of the payload
+//!   and a serialized [`LogRecord`].
+//!
+//! On startup, the snapshot is loaded and all committed log records with a sequence
+//! number newer than the snapshot are replayed. Transactions after the last commit
+//! and a torn record at the end of the log (e.g., due to a crash in the middle of a
+//! write) are discarded. A corrupted record anywhere else in the log is an error, as
+//! discarding it would also discard the committed records after it.
+//!
+//! [`Snapshot`]: super::generated::storage::Snapshot
+//! [`LogRecord`]: super::generated::storage::LogRecord
+use std;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use protobuf::{self, Message};
+
+use ekiden_consensus_api::StoredTx;
+
+use super::generated::storage::{Commit, ContractSnapshot, LogRecord, Snapshot};
+use super::state::{State, StateInitialized};
+
+/// Name of the snapshot file.
+const SNAPSHOT_FILE: &'static str = "snapshot";
+/// Name of the temporary file used while writing a new snapshot.
+const SNAPSHOT_TEMP_FILE: &'static str = "snapshot.tmp";
+/// Name of the transaction log file.
+const LOG_FILE: &'static str = "log";
+/// Size of the log record header (length + checksum).
+const LOG_HEADER_SIZE: u64 = 8;
+/// Maximum size of a log record payload.
+const MAX_RECORD_SIZE: u64 = 256 * 1024 * 1024;
+
+/// State recovered from storage.
+pub struct RecoveredState {
+    /// Committed contract states.
+    pub contracts: BTreeMap<Vec<u8>, StateInitialized>,
+    /// Height of the last committed block.
+    pub last_block_height: u64,
+    /// Application state hash as of the last committed block.
+    pub app_hash: Vec<u8>,
+}
+
+/// On-disk storage backend for the consensus state.
+pub struct Storage {
+    /// Storage directory.
+    path: PathBuf,
+    /// Append-only transaction log.
+    log: File,
+    /// Sequence number of the last log record.
+    sequence: u64,
+}
+
+impl Storage {
+    /// Open storage in the given directory, creating it if it does not exist.
+    ///
+    /// Returns the storage together with the recovered state. Checkpoints replayed
+    /// from the log retain up to `history_window` diffs.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        history_window: u64,
+    ) -> Result<(Self, RecoveredState), Box<std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join(LOG_FILE))?;
+        let (recovered, committed_length, committed_sequence) =
+            Self::recover(&path, Some(&mut log), history_window)?;
+
+        // Discard uncommitted transactions and any torn record at the end of the log.
+        log.set_len(committed_length)?;
+        log.sync_all()?;
+        log.seek(SeekFrom::End(0))?;
+
+        Ok((
+            Storage {
+                path,
+                log,
+                sequence: committed_sequence,
+            },
+            recovered,
+        ))
+    }
+
+    /// Read the committed state from the given directory without modifying it.
+    ///
+    /// Checkpoints replayed from the log retain up to `history_window` diffs. A missing
+    /// directory is treated as empty storage.
+    pub fn read<P: AsRef<Path>>(
+        path: P,
+        history_window: u64,
+    ) -> Result<RecoveredState, Box<std::error::Error>> {
+        let path = path.as_ref();
+        let mut log = match File::open(path.join(LOG_FILE)) {
+            Ok(log) => Some(log),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error.into()),
+        };
+
+        let (recovered, _, _) = Self::recover(path, log.as_mut(), history_window)?;
+        Ok(recovered)
+    }
+
+    /// Recover the committed state from the snapshot and the log.
+    ///
+    /// Returns the recovered state, the length of the committed part of the log and
+    /// the sequence number of the last committed log record.
+    fn recover(
+        path: &Path,
+        log: Option<&mut File>,
+        history_window: u64,
+    ) -> Result<(RecoveredState, u64, u64), Box<std::error::Error>> {
+        // Load the latest snapshot.
+        let (mut sequence, mut recovered) = match File::open(path.join(SNAPSHOT_FILE)) {
+            Ok(mut file) => {
+                let mut buffer = Vec::new();
+                file.read_to_end(&mut buffer)?;
+
+                let mut snapshot: Snapshot = protobuf::parse_from_bytes(&buffer)?;
+                (
+                    snapshot.get_sequence(),
+                    RecoveredState {
+                        contracts: contracts_from_snapshots(snapshot.take_contracts().into_vec())?,
+                        last_block_height: snapshot.get_last_commit().get_block_height(),
+                        app_hash: snapshot.get_last_commit().get_app_hash().to_vec(),
+                    },
+                )
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => (
+                0,
+                RecoveredState {
+                    contracts: BTreeMap::new(),
+                    last_block_height: 0,
+                    app_hash: Vec::new(),
+                },
+            ),
+            Err(error) => return Err(error.into()),
+        };
+        if State::compute_app_hash(&recovered.contracts) != recovered.app_hash {
+            return Err(From::from("Snapshot does not match committed hash"));
+        }
+
+        let log = match log {
+            Some(log) => log,
+            None => return Ok((recovered, 0, sequence)),
+        };
+
+        // Replay committed transactions from the log which are not yet part of the
+        // snapshot.
+        let mut pending: Vec<StoredTx> = Vec::new();
+        let mut valid_length = 0;
+        let mut committed_length = 0;
+        let mut committed_sequence = sequence;
+        while let Some((mut record, length)) = Self::read_record(log)? {
+            valid_length += length;
+
+            if record.get_sequence() <= sequence {
+                committed_length = valid_length;
+                continue;
+            }
+            sequence = record.get_sequence();
+
+            if record.has_tx() {
+                pending.push(protobuf::parse_from_bytes(&record.take_tx())?);
+            } else if record.has_commit() {
+                for stored in pending.drain(..) {
+                    State::apply(&mut recovered.contracts, stored, history_window)?;
+                }
+
+                let commit = record.take_commit();
+                if State::compute_app_hash(&recovered.contracts) != commit.get_app_hash() {
+                    return Err(From::from("Recovered state does not match committed hash"));
+                }
+
+                recovered.last_block_height = commit.get_block_height();
+                recovered.app_hash = commit.get_app_hash().to_vec();
+                committed_length = valid_length;
+                committed_sequence = sequence;
+            } else {
+                return Err(From::from("Unrecognized LogRecord variant"));
+            }
+        }
+
+        Ok((recovered, committed_length, committed_sequence))
+    }
+
+    /// Read a single record from the log.
+    ///
+    /// Returns `None` when the end of the log is reached or the next record is torn,
+    /// i.e., it is incomplete or corrupted and it is the last record of the log. A
+    /// corrupted record which is followed by other records was not torn by a crash, so
+    /// it is an error. A corrupted length is never trusted beyond the remaining length
+    /// of the log.
+    fn read_record(log: &mut File) -> Result<Option<(LogRecord, u64)>, Box<std::error::Error>> {
+        let length = match log.read_u32::<LittleEndian>() {
+            Ok(length) => length,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let checksum = match log.read_u32::<LittleEndian>() {
+            Ok(checksum) => checksum,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let position = log.seek(SeekFrom::Current(0))?;
+        let remaining = log.metadata()?.len().saturating_sub(position);
+        if length as u64 > remaining {
+            return Ok(None);
+        }
+
+        // Only the last record can be torn, as records are only ever appended.
+        let corrupted = || -> Result<Option<(LogRecord, u64)>, Box<std::error::Error>> {
+            if length as u64 == remaining {
+                Ok(None)
+            } else {
+                Err(From::from(format!(
+                    "Log is corrupted at offset {}",
+                    position - LOG_HEADER_SIZE
+                )))
+            }
+        };
+        if length as u64 > MAX_RECORD_SIZE {
+            return corrupted();
+        }
+
+        let mut payload = vec![0; length as usize];
+        match log.read_exact(&mut payload) {
+            Ok(_) => {}
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        }
+
+        if crc32::checksum_ieee(&payload) != checksum {
+            return corrupted();
+        }
+
+        match protobuf::parse_from_bytes(&payload) {
+            Ok(record) => Ok(Some((record, LOG_HEADER_SIZE + length as u64))),
+            _ => corrupted(),
+        }
+    }
+
+    /// Append a record to the log.
+    fn append(&mut self, mut record: LogRecord) -> Result<(), Box<std::error::Error>> {
+        record.set_sequence(self.sequence + 1);
+        let payload = record.write_to_bytes()?;
+        if payload.len() as u64 > MAX_RECORD_SIZE {
+            return Err(From::from("Log record too large"));
+        }
+
+        let mut buffer = Vec::with_capacity(LOG_HEADER_SIZE as usize + payload.len());
+        buffer.write_u32::<LittleEndian>(payload.len() as u32)?;
+        buffer.write_u32::<LittleEndian>(crc32::checksum_ieee(&payload))?;
+        buffer.extend_from_slice(&payload);
+
+        self.log.write_all(&buffer)?;
+        self.sequence += 1;
+
+        Ok(())
+    }
+
+    /// Append a delivered transaction to the log.
+    ///
+    /// The transaction is only durable after the next call to `append_commit`.
+    pub fn append_tx(&mut self, tx: &[u8]) -> Result<(), Box<std::error::Error>> {
+        let mut record = LogRecord::new();
+        record.set_tx(tx.to_vec());
+
+        self.append(record)
+    }
+
+    /// Durably commit all transactions appended since the previous commit.
+    pub fn append_commit(
+        &mut self,
+        block_height: u64,
+        app_hash: &[u8],
+    ) -> Result<(), Box<std::error::Error>> {
+        let mut record = LogRecord::new();
+        {
+            let commit = record.mut_commit();
+            commit.set_block_height(block_height);
+            commit.set_app_hash(app_hash.to_vec());
+        }
+
+        self.append(record)?;
+        self.log.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Atomically write a snapshot of the given committed state and truncate the log.
+    ///
+    /// The snapshot covers all records appended so far, so it must only be written
+    /// immediately after a commit.
+    pub fn write_snapshot(
+        &mut self,
+        contracts: &BTreeMap<Vec<u8>, StateInitialized>,
+        block_height: u64,
+        app_hash: &[u8],
+    ) -> Result<(), Box<std::error::Error>> {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_sequence(self.sequence);
+        snapshot.set_contracts(contracts_to_snapshots(contracts));
+        {
+            let mut commit = Commit::new();
+            commit.set_block_height(block_height);
+            commit.set_app_hash(app_hash.to_vec());
+            snapshot.set_last_commit(commit);
+        }
+
+        // Write to a temporary file first and then atomically replace the snapshot.
+        let temp_path = self.path.join(SNAPSHOT_TEMP_FILE);
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&snapshot.write_to_bytes()?)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, self.path.join(SNAPSHOT_FILE))?;
+        File::open(&self.path)?.sync_all()?;
+
+        // All records are now part of the snapshot, so the log can be truncated. If we
+        // crash before this is done, the records will be skipped during recovery as their
+        // sequence numbers are not newer than the snapshot.
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.log.seek(SeekFrom::Start(0))?;
+
+        Ok(())
+    }
+}
+
+/// Convert contract states to their serialized form.
+pub fn contracts_to_snapshots(
+    contracts: &BTreeMap<Vec<u8>, StateInitialized>,
+) -> protobuf::RepeatedField<ContractSnapshot> {
+    contracts
+        .iter()
+        .map(|(contract_id, si)| {
+            let mut contract = ContractSnapshot::new();
+            contract.set_contract_id(contract_id.clone());
+            contract.set_checkpoint(si.checkpoint.clone());
+            contract.set_checkpoint_height(si.checkpoint_height);
+            contract.set_diffs(protobuf::RepeatedField::from_vec(si.diffs.clone()));
+            contract.set_history(protobuf::RepeatedField::from_vec(si.history.clone()));
+            contract
+        })
+        .collect()
+}
+
+/// Convert serialized contract states back to contract states.
+///
+/// Fails if the state of a contract is inconsistent, i.e., if its diffs or its history
+/// do not fit between height zero and the maximum height.
+pub fn contracts_from_snapshots(
+    contracts: Vec<ContractSnapshot>,
+) -> Result<BTreeMap<Vec<u8>, StateInitialized>, Box<std::error::Error>> {
+    contracts
+        .into_iter()
+        .map(|mut contract| {
+            let checkpoint_height = contract.get_checkpoint_height();
+            if checkpoint_height < contract.get_history().len() as u64
+                || checkpoint_height
+                    .checked_add(contract.get_diffs().len() as u64)
+                    .is_none()
+            {
+                return Err(From::from("Snapshot contains inconsistent contract state"));
+            }
+
+            Ok((
+                contract.take_contract_id(),
+                StateInitialized {
+                    checkpoint: contract.take_checkpoint(),
+                    checkpoint_height: checkpoint_height,
+                    diffs: contract.take_diffs().into_vec(),
+                    history: contract.take_history().into_vec(),
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use ekiden_enclave_common::quote::MrEnclave;
+
+    use super::super::state::{CheckpointPolicy, State};
+    use super::super::test_utils::TestEnclave;
+    use super::{LOG_FILE, LOG_HEADER_SIZE};
+
+    static TEST_DIR_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Create a fresh storage directory for a test.
+    fn test_dir() -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "ekiden-consensus-storage-{}-{}",
+            process::id(),
+            TEST_DIR_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Open state that trusts the test enclave.
+    fn open(path: &PathBuf, enclave: &TestEnclave) -> State {
+        State::open(
+            path,
+            vec![enclave.mr_enclave.clone()],
+            CheckpointPolicy {
+                max_diffs: 0,
+                max_diffs_size: 0,
+                history_window: 1,
+            },
+        ).unwrap()
+    }
+
+    /// Deliver a transaction computed on the current state and commit it in its own block.
+    fn deliver<F>(state: &mut State, tx: F)
+    where
+        F: FnOnce(&State) -> Vec<u8>,
+    {
+        let tx = tx(state);
+        state.deliver_tx(&tx).unwrap();
+        state.commit().unwrap();
+    }
+
+    #[test]
+    fn test_empty_storage() {
+        let path = test_dir();
+        let state = State::open(&path, vec![], CheckpointPolicy::default()).unwrap();
+        assert!(state.contracts.is_empty());
+        assert_eq!(state.last_block_height, 0);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        let app_hash = {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff2"));
+            // State is dropped without a checkpoint, as if the node was killed.
+            state.app_hash.clone()
+        };
+
+        let state = open(&path, &enclave);
+        {
+            let si = &state.contracts[&enclave.contract_id()];
+            assert_eq!(si.checkpoint, b"helloworld");
+            assert_eq!(si.checkpoint_height, 1);
+            assert_eq!(si.diffs, vec![b"diff1".to_vec(), b"diff2".to_vec()]);
+        }
+        assert_eq!(state.last_block_height, 3);
+        assert_eq!(state.app_hash, app_hash);
+        drop(state);
+
+        // Checkpoint and recover again.
+        {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.checkpoint_tx(state, b"folded"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff3"));
+        }
+
+        let state = open(&path, &enclave);
+        {
+            let si = &state.contracts[&enclave.contract_id()];
+            assert_eq!(si.checkpoint, b"folded");
+            assert_eq!(si.checkpoint_height, 3);
+            assert_eq!(si.diffs, vec![b"diff3".to_vec()]);
+            assert_eq!(si.history, vec![b"diff2".to_vec()]);
+        }
+        assert_eq!(state.last_block_height, 5);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_contracts() {
+        let path = test_dir();
+        let token = TestEnclave::new(MrEnclave([1; 32]));
+        let key_manager = TestEnclave::new(MrEnclave([2; 32]));
+        let open = |path: &PathBuf| {
+            State::open(
+                path,
+                vec![token.mr_enclave.clone(), key_manager.mr_enclave.clone()],
+                CheckpointPolicy::default(),
+            ).unwrap()
+        };
+
+        let app_hash = {
+            let mut state = open(&path);
+            deliver(&mut state, |state| token.replace_tx(state, b"token"));
+            deliver(&mut state, |state| key_manager.replace_tx(state, b"key manager"));
+            deliver(&mut state, |state| token.diff_tx(state, b"diff1"));
+            state.app_hash.clone()
+        };
+
+        let state = open(&path);
+        assert_eq!(state.contracts.len(), 2);
+        assert_eq!(state.contracts[&token.contract_id()].checkpoint, b"token");
+        assert_eq!(
+            state.contracts[&token.contract_id()].diffs,
+            vec![b"diff1".to_vec()]
+        );
+        assert_eq!(
+            state.contracts[&key_manager.contract_id()].checkpoint,
+            b"key manager"
+        );
+        assert_eq!(state.app_hash, app_hash);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_uncommitted() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            // Killed after delivering a transaction but before the block was committed.
+            let tx = enclave.diff_tx(&state, b"diff1");
+            state.deliver_tx(&tx).unwrap();
+        }
+
+        let mut state = open(&path, &enclave);
+        assert!(state.contracts[&enclave.contract_id()].diffs.is_empty());
+        assert_eq!(state.last_block_height, 1);
+
+        // Tendermint replays the block.
+        deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+        drop(state);
+
+        let state = open(&path, &enclave);
+        assert_eq!(
+            state.contracts[&enclave.contract_id()].diffs,
+            vec![b"diff1".to_vec()]
+        );
+        assert_eq!(state.last_block_height, 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_torn_write() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+        }
+
+        // Simulate a crash in the middle of appending the next diff, before the
+        // checkpoint could be written.
+        {
+            let mut log = OpenOptions::new()
+                .append(true)
+                .open(path.join(LOG_FILE))
+                .unwrap();
+            log.write_all(&[42, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+        }
+
+        {
+            let mut state = open(&path, &enclave);
+            {
+                let si = &state.contracts[&enclave.contract_id()];
+                assert_eq!(si.checkpoint, b"helloworld");
+                assert_eq!(si.diffs, vec![b"diff1".to_vec()]);
+            }
+
+            // The log must remain usable after the torn record is discarded.
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff2"));
+        }
+
+        let state = open(&path, &enclave);
+        assert_eq!(
+            state.contracts[&enclave.contract_id()].diffs,
+            vec![b"diff1".to_vec(), b"diff2".to_vec()]
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_corrupted_length() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+        }
+
+        // A corrupted length must not be trusted for allocating the record.
+        {
+            let mut log = OpenOptions::new()
+                .append(true)
+                .open(path.join(LOG_FILE))
+                .unwrap();
+            log.write_all(&[255, 255, 255, 255, 0, 0, 0, 0]).unwrap();
+        }
+
+        let state = open(&path, &enclave);
+        assert_eq!(
+            state.contracts[&enclave.contract_id()].diffs,
+            vec![b"diff1".to_vec()]
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_corrupted_record() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+        }
+        let log = fs::read(path.join(LOG_FILE)).unwrap();
+
+        // A corrupted last record is discarded as if it was torn.
+        {
+            let mut corrupted = log.clone();
+            let last = corrupted.len() - 1;
+            corrupted[last] ^= 1;
+            fs::write(path.join(LOG_FILE), &corrupted).unwrap();
+
+            let state = open(&path, &enclave);
+            let si = &state.contracts[&enclave.contract_id()];
+            assert_eq!(si.checkpoint, b"helloworld");
+            assert!(si.diffs.is_empty());
+        }
+
+        // A corrupted record followed by committed records is an error.
+        let mut corrupted = log.clone();
+        corrupted[LOG_HEADER_SIZE as usize] ^= 1;
+        fs::write(path.join(LOG_FILE), &corrupted).unwrap();
+        assert!(State::open(&path, vec![], CheckpointPolicy::default()).is_err());
+
+        // The log is left as it is, so that it can be repaired.
+        assert_eq!(fs::read(path.join(LOG_FILE)).unwrap(), corrupted);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_snapshot_before_truncate() {
+        let path = test_dir();
+        let enclave = TestEnclave::new(MrEnclave([1; 32]));
+
+        // Simulate a crash after the snapshot was written but before the log was
+        // truncated by keeping a copy of the log.
+        let log_copy = {
+            let mut state = open(&path, &enclave);
+            deliver(&mut state, |state| enclave.replace_tx(state, b"helloworld"));
+            deliver(&mut state, |state| enclave.diff_tx(state, b"diff1"));
+            let log_copy = fs::read(path.join(LOG_FILE)).unwrap();
+            deliver(&mut state, |state| enclave.checkpoint_tx(state, b"folded"));
+            log_copy
+        };
+        fs::write(path.join(LOG_FILE), &log_copy).unwrap();
+
+        let state = open(&path, &enclave);
+        {
+            let si = &state.contracts[&enclave.contract_id()];
+            assert_eq!(si.checkpoint, b"folded");
+            assert_eq!(si.checkpoint_height, 2);
+            assert!(si.diffs.is_empty());
+            assert_eq!(si.history, vec![b"diff1".to_vec()]);
+        }
+        assert_eq!(state.last_block_height, 3);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
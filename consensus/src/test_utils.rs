@@ -0,0 +1,145 @@
+//! Utilities for producing signed transactions in tests.
+use base64;
+use protobuf::Message;
+use sodalite;
+
+use ekiden_consensus_api::StoredTx;
+use ekiden_enclave_common::api::{AvReport, IdentityProof};
+use ekiden_enclave_common::identity::{pack_public_identity, pack_report_data,
+                                      PublicIdentityComponents};
+use ekiden_enclave_common::quote::MrEnclave;
+use ekiden_enclave_common::signature::{self, SignatureContext,
+                                       SIGNATURE_CONTEXT_STATE_CHECKPOINT,
+                                       SIGNATURE_CONTEXT_STATE_DIFF,
+                                       SIGNATURE_CONTEXT_STATE_REPLACE};
+
+use super::state::State;
+
+/// Offset of MRENCLAVE in a quote body.
+const QUOTE_MR_ENCLAVE_OFFSET: usize = 112;
+/// Offset of report data in a quote body.
+const QUOTE_REPORT_DATA_OFFSET: usize = 368;
+/// Length of a quote body.
+const QUOTE_BODY_LEN: usize = 432;
+
+/// A fake compute enclave with an identity proof that passes quote verification.
+pub struct TestEnclave {
+    pub mr_enclave: MrEnclave,
+    identity_proof: IdentityProof,
+    sign_key_priv: sodalite::SignSecretKey,
+}
+
+impl TestEnclave {
+    pub fn new(mr_enclave: MrEnclave) -> Self {
+        let mut public = PublicIdentityComponents {
+            rpc_key_e_pub: [0; sodalite::BOX_PUBLIC_KEY_LEN],
+            sign_key_pub: [0; sodalite::SIGN_PUBLIC_KEY_LEN],
+        };
+        let mut rpc_key_e_priv = [0; sodalite::BOX_SECRET_KEY_LEN];
+        let mut sign_key_priv = [0; sodalite::SIGN_SECRET_KEY_LEN];
+        sodalite::box_keypair_seed(&mut public.rpc_key_e_pub, &mut rpc_key_e_priv, &[1; 32]);
+        sodalite::sign_keypair_seed(&mut public.sign_key_pub, &mut sign_key_priv, &[2; 32]);
+        let public_identity = pack_public_identity(&public);
+
+        let mut quote_body = vec![0; QUOTE_BODY_LEN];
+        quote_body[QUOTE_MR_ENCLAVE_OFFSET..QUOTE_MR_ENCLAVE_OFFSET + 32]
+            .copy_from_slice(&mr_enclave[..]);
+        quote_body[QUOTE_REPORT_DATA_OFFSET..]
+            .copy_from_slice(&pack_report_data(&public_identity).d[..]);
+
+        let mut av_report = AvReport::new();
+        av_report.set_body(
+            format!(
+                "{{\"isvEnclaveQuoteStatus\": \"OK\", \"isvEnclaveQuoteBody\": \"{}\"}}",
+                base64::encode(&quote_body)
+            ).into_bytes(),
+        );
+
+        let mut identity_proof = IdentityProof::new();
+        identity_proof.set_public_identity(public_identity);
+        identity_proof.set_av_report(av_report);
+
+        TestEnclave {
+            mr_enclave,
+            identity_proof,
+            sign_key_priv,
+        }
+    }
+
+    /// Identifier of the contract running in the enclave.
+    pub fn contract_id(&self) -> Vec<u8> {
+        self.mr_enclave.0.to_vec()
+    }
+
+    /// Identity proof of the enclave.
+    pub fn identity_proof(&self) -> IdentityProof {
+        self.identity_proof.clone()
+    }
+
+    /// Sign a state update, binding it to the height of the state it produces and the
+    /// hash of the state it was computed on.
+    pub fn sign_state_update(
+        &self,
+        context: &SignatureContext,
+        signed_height: u64,
+        base_state_hash: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        signature::sign(
+            &self.sign_key_priv,
+            context,
+            &signature::pack_state_update(signed_height, base_state_hash, payload),
+        )
+    }
+
+    /// Sign a transaction computed on the given state.
+    fn sign(
+        &self,
+        mut stored: StoredTx,
+        context: &SignatureContext,
+        state: &State,
+        signed_height: u64,
+    ) -> Vec<u8> {
+        let payload = if stored.has_diff() {
+            stored.get_diff().to_vec()
+        } else if stored.has_replace() {
+            stored.get_replace().to_vec()
+        } else {
+            stored.get_checkpoint().to_vec()
+        };
+
+        let base_state_hash = state.contract_hash(&self.contract_id());
+        stored.set_contract_id(self.contract_id());
+        stored.set_base_height(state.height(&self.contract_id()));
+        stored.set_signature(self.sign_state_update(
+            context,
+            signed_height,
+            &base_state_hash,
+            &payload,
+        ));
+        stored.set_base_state_hash(base_state_hash);
+        stored.set_identity_proof(self.identity_proof());
+        stored.write_to_bytes().unwrap()
+    }
+
+    pub fn replace_tx(&self, state: &State, payload: &[u8]) -> Vec<u8> {
+        let mut stored = StoredTx::new();
+        stored.set_replace(payload.to_vec());
+        let height = state.height(&self.contract_id());
+        self.sign(stored, &SIGNATURE_CONTEXT_STATE_REPLACE, state, height + 1)
+    }
+
+    pub fn diff_tx(&self, state: &State, payload: &[u8]) -> Vec<u8> {
+        let mut stored = StoredTx::new();
+        stored.set_diff(payload.to_vec());
+        let height = state.height(&self.contract_id());
+        self.sign(stored, &SIGNATURE_CONTEXT_STATE_DIFF, state, height + 1)
+    }
+
+    pub fn checkpoint_tx(&self, state: &State, payload: &[u8]) -> Vec<u8> {
+        let mut stored = StoredTx::new();
+        stored.set_checkpoint(payload.to_vec());
+        let height = state.height(&self.contract_id());
+        self.sign(stored, &SIGNATURE_CONTEXT_STATE_CHECKPOINT, state, height)
+    }
+}
@@ -1,14 +1,78 @@
-extern crate consensus as lib;
+//! Integration tests of the consensus node, run with `cargo test --features test-utils`.
+extern crate ekiden_consensus as lib;
+extern crate ekiden_consensus_api;
+extern crate ekiden_enclave_common;
 extern crate grpc;
 
 use std::{thread, time};
 
-use lib::generated::consensus;
-use lib::generated::consensus_grpc;
-use lib::generated::consensus_grpc::Consensus;
+use ekiden_consensus_api as consensus;
+use ekiden_consensus_api::Consensus;
+use ekiden_enclave_common::quote::MrEnclave;
+use ekiden_enclave_common::signature::{SIGNATURE_CONTEXT_STATE_DIFF,
+                                       SIGNATURE_CONTEXT_STATE_REPLACE};
+use lib::test_utils::TestEnclave;
+
+/// Get the height and hash of the current state of the enclave's contract.
+fn current_state(client: &consensus::ConsensusClient, enclave: &TestEnclave) -> (u64, Vec<u8>) {
+    let mut req = consensus::GetRequest::new();
+    req.set_contract_id(enclave.contract_id());
+    match client.get(grpc::RequestOptions::new(), req).wait() {
+        Ok((_, resp, _)) => (
+            resp.get_checkpoint().get_height() + resp.get_diffs().len() as u64,
+            resp.get_state_hash().to_vec(),
+        ),
+        Err(_) => (0, Vec::new()),
+    }
+}
+
+/// Replace the state with a payload signed by the enclave.
+fn replace(client: &consensus::ConsensusClient, enclave: &TestEnclave, payload: &[u8]) {
+    let (height, state_hash) = current_state(client, enclave);
+
+    let mut req = consensus::ReplaceRequest::new();
+    req.set_payload(payload.to_vec());
+    req.set_base_height(height);
+    req.set_signature(enclave.sign_state_update(
+        &SIGNATURE_CONTEXT_STATE_REPLACE,
+        height + 1,
+        &state_hash,
+        payload,
+    ));
+    req.set_base_state_hash(state_hash);
+    req.set_identity_proof(enclave.identity_proof());
+    req.set_contract_id(enclave.contract_id());
+    client
+        .replace(grpc::RequestOptions::new(), req)
+        .wait()
+        .unwrap();
+}
+
+/// Add a diff signed by the enclave.
+fn add_diff(client: &consensus::ConsensusClient, enclave: &TestEnclave, payload: &[u8]) {
+    let (height, state_hash) = current_state(client, enclave);
+
+    let mut req = consensus::AddDiffRequest::new();
+    req.set_payload(payload.to_vec());
+    req.set_base_height(height);
+    req.set_signature(enclave.sign_state_update(
+        &SIGNATURE_CONTEXT_STATE_DIFF,
+        height + 1,
+        &state_hash,
+        payload,
+    ));
+    req.set_base_state_hash(state_hash);
+    req.set_identity_proof(enclave.identity_proof());
+    req.set_contract_id(enclave.contract_id());
+    client
+        .add_diff(grpc::RequestOptions::new(), req)
+        .wait()
+        .unwrap();
+}
 
 #[test]
 fn processes_requests() {
+    let enclave = TestEnclave::new(MrEnclave([1; 32]));
     let config = lib::Config {
         tendermint_host: String::from("localhost"),
         tendermint_port: 46657,
@@ -16,6 +80,9 @@ fn processes_requests() {
         grpc_port: 9002,
         no_tendermint: true,
         artificial_delay: 0,
+        storage_path: None,
+        trusted_enclaves: vec![enclave.mr_enclave.clone()],
+        checkpoint_policy: lib::CheckpointPolicy::default(),
     };
     let client_port = config.grpc_port;
 
@@ -27,11 +94,12 @@ fn processes_requests() {
     thread::sleep(time::Duration::from_millis(3000));
 
     let client =
-        consensus_grpc::ConsensusClient::new_plain("localhost", client_port, Default::default())
+        consensus::ConsensusClient::new_plain("localhost", client_port, Default::default())
             .unwrap();
 
     // Get latest state - should be empty
-    let req = consensus::GetRequest::new();
+    let mut req = consensus::GetRequest::new();
+    req.set_contract_id(enclave.contract_id());
     match client.get(grpc::RequestOptions::new(), req).wait() {
         Ok(_resp) => {
             panic!("First `get` should return an error");
@@ -44,6 +112,7 @@ fn processes_requests() {
     // Get diffs - should be empty
     let mut req = consensus::GetDiffsRequest::new();
     req.set_since_height(0);
+    req.set_contract_id(enclave.contract_id());
     match client.get_diffs(grpc::RequestOptions::new(), req).wait() {
         Ok(_resp) => {
             panic!("First `get` should return an error");
@@ -53,15 +122,22 @@ fn processes_requests() {
         }
     }
 
-    // Set state to `helloworld`
+    // Unsigned updates are rejected.
     let mut req = consensus::ReplaceRequest::new();
-    req.set_payload(String::from("helloworld").into_bytes());
-    client
-        .replace(grpc::RequestOptions::new(), req)
-        .wait()
-        .unwrap();
+    req.set_payload(String::from("unsigned").into_bytes());
+    req.set_contract_id(enclave.contract_id());
+    assert!(
+        client
+            .replace(grpc::RequestOptions::new(), req)
+            .wait()
+            .is_err()
+    );
+
+    // Set state to `helloworld`
+    replace(&client, &enclave, b"helloworld");
 
-    let req = consensus::GetRequest::new();
+    let mut req = consensus::GetRequest::new();
+    req.set_contract_id(enclave.contract_id());
     let (_, resp, _) = client.get(grpc::RequestOptions::new(), req).wait().unwrap();
     assert_eq!(
         resp.get_checkpoint().get_payload(),
@@ -69,31 +145,17 @@ fn processes_requests() {
     );
 
     // Set state to `successor`
-    let mut req = consensus::ReplaceRequest::new();
-    req.set_payload(String::from("successor").into_bytes());
-    client
-        .replace(grpc::RequestOptions::new(), req)
-        .wait()
-        .unwrap();
+    replace(&client, &enclave, b"successor");
 
     // Add `diff1`
-    let mut req = consensus::AddDiffRequest::new();
-    req.set_payload(String::from("diff1").into_bytes());
-    client
-        .add_diff(grpc::RequestOptions::new(), req)
-        .wait()
-        .unwrap();
+    add_diff(&client, &enclave, b"diff1");
 
     // Add `diff2`
-    let mut req = consensus::AddDiffRequest::new();
-    req.set_payload(String::from("diff2").into_bytes());
-    client
-        .add_diff(grpc::RequestOptions::new(), req)
-        .wait()
-        .unwrap();
+    add_diff(&client, &enclave, b"diff2");
 
     // Call get, check checkpoint, diffs
-    let req = consensus::GetRequest::new();
+    let mut req = consensus::GetRequest::new();
+    req.set_contract_id(enclave.contract_id());
     let (_, resp, _) = client.get(grpc::RequestOptions::new(), req).wait().unwrap();
     assert_eq!(
         resp.get_checkpoint().get_payload(),
@@ -107,6 +169,7 @@ fn processes_requests() {
     // Call get_diffs
     let mut req = consensus::GetDiffsRequest::new();
     req.set_since_height(3);
+    req.set_contract_id(enclave.contract_id());
     let (_, resp, _) = client
         .get_diffs(grpc::RequestOptions::new(), req)
         .wait()
@@ -120,14 +183,10 @@ fn processes_requests() {
         scale[i] = i as u8;
     }
 
-    let mut req = consensus::ReplaceRequest::new();
-    req.set_payload(scale.clone());
-    client
-        .replace(grpc::RequestOptions::new(), req)
-        .wait()
-        .unwrap();
+    replace(&client, &enclave, &scale);
 
-    let req = consensus::GetRequest::new();
+    let mut req = consensus::GetRequest::new();
+    req.set_contract_id(enclave.contract_id());
     let (_, resp, _) = client.get(grpc::RequestOptions::new(), req).wait().unwrap();
     assert_eq!(resp.get_checkpoint().get_payload(), &scale[..]);
 
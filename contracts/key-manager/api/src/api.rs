@@ -8,4 +8,12 @@ rpc_api! {
     }
 
     rpc get_or_create_key(GetOrCreateKeyRequest) -> GetOrCreateKeyResponse;
+
+    rpc get_key(GetKeyRequest) -> GetKeyResponse;
+
+    rpc get_current_key(GetCurrentKeyRequest) -> GetCurrentKeyResponse;
+
+    rpc rotate_key(RotateKeyRequest) -> RotateKeyResponse;
+
+    rpc retire_keys(RetireKeysRequest) -> RetireKeysResponse;
 }
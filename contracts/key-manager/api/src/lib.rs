@@ -9,4 +9,7 @@ extern crate ekiden_core;
 mod api;
 mod generated;
 
-pub use generated::api::{GetOrCreateKeyRequest, GetOrCreateKeyResponse};
+pub use generated::api::{GetCurrentKeyRequest, GetCurrentKeyResponse, GetKeyRequest,
+                         GetKeyResponse, GetOrCreateKeyRequest, GetOrCreateKeyResponse,
+                         RetireKeysRequest, RetireKeysResponse, RotateKeyRequest,
+                         RotateKeyResponse};
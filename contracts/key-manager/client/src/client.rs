@@ -27,6 +27,10 @@ pub struct KeyManager {
     client: Option<key_manager::Client<OcallContractClientBackend>>,
     /// Local key cache.
     cache: HashMap<String, Vec<u8>>,
+    /// Local cache of keys of specific epochs.
+    epoch_cache: HashMap<(String, u64), Vec<u8>>,
+    /// Newest known epoch of each named key, until it is refreshed.
+    current_epochs: HashMap<String, u64>,
 }
 
 lazy_static! {
@@ -41,6 +45,8 @@ impl KeyManager {
             mr_enclave: None,
             client: None,
             cache: HashMap::new(),
+            epoch_cache: HashMap::new(),
+            current_epochs: HashMap::new(),
         }
     }
 
@@ -94,6 +100,18 @@ impl KeyManager {
     /// This will make the client re-fetch the keys from the key manager.
     pub fn clear_cache(&mut self) {
         self.cache.clear();
+        self.epoch_cache.clear();
+        self.current_epochs.clear();
+    }
+
+    /// Forget the newest known epochs of all named keys.
+    ///
+    /// Keys of specific epochs remain cached, but the next request for the newest key
+    /// of each name asks the key manager, so that epochs created by other clients are
+    /// picked up. The database does this whenever it imports a state, i.e., once for
+    /// each batch.
+    pub fn refresh_current_epochs(&mut self) {
+        self.current_epochs.clear();
     }
 
     /// Get or create named key.
@@ -133,4 +151,142 @@ impl KeyManager {
             }
         }
     }
+
+    /// Remember a key of a given epoch, which is then also the newest known epoch if
+    /// it is newer than all previously seen epochs.
+    fn cache_epoch(&mut self, name: &str, epoch: u64, key: &[u8]) {
+        self.epoch_cache.insert((name.to_string(), epoch), key.to_vec());
+
+        let current_epoch = self.current_epochs.entry(name.to_string()).or_insert(epoch);
+        if epoch > *current_epoch {
+            *current_epoch = epoch;
+        }
+    }
+
+    /// Get the key of a given epoch of a named key.
+    ///
+    /// If the named key does not yet exist, the key manager will generate one with
+    /// epoch 0. Keys of retired epochs cannot be retrieved.
+    pub fn get_key(&mut self, name: &str, size: usize, epoch: u64) -> Result<Vec<u8>> {
+        // Check cache first.
+        if let Some(key) = self.epoch_cache.get(&(name.to_string(), epoch)) {
+            return Ok(key.clone());
+        }
+
+        // Ensure manager is connected.
+        self.connect()?;
+
+        let mut request = key_manager::GetKeyRequest::new();
+        request.set_name(name.to_string());
+        request.set_size(size as u32);
+        request.set_epoch(epoch);
+
+        let mut response = self.client
+            .as_mut()
+            .unwrap()
+            .get_key(request)
+            .wait()
+            .map_err(|error| {
+                Error::new(format!("Failed to call key manager: {}", error.message))
+            })?;
+
+        let key = response.take_key();
+        self.cache_epoch(name, epoch, &key);
+
+        Ok(key)
+    }
+
+    /// Get the newest epoch of a named key and its key.
+    ///
+    /// The newest epoch is cached locally, so an epoch created by another client is
+    /// only used once a key of that epoch has been retrieved or the newest epochs are
+    /// refreshed (see [`refresh_current_epochs`]).
+    ///
+    /// [`refresh_current_epochs`]: KeyManager::refresh_current_epochs
+    pub fn get_current_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+        // Check cache first.
+        let cached_epoch = self.current_epochs.get(name).cloned();
+        if let Some(epoch) = cached_epoch {
+            return Ok((epoch, self.get_key(name, size, epoch)?));
+        }
+
+        // Ensure manager is connected.
+        self.connect()?;
+
+        let mut request = key_manager::GetCurrentKeyRequest::new();
+        request.set_name(name.to_string());
+        request.set_size(size as u32);
+
+        let mut response = self.client
+            .as_mut()
+            .unwrap()
+            .get_current_key(request)
+            .wait()
+            .map_err(|error| {
+                Error::new(format!("Failed to call key manager: {}", error.message))
+            })?;
+
+        let epoch = response.get_epoch();
+        let key = response.take_key();
+        self.cache_epoch(name, epoch, &key);
+
+        Ok((epoch, key))
+    }
+
+    /// Generate a key for a new epoch of a named key, which becomes its newest epoch.
+    pub fn rotate_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+        // Ensure manager is connected.
+        self.connect()?;
+
+        let mut request = key_manager::RotateKeyRequest::new();
+        request.set_name(name.to_string());
+        request.set_size(size as u32);
+
+        let mut response = self.client
+            .as_mut()
+            .unwrap()
+            .rotate_key(request)
+            .wait()
+            .map_err(|error| {
+                Error::new(format!("Failed to call key manager: {}", error.message))
+            })?;
+
+        let epoch = response.get_epoch();
+        let key = response.take_key();
+        self.cache_epoch(name, epoch, &key);
+
+        Ok((epoch, key))
+    }
+
+    /// Retire all epochs of a named key before the given epoch.
+    ///
+    /// Keys of retired epochs are discarded by the key manager and can no longer be
+    /// retrieved, so no data may remain encrypted under them.
+    pub fn retire_keys(&mut self, name: &str, epoch: u64) -> Result<()> {
+        // Ensure manager is connected.
+        self.connect()?;
+
+        let mut request = key_manager::RetireKeysRequest::new();
+        request.set_name(name.to_string());
+        request.set_epoch(epoch);
+
+        self.client
+            .as_mut()
+            .unwrap()
+            .retire_keys(request)
+            .wait()
+            .map_err(|error| {
+                Error::new(format!("Failed to call key manager: {}", error.message))
+            })?;
+
+        self.epoch_cache.retain(|&(ref cached_name, cached_epoch), _| {
+            cached_name != name || cached_epoch >= epoch
+        });
+        if epoch > 0 {
+            // The key of epoch 0 is the one returned by `get_or_create_key`.
+            self.cache.remove(name);
+        }
+
+        Ok(())
+    }
 }
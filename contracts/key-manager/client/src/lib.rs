@@ -11,8 +11,10 @@ extern crate ekiden_rpc_common;
 extern crate ekiden_rpc_trusted;
 
 mod client;
+mod ticket;
 
 pub use client::KeyManager;
+pub use ticket::{KeyManagerKeyStore, KeyManagerTicketKeySource};
 
 /// Helper macro to configure key manager contract identity from a generated file.
 ///
@@ -39,3 +41,33 @@ macro_rules! use_key_manager_contract {
         }
     }
 }
+
+/// Helper macro to obtain the session ticket keys from the key manager.
+///
+/// Session tickets of the contract's secure channels are then encrypted with keys
+/// obtained from the key manager, so clients can resume their sessions with any
+/// compute node of the contract (see [`KeyManagerTicketKeySource`]). The key manager
+/// contract must be configured with [`use_key_manager_contract`].
+///
+/// Ticket keys are obtained while the secure channel context is locked, so requests
+/// which issue or redeem tickets wait for the key manager whenever a key is not yet
+/// cached.
+///
+/// [`KeyManagerTicketKeySource`]: KeyManagerTicketKeySource
+/// [`use_key_manager_contract`]: use_key_manager_contract
+#[macro_export]
+macro_rules! use_key_manager_ticket_keys {
+    () => {
+        #[cfg(target_env = "sgx")]
+        global_ctors_object! {
+            KEY_MANAGER_TICKET_KEYS_INIT, key_manager_ticket_keys_init = {
+                use ekiden_trusted::key_manager::{KeyManagerKeyStore, KeyManagerTicketKeySource};
+                use ekiden_trusted::rpc::secure_channel::SecureChannelContext;
+
+                // Share the session ticket keys between all instances of the contract.
+                SecureChannelContext::get()
+                    .set_ticket_key_source(KeyManagerTicketKeySource::new(KeyManagerKeyStore));
+            }
+        }
+    }
+}
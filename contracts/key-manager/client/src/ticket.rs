@@ -0,0 +1,36 @@
+//! Session ticket keys obtained from the key manager.
+use ekiden_common::error::Result;
+use ekiden_rpc_trusted::secure_channel::{EpochKeyStore, SharedTicketKeySource};
+
+use super::client::KeyManager;
+
+/// Key store which uses the epochs of named keys of the key manager.
+pub struct KeyManagerKeyStore;
+
+impl EpochKeyStore for KeyManagerKeyStore {
+    fn get_current_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+        KeyManager::get()?.get_current_key(name, size)
+    }
+
+    fn get_key(&mut self, name: &str, size: usize, epoch: u64) -> Result<Vec<u8>> {
+        KeyManager::get()?.get_key(name, size, epoch)
+    }
+
+    fn rotate_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+        KeyManager::get()?.rotate_key(name, size)
+    }
+
+    fn refresh_current_epochs(&mut self) {
+        if let Ok(mut key_manager) = KeyManager::get() {
+            key_manager.refresh_current_epochs();
+        }
+    }
+}
+
+/// Ticket key source which uses the epochs of a named key of the key manager.
+///
+/// All instances of the contract obtain the same keys, so sessions can be resumed
+/// on any compute node. Keys are cached by the key manager client, so the key manager
+/// is only called when a new epoch is first used and when the ticket key lifetime has
+/// passed.
+pub type KeyManagerTicketKeySource = SharedTicketKeySource<KeyManagerKeyStore>;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::collections::hash_map::Entry;
 #[cfg(not(target_env = "sgx"))]
 use std::sync::{Mutex, MutexGuard};
@@ -11,10 +11,30 @@ use ekiden_core::enclave::quote::MrEnclave;
 use ekiden_core::error::{Error, Result};
 use ekiden_core::random;
 
+/// Epochs of a named key.
+struct KeyEpochs {
+    /// Keys of all epochs which have not been retired, by epoch.
+    keys: BTreeMap<u64, Vec<u8>>,
+    /// All epochs before this one have been retired.
+    retired_before: u64,
+}
+
+impl KeyEpochs {
+    /// Newest epoch and its key.
+    fn current(&self) -> (u64, Vec<u8>) {
+        let (epoch, key) = self.keys
+            .iter()
+            .next_back()
+            .expect("Current key epoch is never retired");
+
+        (*epoch, key.clone())
+    }
+}
+
 /// Key store, which actually stores the key manager keys.
 pub struct KeyStore {
     /// Key store map.
-    keys: HashMap<MrEnclave, HashMap<String, Vec<u8>>>,
+    keys: HashMap<MrEnclave, HashMap<String, KeyEpochs>>,
 }
 
 lazy_static! {
@@ -51,47 +71,126 @@ impl KeyStore {
         Ok(key)
     }
 
-    /// Get or create a named key.
+    /// Get the epochs of a named key, creating the key with epoch 0 if it does not
+    /// yet exist.
     ///
     /// Each contract (identified by its MRENCLAVE) can store multiple keys in the
     /// key store, each is identified by its name string. The key size must be
     /// specified and is checked when retrieving an existing key.
-    pub fn get_or_create_key(
+    fn get_or_create_epochs(
         &mut self,
         mr_enclave: &MrEnclave,
         name: &str,
         size: usize,
-    ) -> Result<Vec<u8>> {
-        let key = match self.keys.entry(mr_enclave.clone()) {
-            Entry::Occupied(mut entry) => {
-                // This enclave already has some keys stored. Check if it also stores
-                // the target named key.
-                match entry.get_mut().entry(name.to_string()) {
-                    Entry::Occupied(entry) => entry.get().clone(),
-                    Entry::Vacant(entry) => {
-                        let key = KeyStore::generate_key(size)?;
-                        entry.insert(key).clone()
-                    }
-                }
-            }
-            Entry::Vacant(mut entry) => {
-                // This enclave has nothing stored yet. Create an empty hashmap for it
-                // and create the given key.
-                let key = KeyStore::generate_key(size)?;
-                let new_key = key.clone();
-                let mut map = HashMap::new();
-                map.insert(name.to_string(), key);
-                entry.insert(map);
-
-                new_key
+    ) -> Result<&mut KeyEpochs> {
+        let epochs = match self.keys
+            .entry(mr_enclave.clone())
+            .or_insert_with(HashMap::new)
+            .entry(name.to_string())
+        {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut keys = BTreeMap::new();
+                keys.insert(0, KeyStore::generate_key(size)?);
+
+                entry.insert(KeyEpochs {
+                    keys,
+                    retired_before: 0,
+                })
             }
         };
 
         // Check key length.
-        if key.len() != size {
+        if epochs.current().1.len() != size {
             return Err(Error::new("Existing key with incompatible length"));
         }
 
-        Ok(key)
+        Ok(epochs)
+    }
+
+    /// Get or create a named key.
+    ///
+    /// This is the key of epoch 0, which is used by contracts that do not rotate
+    /// their keys.
+    pub fn get_or_create_key(
+        &mut self,
+        mr_enclave: &MrEnclave,
+        name: &str,
+        size: usize,
+    ) -> Result<Vec<u8>> {
+        self.get_key(mr_enclave, name, size, 0)
+    }
+
+    /// Get the key of a given epoch of a named key.
+    ///
+    /// If the named key does not yet exist, it is created with epoch 0.
+    pub fn get_key(
+        &mut self,
+        mr_enclave: &MrEnclave,
+        name: &str,
+        size: usize,
+        epoch: u64,
+    ) -> Result<Vec<u8>> {
+        let epochs = self.get_or_create_epochs(mr_enclave, name, size)?;
+        if epoch < epochs.retired_before {
+            return Err(Error::new("Key epoch has been retired"));
+        }
+
+        match epochs.keys.get(&epoch) {
+            Some(key) => Ok(key.clone()),
+            None => Err(Error::new("Unknown key epoch")),
+        }
+    }
+
+    /// Get the newest epoch of a named key and its key.
+    ///
+    /// If the named key does not yet exist, it is created with epoch 0.
+    pub fn get_current_key(
+        &mut self,
+        mr_enclave: &MrEnclave,
+        name: &str,
+        size: usize,
+    ) -> Result<(u64, Vec<u8>)> {
+        Ok(self.get_or_create_epochs(mr_enclave, name, size)?.current())
+    }
+
+    /// Generate a key for a new epoch of a named key, which becomes its newest epoch.
+    ///
+    /// Keys of previous epochs remain available until they are retired.
+    pub fn rotate_key(
+        &mut self,
+        mr_enclave: &MrEnclave,
+        name: &str,
+        size: usize,
+    ) -> Result<(u64, Vec<u8>)> {
+        let epochs = self.get_or_create_epochs(mr_enclave, name, size)?;
+        let epoch = epochs.current().0 + 1;
+        let key = KeyStore::generate_key(size)?;
+        epochs.keys.insert(epoch, key.clone());
+
+        Ok((epoch, key))
+    }
+
+    /// Retire all epochs of a named key before the given epoch.
+    ///
+    /// Keys of retired epochs are discarded and can no longer be retrieved. The newest
+    /// epoch cannot be retired.
+    pub fn retire_keys(&mut self, mr_enclave: &MrEnclave, name: &str, epoch: u64) -> Result<()> {
+        let epochs = match self.keys
+            .get_mut(mr_enclave)
+            .and_then(|keys| keys.get_mut(name))
+        {
+            Some(epochs) => epochs,
+            None => return Err(Error::new("Unknown key")),
+        };
+
+        if epoch > epochs.current().0 {
+            return Err(Error::new("Cannot retire the newest key epoch"));
+        }
+
+        epochs.keys = epochs.keys.split_off(&epoch);
+        epochs.retired_before = epochs.retired_before.max(epoch);
+
+        Ok(())
     }
 }
@@ -11,7 +11,10 @@ extern crate ekiden_trusted;
 mod key_store;
 
 use ekiden_core::error::Result;
-use ekiden_key_manager_api::{with_api, GetOrCreateKeyRequest, GetOrCreateKeyResponse};
+use ekiden_key_manager_api::{with_api, GetCurrentKeyRequest, GetCurrentKeyResponse, GetKeyRequest,
+                             GetKeyResponse, GetOrCreateKeyRequest, GetOrCreateKeyResponse,
+                             RetireKeysRequest, RetireKeysResponse, RotateKeyRequest,
+                             RotateKeyResponse};
 use ekiden_trusted::enclave::enclave_init;
 use ekiden_trusted::rpc::create_enclave_rpc;
 use ekiden_trusted::rpc::request::Request;
@@ -41,3 +44,71 @@ fn get_or_create_key(request: &Request<GetOrCreateKeyRequest>) -> Result<GetOrCr
 
     Ok(response)
 }
+
+fn get_key(request: &Request<GetKeyRequest>) -> Result<GetKeyResponse> {
+    let mut response = GetKeyResponse::new();
+
+    // Query the key store.
+    {
+        let mut key_store = KeyStore::get();
+        response.set_key(key_store.get_key(
+            // Unwrap here is safe as this contract requires mutual authentication.
+            &request.get_client_mr_enclave().as_ref().unwrap(),
+            request.get_name(),
+            request.get_size() as usize,
+            request.get_epoch(),
+        )?);
+    }
+
+    Ok(response)
+}
+
+fn get_current_key(request: &Request<GetCurrentKeyRequest>) -> Result<GetCurrentKeyResponse> {
+    let mut response = GetCurrentKeyResponse::new();
+
+    // Query the key store.
+    {
+        let mut key_store = KeyStore::get();
+        let (epoch, key) = key_store.get_current_key(
+            // Unwrap here is safe as this contract requires mutual authentication.
+            &request.get_client_mr_enclave().as_ref().unwrap(),
+            request.get_name(),
+            request.get_size() as usize,
+        )?;
+        response.set_epoch(epoch);
+        response.set_key(key);
+    }
+
+    Ok(response)
+}
+
+fn rotate_key(request: &Request<RotateKeyRequest>) -> Result<RotateKeyResponse> {
+    let mut response = RotateKeyResponse::new();
+
+    // Generate a new key epoch.
+    {
+        let mut key_store = KeyStore::get();
+        let (epoch, key) = key_store.rotate_key(
+            // Unwrap here is safe as this contract requires mutual authentication.
+            &request.get_client_mr_enclave().as_ref().unwrap(),
+            request.get_name(),
+            request.get_size() as usize,
+        )?;
+        response.set_epoch(epoch);
+        response.set_key(key);
+    }
+
+    Ok(response)
+}
+
+fn retire_keys(request: &Request<RetireKeysRequest>) -> Result<RetireKeysResponse> {
+    let mut key_store = KeyStore::get();
+    key_store.retire_keys(
+        // Unwrap here is safe as this contract requires mutual authentication.
+        &request.get_client_mr_enclave().as_ref().unwrap(),
+        request.get_name(),
+        request.get_epoch(),
+    )?;
+
+    Ok(RetireKeysResponse::new())
+}
@@ -0,0 +1,135 @@
+//! Admin set stored in the contract's database.
+use ekiden_db_trusted::schema::descriptor::SetDescriptor;
+use ekiden_rpc_trusted::dispatcher::AdminSet;
+
+/// Schema namespace reserved for the admin set.
+const ADMIN_SET_NAMESPACE: &'static str = "ekiden";
+/// Field name of the admin set.
+const ADMIN_SET_NAME: &'static str = "admins";
+
+/// Clients which are allowed to call methods with the admin policy, stored in the
+/// contract's database.
+///
+/// The admin set is part of the contract state, so it persists across batches and is
+/// the same on every compute node. Changes are only kept if the request making them
+/// succeeds, like any other database change.
+pub struct DatabaseAdminSet;
+
+impl DatabaseAdminSet {
+    /// Descriptor of the admin set field.
+    fn descriptor() -> SetDescriptor<Vec<u8>> {
+        SetDescriptor::new(ADMIN_SET_NAMESPACE, ADMIN_SET_NAME)
+    }
+
+    /// Add a client long-term public key to the admin set.
+    ///
+    /// Returns true if the key was not yet in the set.
+    pub fn insert(public_key: &[u8]) -> bool {
+        DatabaseAdminSet::descriptor().insert(&public_key.to_vec())
+    }
+
+    /// Remove a client long-term public key from the admin set.
+    ///
+    /// Returns true if the key was in the set.
+    pub fn remove(public_key: &[u8]) -> bool {
+        DatabaseAdminSet::descriptor().remove(&public_key.to_vec())
+    }
+
+    /// Returns true if there are no admins.
+    pub fn is_empty() -> bool {
+        DatabaseAdminSet::descriptor().is_empty()
+    }
+}
+
+impl AdminSet for DatabaseAdminSet {
+    fn contains(&self, public_key: &[u8]) -> bool {
+        DatabaseAdminSet::descriptor().contains(&public_key.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ekiden_common::error::Result;
+    use ekiden_common::serializer::Serializable;
+    use ekiden_db_trusted::{Database, DatabaseHandle};
+    use ekiden_rpc_common::api;
+    use ekiden_rpc_common::reflection::{ApiMethodDescriptor, AuthorizationPolicy};
+    use ekiden_rpc_trusted::dispatcher::{Dispatcher, EnclaveMethod};
+    use ekiden_rpc_trusted::request::Request;
+
+    use super::super::transaction::DatabaseTransaction;
+    use super::DatabaseAdminSet;
+
+    /// Add the given admin, which must be called by an admin.
+    fn add_admin(request: &Request<Vec<u8>>) -> Result<()> {
+        DatabaseAdminSet::insert(request);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_database_admin_set() {
+        DatabaseHandle::instance().clear();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.set_transaction(DatabaseTransaction);
+        dispatcher.set_admin_set(DatabaseAdminSet);
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: "add_admin".to_owned(),
+                client_attestation_required: false,
+                policies: vec![AuthorizationPolicy::Admin],
+            },
+            add_admin,
+        ));
+
+        let dispatch = |caller: &[u8], admin: &[u8]| -> api::PlainClientResponse_Code {
+            // Without a client short-term key, the response is not sent over a secure
+            // channel, so no session is needed.
+            let request = Request::new(
+                admin.to_vec().write().unwrap(),
+                "add_admin".to_owned(),
+                None,
+                Some(caller.to_vec()),
+                None,
+            );
+            let mut response = dispatcher.dispatch(request);
+            response.take_message().get_plain_response().get_code()
+        };
+
+        // Without admins, admin methods cannot be called.
+        assert!(DatabaseAdminSet::is_empty());
+        assert_eq!(
+            dispatch(b"alice", b"bob"),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+
+        // The admin set is read from the database.
+        DatabaseAdminSet::insert(b"alice");
+        assert_eq!(
+            dispatch(b"alice", b"bob"),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+        assert_eq!(
+            dispatch(b"bob", b"carol"),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+        assert_eq!(
+            dispatch(b"carol", b"dave"),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+
+        // Admins can be removed, and the admin set goes with the state.
+        assert!(DatabaseAdminSet::remove(b"alice"));
+        assert_eq!(
+            dispatch(b"alice", b"eve"),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+
+        DatabaseHandle::instance().clear();
+        assert_eq!(
+            dispatch(b"carol", b"eve"),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+    }
+}
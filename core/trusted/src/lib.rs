@@ -1,10 +1,17 @@
 #![feature(use_extern_macros)]
 
+extern crate ekiden_common;
 extern crate ekiden_db_trusted;
 extern crate ekiden_enclave_trusted;
 extern crate ekiden_key_manager_client;
 extern crate ekiden_rpc_trusted;
 
+#[cfg(test)]
+extern crate ekiden_rpc_common;
+
+mod admin;
+mod transaction;
+
 pub mod enclave {
     pub use ekiden_enclave_trusted::*;
 }
@@ -15,6 +22,9 @@ pub mod rpc {
 
 pub mod db {
     pub use ekiden_db_trusted::*;
+
+    pub use super::admin::DatabaseAdminSet;
+    pub use super::transaction::DatabaseTransaction;
 }
 
 pub mod key_manager {
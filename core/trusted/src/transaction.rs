@@ -0,0 +1,110 @@
+//! Database transactions around RPC requests.
+use ekiden_common::error::Result;
+use ekiden_db_trusted::DatabaseHandle;
+use ekiden_db_trusted::migration::Migrations;
+use ekiden_db_trusted::schema::key;
+use ekiden_rpc_trusted::dispatcher::RequestTransaction;
+
+/// Runs each RPC request in its own database savepoint.
+pub struct DatabaseTransaction;
+
+impl RequestTransaction for DatabaseTransaction {
+    fn begin(&self) {
+        DatabaseHandle::instance().begin();
+    }
+
+    fn commit(&self) -> Result<()> {
+        // Stamp a state created by the request with the format of its map keys and its
+        // schema version, so read-only calls never modify the state.
+        key::record_key_format();
+        Migrations::get().record_version();
+
+        DatabaseHandle::instance().commit()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        DatabaseHandle::instance().rollback()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ekiden_common::error::{Error, Result};
+    use ekiden_common::serializer::{Deserializable, Serializable};
+    use ekiden_db_trusted::{Database, DatabaseHandle};
+    use ekiden_rpc_common::api;
+    use ekiden_rpc_common::reflection::ApiMethodDescriptor;
+    use ekiden_rpc_trusted::dispatcher::{Dispatcher, EnclaveMethod};
+    use ekiden_rpc_trusted::request::Request;
+
+    use super::DatabaseTransaction;
+
+    /// Balance of the given account.
+    fn balance(account: &[u8]) -> Option<u64> {
+        DatabaseHandle::instance()
+            .get(account)
+            .map(|value| Deserializable::read(&value).unwrap())
+    }
+
+    /// Transfer tokens from alice to bob, in the same order as the token contract: the
+    /// sender is debited before the recipient is credited.
+    fn transfer(request: &Request<u64>) -> Result<()> {
+        let value = **request;
+        let from_balance = balance(b"alice").unwrap();
+        DatabaseHandle::instance().insert(b"alice", &(from_balance - value).write()?);
+
+        let to_balance = balance(b"bob").unwrap_or(0);
+        if to_balance + value > 5 {
+            return Err(Error::new("Recipient balance limit exceeded"));
+        }
+        DatabaseHandle::instance().insert(b"bob", &(to_balance + value).write()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch_rollback() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+            db.insert(b"alice", &10u64.write().unwrap());
+        }
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.set_transaction(DatabaseTransaction);
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: "transfer".to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            transfer,
+        ));
+
+        let dispatch = |value: u64| -> api::PlainClientResponse_Code {
+            let request = Request::new(
+                value.write().unwrap(),
+                "transfer".to_owned(),
+                None,
+                None,
+                None,
+            );
+            let mut response = dispatcher.dispatch(request);
+            response.take_message().get_plain_response().get_code()
+        };
+
+        // Successful requests keep all of their writes.
+        assert_eq!(dispatch(3), api::PlainClientResponse_Code::SUCCESS);
+        assert_eq!(balance(b"alice"), Some(7));
+        assert_eq!(balance(b"bob"), Some(3));
+
+        // Failed requests discard the writes made before the failure.
+        assert_eq!(dispatch(4), api::PlainClientResponse_Code::ERROR);
+        assert_eq!(balance(b"alice"), Some(7));
+        assert_eq!(balance(b"bob"), Some(3));
+
+        assert_eq!(dispatch(2), api::PlainClientResponse_Code::SUCCESS);
+        assert_eq!(balance(b"alice"), Some(5));
+        assert_eq!(balance(b"bob"), Some(5));
+    }
+}
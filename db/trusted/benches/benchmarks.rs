@@ -3,25 +3,62 @@
 extern crate test;
 
 extern crate ekiden_db_trusted;
+extern crate ekiden_enclave_trusted;
 
 use test::Bencher;
 
 use ekiden_db_trusted::{Database, DatabaseHandle};
-use ekiden_db_trusted::ecalls::{db_state_apply, db_state_diff, db_state_get, db_state_set};
+use ekiden_db_trusted::ecalls::{db_state_apply, db_state_diff, db_state_get, db_state_get_diff,
+                                db_state_set};
+
+/// Number of keys in the large dummy state.
+const LARGE_STATE_KEYS: usize = 10_000;
+/// Capacity of buffers for the large dummy state and its diffs.
+const LARGE_STATE_CAPACITY: usize = 4 * 1024 * 1024;
 
 /// Populate the database with some dummy state.
 fn generate_dummy_state() {
+    // State updates are signed with the enclave identity.
+    ekiden_enclave_trusted::identity::nosgx_init_dummy();
+
     let mut db = DatabaseHandle::instance();
     db.insert(b"example_key1", &vec![42; 128]);
     db.insert(b"example_key2", &vec![21; 128]);
 }
 
+/// Populate the database with a large dummy state of 128-byte values.
+fn generate_large_dummy_state() {
+    // State updates are signed with the enclave identity.
+    ekiden_enclave_trusted::identity::nosgx_init_dummy();
+
+    let mut db = DatabaseHandle::instance();
+    db.clear();
+    for index in 0..LARGE_STATE_KEYS {
+        db.insert(format!("example_key{}", index).as_bytes(), &vec![42; 128]);
+    }
+}
+
 /// Export current database state.
 fn export_db_state() -> Vec<u8> {
-    let mut state: Vec<u8> = Vec::with_capacity(64 * 1024);
+    export_db_state_with_capacity(64 * 1024)
+}
+
+/// Export current database state into a buffer of the given capacity.
+fn export_db_state_with_capacity(capacity: usize) -> Vec<u8> {
+    let mut state: Vec<u8> = Vec::with_capacity(capacity);
     let mut state_length = 0;
+    let base_state_hash = vec![0; 32];
+    let mut signature = vec![0; 64];
 
-    db_state_get(state.as_mut_ptr(), state.capacity(), &mut state_length);
+    db_state_get(
+        state.as_mut_ptr(),
+        state.capacity(),
+        &mut state_length,
+        0,
+        base_state_hash.as_ptr(),
+        base_state_hash.len(),
+        signature.as_mut_ptr(),
+    );
 
     unsafe {
         state.set_len(state_length);
@@ -150,3 +187,129 @@ fn benchmark_apply(b: &mut Bencher) {
         );
     });
 }
+
+/// Change a single key of the large dummy state.
+///
+/// Returns the old state, the new state and the write set diff between them.
+fn change_large_dummy_state() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    generate_large_dummy_state();
+    let old_state = export_db_state_with_capacity(LARGE_STATE_CAPACITY);
+    db_state_set(old_state.as_ptr(), old_state.len());
+
+    {
+        let mut db = DatabaseHandle::instance();
+        db.insert(b"example_key1", &vec![21; 128]);
+    }
+
+    let new_state = export_db_state_with_capacity(LARGE_STATE_CAPACITY);
+    let write_set = export_db_diff();
+
+    (old_state, new_state, write_set)
+}
+
+/// Export the changes since the database state was last set.
+fn export_db_diff() -> Vec<u8> {
+    let mut diff: Vec<u8> = Vec::with_capacity(64 * 1024);
+    let mut diff_length = 0;
+    let base_state_hash = vec![0; 32];
+    let mut signature = vec![0; 64];
+
+    db_state_get_diff(
+        diff.as_mut_ptr(),
+        diff.capacity(),
+        &mut diff_length,
+        0,
+        base_state_hash.as_ptr(),
+        base_state_hash.len(),
+        signature.as_mut_ptr(),
+    );
+
+    unsafe {
+        diff.set_len(diff_length);
+    }
+    assert!(!diff.is_empty());
+
+    diff
+}
+
+/// Compute a patch between two database states.
+fn compute_db_diff(old_state: &[u8], new_state: &[u8]) -> Vec<u8> {
+    let mut diff: Vec<u8> = Vec::with_capacity(LARGE_STATE_CAPACITY);
+    let mut diff_length = 0;
+
+    db_state_diff(
+        old_state.as_ptr(),
+        old_state.len(),
+        new_state.as_ptr(),
+        new_state.len(),
+        diff.as_mut_ptr(),
+        diff.capacity(),
+        &mut diff_length,
+    );
+
+    unsafe {
+        diff.set_len(diff_length);
+    }
+    assert!(!diff.is_empty());
+
+    diff
+}
+
+/// Apply a diff to a database state.
+fn apply_db_diff(old_state: &[u8], diff: &[u8]) {
+    let mut output: Vec<u8> = Vec::with_capacity(LARGE_STATE_CAPACITY);
+    let mut output_length = 0;
+
+    db_state_apply(
+        old_state.as_ptr(),
+        old_state.len(),
+        diff.as_ptr(),
+        diff.len(),
+        output.as_mut_ptr(),
+        output.capacity(),
+        &mut output_length,
+    );
+
+    assert!(output_length > 0);
+}
+
+/// Benchmark computing a patch between two large states that differ in a single key.
+#[bench]
+fn benchmark_diff_large(b: &mut Bencher) {
+    let (old_state, new_state, _) = change_large_dummy_state();
+
+    b.iter(|| {
+        compute_db_diff(&old_state, &new_state);
+    });
+}
+
+/// Benchmark exporting the write set of a single key change on a large state.
+#[bench]
+fn benchmark_write_set_large(b: &mut Bencher) {
+    change_large_dummy_state();
+
+    b.iter(|| {
+        export_db_diff();
+    });
+}
+
+/// Benchmark applying a patch of a single key change to a large state.
+#[bench]
+fn benchmark_apply_large(b: &mut Bencher) {
+    let (old_state, new_state, _) = change_large_dummy_state();
+    let diff = compute_db_diff(&old_state, &new_state);
+
+    b.iter(|| {
+        apply_db_diff(&old_state, &diff);
+    });
+}
+
+/// Benchmark applying the write set of a single key change to a large state.
+#[bench]
+fn benchmark_apply_write_set_large(b: &mut Bencher) {
+    let (old_state, _, write_set) = change_large_dummy_state();
+
+    b.iter(|| {
+        apply_db_diff(&old_state, &write_set);
+    });
+}
@@ -1,3 +1,6 @@
+#[cfg(not(target_env = "sgx"))]
+use std::cell::Cell;
+
 use sodalite;
 
 use ekiden_common::error::{Error, Result};
@@ -10,25 +13,174 @@ use super::generated::database::CryptoSecretbox;
 
 const SECRETBOX_ZEROBYTES: usize = 32;
 
-/// Retrieve or generate state secret key.
+/// Block size of SHA-512, used by HMAC.
+const HMAC_BLOCK_LEN: usize = 128;
+/// Context of the key derived from the state key for state hashes.
+const STATE_HASH_KEY_CONTEXT: &'static [u8] = b"EkS-Hash";
+
+/// Name of the state key in the key manager.
 #[cfg(target_env = "sgx")]
-fn get_state_key() -> Result<sodalite::SecretboxKey> {
-    let key = KeyManager::get()?.get_or_create_key("state", sodalite::SECRETBOX_KEY_LEN)?;
+const STATE_KEY_NAME: &'static str = "state";
+
+#[cfg(not(target_env = "sgx"))]
+thread_local! {
+    // Key epochs used in unit tests (on non-SGX), as the newest epoch and the first
+    // epoch which has not been retired. Each test runs in its own thread, so tests
+    // which rotate or retire keys do not affect other tests.
+    static STATE_KEY_EPOCHS: Cell<(u64, u64)> = Cell::new((0, 0));
+}
+
+/// Convert a key received from the key manager into a state secret key.
+#[cfg(target_env = "sgx")]
+fn to_state_key(key: &[u8]) -> sodalite::SecretboxKey {
     let mut state_key = [0; sodalite::SECRETBOX_KEY_LEN];
-    state_key.copy_from_slice(key.as_slice());
+    state_key.copy_from_slice(key);
 
-    Ok(state_key)
+    state_key
+}
+
+/// Retrieve or generate state secret key of the given epoch.
+#[cfg(target_env = "sgx")]
+fn get_state_key(epoch: u64) -> Result<sodalite::SecretboxKey> {
+    let key = KeyManager::get()?.get_key(STATE_KEY_NAME, sodalite::SECRETBOX_KEY_LEN, epoch)?;
+
+    Ok(to_state_key(&key))
 }
 
 #[cfg(not(target_env = "sgx"))]
-fn get_state_key() -> Result<sodalite::SecretboxKey> {
+fn get_state_key(epoch: u64) -> Result<sodalite::SecretboxKey> {
     // This implementation is used in unit tests (on non-SGX).
-    Ok([42; sodalite::SECRETBOX_KEY_LEN])
+    let (current_epoch, retired_before) = STATE_KEY_EPOCHS.with(|epochs| epochs.get());
+    if epoch < retired_before {
+        return Err(Error::new("Key epoch has been retired"));
+    }
+    if epoch > current_epoch {
+        return Err(Error::new("Unknown key epoch"));
+    }
+
+    Ok([42u8.wrapping_add(epoch as u8); sodalite::SECRETBOX_KEY_LEN])
+}
+
+/// Retrieve or generate the state secret key of the newest epoch.
+#[cfg(target_env = "sgx")]
+fn get_current_state_key() -> Result<(u64, sodalite::SecretboxKey)> {
+    let (epoch, key) =
+        KeyManager::get()?.get_current_key(STATE_KEY_NAME, sodalite::SECRETBOX_KEY_LEN)?;
+
+    Ok((epoch, to_state_key(&key)))
+}
+
+#[cfg(not(target_env = "sgx"))]
+fn get_current_state_key() -> Result<(u64, sodalite::SecretboxKey)> {
+    let epoch = current_state_key_epoch()?;
+
+    Ok((epoch, get_state_key(epoch)?))
+}
+
+/// Newest epoch of the state secret key.
+#[cfg(target_env = "sgx")]
+pub fn current_state_key_epoch() -> Result<u64> {
+    Ok(get_current_state_key()?.0)
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn current_state_key_epoch() -> Result<u64> {
+    Ok(STATE_KEY_EPOCHS.with(|epochs| epochs.get().0))
+}
+
+/// Make the next use of the newest epoch of the state secret key ask the key manager,
+/// so that an epoch created by another node is picked up.
+#[cfg(target_env = "sgx")]
+pub fn refresh_state_key_epoch() -> Result<()> {
+    KeyManager::get()?.refresh_current_epochs();
+
+    Ok(())
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn refresh_state_key_epoch() -> Result<()> {
+    Ok(())
+}
+
+/// Generate a state secret key for a new epoch, which is used for all state that is
+/// encrypted afterwards.
+///
+/// Returns the new epoch.
+#[cfg(target_env = "sgx")]
+pub fn rotate_state_key() -> Result<u64> {
+    let (epoch, _) = KeyManager::get()?.rotate_key(STATE_KEY_NAME, sodalite::SECRETBOX_KEY_LEN)?;
+
+    Ok(epoch)
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn rotate_state_key() -> Result<u64> {
+    STATE_KEY_EPOCHS.with(|epochs| {
+        let (current_epoch, retired_before) = epochs.get();
+        epochs.set((current_epoch + 1, retired_before));
+
+        Ok(current_epoch + 1)
+    })
+}
+
+/// Retire all epochs of the state secret key before the given epoch, so that state
+/// encrypted under them can no longer be decrypted.
+#[cfg(target_env = "sgx")]
+pub fn retire_state_keys(epoch: u64) -> Result<()> {
+    KeyManager::get()?.retire_keys(STATE_KEY_NAME, epoch)
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn retire_state_keys(epoch: u64) -> Result<()> {
+    STATE_KEY_EPOCHS.with(|epochs| {
+        let (current_epoch, retired_before) = epochs.get();
+        if epoch > current_epoch {
+            return Err(Error::new("Cannot retire the newest key epoch"));
+        }
+        epochs.set((current_epoch, retired_before.max(epoch)));
+
+        Ok(())
+    })
+}
+
+/// HMAC-SHA-512 of a message under a key of at most `HMAC_BLOCK_LEN` bytes.
+fn hmac(key: &[u8], message: &[u8]) -> [u8; sodalite::HASH_LEN] {
+    assert!(key.len() <= HMAC_BLOCK_LEN);
+
+    let mut inner = vec![0x36; HMAC_BLOCK_LEN];
+    let mut outer = vec![0x5c; HMAC_BLOCK_LEN];
+    for (index, byte) in key.iter().enumerate() {
+        inner[index] ^= *byte;
+        outer[index] ^= *byte;
+    }
+
+    inner.extend_from_slice(message);
+    let mut inner_hash = [0; sodalite::HASH_LEN];
+    sodalite::hash(&mut inner_hash, &inner);
+
+    outer.extend_from_slice(&inner_hash);
+    let mut result = [0; sodalite::HASH_LEN];
+    sodalite::hash(&mut result, &outer);
+
+    result
+}
+
+/// Keyed hash of a state plaintext under the newest state key.
+///
+/// The hash key is derived from the state key, so only enclaves of the contract can
+/// compute the hash, and a state cannot be guessed by hashing candidates outside the
+/// enclave. Enclaves using the same key epoch obtain the same hash for the same
+/// plaintext. Returns the full HMAC-SHA-512, which callers may truncate.
+pub fn hash_state(state: &[u8]) -> Result<[u8; sodalite::HASH_LEN]> {
+    let (_, state_key) = get_current_state_key()?;
+    let hash_key = hmac(&state_key, STATE_HASH_KEY_CONTEXT);
+
+    Ok(hmac(&hash_key, state))
 }
 
 /// Open encrypted state box.
 pub fn decrypt_state(encrypted_state: &CryptoSecretbox) -> Result<Vec<u8>> {
-    let state_key = get_state_key()?;
+    let state_key = get_state_key(encrypted_state.get_key_epoch())?;
     let encrypted_state_ciphertext = encrypted_state.get_ciphertext();
 
     let mut encrypted_state_nonce: sodalite::SecretboxNonce = [0; sodalite::SECRETBOX_NONCE_LEN];
@@ -49,9 +201,11 @@ pub fn decrypt_state(encrypted_state: &CryptoSecretbox) -> Result<Vec<u8>> {
     Ok(state_raw_padded[SECRETBOX_ZEROBYTES..].to_vec())
 }
 
-/// Generate encrypted state box.
+/// Generate encrypted state box under the newest state key.
+///
+/// The box does not depend on any earlier epoch, so its base epoch is its own epoch.
 pub fn encrypt_state(mut state: Vec<u8>) -> Result<CryptoSecretbox> {
-    let state_key = get_state_key()?;
+    let (key_epoch, state_key) = get_current_state_key()?;
 
     let mut state_raw_padded = vec![0; SECRETBOX_ZEROBYTES];
     state_raw_padded.append(&mut state);
@@ -74,6 +228,30 @@ pub fn encrypt_state(mut state: Vec<u8>) -> Result<CryptoSecretbox> {
     let mut encrypted_state = CryptoSecretbox::new();
     encrypted_state.set_ciphertext(encrypted_state_ciphertext);
     encrypted_state.set_nonce(encrypted_state_nonce.to_vec());
+    encrypted_state.set_key_epoch(key_epoch);
+    encrypted_state.set_base_key_epoch(key_epoch);
 
     Ok(encrypted_state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::hmac;
+
+    #[test]
+    fn test_hmac() {
+        // Test case 2 of RFC 4231.
+        let expected = [
+            0x16, 0x4b, 0x7a, 0x7b, 0xfc, 0xf8, 0x19, 0xe2, 0xe3, 0x95, 0xfb, 0xe7,
+            0x3b, 0x56, 0xe0, 0xa3, 0x87, 0xbd, 0x64, 0x22, 0x2e, 0x83, 0x1f, 0xd6,
+            0x10, 0x27, 0x0c, 0xd7, 0xea, 0x25, 0x05, 0x54, 0x97, 0x58, 0xbf, 0x75,
+            0xc0, 0x5a, 0x99, 0x4a, 0x6d, 0x03, 0x4f, 0x65, 0xf8, 0xf0, 0xe6, 0xfd,
+            0xca, 0xea, 0xb1, 0xa3, 0x4d, 0x4a, 0x6b, 0x4b, 0x63, 0x6e, 0x07, 0x0a,
+            0x38, 0xbc, 0xe7, 0x37,
+        ];
+        assert_eq!(
+            &hmac(b"Jefe", b"what do ya want for nothing?")[..],
+            &expected[..]
+        );
+    }
+}
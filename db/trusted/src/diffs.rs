@@ -1,18 +1,24 @@
 use std;
+use std::cmp;
+use std::collections::BTreeMap;
 
 use bsdiff;
 use bzip2;
 use protobuf;
 use protobuf::Message;
 
-use ekiden_common::error::Result;
+use ekiden_common::error::{Error, Result};
 
+use super::Database;
 use super::crypto;
-use super::generated::database::{BsdiffPatch, CryptoSecretbox};
+use super::generated::database::{BsdiffPatch, CryptoSecretbox, Diff, State, State_KeyValue,
+                                 WriteSet};
+use super::handle::open_state_tree;
+use super::merkle::UntrustedNodeStore;
 
 /// Diff: create a summary of changes that can be applied to `old` to recreate `new`.
 /// This is the actual diffing algorithm implementation.
-fn diff_internal(old: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+fn diff_internal(old: &[u8], new: &[u8]) -> Result<BsdiffPatch> {
     let mut enc = bzip2::write::BzEncoder::new(
         std::io::Cursor::new(Vec::new()),
         bzip2::Compression::Default,
@@ -21,31 +27,141 @@ fn diff_internal(old: &[u8], new: &[u8]) -> Result<Vec<u8>> {
     let mut m = BsdiffPatch::new();
     m.set_new_length(new.len() as u64);
     m.set_patch_bz2(enc.finish()?.into_inner());
-    Ok(m.write_to_bytes()?)
+    Ok(m)
 }
 
 /// Apply: change `old` as specified by `diff`.
 /// `apply_internal(&old, &diff_internal(&old, &new))` should be the same as `new`.
-fn apply_internal(old: &[u8], diff: &[u8]) -> Result<Vec<u8>> {
-    let m: BsdiffPatch = protobuf::parse_from_bytes(diff)?;
+fn apply_internal(old: &[u8], m: &BsdiffPatch) -> Result<Vec<u8>> {
     let mut dec = bzip2::read::BzDecoder::new(std::io::Cursor::new(m.get_patch_bz2()));
     let mut new = vec![0; m.get_new_length() as usize];
     bsdiff::patch::patch(old, &mut dec, &mut new)?;
     Ok(new)
 }
 
+/// Apply a write set to a serialized state.
+///
+/// The cost is proportional to the size of the state, as the state is a single blob,
+/// but unlike a patch, the write set itself only grows with the number of changed keys.
+fn apply_write_set(old: &[u8], write_set: &WriteSet) -> Result<Vec<u8>> {
+    let mut old: State = protobuf::parse_from_bytes(old)?;
+    if !old.get_root().is_empty() || cfg!(feature = "paged_state") {
+        return apply_write_set_paged(&old, write_set);
+    }
+
+    // Entries are kept ordered by key, so that the new state is canonical.
+    let mut state = BTreeMap::new();
+    if !write_set.get_cleared() {
+        for kv in old.take_state().iter_mut() {
+            state.insert(kv.take_key(), kv.take_value());
+        }
+    }
+
+    for write in write_set.get_writes() {
+        if write.get_removed() {
+            state.remove(write.get_key());
+        } else {
+            state.insert(write.get_key().to_vec(), write.get_value().to_vec());
+        }
+    }
+
+    let mut new = State::new();
+    {
+        let items = new.mut_state();
+        for (key, value) in state {
+            let mut item = State_KeyValue::new();
+            item.set_key(key);
+            item.set_value(value);
+
+            items.push(item);
+        }
+    }
+
+    Ok(new.write_to_bytes()?)
+}
+
+/// Apply a write set to a state kept in untrusted storage.
+///
+/// Only the nodes on the paths of the written keys are loaded. A state which is kept
+/// in memory is moved to untrusted storage first.
+fn apply_write_set_paged(old: &State, write_set: &WriteSet) -> Result<Vec<u8>> {
+    let mut tree = open_state_tree(old, UntrustedNodeStore::new())?;
+    if write_set.get_cleared() {
+        tree.clear();
+    }
+
+    for write in write_set.get_writes() {
+        if write.get_removed() {
+            tree.delete(write.get_key())?;
+        } else {
+            tree.update(write.get_key(), write.get_value())?;
+        }
+    }
+
+    let mut new = State::new();
+    new.set_root(tree.root_hash().to_vec());
+
+    Ok(new.write_to_bytes()?)
+}
+
 pub fn diff(old: &CryptoSecretbox, new: &CryptoSecretbox) -> Result<CryptoSecretbox> {
     let old = crypto::decrypt_state(&old)?;
     let new = crypto::decrypt_state(&new)?;
-    let diff = diff_internal(&old, &new)?;
 
-    Ok(crypto::encrypt_state(diff)?)
+    let mut diff = Diff::new();
+    diff.set_bsdiff_patch(diff_internal(&old, &new)?);
+
+    Ok(crypto::encrypt_state(diff.write_to_bytes()?)?)
 }
 
+/// Apply a diff to a state.
+///
+/// The new state still depends on the checkpoint and diffs which the old state was
+/// folded from, so its base key epoch is the oldest epoch of those and the diff.
 pub fn apply(old: &CryptoSecretbox, diff: &CryptoSecretbox) -> Result<CryptoSecretbox> {
+    // The new state is encrypted under the newest key, so nodes of a state kept in
+    // untrusted storage must be re-encrypted as well.
+    let stale = old.get_key_epoch() < crypto::current_state_key_epoch()?;
+    let base_key_epoch = cmp::min(old.get_base_key_epoch(), diff.get_key_epoch());
+
     let old = crypto::decrypt_state(&old)?;
-    let diff = crypto::decrypt_state(&diff)?;
-    let new = apply_internal(&old, &diff)?;
+    let diff: Diff = protobuf::parse_from_bytes(&crypto::decrypt_state(&diff)?)?;
+
+    let new = if diff.has_bsdiff_patch() {
+        apply_internal(&old, diff.get_bsdiff_patch())?
+    } else if diff.has_write_set() {
+        apply_write_set(&old, diff.get_write_set())?
+    } else {
+        return Err(Error::new("Unrecognized diff variant"));
+    };
+
+    if stale {
+        let state: State = protobuf::parse_from_bytes(&new)?;
+        if !state.get_root().is_empty() {
+            open_state_tree(&state, UntrustedNodeStore::new())?.rewrite_nodes()?;
+        }
+    }
+
+    let mut new = crypto::encrypt_state(new)?;
+    new.set_base_key_epoch(base_key_epoch);
+
+    Ok(new)
+}
+
+/// Fold diffs into a checkpoint, producing a new checkpoint.
+///
+/// The new checkpoint replaces the old checkpoint and the diffs, so unlike a state
+/// obtained with [`apply`], it only depends on the key epoch it is encrypted under.
+///
+/// [`apply`]: self::apply
+pub fn fold(checkpoint: &CryptoSecretbox, diffs: &[CryptoSecretbox]) -> Result<CryptoSecretbox> {
+    let mut state = checkpoint.clone();
+    for diff in diffs {
+        state = apply(&state, diff)?;
+    }
+
+    let key_epoch = state.get_key_epoch();
+    state.set_base_key_epoch(key_epoch);
 
-    Ok(crypto::encrypt_state(new)?)
+    Ok(state)
 }
@@ -1,9 +1,29 @@
+use std::cmp;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+use ekiden_common::error::Result;
 use ekiden_common::profile_block;
+use ekiden_common::serializer::{Deserializable, Serializable};
+use ekiden_enclave_common::consensus_hash;
+use ekiden_enclave_common::signature::{pack_state_update, SignatureContext,
+                                       SIGNATURE_CONTEXT_STATE_CHECKPOINT,
+                                       SIGNATURE_CONTEXT_STATE_DIFF,
+                                       SIGNATURE_CONTEXT_STATE_HASH,
+                                       SIGNATURE_CONTEXT_STATE_REPLACE, SIGNATURE_LEN};
+use ekiden_enclave_trusted::identity;
 use ekiden_enclave_trusted::utils::{read_enclave_request, write_enclave_response};
 
 use super::diffs;
+use super::generated::database::CryptoSecretbox;
 use super::handle::DatabaseHandle;
+use super::merkle::HASH_LEN;
+use super::migration;
+use super::schema::key;
 
+/// Compute the difference between two states.
+///
+/// The difference is not signed, as both states are supplied by the host. Only
+/// diffs exported by the enclave itself (see `db_state_get_diff`) are signed.
 #[no_mangle]
 pub extern "C" fn db_state_diff(
     old: *const u8,
@@ -54,20 +74,57 @@ pub extern "C" fn db_state_apply(
     write_enclave_response(&result, new, new_capacity, new_length);
 }
 
+/// Import the state and bring it up to the current schema version.
+///
+/// If this fails, the error message is copied into the error buffer, truncated to its
+/// capacity. Otherwise the error length is zero.
 #[no_mangle]
-pub extern "C" fn db_state_set(state: *const u8, state_length: usize) {
+pub extern "C" fn db_state_set(
+    state: *const u8,
+    state_length: usize,
+    error: *mut u8,
+    error_capacity: usize,
+    error_length: *mut usize,
+) {
     profile_block!();
 
     let state = read_enclave_request(state, state_length);
 
-    // TODO: Propagate errors.
-    DatabaseHandle::instance()
-        .import(&state)
-        .expect("Error importing state");
+    let message = match import_state(&state) {
+        Ok(()) => vec![],
+        Err(error) => error.message.into_bytes(),
+    };
+
+    // The error buffer is copied out of the enclave by the EDL.
+    let length = cmp::min(message.len(), error_capacity);
+    let error = unsafe { from_raw_parts_mut(error, error_capacity) };
+    error[..length].copy_from_slice(&message[..length]);
+    unsafe {
+        *error_length = length;
+    }
+}
+
+/// Import the state and bring it up to the current schema version.
+fn import_state(state: &CryptoSecretbox) -> Result<()> {
+    DatabaseHandle::instance().import(state)?;
+
+    // Reject states with map keys in an encoding which can no longer be read.
+    key::check_key_format()?;
+
+    // Bring the state up to the current schema version before any requests see it.
+    migration::migrate()
 }
 
 #[no_mangle]
-pub extern "C" fn db_state_get(state: *mut u8, state_capacity: usize, state_length: *mut usize) {
+pub extern "C" fn db_state_get(
+    state: *mut u8,
+    state_capacity: usize,
+    state_length: *mut usize,
+    height: u64,
+    base_state_hash: *const u8,
+    base_state_hash_length: usize,
+    signature: *mut u8,
+) {
     profile_block!();
 
     // TODO: Propagate errors.
@@ -75,6 +132,152 @@ pub extern "C" fn db_state_get(state: *mut u8, state_capacity: usize, state_leng
         .export()
         .expect("Error exporting state");
 
+    // The base state hash is copied into the enclave by the EDL.
+    let base_state_hash = unsafe { from_raw_parts(base_state_hash, base_state_hash_length) };
+
+    // Only sign state that was actually exported.
+    let result_bytes = result.write().expect("Failed to serialize state");
+    if !result_bytes.is_empty() {
+        sign_state_update(
+            &SIGNATURE_CONTEXT_STATE_REPLACE,
+            height,
+            base_state_hash,
+            &result_bytes,
+            signature,
+        );
+    }
+
     // Copy back response.
     write_enclave_response(&result, state, state_capacity, state_length);
 }
+
+/// Export the changes made since the state was last set as a signed diff.
+#[no_mangle]
+pub extern "C" fn db_state_get_diff(
+    diff: *mut u8,
+    diff_capacity: usize,
+    diff_length: *mut usize,
+    height: u64,
+    base_state_hash: *const u8,
+    base_state_hash_length: usize,
+    signature: *mut u8,
+) {
+    profile_block!();
+
+    // TODO: Propagate errors.
+    let result = DatabaseHandle::instance()
+        .export_diff()
+        .expect("Error exporting diff");
+
+    // The base state hash is copied into the enclave by the EDL.
+    let base_state_hash = unsafe { from_raw_parts(base_state_hash, base_state_hash_length) };
+
+    // Only sign a diff that was actually exported.
+    let result_bytes = result.write().expect("Failed to serialize diff");
+    if !result_bytes.is_empty() {
+        sign_state_update(
+            &SIGNATURE_CONTEXT_STATE_DIFF,
+            height,
+            base_state_hash,
+            &result_bytes,
+            signature,
+        );
+    }
+
+    // Copy back response.
+    write_enclave_response(&result, diff, diff_capacity, diff_length);
+}
+
+/// Fold a checkpoint and the diffs on top of it into a new signed checkpoint.
+///
+/// The diffs are applied in the enclave, so only a state which the enclave derived
+/// itself is signed. The signature is bound to the height of the folded state and to
+/// the consensus hash of the checkpoint and diffs it was folded from, so consensus only
+/// accepts the checkpoint as a compaction of its current state. The folded state is
+/// returned.
+#[no_mangle]
+pub extern "C" fn db_state_checkpoint(
+    checkpoint: *const u8,
+    checkpoint_length: usize,
+    checkpoint_height: u64,
+    diffs: *const u8,
+    diffs_length: usize,
+    state: *mut u8,
+    state_capacity: usize,
+    state_length: *mut usize,
+    signature: *mut u8,
+) {
+    profile_block!();
+
+    // Consensus hashes the checkpoint as submitted, so hash it before parsing it.
+    let checkpoint = unsafe { from_raw_parts(checkpoint, checkpoint_length) }.to_vec();
+    let diffs: Vec<Vec<u8>> = read_enclave_request(diffs, diffs_length);
+    if diffs.is_empty() {
+        panic!("Nothing to checkpoint");
+    }
+
+    let base_state_hash =
+        consensus_hash::contract_state_hash(checkpoint_height, &checkpoint, &diffs);
+
+    // TODO: Propagate errors.
+    let checkpoint = CryptoSecretbox::read(&checkpoint).expect("Malformed checkpoint");
+    let diffs = diffs
+        .iter()
+        .map(|diff| CryptoSecretbox::read(diff).expect("Malformed diff"))
+        .collect::<Vec<_>>();
+    let result = diffs::fold(&checkpoint, &diffs).expect("Error while applying diff");
+    DatabaseHandle::validate(&result).expect("Error validating state");
+
+    sign_state_update(
+        &SIGNATURE_CONTEXT_STATE_CHECKPOINT,
+        checkpoint_height + diffs.len() as u64,
+        &base_state_hash,
+        &result.write().expect("Failed to serialize state"),
+        signature,
+    );
+
+    // Copy back response.
+    write_enclave_response(&result, state, state_capacity, state_length);
+}
+
+/// Compute the keyed hash of the canonical plaintext of the current state and sign it.
+///
+/// The state is not modified. The hash buffer must be `HASH_LEN` bytes long and the
+/// signature buffer must be `SIGNATURE_LEN` bytes long.
+#[no_mangle]
+pub extern "C" fn db_state_hash(hash: *mut u8, signature: *mut u8) {
+    profile_block!();
+
+    // TODO: Propagate errors.
+    let result = DatabaseHandle::instance()
+        .state_hash()
+        .expect("Error hashing state");
+
+    let result_signature = identity::sign(&SIGNATURE_CONTEXT_STATE_HASH, &result);
+
+    let hash = unsafe { from_raw_parts_mut(hash, HASH_LEN) };
+    hash.copy_from_slice(&result);
+
+    let signature = unsafe { from_raw_parts_mut(signature, SIGNATURE_LEN) };
+    signature.copy_from_slice(&result_signature);
+}
+
+/// Sign a state update, binding it to the height of the state it produces and the hash
+/// of the state it was computed on.
+///
+/// The signature buffer must be `SIGNATURE_LEN` bytes long.
+fn sign_state_update(
+    context: &SignatureContext,
+    height: u64,
+    base_state_hash: &[u8],
+    payload: &[u8],
+    signature: *mut u8,
+) {
+    let result = identity::sign(
+        context,
+        &pack_state_update(height, base_state_hash, payload),
+    );
+
+    let signature = unsafe { from_raw_parts_mut(signature, SIGNATURE_LEN) };
+    signature.copy_from_slice(&result);
+}
@@ -1,5 +1,7 @@
 //! Low-level key-value database interface.
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Included, Unbounded};
+use std::mem;
 #[cfg(not(target_env = "sgx"))]
 use std::sync::{Mutex, MutexGuard};
 #[cfg(target_env = "sgx")]
@@ -9,22 +11,201 @@ use std::sync::SgxMutexGuard as MutexGuard;
 
 use protobuf::{self, Message};
 
-use ekiden_common::error::Result;
+use ekiden_common::error::{Error, Result};
 
 use super::Database;
 use super::crypto;
-use super::generated::database::{CryptoSecretbox, State, State_KeyValue};
+use super::generated::database::{CryptoSecretbox, Diff, State, State_KeyValue,
+                                 WriteSet_Write};
+use super::merkle::{self, Hash, MerkleDatabase, MerkleProof, UntrustedNodeStore, EMPTY_HASH};
+
+/// Storage backend of the database.
+enum Backend {
+    /// All state is kept in enclave memory.
+    Memory(BTreeMap<Vec<u8>, Vec<u8>>),
+    /// State is kept in an authenticated tree, with encrypted nodes stored outside the
+    /// enclave. Only the root hash and a small node cache are kept in enclave memory.
+    Paged(MerkleDatabase<UntrustedNodeStore>),
+}
+
+/// Contents of a backend before it was cleared.
+enum Snapshot {
+    Memory(BTreeMap<Vec<u8>, Vec<u8>>),
+    Paged(Hash),
+}
+
+impl Backend {
+    /// Construct an empty backend of the kind selected at build time.
+    fn new() -> Self {
+        if cfg!(feature = "paged_state") {
+            Backend::Paged(MerkleDatabase::new(UntrustedNodeStore::new()))
+        } else {
+            Backend::Memory(BTreeMap::new())
+        }
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        match *self {
+            Backend::Memory(ref items) => items.contains_key(key),
+            Backend::Paged(ref tree) => tree.contains_key(key),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Backend::Memory(ref items) => items.get(key).cloned(),
+            Backend::Paged(ref tree) => tree.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Backend::Memory(ref mut items) => items.insert(key.to_owned(), value.to_owned()),
+            Backend::Paged(ref mut tree) => tree.insert(key, value),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Backend::Memory(ref mut items) => items.remove(key),
+            Backend::Paged(ref mut tree) => tree.remove(key),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            Backend::Memory(ref items) => items.is_empty(),
+            Backend::Paged(ref tree) => tree.root_hash() == EMPTY_HASH,
+        }
+    }
+
+    fn range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        match *self {
+            Backend::Memory(ref items) => {
+                let end = match end {
+                    Some(end) => Excluded(end.to_vec()),
+                    None => Unbounded,
+                };
+                if let Excluded(ref end) = end {
+                    if &end[..] <= start {
+                        return vec![];
+                    }
+                }
+
+                items
+                    .range((Included(start.to_vec()), end))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            }
+            Backend::Paged(ref tree) => tree.range(start, end),
+        }
+    }
+
+    /// Return the first error encountered while accessing the backend.
+    fn check(&self) -> Result<()> {
+        match *self {
+            Backend::Memory(_) => Ok(()),
+            Backend::Paged(ref tree) => tree.check(),
+        }
+    }
+
+    /// Clear the backend, returning a snapshot of its previous contents.
+    fn take(&mut self) -> Snapshot {
+        match *self {
+            Backend::Memory(ref mut items) => {
+                Snapshot::Memory(mem::replace(items, BTreeMap::new()))
+            }
+            Backend::Paged(ref mut tree) => {
+                let root = tree.root_hash();
+                tree.clear();
+                Snapshot::Paged(root)
+            }
+        }
+    }
+
+    /// Restore the contents of the backend from a snapshot.
+    fn restore(&mut self, snapshot: Snapshot) {
+        match (self, snapshot) {
+            (&mut Backend::Memory(ref mut items), Snapshot::Memory(previous)) => *items = previous,
+            (&mut Backend::Paged(ref mut tree), Snapshot::Paged(root)) => tree.set_root_hash(root),
+            _ => panic!("Snapshot of a different backend"),
+        }
+    }
+}
+
+/// Change which can be undone when a savepoint is rolled back.
+enum Undo {
+    /// A key was written. Holds its previous value and its previous entry in the write set.
+    Write {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        write: Option<Option<Vec<u8>>>,
+    },
+    /// The state was cleared. Holds the previous contents and write set.
+    Clear {
+        snapshot: Snapshot,
+        cleared: bool,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    },
+}
+
+/// Savepoint which changes can be rolled back to.
+struct Savepoint {
+    /// Dirtyness flag at the time the savepoint was created.
+    dirty: bool,
+    /// Changes made since the savepoint was created, in order.
+    undo: Vec<Undo>,
+}
+
+/// Open the state tree of a serialized state in untrusted storage.
+///
+/// A state which is kept in memory is moved into a new tree.
+pub(crate) fn open_state_tree(
+    state: &State,
+    store: UntrustedNodeStore,
+) -> Result<MerkleDatabase<UntrustedNodeStore>> {
+    if !state.get_root().is_empty() {
+        let root = merkle::hash_from_slice(state.get_root())?;
+        return Ok(MerkleDatabase::open(store, root));
+    }
+
+    let mut tree = MerkleDatabase::new(store);
+    for kv in state.get_state() {
+        tree.update(kv.get_key(), kv.get_value())?;
+    }
+
+    Ok(tree)
+}
 
 /// Database handle.
 ///
 /// This is a concrete implementation of the [`Database`] interface.
 ///
+/// By default, the whole state is kept in enclave memory. When built with the
+/// `paged_state` feature, the state is instead kept in untrusted storage and the
+/// exported state only contains the root hash of the state tree, so the state can
+/// exceed the size of the enclave page cache. Note that in this case all compute
+/// nodes of a contract must have access to the same untrusted storage.
+///
 /// [`Database`]: super::Database
 pub struct DatabaseHandle {
     /// Current database state.
-    state: HashMap<Vec<u8>, Vec<u8>>,
-    /// Dirtyness flag.
+    backend: Backend,
+    /// Dirtyness flag, set when the state is written.
     dirty: bool,
+    /// True if the state must be exported even if it was not written, because it is
+    /// encrypted under an older state key or was moved to untrusted storage.
+    reencrypt: bool,
+    /// True if the state was cleared since the last import.
+    cleared: bool,
+    /// Keys written since the last import (or clear) and their new values. Removed keys
+    /// have no value.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Active savepoints, innermost last.
+    savepoints: Vec<Savepoint>,
+    /// Oldest epoch of the state key which the checkpoint and diffs that the last
+    /// imported state was folded from were encrypted under.
+    base_key_epoch: Option<u64>,
 }
 
 lazy_static! {
@@ -36,8 +217,13 @@ impl DatabaseHandle {
     /// Construct new database interface.
     fn new() -> Self {
         DatabaseHandle {
-            state: HashMap::new(),
+            backend: Backend::new(),
             dirty: false,
+            reencrypt: false,
+            cleared: false,
+            writes: BTreeMap::new(),
+            savepoints: vec![],
+            base_key_epoch: None,
         }
     }
 
@@ -49,74 +235,357 @@ impl DatabaseHandle {
         DB.lock().unwrap()
     }
 
+    /// Returns true if the database contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.backend.is_empty()
+    }
+
+    /// Take the node store of the current backend, so that its cache is retained when
+    /// a new state is imported.
+    fn take_node_store(&mut self) -> UntrustedNodeStore {
+        match mem::replace(&mut self.backend, Backend::Memory(BTreeMap::new())) {
+            Backend::Paged(tree) => tree.into_store(),
+            Backend::Memory(_) => UntrustedNodeStore::new(),
+        }
+    }
+
+    /// Create a savepoint.
+    ///
+    /// Changes made after this call can be discarded with [`rollback`] or kept with
+    /// [`commit`]. Savepoints may be nested, in which case changes committed in an inner
+    /// savepoint are still discarded if the outer savepoint is rolled back.
+    ///
+    /// [`rollback`]: DatabaseHandle::rollback
+    /// [`commit`]: DatabaseHandle::commit
+    pub fn begin(&mut self) {
+        self.savepoints.push(Savepoint {
+            dirty: self.dirty,
+            undo: vec![],
+        });
+    }
+
+    /// Keep the changes made since the innermost savepoint and release it.
+    pub fn commit(&mut self) -> Result<()> {
+        let savepoint = match self.savepoints.pop() {
+            Some(savepoint) => savepoint,
+            None => return Err(Error::new("No active savepoint")),
+        };
+
+        // Changes remain part of the outer savepoint, if any.
+        if let Some(outer) = self.savepoints.last_mut() {
+            outer.undo.extend(savepoint.undo);
+        }
+
+        Ok(())
+    }
+
+    /// Discard the changes made since the innermost savepoint and release it.
+    pub fn rollback(&mut self) -> Result<()> {
+        let savepoint = match self.savepoints.pop() {
+            Some(savepoint) => savepoint,
+            None => return Err(Error::new("No active savepoint")),
+        };
+
+        for undo in savepoint.undo.into_iter().rev() {
+            match undo {
+                Undo::Write { key, value, write } => {
+                    match value {
+                        Some(value) => self.backend.insert(&key, &value),
+                        None => self.backend.remove(&key),
+                    };
+                    match write {
+                        Some(write) => self.writes.insert(key, write),
+                        None => self.writes.remove(&key),
+                    };
+                }
+                Undo::Clear {
+                    snapshot,
+                    cleared,
+                    writes,
+                } => {
+                    self.backend.restore(snapshot);
+                    self.cleared = cleared;
+                    self.writes = writes;
+                }
+            }
+        }
+
+        self.dirty = savepoint.dirty;
+
+        Ok(())
+    }
+
+    /// Record a write of a key, so that it can be undone.
+    fn record_write(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+        let write = self.writes.get(key).cloned();
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.undo.push(Undo::Write {
+                key: key.to_owned(),
+                value,
+                write,
+            });
+        }
+    }
+
     /// Import database.
+    ///
+    /// A state which is kept in memory is moved to untrusted storage if the database
+    /// is built with the `paged_state` feature. A state which is encrypted under an
+    /// older state key is re-encrypted under the newest key when it is exported.
+    ///
+    /// The newest epoch of the state key is fetched from the key manager again, so that
+    /// each batch uses an epoch created by another node since the previous batch.
     pub(crate) fn import(&mut self, state: &CryptoSecretbox) -> Result<()> {
+        crypto::refresh_state_key_epoch()?;
+
+        let base_key_epoch = state.get_base_key_epoch();
+        let stale = state.get_key_epoch() < crypto::current_state_key_epoch()?;
         let mut state: State = protobuf::parse_from_bytes(&crypto::decrypt_state(&state)?)?;
 
-        self.state.clear();
-        for kv in state.take_state().iter_mut() {
-            self.state.insert(kv.take_key(), kv.take_value());
-        }
+        // Moving a state to untrusted storage changes the exported state.
+        let converted = state.get_root().is_empty() && cfg!(feature = "paged_state");
 
+        let backend = if !state.get_root().is_empty() || cfg!(feature = "paged_state") {
+            let store = self.take_node_store();
+            let mut tree = open_state_tree(&state, store)?;
+            if stale {
+                tree.rewrite_nodes()?;
+            }
+
+            Backend::Paged(tree)
+        } else {
+            let mut items = BTreeMap::new();
+            for kv in state.take_state().iter_mut() {
+                items.insert(kv.take_key(), kv.take_value());
+            }
+            Backend::Memory(items)
+        };
+
+        self.backend = backend;
         self.dirty = false;
+        self.reencrypt = converted || stale;
+        self.cleared = false;
+        self.writes.clear();
+        self.savepoints.clear();
+        self.base_key_epoch = Some(base_key_epoch);
 
         Ok(())
     }
 
+    /// Generate a state key for a new epoch and re-encrypt the state under it.
+    ///
+    /// The state is re-encrypted when it is next exported, while state which is still
+    /// encrypted under previous epochs remains readable until the epochs are retired
+    /// with [`retire_keys`]. Returns the new epoch.
+    ///
+    /// [`retire_keys`]: DatabaseHandle::retire_keys
+    pub fn rotate_key(&mut self) -> Result<u64> {
+        let epoch = crypto::rotate_state_key()?;
+        if let Backend::Paged(ref mut tree) = self.backend {
+            tree.rewrite_nodes()?;
+        }
+        self.reencrypt = true;
+
+        Ok(epoch)
+    }
+
+    /// Retire all epochs of the state key before the given epoch.
+    ///
+    /// State encrypted under retired epochs can no longer be decrypted. The imported
+    /// state is folded from the checkpoint and diffs held by consensus, so this fails
+    /// until a checkpoint encrypted under the given epoch or a newer one has been
+    /// committed and all diffs since are encrypted under such epochs as well. Until
+    /// then, the key of epoch 0 used by contracts which do not rotate their keys also
+    /// remains available. Any other copies of earlier states and diffs which are still
+    /// needed must have been re-encrypted as well.
+    pub fn retire_keys(&mut self, epoch: u64) -> Result<()> {
+        if let Some(base_key_epoch) = self.base_key_epoch {
+            if base_key_epoch < epoch {
+                return Err(Error::new(
+                    "Committed state is still encrypted under a key epoch which would be retired",
+                ));
+            }
+        }
+
+        crypto::retire_state_keys(epoch)
+    }
+
+    /// Check that an encrypted state is a valid database state, without importing it.
+    pub(crate) fn validate(state: &CryptoSecretbox) -> Result<()> {
+        let state: State = protobuf::parse_from_bytes(&crypto::decrypt_state(&state)?)?;
+        if !state.get_root().is_empty() {
+            merkle::hash_from_slice(state.get_root())?;
+        }
+
+        Ok(())
+    }
+
+    /// Prove the value of a key in the current state, or that the key is not present.
+    ///
+    /// Returns the root hash of the state tree together with the proof. This is only
+    /// available for state kept in untrusted storage.
+    pub fn prove(&self, key: &[u8]) -> Result<(Hash, MerkleProof)> {
+        match self.backend {
+            Backend::Memory(_) => Err(Error::new("Proofs require paged state")),
+            Backend::Paged(ref tree) => {
+                tree.check()?;
+                Ok((tree.root_hash(), tree.prove(key)?))
+            }
+        }
+    }
+
     /// Export database.
     ///
-    /// If nothing was modified since the last import, this method will return an
-    /// uninitialized CryptoSecretbox.
+    /// If nothing was modified since the last import and the state does not need to be
+    /// re-encrypted, this method will return an uninitialized CryptoSecretbox. Fails if
+    /// the state could not be accessed since the last import, as the state may then be
+    /// incomplete.
     pub(crate) fn export(&mut self) -> Result<CryptoSecretbox> {
-        if !self.dirty {
+        self.backend.check()?;
+        if !self.dirty && !self.reencrypt {
             // Database has not changed, we don't need to export anything.
             return Ok(CryptoSecretbox::new());
         }
 
+        Ok(crypto::encrypt_state(self.canonical_state()?)?)
+    }
+
+    /// Serialize the current state in its canonical form.
+    ///
+    /// Entries are ordered by key, so enclaves with the same state contents produce the
+    /// same plaintext, regardless of the order in which the entries were written. A
+    /// state kept in untrusted storage is represented by the root hash of its tree,
+    /// which only depends on its contents as well.
+    fn canonical_state(&self) -> Result<Vec<u8>> {
+        self.backend.check()?;
+
         let mut state = State::new();
+        match self.backend {
+            Backend::Memory(ref items) => {
+                let state_items = state.mut_state();
+                for (key, value) in items {
+                    let mut item = State_KeyValue::new();
+                    item.set_key(key.clone());
+                    item.set_value(value.clone());
+
+                    state_items.push(item);
+                }
+            }
+            Backend::Paged(ref tree) => state.set_root(tree.root_hash().to_vec()),
+        }
+
+        Ok(state.write_to_bytes()?)
+    }
+
+    /// Keyed hash of the canonical plaintext of the current state.
+    ///
+    /// Compute nodes with the same state contents obtain the same hash, so they can
+    /// commit to a state without revealing it. The hash is keyed with a key derived from
+    /// the newest state key, so it cannot be used to test guesses of the state outside
+    /// of the contract's enclaves.
+    pub fn state_hash(&self) -> Result<Hash> {
+        let digest = crypto::hash_state(&self.canonical_state()?)?;
+
+        let mut result = [0; merkle::HASH_LEN];
+        result.copy_from_slice(&digest[..merkle::HASH_LEN]);
+
+        Ok(result)
+    }
+
+    /// Export the changes made since the last import as a diff.
+    ///
+    /// Unlike computing a diff between the imported and the exported state, the size of
+    /// the diff only depends on the keys that were written.
+    ///
+    /// If nothing was written since the last import, this method will return an
+    /// uninitialized CryptoSecretbox. Re-encrypting the state does not change its
+    /// contents, so it does not produce a diff.
+    pub(crate) fn export_diff(&mut self) -> Result<CryptoSecretbox> {
+        self.backend.check()?;
+        if !self.dirty {
+            // Database has not changed, we don't need to export anything.
+            return Ok(CryptoSecretbox::new());
+        }
+
+        let mut diff = Diff::new();
         {
-            let items = state.mut_state();
-            for (key, value) in &self.state {
-                let mut item = State_KeyValue::new();
-                item.set_key(key.clone());
-                item.set_value(value.clone());
+            let write_set = diff.mut_write_set();
+            write_set.set_cleared(self.cleared);
 
-                items.push(item);
+            // Writes are ordered by key, so the diff is canonical as well.
+            let writes = write_set.mut_writes();
+            for (key, value) in &self.writes {
+                let mut write = WriteSet_Write::new();
+                write.set_key(key.clone());
+                match *value {
+                    Some(ref value) => write.set_value(value.clone()),
+                    None => write.set_removed(true),
+                }
+
+                writes.push(write);
             }
         }
 
-        Ok(crypto::encrypt_state(state.write_to_bytes()?)?)
+        Ok(crypto::encrypt_state(diff.write_to_bytes()?)?)
     }
 }
 
 impl Database for DatabaseHandle {
     fn contains_key(&self, key: &[u8]) -> bool {
-        self.state.contains_key(key)
+        self.backend.contains_key(key)
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.state.get(key).cloned()
+        self.backend.get(key)
+    }
+
+    fn range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.backend.range(start, end)
     }
 
     fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.backend.insert(key, value);
+        self.record_write(key, previous.clone());
+
         self.dirty = true;
-        self.state.insert(key.to_owned(), value.to_owned())
+        self.writes.insert(key.to_owned(), Some(value.to_owned()));
+        previous
     }
 
     fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.backend.remove(key);
+        self.record_write(key, previous.clone());
+
         self.dirty = true;
-        self.state.remove(key)
+        self.writes.insert(key.to_owned(), None);
+        previous
     }
 
     /// Clear database state.
     fn clear(&mut self) {
+        let snapshot = self.backend.take();
+        let writes = mem::replace(&mut self.writes, BTreeMap::new());
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.undo.push(Undo::Clear {
+                snapshot,
+                cleared: self.cleared,
+                writes,
+            });
+        }
+
         self.dirty = true;
-        self.state.clear();
+        self.cleared = true;
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use protobuf::Message;
+
+    use super::super::crypto;
+    use super::super::diffs;
+    use super::super::generated::database::State;
+    use super::super::merkle::{self, MerkleDatabase, UntrustedNodeStore};
     use super::{Database, DatabaseHandle};
 
     #[test]
@@ -134,4 +603,264 @@ mod tests {
 
         assert_eq!(db.get(b"foo"), None);
     }
+
+    #[test]
+    fn test_range() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"b", b"2");
+        db.insert(b"a", b"1");
+        db.insert(b"ab", b"3");
+        db.insert(b"c", b"4");
+
+        let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(db.range(b"", None)),
+            vec![b"a".to_vec(), b"ab".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(keys(db.range(b"ab", Some(b"c"))), vec![b"ab".to_vec(), b"b".to_vec()]);
+        assert_eq!(keys(db.range(b"c", Some(b"a"))), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(db.prefix(b"a")), vec![b"a".to_vec(), b"ab".to_vec()]);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"foo", b"hello world");
+        db.insert(b"bar", b"another data value");
+        let old_state = db.export().unwrap();
+        db.import(&old_state).unwrap();
+
+        // Rolled back changes are neither visible nor exported.
+        db.begin();
+        db.insert(b"foo", b"changed");
+        db.insert(b"foo", b"changed again");
+        db.remove(b"bar");
+        db.insert(b"baz", b"new value");
+        db.rollback().unwrap();
+
+        assert_eq!(db.get(b"foo"), Some(b"hello world".to_vec()));
+        assert_eq!(db.get(b"bar"), Some(b"another data value".to_vec()));
+        assert_eq!(db.get(b"baz"), None);
+        assert!(db.export().unwrap().get_ciphertext().is_empty());
+        assert!(db.export_diff().unwrap().get_ciphertext().is_empty());
+
+        // Committed changes are kept.
+        db.begin();
+        db.insert(b"foo", b"changed");
+        db.commit().unwrap();
+
+        // Changes committed in a nested savepoint are discarded with the outer one.
+        db.begin();
+        db.clear();
+        db.begin();
+        db.insert(b"baz", b"new value");
+        db.commit().unwrap();
+        assert_eq!(db.get(b"foo"), None);
+        db.rollback().unwrap();
+
+        assert!(db.rollback().is_err());
+        assert!(db.commit().is_err());
+
+        assert_eq!(db.get(b"foo"), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"bar"), Some(b"another data value".to_vec()));
+        assert_eq!(db.get(b"baz"), None);
+
+        let diff = db.export_diff().unwrap();
+        db.import(&diffs::apply(&old_state, &diff).unwrap()).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"bar"), Some(b"another data value".to_vec()));
+        assert_eq!(db.get(b"baz"), None);
+    }
+
+    #[test]
+    fn test_export_diff() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"foo", b"hello world");
+        db.insert(b"bar", b"another data value");
+        let old_state = db.export().unwrap();
+
+        // Nothing is exported if there were no changes since the import.
+        db.import(&old_state).unwrap();
+        assert!(db.export_diff().unwrap().get_ciphertext().is_empty());
+
+        db.insert(b"foo", b"changed");
+        db.remove(b"bar");
+        db.insert(b"baz", b"new value");
+        let diff = db.export_diff().unwrap();
+
+        let new_state = diffs::apply(&old_state, &diff).unwrap();
+        db.import(&new_state).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"bar"), None);
+        assert_eq!(db.get(b"baz"), Some(b"new value".to_vec()));
+
+        // Writes after a clear apply to an empty state.
+        db.clear();
+        db.insert(b"bar", b"after clear");
+        let diff = db.export_diff().unwrap();
+
+        let new_state = diffs::apply(&new_state, &diff).unwrap();
+        db.import(&new_state).unwrap();
+        assert_eq!(db.get(b"foo"), None);
+        assert_eq!(db.get(b"baz"), None);
+        assert_eq!(db.get(b"bar"), Some(b"after clear".to_vec()));
+    }
+
+    #[test]
+    fn test_diff_bsdiff() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"foo", b"hello world");
+        let old_state = db.export().unwrap();
+
+        db.insert(b"foo", b"changed");
+        let new_state = db.export().unwrap();
+
+        let diff = diffs::diff(&old_state, &new_state).unwrap();
+        db.import(&diffs::apply(&old_state, &diff).unwrap()).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"changed".to_vec()));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"foo", b"hello world");
+        let old_state = db.export().unwrap();
+        db.import(&old_state).unwrap();
+
+        let epoch = db.rotate_key().unwrap();
+        assert!(epoch > old_state.get_key_epoch());
+        let new_state = db.export().unwrap();
+        assert_eq!(new_state.get_key_epoch(), epoch);
+
+        // State encrypted under an older epoch is re-encrypted when it is exported, but
+        // there is no diff without writes.
+        db.import(&old_state).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"hello world".to_vec()));
+        assert!(db.export_diff().unwrap().get_ciphertext().is_empty());
+        assert_eq!(db.export().unwrap().get_key_epoch(), epoch);
+        assert!(db.retire_keys(epoch).is_err());
+
+        // A state folded from a checkpoint under an older epoch still depends on it,
+        // until the diffs are folded into a new checkpoint.
+        db.import(&old_state).unwrap();
+        db.insert(b"bar", b"written");
+        let diff = db.export_diff().unwrap();
+        let folded_state = diffs::apply(&old_state, &diff).unwrap();
+        assert_eq!(folded_state.get_key_epoch(), epoch);
+        assert_eq!(folded_state.get_base_key_epoch(), old_state.get_key_epoch());
+        db.import(&folded_state).unwrap();
+        assert!(db.retire_keys(epoch).is_err());
+
+        let checkpoint = diffs::fold(&old_state, &[diff]).unwrap();
+        assert_eq!(checkpoint.get_base_key_epoch(), epoch);
+        db.import(&checkpoint).unwrap();
+        assert_eq!(db.get(b"bar"), Some(b"written".to_vec()));
+        db.retire_keys(epoch).unwrap();
+        assert!(crypto::decrypt_state(&old_state).is_err());
+
+        // Nodes of a state kept in untrusted storage are re-encrypted as well.
+        let mut tree = MerkleDatabase::new(UntrustedNodeStore::new());
+        tree.insert(b"foo", b"paged");
+        let root = tree.root_hash();
+        let mut state = State::new();
+        state.set_root(root.to_vec());
+        let paged_state = crypto::encrypt_state(state.write_to_bytes().unwrap()).unwrap();
+
+        db.import(&paged_state).unwrap();
+        let epoch = db.rotate_key().unwrap();
+        let rotated_state = db.export().unwrap();
+        db.import(&rotated_state).unwrap();
+        db.retire_keys(epoch).unwrap();
+
+        assert!(crypto::decrypt_state(&new_state).is_err());
+        assert!(crypto::decrypt_state(&paged_state).is_err());
+        let tree = MerkleDatabase::open(UntrustedNodeStore::new(), root);
+        assert_eq!(tree.get(b"foo"), Some(b"paged".to_vec()));
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let mut db = DatabaseHandle::instance();
+
+        db.clear();
+        db.insert(b"foo", b"hello world");
+        db.insert(b"bar", b"another data value");
+        let state = crypto::decrypt_state(&db.export().unwrap()).unwrap();
+        let hash = db.state_hash().unwrap();
+
+        // The hash is keyed, so it differs from a plain hash of the state.
+        assert!(&hash[..] != &merkle::hash(&db.canonical_state().unwrap())[..]);
+
+        // The same contents written in a different order give the same state and hash.
+        db.clear();
+        db.insert(b"bar", b"another data value");
+        db.insert(b"baz", b"removed");
+        db.insert(b"foo", b"hello world");
+        db.remove(b"baz");
+        assert_eq!(crypto::decrypt_state(&db.export().unwrap()).unwrap(), state);
+        assert_eq!(db.state_hash().unwrap(), hash);
+
+        db.insert(b"foo", b"changed");
+        assert!(db.state_hash().unwrap() != hash);
+
+        // Applying a diff gives the canonical state as well.
+        let old_state = db.export().unwrap();
+        db.import(&old_state).unwrap();
+        db.insert(b"foo", b"hello world");
+        db.insert(b"aaa", b"first");
+        db.remove(b"aaa");
+        let diff = db.export_diff().unwrap();
+        let new_state = diffs::apply(&old_state, &diff).unwrap();
+        assert_eq!(crypto::decrypt_state(&new_state).unwrap(), state);
+    }
+
+    #[test]
+    fn test_paged_state() {
+        let mut db = DatabaseHandle::instance();
+
+        let mut tree = MerkleDatabase::new(UntrustedNodeStore::new());
+        tree.insert(b"foo", b"hello world");
+        tree.insert(b"bar", b"another data value");
+
+        let mut state = State::new();
+        state.set_root(tree.root_hash().to_vec());
+        let old_state = crypto::encrypt_state(state.write_to_bytes().unwrap()).unwrap();
+
+        db.import(&old_state).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"hello world".to_vec()));
+        assert_eq!(db.get(b"bar"), Some(b"another data value".to_vec()));
+
+        // Keys can be proven against the root of the exported state.
+        let (root, proof) = db.prove(b"foo").unwrap();
+        assert_eq!(root, tree.root_hash());
+        assert!(proof.verify(&root, b"foo", Some(b"hello world")));
+
+        db.insert(b"foo", b"changed");
+        db.remove(b"bar");
+        let diff = db.export_diff().unwrap();
+        let exported_state = db.export().unwrap();
+
+        // Applying the write set to the root yields the same state.
+        let new_state = diffs::apply(&old_state, &diff).unwrap();
+        assert_eq!(
+            crypto::decrypt_state(&new_state).unwrap(),
+            crypto::decrypt_state(&exported_state).unwrap()
+        );
+
+        db.import(&new_state).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"bar"), None);
+    }
 }
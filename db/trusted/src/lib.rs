@@ -1,6 +1,9 @@
 #![feature(core_intrinsics)]
 #![feature(use_extern_macros)]
 
+#[cfg(target_env = "sgx")]
+extern crate sgx_types;
+
 extern crate bsdiff;
 extern crate bzip2;
 #[macro_use]
@@ -10,6 +13,7 @@ extern crate sodalite;
 
 #[macro_use]
 extern crate ekiden_common;
+extern crate ekiden_enclave_common;
 extern crate ekiden_enclave_trusted;
 extern crate ekiden_key_manager_client;
 
@@ -17,15 +21,22 @@ mod generated;
 
 mod crypto;
 mod diffs;
+mod untrusted;
 #[doc(hidden)]
 pub mod ecalls;
 
 pub mod handle;
 pub use handle::DatabaseHandle;
 
+pub mod merkle;
+pub use merkle::MerkleDatabase;
+
 #[macro_use]
 pub mod schema;
 
+#[macro_use]
+pub mod migration;
+
 /// Database interface exposed to contracts.
 pub trait Database {
     /// Returns true if the database contains a value for the specified key.
@@ -50,4 +61,34 @@ pub trait Database {
 
     /// Clear database state.
     fn clear(&mut self);
+
+    /// Fetch all entries with keys in the range from `start` (inclusive) to `end`
+    /// (exclusive), ordered by key. If `end` is [`None`], the range is unbounded.
+    ///
+    /// Keys are ordered lexicographically by their bytes, so the order is the same in all
+    /// enclaves.
+    ///
+    /// [`None`]: std::option::Option
+    fn range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Fetch all entries with keys that start with the given prefix, ordered by key.
+    fn prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.range(prefix, prefix_end(prefix).as_ref().map(|end| end.as_slice()))
+    }
+}
+
+/// Smallest key which is greater than all keys with the given prefix, or [`None`] if there
+/// is no such key.
+///
+/// [`None`]: std::option::Option
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
 }
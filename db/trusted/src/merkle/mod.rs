@@ -0,0 +1,742 @@
+//! Authenticated database backed by a sparse Merkle tree.
+//!
+//! Each key is mapped to a 256-bit path by hashing it. A subtree which contains a single
+//! key is represented by its leaf, so the tree only has internal nodes where the paths
+//! of at least two keys diverge. The shape of the tree and therefore its root hash only
+//! depend on its contents, not on the order in which keys were inserted.
+//!
+//! Nodes are kept in a [`NodeStore`] and addressed by their hash. Every node is checked
+//! against its hash when it is loaded, so the store does not need to be trusted and the
+//! database only loads the nodes on the paths of the keys that it touches. Range queries
+//! are the exception: as keys are placed by their hash, they load the whole tree.
+//!
+//! The root hash is not committed to consensus on its own. Consensus commits to the
+//! encrypted exported state, which contains the root hash. Proofs from [`prove`] are
+//! therefore verified against the root of a state exported by the enclave.
+//!
+//! [`NodeStore`]: self::store::NodeStore
+//! [`prove`]: MerkleDatabase::prove
+use std::cell::RefCell;
+
+use protobuf::{self, Message};
+use sodalite;
+
+use ekiden_common::error::{Error, Result};
+
+use super::Database;
+use super::generated::database::{MerkleNode, MerkleNode_Internal, MerkleNode_Leaf};
+
+mod proof;
+mod store;
+
+pub use self::proof::MerkleProof;
+pub use self::store::{MemoryNodeStore, NodeStore, UntrustedNodeStore};
+
+/// Length of a node hash.
+pub const HASH_LEN: usize = 32;
+/// Hash of a tree node.
+pub type Hash = [u8; HASH_LEN];
+/// Hash of an empty subtree.
+pub const EMPTY_HASH: Hash = [0; HASH_LEN];
+
+/// Number of bits in a key path.
+const PATH_BITS: usize = HASH_LEN * 8;
+
+/// Domain separation prefix for leaf nodes.
+const LEAF_PREFIX: u8 = 0x00;
+/// Domain separation prefix for internal nodes.
+const INTERNAL_PREFIX: u8 = 0x01;
+
+/// Hash function used for keys, values and nodes.
+pub(crate) fn hash(data: &[u8]) -> Hash {
+    let mut digest = [0; sodalite::HASH_LEN];
+    sodalite::hash(&mut digest, data);
+
+    let mut result = [0; HASH_LEN];
+    result.copy_from_slice(&digest[..HASH_LEN]);
+    result
+}
+
+/// Hash of a leaf node.
+fn leaf_hash(key_hash: &Hash, value_hash: &Hash) -> Hash {
+    let mut data = Vec::with_capacity(1 + 2 * HASH_LEN);
+    data.push(LEAF_PREFIX);
+    data.extend_from_slice(key_hash);
+    data.extend_from_slice(value_hash);
+    hash(&data)
+}
+
+/// Hash of an internal node.
+fn internal_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut data = Vec::with_capacity(1 + 2 * HASH_LEN);
+    data.push(INTERNAL_PREFIX);
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    hash(&data)
+}
+
+/// Convert a serialized hash into a [`Hash`].
+pub fn hash_from_slice(data: &[u8]) -> Result<Hash> {
+    if data.len() != HASH_LEN {
+        return Err(Error::new("Malformed hash"));
+    }
+
+    let mut result = [0; HASH_LEN];
+    result.copy_from_slice(data);
+    Ok(result)
+}
+
+/// Bit of a path at the given depth, starting with the most significant bit. Set bits
+/// lead to the right subtree.
+fn path_bit(path: &Hash, depth: usize) -> bool {
+    path[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/// Decoded tree node.
+enum Node {
+    Internal { left: Hash, right: Hash },
+    Leaf { key: Vec<u8>, value: Vec<u8> },
+}
+
+impl Node {
+    fn hash(&self) -> Hash {
+        match *self {
+            Node::Internal {
+                ref left,
+                ref right,
+            } => internal_hash(left, right),
+            Node::Leaf { ref key, ref value } => leaf_hash(&hash(key), &hash(value)),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut node = MerkleNode::new();
+        match *self {
+            Node::Internal {
+                ref left,
+                ref right,
+            } => {
+                let mut internal = MerkleNode_Internal::new();
+                internal.set_left(left.to_vec());
+                internal.set_right(right.to_vec());
+                node.set_internal(internal);
+            }
+            Node::Leaf { ref key, ref value } => {
+                let mut leaf = MerkleNode_Leaf::new();
+                leaf.set_key(key.clone());
+                leaf.set_value(value.clone());
+                node.set_leaf(leaf);
+            }
+        }
+
+        Ok(node.write_to_bytes()?)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut node: MerkleNode = protobuf::parse_from_bytes(data)?;
+        if node.has_internal() {
+            let internal = node.take_internal();
+
+            Ok(Node::Internal {
+                left: hash_from_slice(internal.get_left())?,
+                right: hash_from_slice(internal.get_right())?,
+            })
+        } else if node.has_leaf() {
+            let mut leaf = node.take_leaf();
+
+            Ok(Node::Leaf {
+                key: leaf.take_key(),
+                value: leaf.take_value(),
+            })
+        } else {
+            Err(Error::new("Unrecognized tree node variant"))
+        }
+    }
+}
+
+/// Database backed by an authenticated sparse Merkle tree.
+///
+/// This is an alternative implementation of the [`Database`] interface, where the root
+/// hash commits to the whole database and individual keys can be proven with a
+/// [`MerkleProof`].
+///
+/// The [`Database`] interface can't return errors, so when it fails to load a node
+/// (e.g., because the untrusted store returned a corrupted node), the operation has no
+/// effect and the error is recorded instead. It is returned by [`check`], which must
+/// be called before the database is relied on.
+///
+/// [`Database`]: super::Database
+/// [`MerkleProof`]: self::proof::MerkleProof
+/// [`check`]: MerkleDatabase::check
+pub struct MerkleDatabase<S: NodeStore> {
+    /// Node storage.
+    store: S,
+    /// Hash of the root node.
+    root: Hash,
+    /// First error encountered by an operation of the [`Database`] interface.
+    ///
+    /// [`Database`]: super::Database
+    error: RefCell<Option<Error>>,
+}
+
+impl<S: NodeStore> MerkleDatabase<S> {
+    /// Construct an empty database.
+    pub fn new(store: S) -> Self {
+        Self::open(store, EMPTY_HASH)
+    }
+
+    /// Open the database with the given root in a node store.
+    ///
+    /// The nodes are only loaded when they are needed.
+    pub fn open(store: S, root: Hash) -> Self {
+        MerkleDatabase {
+            store,
+            root,
+            error: RefCell::new(None),
+        }
+    }
+
+    /// Return the first error encountered by an operation of the [`Database`] interface.
+    ///
+    /// [`Database`]: super::Database
+    pub fn check(&self) -> Result<()> {
+        match *self.error.borrow() {
+            Some(ref error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the error of a failed operation of the [`Database`] interface.
+    ///
+    /// [`Database`]: super::Database
+    fn record<T: Default>(&self, result: Result<T>) -> T {
+        match result {
+            Ok(value) => value,
+            Err(error) => {
+                let mut recorded = self.error.borrow_mut();
+                if recorded.is_none() {
+                    *recorded = Some(error);
+                }
+
+                T::default()
+            }
+        }
+    }
+
+    /// Hash of the root node, which commits to the contents of the database.
+    pub fn root_hash(&self) -> Hash {
+        self.root
+    }
+
+    /// Switch to another root in the same node store.
+    pub fn set_root_hash(&mut self, root: Hash) {
+        self.root = root;
+    }
+
+    /// Node storage.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Consume the database and return its node storage.
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Fetch the value of a key.
+    pub fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let path = hash(key);
+        let mut current = self.root;
+        let mut depth = 0;
+
+        while current != EMPTY_HASH {
+            match self.load(&current)? {
+                Node::Internal { left, right } => {
+                    current = if path_bit(&path, depth) { right } else { left };
+                    depth += 1;
+                }
+                Node::Leaf {
+                    key: leaf_key,
+                    value,
+                } => {
+                    return Ok(if leaf_key == key { Some(value) } else { None });
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Set the value of a key, returning the previous value.
+    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root = self.root;
+        let (root, previous) = self.insert_at(root, 0, &hash(key), key, value)?;
+        self.root = root;
+
+        Ok(previous)
+    }
+
+    /// Remove a key, returning its previous value.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root = self.root;
+        let (root, previous) = self.remove_at(root, 0, &hash(key), key)?;
+        self.root = root;
+
+        Ok(previous)
+    }
+
+    /// Fetch all entries with keys in the range from `start` (inclusive) to `end`
+    /// (exclusive), ordered by key.
+    ///
+    /// As keys are placed in the tree by their hash, this loads every node of the tree,
+    /// regardless of the size of the range.
+    pub fn entries(&self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = vec![];
+        let mut pending = vec![self.root];
+        while let Some(current) = pending.pop() {
+            if current == EMPTY_HASH {
+                continue;
+            }
+
+            match self.load(&current)? {
+                Node::Internal { left, right } => {
+                    pending.push(left);
+                    pending.push(right);
+                }
+                Node::Leaf { key, value } => {
+                    if &key[..] >= start && end.map_or(true, |end| &key[..] < end) {
+                        entries.push((key, value));
+                    }
+                }
+            }
+        }
+
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    /// Store all nodes of the tree again.
+    ///
+    /// For a store which encrypts nodes, this re-encrypts them under the current key.
+    pub fn rewrite_nodes(&mut self) -> Result<()> {
+        let mut pending = vec![self.root];
+        while let Some(current) = pending.pop() {
+            if current == EMPTY_HASH {
+                continue;
+            }
+
+            let node = self.load(&current)?;
+            self.store.insert(current, node.encode()?)?;
+            if let Node::Internal { left, right } = node {
+                pending.push(left);
+                pending.push(right);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prove the value of a key, or that the key is not present.
+    pub fn prove(&self, key: &[u8]) -> Result<MerkleProof> {
+        let path = hash(key);
+        let mut current = self.root;
+        let mut proof = MerkleProof {
+            siblings: vec![],
+            leaf: None,
+        };
+
+        while current != EMPTY_HASH {
+            match self.load(&current)? {
+                Node::Internal { left, right } => {
+                    let (next, sibling) = if path_bit(&path, proof.siblings.len()) {
+                        (right, left)
+                    } else {
+                        (left, right)
+                    };
+                    proof.siblings.push(sibling);
+                    current = next;
+                }
+                Node::Leaf { key, value } => {
+                    proof.leaf = Some((hash(&key), hash(&value)));
+                    break;
+                }
+            }
+        }
+
+        Ok(proof)
+    }
+
+    /// Load a node and check that it matches its hash.
+    fn load(&self, hash: &Hash) -> Result<Node> {
+        let data = self.store
+            .get(hash)?
+            .ok_or(Error::new("Missing tree node"))?;
+        let node = Node::decode(&data)?;
+        if node.hash() != *hash {
+            return Err(Error::new("Tree node does not match its hash"));
+        }
+
+        Ok(node)
+    }
+
+    /// Store a node and return its hash.
+    fn save(&mut self, node: Node) -> Result<Hash> {
+        let hash = node.hash();
+        self.store.insert(hash, node.encode()?)?;
+
+        Ok(hash)
+    }
+
+    /// Set the value of a key in the subtree at the given depth.
+    ///
+    /// Returns the hash of the new subtree and the previous value.
+    fn insert_at(
+        &mut self,
+        current: Hash,
+        depth: usize,
+        path: &Hash,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(Hash, Option<Vec<u8>>)> {
+        let new_leaf = || Node::Leaf {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        if current == EMPTY_HASH {
+            return Ok((self.save(new_leaf())?, None));
+        }
+
+        match self.load(&current)? {
+            Node::Internal { left, right } => {
+                let (left, right, previous) = if path_bit(path, depth) {
+                    let (right, previous) = self.insert_at(right, depth + 1, path, key, value)?;
+                    (left, right, previous)
+                } else {
+                    let (left, previous) = self.insert_at(left, depth + 1, path, key, value)?;
+                    (left, right, previous)
+                };
+
+                Ok((self.save(Node::Internal { left, right })?, previous))
+            }
+            Node::Leaf {
+                key: leaf_key,
+                value: leaf_value,
+            } => {
+                if leaf_key == key {
+                    return Ok((self.save(new_leaf())?, Some(leaf_value)));
+                }
+
+                // Push both leaves down until their paths diverge.
+                let leaf_path = hash(&leaf_key);
+                let new_leaf = self.save(new_leaf())?;
+                let subtree = self.split(depth, path, new_leaf, &leaf_path, current)?;
+
+                Ok((subtree, None))
+            }
+        }
+    }
+
+    /// Build the subtree at the given depth which contains two leaves.
+    fn split(
+        &mut self,
+        depth: usize,
+        path_a: &Hash,
+        leaf_a: Hash,
+        path_b: &Hash,
+        leaf_b: Hash,
+    ) -> Result<Hash> {
+        if depth == PATH_BITS {
+            return Err(Error::new("Different keys with the same path"));
+        }
+
+        let node = match (path_bit(path_a, depth), path_bit(path_b, depth)) {
+            (false, true) => Node::Internal {
+                left: leaf_a,
+                right: leaf_b,
+            },
+            (true, false) => Node::Internal {
+                left: leaf_b,
+                right: leaf_a,
+            },
+            (false, false) => Node::Internal {
+                left: self.split(depth + 1, path_a, leaf_a, path_b, leaf_b)?,
+                right: EMPTY_HASH,
+            },
+            (true, true) => Node::Internal {
+                left: EMPTY_HASH,
+                right: self.split(depth + 1, path_a, leaf_a, path_b, leaf_b)?,
+            },
+        };
+
+        self.save(node)
+    }
+
+    /// Remove a key from the subtree at the given depth.
+    ///
+    /// Returns the hash of the new subtree and the previous value.
+    fn remove_at(
+        &mut self,
+        current: Hash,
+        depth: usize,
+        path: &Hash,
+        key: &[u8],
+    ) -> Result<(Hash, Option<Vec<u8>>)> {
+        if current == EMPTY_HASH {
+            return Ok((EMPTY_HASH, None));
+        }
+
+        match self.load(&current)? {
+            Node::Internal { left, right } => {
+                let (left, right, previous) = if path_bit(path, depth) {
+                    let (right, previous) = self.remove_at(right, depth + 1, path, key)?;
+                    (left, right, previous)
+                } else {
+                    let (left, previous) = self.remove_at(left, depth + 1, path, key)?;
+                    (left, right, previous)
+                };
+
+                if previous.is_none() {
+                    return Ok((current, None));
+                }
+
+                // A subtree with a single leaf is represented by the leaf.
+                let remaining = if left == EMPTY_HASH {
+                    Some(right)
+                } else if right == EMPTY_HASH {
+                    Some(left)
+                } else {
+                    None
+                };
+                if let Some(remaining) = remaining {
+                    if remaining == EMPTY_HASH {
+                        return Ok((EMPTY_HASH, previous));
+                    }
+                    if let Node::Leaf { .. } = self.load(&remaining)? {
+                        return Ok((remaining, previous));
+                    }
+                }
+
+                Ok((self.save(Node::Internal { left, right })?, previous))
+            }
+            Node::Leaf {
+                key: leaf_key,
+                value: leaf_value,
+            } => {
+                if leaf_key == key {
+                    Ok((EMPTY_HASH, Some(leaf_value)))
+                } else {
+                    Ok((current, None))
+                }
+            }
+        }
+    }
+}
+
+impl<S: NodeStore> Database for MerkleDatabase<S> {
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.record(self.lookup(key))
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let result = self.update(key, value);
+        self.record(result)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let result = self.delete(key);
+        self.record(result)
+    }
+
+    /// Clear database state.
+    ///
+    /// Nodes of the previous tree are left in the node store.
+    fn clear(&mut self) {
+        self.root = EMPTY_HASH;
+    }
+
+    /// Fetch all entries with keys in the given range.
+    ///
+    /// This loads the whole tree, see [`entries`].
+    ///
+    /// [`entries`]: MerkleDatabase::entries
+    fn range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.record(self.entries(start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ekiden_common::error::Result;
+
+    use super::super::Database;
+    use super::{Hash, MemoryNodeStore, MerkleDatabase, NodeStore, UntrustedNodeStore,
+                EMPTY_HASH};
+
+    /// Node store which allows nodes to be tampered with.
+    #[derive(Default)]
+    struct TestNodeStore {
+        nodes: HashMap<Hash, Vec<u8>>,
+    }
+
+    impl NodeStore for TestNodeStore {
+        fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+            Ok(self.nodes.get(hash).cloned())
+        }
+
+        fn insert(&mut self, hash: Hash, node: Vec<u8>) -> Result<()> {
+            self.nodes.insert(hash, node);
+
+            Ok(())
+        }
+    }
+
+    fn key(index: usize) -> Vec<u8> {
+        format!("key{}", index).into_bytes()
+    }
+
+    #[test]
+    fn test_basic_operations() {
+        let mut db = MerkleDatabase::new(MemoryNodeStore::new());
+        assert_eq!(db.root_hash(), EMPTY_HASH);
+
+        assert_eq!(db.insert(b"foo", b"hello world"), None);
+        assert_eq!(db.insert(b"bar", b"another data value"), None);
+        assert_eq!(db.get(b"foo"), Some(b"hello world".to_vec()));
+        assert_eq!(db.get(b"another"), None);
+        assert!(db.contains_key(b"bar"));
+
+        assert_eq!(db.insert(b"foo", b"changed"), Some(b"hello world".to_vec()));
+        assert_eq!(db.remove(b"foo"), Some(b"changed".to_vec()));
+        assert_eq!(db.remove(b"foo"), None);
+        assert_eq!(db.get(b"foo"), None);
+        assert_eq!(db.get(b"bar"), Some(b"another data value".to_vec()));
+
+        db.insert(b"baz", b"value");
+        assert_eq!(
+            db.range(b"b", None),
+            vec![
+                (b"bar".to_vec(), b"another data value".to_vec()),
+                (b"baz".to_vec(), b"value".to_vec()),
+            ]
+        );
+        assert_eq!(
+            db.range(b"bar", Some(b"baz")),
+            vec![(b"bar".to_vec(), b"another data value".to_vec())]
+        );
+        assert_eq!(db.prefix(b"ba").len(), 2);
+        assert_eq!(db.prefix(b"c"), vec![]);
+
+        db.clear();
+        assert_eq!(db.get(b"bar"), None);
+        assert_eq!(db.root_hash(), EMPTY_HASH);
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let mut forward = MerkleDatabase::new(MemoryNodeStore::new());
+        let mut backward = MerkleDatabase::new(MemoryNodeStore::new());
+        for index in 0..100 {
+            forward.insert(&key(index), b"value");
+            backward.insert(&key(99 - index), b"value");
+        }
+
+        // The root only depends on the contents.
+        assert_eq!(forward.root_hash(), backward.root_hash());
+
+        let root = forward.root_hash();
+        forward.insert(&key(1000), b"value");
+        assert!(forward.root_hash() != root);
+        forward.remove(&key(1000));
+        assert_eq!(forward.root_hash(), root);
+
+        for index in 0..100 {
+            forward.remove(&key(index));
+        }
+        assert_eq!(forward.root_hash(), EMPTY_HASH);
+
+        // Previous versions remain available in the node store.
+        let previous = MerkleDatabase::open(forward.store, root);
+        for index in 0..100 {
+            assert_eq!(previous.get(&key(index)), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_proofs() {
+        let mut db = MerkleDatabase::new(MemoryNodeStore::new());
+        for index in 0..100 {
+            db.insert(&key(index), format!("value{}", index).as_bytes());
+        }
+        let root = db.root_hash();
+
+        for index in 0..100 {
+            let value = format!("value{}", index).into_bytes();
+            let proof = db.prove(&key(index)).unwrap();
+            assert!(proof.verify(&root, &key(index), Some(&value)));
+            assert!(!proof.verify(&root, &key(index), Some(b"other value")));
+            assert!(!proof.verify(&root, &key(index), None));
+            assert!(!proof.verify(&EMPTY_HASH, &key(index), Some(&value)));
+        }
+
+        for index in 100..200 {
+            let proof = db.prove(&key(index)).unwrap();
+            assert!(proof.verify(&root, &key(index), None));
+            assert!(!proof.verify(&root, &key(index), Some(b"value")));
+        }
+
+        // Proofs for an empty tree.
+        let empty = MerkleDatabase::new(MemoryNodeStore::new());
+        let proof = empty.prove(b"foo").unwrap();
+        assert!(proof.verify(&EMPTY_HASH, b"foo", None));
+    }
+
+    #[test]
+    fn test_tampered_store() {
+        let mut db = MerkleDatabase::new(TestNodeStore::default());
+        for index in 0..10 {
+            db.insert(&key(index), b"value");
+        }
+        let root = db.root_hash();
+
+        let mut store = db.store;
+        for node in store.nodes.values_mut() {
+            node.push(0);
+        }
+
+        let mut db = MerkleDatabase::open(store, root);
+        assert!(db.lookup(&key(0)).is_err());
+        assert!(db.prove(&key(0)).is_err());
+
+        // Errors of the database interface are recorded instead.
+        db.check().unwrap();
+        assert_eq!(db.get(&key(0)), None);
+        assert_eq!(db.insert(&key(0), b"changed"), None);
+        assert_eq!(db.range(b"", None), vec![]);
+        assert_eq!(db.root_hash(), root);
+        assert!(db.check().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_store() {
+        let mut db = MerkleDatabase::new(UntrustedNodeStore::new());
+        for index in 0..2000 {
+            db.insert(&key(index), format!("value{}", index).as_bytes());
+        }
+        let root = db.root_hash();
+
+        // Nodes evicted from the cache are fetched from the untrusted store.
+        let db = MerkleDatabase::open(UntrustedNodeStore::new(), root);
+        for index in 0..2000 {
+            let value = format!("value{}", index).into_bytes();
+            assert_eq!(db.get(&key(index)), Some(value));
+        }
+        assert_eq!(db.get(&key(2000)), None);
+    }
+}
@@ -0,0 +1,60 @@
+//! Proofs of inclusion and exclusion of keys in the authenticated state tree.
+use super::{hash, internal_hash, leaf_hash, path_bit, Hash, EMPTY_HASH, PATH_BITS};
+
+/// Proof that a key has a given value, or is not present, in a tree with a given root.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    /// Hashes of the siblings along the path of the key, starting at the root.
+    pub siblings: Vec<Hash>,
+    /// Key hash and value hash of the leaf at the end of the path. None if the path
+    /// ends in an empty subtree.
+    ///
+    /// For a key which is not present, this may be a leaf of another key which shares
+    /// the path prefix.
+    pub leaf: Option<(Hash, Hash)>,
+}
+
+impl MerkleProof {
+    /// Verify the proof against a tree root.
+    ///
+    /// If `value` is None, the proof must show that the key is not present.
+    pub fn verify(&self, root: &Hash, key: &[u8], value: Option<&[u8]>) -> bool {
+        let path = hash(key);
+        let depth = self.siblings.len();
+        if depth > PATH_BITS {
+            return false;
+        }
+
+        let mut current = match (value, self.leaf) {
+            (Some(value), Some((key_hash, value_hash))) => {
+                if key_hash != path || value_hash != hash(value) {
+                    return false;
+                }
+
+                leaf_hash(&key_hash, &value_hash)
+            }
+            (Some(_), None) => return false,
+            (None, Some((key_hash, value_hash))) => {
+                // The leaf must be of another key, located on the path of this key.
+                if key_hash == path
+                    || (0..depth).any(|bit| path_bit(&key_hash, bit) != path_bit(&path, bit))
+                {
+                    return false;
+                }
+
+                leaf_hash(&key_hash, &value_hash)
+            }
+            (None, None) => EMPTY_HASH,
+        };
+
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            current = if path_bit(&path, depth) {
+                internal_hash(sibling, &current)
+            } else {
+                internal_hash(&current, sibling)
+            };
+        }
+
+        current == *root
+    }
+}
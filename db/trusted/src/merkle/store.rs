@@ -0,0 +1,130 @@
+//! Storage for nodes of the authenticated state tree.
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use protobuf::{self, Message};
+
+use ekiden_common::error::Result;
+
+use super::super::crypto;
+use super::super::generated::database::CryptoSecretbox;
+use super::super::untrusted;
+use super::Hash;
+
+/// Maximum number of decrypted nodes cached in enclave memory by an [`UntrustedNodeStore`].
+const NODE_CACHE_SIZE: usize = 1024;
+
+/// Storage for serialized tree nodes, addressed by node hash.
+///
+/// The store does not need to be trusted, as every node is checked against its hash
+/// when it is loaded.
+pub trait NodeStore {
+    /// Fetch the node with the given hash.
+    fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>>;
+
+    /// Store a node under its hash.
+    fn insert(&mut self, hash: Hash, node: Vec<u8>) -> Result<()>;
+}
+
+/// Node store which keeps all nodes in enclave memory.
+///
+/// Nodes are never removed, so all previous versions of the tree remain available.
+#[derive(Default)]
+pub struct MemoryNodeStore {
+    nodes: HashMap<Hash, Vec<u8>>,
+}
+
+impl MemoryNodeStore {
+    /// Construct an empty node store.
+    pub fn new() -> Self {
+        MemoryNodeStore {
+            nodes: HashMap::new(),
+        }
+    }
+}
+
+impl NodeStore for MemoryNodeStore {
+    fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        Ok(self.nodes.get(hash).cloned())
+    }
+
+    fn insert(&mut self, hash: Hash, node: Vec<u8>) -> Result<()> {
+        self.nodes.insert(hash, node);
+
+        Ok(())
+    }
+}
+
+/// Bounded cache of decrypted nodes, evicting the oldest entries first.
+#[derive(Default)]
+struct NodeCache {
+    nodes: HashMap<Hash, Vec<u8>>,
+    order: VecDeque<Hash>,
+}
+
+impl NodeCache {
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        self.nodes.get(hash).cloned()
+    }
+
+    fn insert(&mut self, hash: Hash, node: Vec<u8>) {
+        if self.nodes.insert(hash, node).is_some() {
+            return;
+        }
+
+        self.order.push_back(hash);
+        if self.order.len() > NODE_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.nodes.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Node store which keeps nodes in an untrusted key-value store outside the enclave.
+///
+/// Each node is encrypted with the state key before it leaves the enclave, so the
+/// untrusted store only learns node hashes and the sizes of nodes. Only a small cache
+/// of recently used nodes is kept in enclave memory, which allows the state to grow
+/// beyond the size of the enclave page cache.
+#[derive(Default)]
+pub struct UntrustedNodeStore {
+    cache: RefCell<NodeCache>,
+}
+
+impl UntrustedNodeStore {
+    /// Construct a node store with an empty cache.
+    pub fn new() -> Self {
+        UntrustedNodeStore {
+            cache: RefCell::new(NodeCache::default()),
+        }
+    }
+}
+
+impl NodeStore for UntrustedNodeStore {
+    fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        if let Some(node) = self.cache.borrow().get(hash) {
+            return Ok(Some(node));
+        }
+
+        let encrypted_node = match untrusted::get(hash)? {
+            Some(encrypted_node) => encrypted_node,
+            None => return Ok(None),
+        };
+        let encrypted_node: CryptoSecretbox = protobuf::parse_from_bytes(&encrypted_node)?;
+        let node = crypto::decrypt_state(&encrypted_node)?;
+
+        self.cache.borrow_mut().insert(*hash, node.clone());
+
+        Ok(Some(node))
+    }
+
+    fn insert(&mut self, hash: Hash, node: Vec<u8>) -> Result<()> {
+        let encrypted_node = crypto::encrypt_state(node.clone())?;
+        untrusted::insert(&hash, &encrypted_node.write_to_bytes()?)?;
+
+        self.cache.borrow_mut().insert(hash, node);
+
+        Ok(())
+    }
+}
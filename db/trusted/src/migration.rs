@@ -0,0 +1,318 @@
+//! Versioned schema migrations.
+//!
+//! The version of the schema that the database state conforms to is stored in the
+//! database itself. Contracts register a migration for each schema version, which
+//! converts the state from the previous version. Pending migrations are run inside
+//! the enclave when a state is imported, before any requests are processed.
+//!
+//! A state which has no stored version was created before schema versioning was used
+//! and is at version zero.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+#[cfg(not(target_env = "sgx"))]
+use std::sync::{Mutex, MutexGuard};
+#[cfg(target_env = "sgx")]
+use std::sync::SgxMutex as Mutex;
+#[cfg(target_env = "sgx")]
+use std::sync::SgxMutexGuard as MutexGuard;
+
+use ekiden_common::error::{Error, Result};
+
+use super::DatabaseHandle;
+use super::schema::descriptor::ScalarDescriptor;
+
+/// Schema namespace reserved for the schema version.
+const VERSION_NAMESPACE: &'static str = "ekiden";
+/// Field name of the schema version.
+const VERSION_NAME: &'static str = "schema_version";
+
+/// Migration handler, converting the database state from the previous schema version.
+type Handler = Arc<Fn() -> Result<()> + Send + Sync>;
+
+/// Registry of schema migrations.
+#[derive(Clone)]
+pub struct Migrations {
+    handlers: BTreeMap<u64, Handler>,
+}
+
+lazy_static! {
+    // Global migration registry.
+    static ref MIGRATIONS: Mutex<Migrations> = Mutex::new(Migrations::new());
+}
+
+/// Descriptor of the stored schema version.
+fn version_descriptor() -> ScalarDescriptor<u64> {
+    ScalarDescriptor::new(VERSION_NAMESPACE, VERSION_NAME)
+}
+
+impl Migrations {
+    /// Construct an empty migration registry.
+    pub fn new() -> Self {
+        Migrations {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Global migration registry instance.
+    ///
+    /// Calling this method will take a lock on the global instance which
+    /// will be released once the value goes out of scope.
+    pub fn get<'a>() -> MutexGuard<'a, Self> {
+        MIGRATIONS.lock().unwrap()
+    }
+
+    /// Register a migration which converts the state from schema version `version - 1`
+    /// to schema version `version`.
+    ///
+    /// Migrations use the same interfaces as contract methods to access the database,
+    /// e.g., descriptors of both the old and the new schema.
+    pub fn add<F>(&mut self, version: u64, handler: F)
+    where
+        F: Fn() -> Result<()> + Send + Sync + 'static,
+    {
+        assert!(version > 0, "Schema versions start at 1");
+        assert!(
+            !self.handlers.contains_key(&version),
+            "Duplicate migration for schema version {}",
+            version
+        );
+
+        self.handlers.insert(version, Arc::new(handler));
+    }
+
+    /// Current schema version, which is the highest version a migration was registered
+    /// for, or zero if there are no migrations.
+    pub fn version(&self) -> u64 {
+        self.handlers.keys().next_back().cloned().unwrap_or(0)
+    }
+
+    /// Run all migrations which have not yet been applied to the current state.
+    ///
+    /// Each migration is run in its own savepoint together with the update of the stored
+    /// schema version, so a failed migration leaves the state at the previous version.
+    /// An empty state is left as is, as there is nothing to migrate.
+    pub fn migrate(&self) -> Result<()> {
+        let version = self.version();
+        let stored_version = match stored_version() {
+            Some(stored_version) => stored_version,
+            None if DatabaseHandle::instance().is_empty() => return Ok(()),
+            None => 0,
+        };
+
+        if stored_version > version {
+            return Err(Error::new(format!(
+                "Database schema version {} is newer than contract schema version {}",
+                stored_version, version
+            )));
+        }
+
+        for (&version, handler) in self.handlers.range(stored_version + 1..) {
+            DatabaseHandle::instance().begin();
+
+            if let Err(error) = handler() {
+                DatabaseHandle::instance().rollback()?;
+                return Err(Error::new(format!(
+                    "Migration to schema version {} failed: {}",
+                    version, error.message
+                )));
+            }
+
+            version_descriptor().insert(&version);
+            DatabaseHandle::instance().commit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Store the current schema version if the state has none.
+    ///
+    /// A non-empty state without a stored version at this point was created by the
+    /// current contract, as states without a version are migrated when imported.
+    pub fn record_version(&self) {
+        let version = self.version();
+        if version == 0 || stored_version().is_some() || DatabaseHandle::instance().is_empty() {
+            return;
+        }
+
+        version_descriptor().insert(&version);
+    }
+}
+
+/// Run all migrations of the global registry which have not yet been applied to the
+/// current state.
+///
+/// The handlers are run after the lock on the global registry is released, so they may
+/// use the registry themselves. See [`Migrations::migrate`].
+///
+/// [`Migrations::migrate`]: self::Migrations::migrate
+pub fn migrate() -> Result<()> {
+    let migrations = Migrations::get().clone();
+    migrations.migrate()
+}
+
+/// Schema version of the current state, if any is stored.
+pub fn stored_version() -> Option<u64> {
+    version_descriptor().get()
+}
+
+/// Registers schema migrations of the contract.
+///
+/// Each migration is a function which converts the database state from the previous
+/// schema version and returns a `Result<()>`. Schema versions start at 1, where the
+/// migration to version 1 converts a state created before any migrations were
+/// registered.
+///
+/// # Examples
+///
+/// ```ignore
+/// database_migrations! {
+///     1 => rename_owner,
+///     2 => backfill_decimals,
+/// }
+/// ```
+#[macro_export]
+macro_rules! database_migrations {
+    ( $( $version:expr => $handler:expr ),* $(,)* ) => {
+        #[cfg(target_env = "sgx")]
+        global_ctors_object! {
+            DATABASE_MIGRATIONS_INIT, database_migrations_init = {
+                use ekiden_trusted::db::migration::Migrations;
+
+                // Register migrations in the global registry.
+                let mut migrations = Migrations::get();
+                $(
+                    migrations.add($version, $handler);
+                )*
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ekiden_common::error::{Error, Result};
+
+    use super::super::{Database, DatabaseHandle};
+    use super::{migrate, stored_version, Migrations};
+
+    database_schema! {
+        pub struct OldSchema {
+            pub owner: String,
+        }
+
+        pub struct NewSchema {
+            pub owners: List<String>,
+            pub decimals: u64,
+        }
+    }
+
+    fn split_owner() -> Result<()> {
+        let old_schema = OldSchema::new();
+        let new_schema = NewSchema::new();
+        if let Some(owner) = old_schema.owner.remove() {
+            new_schema.owners.push(&owner);
+        }
+
+        Ok(())
+    }
+
+    fn backfill_decimals() -> Result<()> {
+        NewSchema::new().decimals.insert(&18);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrations() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let mut migrations = Migrations::new();
+        assert_eq!(migrations.version(), 0);
+        migrations.add(2, backfill_decimals);
+        migrations.add(1, split_owner);
+        assert_eq!(migrations.version(), 2);
+
+        // An empty state is not migrated.
+        migrations.migrate().unwrap();
+        assert_eq!(stored_version(), None);
+
+        // A state without a version is migrated from version zero.
+        let old_schema = OldSchema::new();
+        let new_schema = NewSchema::new();
+        old_schema.owner.insert("alice");
+        migrations.migrate().unwrap();
+
+        assert_eq!(stored_version(), Some(2));
+        assert!(!old_schema.owner.is_present());
+        assert_eq!(new_schema.owners.get(0), Some("alice".to_owned()));
+        assert_eq!(new_schema.decimals.get(), Some(18));
+
+        // Migrations are only run once.
+        new_schema.decimals.insert(&6);
+        migrations.migrate().unwrap();
+        assert_eq!(new_schema.decimals.get(), Some(6));
+
+        // A failed migration is rolled back.
+        migrations.add(3, || {
+            NewSchema::new().decimals.remove();
+            Err(Error::new("failed"))
+        });
+        assert!(migrations.migrate().is_err());
+        assert_eq!(stored_version(), Some(2));
+        assert_eq!(new_schema.decimals.get(), Some(6));
+
+        // A state newer than the contract is rejected.
+        let mut migrations = Migrations::new();
+        migrations.add(1, split_owner);
+        assert!(migrations.migrate().is_err());
+    }
+
+    #[test]
+    fn test_record_version() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let mut migrations = Migrations::new();
+
+        // Without migrations, no version is stored.
+        NewSchema::new().decimals.insert(&18);
+        migrations.record_version();
+        assert_eq!(stored_version(), None);
+
+        migrations.add(1, split_owner);
+        migrations.record_version();
+        assert_eq!(stored_version(), Some(1));
+
+        // An empty state remains empty.
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+        migrations.record_version();
+        assert!(DatabaseHandle::instance().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_global() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        // Handlers may use the global registry while they run.
+        Migrations::get().add(1, || {
+            assert_eq!(Migrations::get().version(), 1);
+            split_owner()
+        });
+
+        OldSchema::new().owner.insert("alice");
+        migrate().unwrap();
+
+        assert_eq!(stored_version(), Some(1));
+        assert_eq!(NewSchema::new().owners.get(0), Some("alice".to_owned()));
+    }
+}
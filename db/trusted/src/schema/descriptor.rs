@@ -1,10 +1,13 @@
 //! Field descriptors used in the schema-based interface.
 use std::borrow::Borrow;
+use std::io::Cursor;
 use std::marker::PhantomData;
+use std::vec;
 
 use ekiden_common::serializer::{Deserializable, Serializable};
 
-use super::super::{Database, DatabaseHandle};
+use super::super::{prefix_end, Database, DatabaseHandle};
+use super::key::{FromOrderedKey, OrderedKey};
 
 /// Descriptor for scalar fields.
 pub struct ScalarDescriptor<T> {
@@ -15,12 +18,83 @@ pub struct ScalarDescriptor<T> {
 
 /// Descriptor for map fields.
 pub struct MapDescriptor<K, V> {
-    namespace: &'static str,
-    name: &'static str,
+    /// Prefix of the keys of all entries in the underlying database.
+    prefix: Vec<u8>,
+    key_type: PhantomData<K>,
+    value_type: PhantomData<V>,
+}
+
+/// Descriptor for map fields with maps as values.
+pub struct NestedMapDescriptor<K, K2, V> {
+    prefix: Vec<u8>,
+    key_type: PhantomData<K>,
+    inner_key_type: PhantomData<K2>,
+    value_type: PhantomData<V>,
+}
+
+/// Descriptor for list fields.
+pub struct ListDescriptor<T> {
+    prefix: Vec<u8>,
+    value_type: PhantomData<T>,
+}
+
+/// Descriptor for set fields.
+pub struct SetDescriptor<T> {
+    prefix: Vec<u8>,
+    value_type: PhantomData<T>,
+}
+
+/// Derive the key of a field in the underlying database.
+fn get_field_key(namespace: &str, name: &str) -> Vec<u8> {
+    let mut key = vec![];
+    namespace.write_to(&mut key).unwrap();
+    name.write_to(&mut key).unwrap();
+
+    key
+}
+
+/// Fetch all entries with keys that start with the given prefix, excluding the
+/// prefix itself.
+fn get_entries(prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let db = DatabaseHandle::instance();
+    let mut entries = db.prefix(prefix);
+    entries.retain(|&(ref key, _)| key.len() > prefix.len());
+
+    entries
+}
+
+/// Iterator over the entries of a map field, ordered by key.
+///
+/// Entries are fetched from the database when the iterator is created, so the map
+/// may be modified while iterating.
+pub struct MapIter<K, V> {
+    entries: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    prefix_length: usize,
     key_type: PhantomData<K>,
     value_type: PhantomData<V>,
 }
 
+impl<K, V> Iterator for MapIter<K, V>
+where
+    K: FromOrderedKey,
+    V: Deserializable,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                let key = K::read_key(&mut Cursor::new(&key[self.prefix_length..]))
+                    .expect("Corrupted state");
+                let value = Deserializable::read(&value).expect("Corrupted state");
+
+                Some((key, value))
+            }
+            None => None,
+        }
+    }
+}
+
 impl<T> ScalarDescriptor<T>
 where
     T: Serializable + Deserializable,
@@ -36,11 +110,7 @@ where
 
     /// Derive the key for storing this field in the underlying database.
     fn get_key(&self) -> Vec<u8> {
-        let mut key = vec![];
-        self.namespace.write_to(&mut key).unwrap();
-        self.name.write_to(&mut key).unwrap();
-
-        key
+        get_field_key(self.namespace, self.name)
     }
 
     /// Insert a value for this field.
@@ -96,38 +166,56 @@ where
 
 impl<K, V> MapDescriptor<K, V>
 where
-    K: Serializable,
+    K: OrderedKey,
     V: Serializable + Deserializable,
 {
     /// Create new map descriptor.
     pub fn new(namespace: &'static str, name: &'static str) -> Self {
+        Self::with_prefix(get_field_key(namespace, name))
+    }
+
+    /// Create new map descriptor for entries with keys under the given prefix.
+    fn with_prefix(prefix: Vec<u8>) -> Self {
         Self {
-            namespace: &namespace,
-            name: &name,
+            prefix,
             key_type: PhantomData,
             value_type: PhantomData,
         }
     }
 
+    /// Derive the prefix of the keys of all entries of this field in the underlying
+    /// database.
+    fn get_key_prefix(&self) -> Vec<u8> {
+        self.prefix.clone()
+    }
+
     /// Derive the key for storing this field in the underlying database.
     ///
-    /// The key may be any borrowed form of the descriptor's key type, but [`Serializable`]
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
     /// on the borrowed form must match those for the key type.
     ///
-    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    /// [`OrderedKey`]: super::key::OrderedKey
     fn get_key_for_subkey<Q>(&self, subkey: &Q) -> Vec<u8>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Serializable,
+        Q: ?Sized + OrderedKey,
     {
-        let mut key = vec![];
-        self.namespace.write_to(&mut key).unwrap();
-        self.name.write_to(&mut key).unwrap();
-        subkey.write_to(&mut key).unwrap();
+        let mut key = self.get_key_prefix();
+        subkey.write_key(&mut key).unwrap();
 
         key
     }
 
+    /// Create an iterator over the given database entries of this field.
+    fn iter_entries(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> MapIter<K, V> {
+        MapIter {
+            entries: entries.into_iter(),
+            prefix_length: self.get_key_prefix().len(),
+            key_type: PhantomData,
+            value_type: PhantomData,
+        }
+    }
+
     /// Insert a value for this field.
     ///
     /// If the database did not have this key present, [`None`] is returned.
@@ -135,19 +223,20 @@ where
     /// If the database did have this key present, the value is updated, and the old value is
     /// returned.
     ///
-    /// The key may be any borrowed form of the descriptor's key type, but [`Serializable`]
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
     /// on the borrowed form must match those for the key type.
     ///
     /// The value may be any borrowed form of the descriptor's value type, but [`Serializable`]
     /// on the borrowed form must match those for the value type.
     ///
     /// [`None`]: std::option::Option
+    /// [`OrderedKey`]: super::key::OrderedKey
     /// [`Serializable`]: ekiden_common::serializer::Serializable
     pub fn insert<Q, P>(&self, key: &Q, value: &P) -> Option<V>
     where
         K: Borrow<Q>,
         V: Borrow<P>,
-        Q: ?Sized + Serializable,
+        Q: ?Sized + OrderedKey,
         P: ?Sized + Serializable,
     {
         let mut db = DatabaseHandle::instance();
@@ -160,14 +249,14 @@ where
 
     /// Fetch a value for this field.
     ///
-    /// The key may be any borrowed form of the descriptor's key type, but [`Serializable`]
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
     /// on the borrowed form must match those for the key type.
     ///
-    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    /// [`OrderedKey`]: super::key::OrderedKey
     pub fn get<Q>(&self, key: &Q) -> Option<V>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Serializable,
+        Q: ?Sized + OrderedKey,
     {
         let db = DatabaseHandle::instance();
         match db.get(&self.get_key_for_subkey(key)) {
@@ -179,14 +268,14 @@ where
     /// Remove a value for this field, returning the value at the key if the key was previously
     /// in the database.
     ///
-    /// The key may be any borrowed form of the descriptor's key type, but [`Serializable`]
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
     /// on the borrowed form must match those for the key type.
     ///
-    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    /// [`OrderedKey`]: super::key::OrderedKey
     pub fn remove<Q>(&self, key: &Q) -> Option<V>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Serializable,
+        Q: ?Sized + OrderedKey,
     {
         let mut db = DatabaseHandle::instance();
         match db.remove(&self.get_key_for_subkey(key)) {
@@ -197,16 +286,414 @@ where
 
     /// Check if a field is present in the underlying database.
     ///
-    /// The key may be any borrowed form of the descriptor's key type, but [`Serializable`]
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
     /// on the borrowed form must match those for the key type.
     ///
-    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    /// [`OrderedKey`]: super::key::OrderedKey
     pub fn contains_key<Q>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
-        Q: ?Sized + Serializable,
+        Q: ?Sized + OrderedKey,
     {
         let db = DatabaseHandle::instance();
         db.contains_key(&self.get_key_for_subkey(key))
     }
+
+    /// Iterate over all entries of this field, ordered by key.
+    pub fn iter(&self) -> MapIter<K, V> {
+        let db = DatabaseHandle::instance();
+        self.iter_entries(db.prefix(&self.get_key_prefix()))
+    }
+
+    /// Iterate over the entries of this field with keys from `start` (inclusive) to `end`
+    /// (exclusive), ordered by key. A bound of [`None`] means that the range is unbounded
+    /// on that side.
+    ///
+    /// The bounds may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
+    /// on the borrowed form must match those for the key type.
+    ///
+    /// [`None`]: std::option::Option
+    /// [`OrderedKey`]: super::key::OrderedKey
+    pub fn range<Q>(&self, start: Option<&Q>, end: Option<&Q>) -> MapIter<K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let prefix = self.get_key_prefix();
+        let start = match start {
+            Some(start) => self.get_key_for_subkey(start),
+            None => prefix.clone(),
+        };
+        let end = match end {
+            Some(end) => Some(self.get_key_for_subkey(end)),
+            None => prefix_end(&prefix),
+        };
+
+        let db = DatabaseHandle::instance();
+        self.iter_entries(db.range(&start, end.as_ref().map(|end| end.as_slice())))
+    }
+
+    /// Iterate over the entries of this field with keys that start with the given key
+    /// prefix, ordered by key.
+    ///
+    /// For strings and byte vectors, this includes all keys with the given prefix. For
+    /// other key types, this only includes the given key.
+    pub fn iter_prefix<Q>(&self, prefix: &Q) -> MapIter<K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let mut key_prefix = self.get_key_prefix();
+        prefix.write_key_prefix(&mut key_prefix).unwrap();
+
+        let db = DatabaseHandle::instance();
+        self.iter_entries(db.prefix(&key_prefix))
+    }
+
+    /// Number of entries of this field.
+    ///
+    /// The number of entries is not stored, so this loads all entries of the field from
+    /// the database and takes time linear in their number and size. Contracts which need
+    /// the number of entries of a large map should keep it in a separate field.
+    pub fn len(&self) -> usize {
+        let db = DatabaseHandle::instance();
+        db.prefix(&self.get_key_prefix()).len()
+    }
+
+    /// Returns true if this field has no entries.
+    ///
+    /// Like [`len`], this loads all entries of the field.
+    ///
+    /// [`len`]: MapDescriptor::len
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all entries of this field.
+    pub fn clear(&self) {
+        let mut db = DatabaseHandle::instance();
+        for (key, _) in db.prefix(&self.get_key_prefix()) {
+            db.remove(&key);
+        }
+    }
+}
+
+impl<K, K2, V> NestedMapDescriptor<K, K2, V>
+where
+    K: OrderedKey,
+    K2: OrderedKey,
+    V: Serializable + Deserializable,
+{
+    /// Create new nested map descriptor.
+    pub fn new(namespace: &'static str, name: &'static str) -> Self {
+        Self {
+            prefix: get_field_key(namespace, name),
+            key_type: PhantomData,
+            inner_key_type: PhantomData,
+            value_type: PhantomData,
+        }
+    }
+
+    /// Get the inner map for the given key.
+    ///
+    /// The key may be any borrowed form of the descriptor's key type, but [`OrderedKey`]
+    /// on the borrowed form must match those for the key type.
+    ///
+    /// [`OrderedKey`]: super::key::OrderedKey
+    pub fn get<Q>(&self, key: &Q) -> MapDescriptor<K2, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let mut prefix = self.prefix.clone();
+        key.write_key(&mut prefix).unwrap();
+
+        MapDescriptor::with_prefix(prefix)
+    }
+
+    /// Remove all entries of all inner maps of this field.
+    pub fn clear(&self) {
+        let mut db = DatabaseHandle::instance();
+        for (key, _) in db.prefix(&self.prefix) {
+            db.remove(&key);
+        }
+    }
+}
+
+impl<T> ListDescriptor<T>
+where
+    T: Serializable + Deserializable,
+{
+    /// Create new list descriptor.
+    ///
+    /// The length of the list is stored under the key of the field and each element
+    /// under the key of the field followed by its index.
+    pub fn new(namespace: &'static str, name: &'static str) -> Self {
+        Self {
+            prefix: get_field_key(namespace, name),
+            value_type: PhantomData,
+        }
+    }
+
+    /// Derive the key for storing the element at the given index.
+    fn get_key_for_index(&self, index: u64) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        index.write_key(&mut key).unwrap();
+
+        key
+    }
+
+    /// Fetch the length of the list.
+    fn get_length<D: Database>(&self, db: &D) -> u64 {
+        match db.get(&self.prefix) {
+            Some(length) => Deserializable::read(&length).expect("Corrupted state"),
+            None => 0,
+        }
+    }
+
+    /// Store the length of the list.
+    fn set_length<D: Database>(&self, db: &mut D, length: u64) {
+        if length == 0 {
+            db.remove(&self.prefix);
+        } else {
+            let length = Serializable::write(&length).expect("Failed to serialize length");
+            db.insert(&self.prefix, &length);
+        }
+    }
+
+    /// Append a value to the end of the list.
+    ///
+    /// The value may be any borrowed form of the descriptor's value type, but [`Serializable`]
+    /// on the borrowed form must match those for the value type.
+    ///
+    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    pub fn push<Q>(&self, value: &Q)
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Serializable,
+    {
+        let mut db = DatabaseHandle::instance();
+        let length = self.get_length(&*db);
+        let value = Serializable::write(value.borrow()).expect("Failed to serialize value");
+        db.insert(&self.get_key_for_index(length), &value);
+        self.set_length(&mut *db, length + 1);
+    }
+
+    /// Remove the last value of the list and return it, or [`None`] if the list is empty.
+    ///
+    /// [`None`]: std::option::Option
+    pub fn pop(&self) -> Option<T> {
+        let mut db = DatabaseHandle::instance();
+        let length = self.get_length(&*db);
+        if length == 0 {
+            return None;
+        }
+
+        let value = db.remove(&self.get_key_for_index(length - 1))
+            .expect("Corrupted state");
+        self.set_length(&mut *db, length - 1);
+
+        Some(Deserializable::read(&value).expect("Corrupted state"))
+    }
+
+    /// Fetch the value at the given index, or [`None`] if the index is out of bounds.
+    ///
+    /// [`None`]: std::option::Option
+    pub fn get(&self, index: u64) -> Option<T> {
+        let db = DatabaseHandle::instance();
+        match db.get(&self.get_key_for_index(index)) {
+            Some(value) => Some(Deserializable::read(&value).expect("Corrupted state")),
+            None => None,
+        }
+    }
+
+    /// Replace the value at the given index and return the previous value.
+    ///
+    /// If the index is out of bounds, nothing is stored and [`None`] is returned.
+    ///
+    /// The value may be any borrowed form of the descriptor's value type, but [`Serializable`]
+    /// on the borrowed form must match those for the value type.
+    ///
+    /// [`None`]: std::option::Option
+    /// [`Serializable`]: ekiden_common::serializer::Serializable
+    pub fn set<Q>(&self, index: u64, value: &Q) -> Option<T>
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Serializable,
+    {
+        let mut db = DatabaseHandle::instance();
+        if index >= self.get_length(&*db) {
+            return None;
+        }
+
+        let value = Serializable::write(value.borrow()).expect("Failed to serialize value");
+        match db.insert(&self.get_key_for_index(index), &value) {
+            Some(value) => Some(Deserializable::read(&value).expect("Corrupted state")),
+            None => None,
+        }
+    }
+
+    /// Number of values in the list.
+    pub fn len(&self) -> u64 {
+        let db = DatabaseHandle::instance();
+        self.get_length(&*db)
+    }
+
+    /// Returns true if the list has no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all values of the list, in order.
+    pub fn iter(&self) -> ListIter<T> {
+        ListIter {
+            entries: get_entries(&self.prefix).into_iter(),
+            value_type: PhantomData,
+        }
+    }
+
+    /// Remove all values of the list.
+    pub fn clear(&self) {
+        let mut db = DatabaseHandle::instance();
+        for (key, _) in db.prefix(&self.prefix) {
+            db.remove(&key);
+        }
+    }
+}
+
+/// Iterator over the values of a list field.
+pub struct ListIter<T> {
+    entries: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    value_type: PhantomData<T>,
+}
+
+impl<T> Iterator for ListIter<T>
+where
+    T: Deserializable,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.entries.next() {
+            Some((_, value)) => Some(Deserializable::read(&value).expect("Corrupted state")),
+            None => None,
+        }
+    }
+}
+
+impl<T> SetDescriptor<T>
+where
+    T: OrderedKey,
+{
+    /// Create new set descriptor.
+    pub fn new(namespace: &'static str, name: &'static str) -> Self {
+        Self {
+            prefix: get_field_key(namespace, name),
+            value_type: PhantomData,
+        }
+    }
+
+    /// Derive the key for storing the given value in the underlying database.
+    ///
+    /// The value may be any borrowed form of the descriptor's value type, but [`OrderedKey`]
+    /// on the borrowed form must match those for the value type.
+    ///
+    /// [`OrderedKey`]: super::key::OrderedKey
+    fn get_key_for_value<Q>(&self, value: &Q) -> Vec<u8>
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let mut key = self.prefix.clone();
+        value.write_key(&mut key).unwrap();
+
+        key
+    }
+
+    /// Add a value to the set.
+    ///
+    /// Returns true if the set did not contain the value.
+    pub fn insert<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let mut db = DatabaseHandle::instance();
+        db.insert(&self.get_key_for_value(value), &[]).is_none()
+    }
+
+    /// Remove a value from the set.
+    ///
+    /// Returns true if the set contained the value.
+    pub fn remove<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let mut db = DatabaseHandle::instance();
+        db.remove(&self.get_key_for_value(value)).is_some()
+    }
+
+    /// Returns true if the set contains the value.
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + OrderedKey,
+    {
+        let db = DatabaseHandle::instance();
+        db.contains_key(&self.get_key_for_value(value))
+    }
+
+    /// Number of values in the set.
+    ///
+    /// As for maps, this loads all values of the set from the database.
+    pub fn len(&self) -> usize {
+        get_entries(&self.prefix).len()
+    }
+
+    /// Returns true if the set has no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all values of the set, in order.
+    pub fn iter(&self) -> SetIter<T> {
+        SetIter {
+            entries: get_entries(&self.prefix).into_iter(),
+            prefix_length: self.prefix.len(),
+            value_type: PhantomData,
+        }
+    }
+
+    /// Remove all values of the set.
+    pub fn clear(&self) {
+        let mut db = DatabaseHandle::instance();
+        for (key, _) in db.prefix(&self.prefix) {
+            db.remove(&key);
+        }
+    }
+}
+
+/// Iterator over the values of a set field, ordered by value.
+pub struct SetIter<T> {
+    entries: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    prefix_length: usize,
+    value_type: PhantomData<T>,
+}
+
+impl<T> Iterator for SetIter<T>
+where
+    T: FromOrderedKey,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.entries.next() {
+            Some((key, _)) => Some(
+                T::read_key(&mut Cursor::new(&key[self.prefix_length..]))
+                    .expect("Corrupted state"),
+            ),
+            None => None,
+        }
+    }
 }
@@ -0,0 +1,364 @@
+//! Order-preserving encoding of map keys.
+//!
+//! Keys are encoded such that comparing the encodings byte by byte gives the same order
+//! as comparing the keys themselves. Encodings are also self-delimiting, so an encoded
+//! key can be followed by further data without affecting the order.
+//!
+//! * Unsigned integers are encoded in big-endian byte order.
+//! * Signed integers are encoded in big-endian byte order with the sign bit flipped.
+//! * Booleans are encoded as a single byte.
+//! * Strings and byte vectors are encoded as their bytes, where each zero byte is
+//!   escaped as `00 ff`, followed by the terminator `00 01`.
+//!
+//! Map keys were previously encoded with the common serializer, which does not preserve
+//! order. States created with that encoding cannot be read with this one, so the
+//! version of the key format is stored in the state and checked when it is imported
+//! (see [`check_key_format`]).
+//!
+//! [`check_key_format`]: self::check_key_format
+use std::io::{Read, Write};
+
+use ekiden_common::error::{Error, Result};
+
+use super::super::DatabaseHandle;
+use super::descriptor::ScalarDescriptor;
+
+/// Escape byte used in the encoding of strings and byte vectors.
+const ESCAPE: u8 = 0x00;
+/// Byte following an escape byte for an escaped zero byte.
+const ESCAPED_ZERO: u8 = 0xff;
+/// Byte following an escape byte for the terminator.
+const TERMINATOR: u8 = 0x01;
+
+/// Schema namespace reserved for the key format version.
+const KEY_FORMAT_NAMESPACE: &'static str = "ekiden";
+/// Field name of the key format version.
+const KEY_FORMAT_NAME: &'static str = "key_format";
+
+/// Version of the encoding of map keys.
+///
+/// A state without a stored version was created with the serializer encoding of map
+/// keys, which this version replaces.
+pub const KEY_FORMAT_VERSION: u64 = 1;
+
+/// Descriptor of the stored key format version.
+fn key_format_descriptor() -> ScalarDescriptor<u64> {
+    ScalarDescriptor::new(KEY_FORMAT_NAMESPACE, KEY_FORMAT_NAME)
+}
+
+/// Check that the current state uses the current encoding of map keys.
+///
+/// A non-empty state without a stored key format version was created with the
+/// serializer encoding of map keys. Such a state is rejected, as its map entries would
+/// silently appear to be missing. It has to be recreated, since the serializer encoding
+/// of a key cannot be converted without knowing the key type.
+pub fn check_key_format() -> Result<()> {
+    match key_format_descriptor().get() {
+        Some(KEY_FORMAT_VERSION) => Ok(()),
+        Some(version) => Err(Error::new(format!(
+            "Unsupported map key format version {} (expected {})",
+            version, KEY_FORMAT_VERSION
+        ))),
+        None if DatabaseHandle::instance().is_empty() => Ok(()),
+        None => Err(Error::new(
+            "State uses the serializer encoding of map keys and must be recreated",
+        )),
+    }
+}
+
+/// Store the current key format version if the state has none.
+///
+/// A non-empty state without a stored version at this point was created with the
+/// current encoding, as other states are rejected when imported.
+pub fn record_key_format() {
+    if key_format_descriptor().is_present() || DatabaseHandle::instance().is_empty() {
+        return;
+    }
+
+    key_format_descriptor().insert(&KEY_FORMAT_VERSION);
+}
+
+/// A type which can be used as a key in maps.
+///
+/// Borrowed forms of key types (e.g., `str`) only need to implement this trait, while
+/// the key types themselves also need to implement [`FromOrderedKey`].
+///
+/// [`FromOrderedKey`]: self::FromOrderedKey
+pub trait OrderedKey {
+    /// Write the encoding of the key into the given writer.
+    ///
+    /// Returns the number of bytes written.
+    fn write_key(&self, writer: &mut Write) -> Result<usize>;
+
+    /// Write an encoding which is a prefix of the encodings of all keys that start with
+    /// this key.
+    ///
+    /// By default, this is the encoding of the key itself.
+    fn write_key_prefix(&self, writer: &mut Write) -> Result<usize> {
+        self.write_key(writer)
+    }
+}
+
+/// A key type which can be decoded from its encoding.
+pub trait FromOrderedKey: OrderedKey + Sized {
+    /// Read an encoded key from the given reader.
+    fn read_key(reader: &mut Read) -> Result<Self>;
+}
+
+/// Write bytes with escaped zero bytes, without the terminator.
+fn write_escaped(bytes: &[u8], writer: &mut Write) -> Result<usize> {
+    let mut length = 0;
+    for (index, chunk) in bytes.split(|&byte| byte == 0).enumerate() {
+        if index > 0 {
+            writer.write_all(&[ESCAPE, ESCAPED_ZERO])?;
+            length += 2;
+        }
+
+        writer.write_all(chunk)?;
+        length += chunk.len();
+    }
+
+    Ok(length)
+}
+
+/// Write bytes with escaped zero bytes, followed by the terminator.
+fn write_terminated(bytes: &[u8], writer: &mut Write) -> Result<usize> {
+    let length = write_escaped(bytes, writer)?;
+    writer.write_all(&[ESCAPE, TERMINATOR])?;
+
+    Ok(length + 2)
+}
+
+/// Read bytes written by [`write_terminated`].
+fn read_terminated(reader: &mut Read) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut byte = [0; 1];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] != ESCAPE {
+            bytes.push(byte[0]);
+            continue;
+        }
+
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            ESCAPED_ZERO => bytes.push(0),
+            TERMINATOR => return Ok(bytes),
+            _ => return Err(Error::new("Malformed key encoding")),
+        }
+    }
+}
+
+impl OrderedKey for str {
+    fn write_key(&self, writer: &mut Write) -> Result<usize> {
+        write_terminated(self.as_bytes(), writer)
+    }
+
+    fn write_key_prefix(&self, writer: &mut Write) -> Result<usize> {
+        write_escaped(self.as_bytes(), writer)
+    }
+}
+
+impl OrderedKey for String {
+    fn write_key(&self, writer: &mut Write) -> Result<usize> {
+        write_terminated(self.as_bytes(), writer)
+    }
+
+    fn write_key_prefix(&self, writer: &mut Write) -> Result<usize> {
+        write_escaped(self.as_bytes(), writer)
+    }
+}
+
+impl FromOrderedKey for String {
+    fn read_key(reader: &mut Read) -> Result<Self> {
+        Ok(String::from_utf8(read_terminated(reader)?)?)
+    }
+}
+
+impl OrderedKey for Vec<u8> {
+    fn write_key(&self, writer: &mut Write) -> Result<usize> {
+        write_terminated(self, writer)
+    }
+
+    fn write_key_prefix(&self, writer: &mut Write) -> Result<usize> {
+        write_escaped(self, writer)
+    }
+}
+
+impl FromOrderedKey for Vec<u8> {
+    fn read_key(reader: &mut Read) -> Result<Self> {
+        read_terminated(reader)
+    }
+}
+
+impl OrderedKey for bool {
+    fn write_key(&self, writer: &mut Write) -> Result<usize> {
+        writer.write_all(&[*self as u8])?;
+        Ok(1)
+    }
+}
+
+impl FromOrderedKey for bool {
+    fn read_key(reader: &mut Read) -> Result<Self> {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::new("Malformed key encoding")),
+        }
+    }
+}
+
+// Order-preserving encoding for integer types.
+macro_rules! impl_ordered_key_integer {
+    ($int_type:ty, $size:expr) => {
+        impl OrderedKey for $int_type {
+            fn write_key(&self, writer: &mut Write) -> Result<usize> {
+                let value = *self as u64;
+                let mut bytes = [0; $size];
+                for (index, byte) in bytes.iter_mut().enumerate() {
+                    *byte = (value >> (8 * ($size - 1 - index))) as u8;
+                }
+
+                writer.write_all(&bytes)?;
+                Ok($size)
+            }
+        }
+
+        impl FromOrderedKey for $int_type {
+            fn read_key(reader: &mut Read) -> Result<Self> {
+                let mut bytes = [0; $size];
+                reader.read_exact(&mut bytes)?;
+
+                let mut value: u64 = 0;
+                for byte in bytes.iter() {
+                    value = (value << 8) | u64::from(*byte);
+                }
+
+                Ok(value as $int_type)
+            }
+        }
+    };
+
+    ($int_type:ty, $unsigned_type:ty, $size:expr) => {
+        impl OrderedKey for $int_type {
+            fn write_key(&self, writer: &mut Write) -> Result<usize> {
+                // Flip the sign bit, so that negative values are ordered first.
+                ((*self as $unsigned_type) ^ (1 << ($size * 8 - 1))).write_key(writer)
+            }
+        }
+
+        impl FromOrderedKey for $int_type {
+            fn read_key(reader: &mut Read) -> Result<Self> {
+                let value = <$unsigned_type>::read_key(reader)?;
+                Ok((value ^ (1 << ($size * 8 - 1))) as $int_type)
+            }
+        }
+    }
+}
+
+impl_ordered_key_integer!(u8, 1);
+impl_ordered_key_integer!(u16, 2);
+impl_ordered_key_integer!(u32, 4);
+impl_ordered_key_integer!(u64, 8);
+impl_ordered_key_integer!(i8, u8, 1);
+impl_ordered_key_integer!(i16, u16, 2);
+impl_ordered_key_integer!(i32, u32, 4);
+impl_ordered_key_integer!(i64, u64, 8);
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::io::Cursor;
+
+    use super::super::super::{Database, DatabaseHandle};
+    use super::{check_key_format, key_format_descriptor, record_key_format, FromOrderedKey,
+                OrderedKey, KEY_FORMAT_VERSION};
+
+    fn encode<K: OrderedKey + ?Sized>(key: &K) -> Vec<u8> {
+        let mut encoded = vec![];
+        key.write_key(&mut encoded).unwrap();
+        encoded
+    }
+
+    fn check_order<K: FromOrderedKey + Ord + Debug>(keys: Vec<K>) {
+        for window in keys.windows(2) {
+            assert!(window[0] < window[1]);
+            assert!(encode(&window[0]) < encode(&window[1]));
+        }
+
+        for key in keys {
+            let mut encoded = encode(&key);
+            encoded.extend_from_slice(b"trailing");
+            let mut reader = Cursor::new(encoded);
+            assert_eq!(K::read_key(&mut reader).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn test_integer_order() {
+        check_order(vec![0u8, 1, 127, 128, 255]);
+        check_order(vec![0u64, 1, 255, 256, 1 << 32, u64::max_value()]);
+        check_order(vec![i32::min_value(), -256, -1, 0, 1, 255, i32::max_value()]);
+        check_order(vec![i64::min_value(), -1, 0, i64::max_value()]);
+        check_order(vec![false, true]);
+    }
+
+    #[test]
+    fn test_bytes_order() {
+        check_order(vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![1],
+            vec![1, 0],
+            vec![255],
+            vec![255, 0],
+        ]);
+        check_order(vec![
+            "".to_owned(),
+            "a".to_owned(),
+            "a\u{0}".to_owned(),
+            "ab".to_owned(),
+            "b".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut prefix = vec![];
+        "ab".write_key_prefix(&mut prefix).unwrap();
+
+        assert!(encode("ab").starts_with(&prefix));
+        assert!(encode("abc").starts_with(&prefix));
+        assert!(encode("ab\u{0}").starts_with(&prefix));
+        assert!(!encode("a").starts_with(&prefix));
+        assert!(!encode("ac").starts_with(&prefix));
+    }
+
+    #[test]
+    fn test_key_format() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        // An empty state has no version and is accepted.
+        record_key_format();
+        assert!(DatabaseHandle::instance().is_empty());
+        check_key_format().unwrap();
+
+        // A non-empty state without a version uses the serializer encoding.
+        DatabaseHandle::instance().insert(b"foo", b"bar");
+        assert!(check_key_format().is_err());
+
+        record_key_format();
+        assert_eq!(key_format_descriptor().get(), Some(KEY_FORMAT_VERSION));
+        check_key_format().unwrap();
+
+        key_format_descriptor().insert(&(KEY_FORMAT_VERSION + 1));
+        assert!(check_key_format().is_err());
+    }
+}
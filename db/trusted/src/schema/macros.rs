@@ -1,19 +1,27 @@
 /// Define a database schema structure.
 ///
 /// Each field in the defined structure is replaced by a descriptor for the given
-/// field type. A [`ScalarDescriptor`] is generated for each scalar field and a
-/// [`MapDescriptor`] is generated for each `Map<K, V>` field.
+/// field type. A [`ScalarDescriptor`] is generated for each scalar field, a
+/// [`MapDescriptor`] for each `Map<K, V>` field, a [`NestedMapDescriptor`] for each
+/// `Map<K, Map<K2, V>>` field, a [`ListDescriptor`] for each `List<T>` field and a
+/// [`SetDescriptor`] for each `Set<T>` field.
 ///
 /// Any type that implements the [`Serializable`] and [`Deserializable`] traits can
-/// be used in the schema struct as a value (either as a scalar field or in a map).
+/// be used in the schema struct as a value (either as a scalar field or in a map or
+/// list).
 ///
-/// Any type that implements the [`Serializable`] trait can be used in the schema
-/// struct as a key in mappings.
+/// Any type that implements the [`OrderedKey`] trait can be used in the schema
+/// struct as a key in mappings or as a value in sets. Map entries and set values can
+/// be iterated in key order.
 ///
 /// [`ScalarDescriptor`]: super::descriptor::ScalarDescriptor
 /// [`MapDescriptor`]: super::descriptor::MapDescriptor
+/// [`NestedMapDescriptor`]: super::descriptor::NestedMapDescriptor
+/// [`ListDescriptor`]: super::descriptor::ListDescriptor
+/// [`SetDescriptor`]: super::descriptor::SetDescriptor
 /// [`Serializable`]: ekiden_common::serializer::Serializable
 /// [`Deserializable`]: ekiden_common::serializer::Deserializable
+/// [`OrderedKey`]: super::key::OrderedKey
 ///
 /// # Examples
 ///
@@ -23,6 +31,9 @@
 ///         pub foo: String,
 ///         pub bar: u64,
 ///         pub mapping: Map<String, u64>,
+///         pub allowance: Map<String, Map<String, u64>>,
+///         pub history: List<u64>,
+///         pub holders: Set<String>,
 ///     }
 /// }
 /// ```
@@ -43,6 +54,31 @@ macro_rules! database_schema {
         )*
     };
 
+    // Internal pattern: parse nested map field.
+    (
+        @parse_body($($args:tt)*) -> (
+            pub $field_name:ident : Map<$key_type:ty, Map<$inner_key_type:ty, $value_type:ty>>,
+            $($tail:tt)*
+        )
+    ) => {
+        database_schema!(
+            @parse_body(
+                $($args)*,
+                (
+                    $field_name,
+                    $crate::schema::descriptor::NestedMapDescriptor<
+                        $key_type,
+                        $inner_key_type,
+                        $value_type
+                    >,
+                    $crate::schema::descriptor::NestedMapDescriptor::new
+                )
+            ) -> (
+                $($tail)*
+            )
+        );
+    };
+
     // Internal pattern: parse map field.
     (
         @parse_body($($args:tt)*) -> (
@@ -64,6 +100,48 @@ macro_rules! database_schema {
         );
     };
 
+    // Internal pattern: parse list field.
+    (
+        @parse_body($($args:tt)*) -> (
+            pub $field_name:ident : List<$value_type:ty>,
+            $($tail:tt)*
+        )
+    ) => {
+        database_schema!(
+            @parse_body(
+                $($args)*,
+                (
+                    $field_name,
+                    $crate::schema::descriptor::ListDescriptor<$value_type>,
+                    $crate::schema::descriptor::ListDescriptor::new
+                )
+            ) -> (
+                $($tail)*
+            )
+        );
+    };
+
+    // Internal pattern: parse set field.
+    (
+        @parse_body($($args:tt)*) -> (
+            pub $field_name:ident : Set<$value_type:ty>,
+            $($tail:tt)*
+        )
+    ) => {
+        database_schema!(
+            @parse_body(
+                $($args)*,
+                (
+                    $field_name,
+                    $crate::schema::descriptor::SetDescriptor<$value_type>,
+                    $crate::schema::descriptor::SetDescriptor::new
+                )
+            ) -> (
+                $($tail)*
+            )
+        );
+    };
+
     // Internal pattern: parse scalar field.
     (
         @parse_body($($args:tt)*) -> (
@@ -1,5 +1,6 @@
 //! Higher-level schema-based database interface.
 pub mod descriptor;
+pub mod key;
 
 #[doc(hidden)]
 #[macro_use]
@@ -7,8 +8,25 @@ pub mod macros;
 
 #[cfg(test)]
 mod tests {
+    use ekiden_common::serializer::{Deserializable, Serializable};
+
     use super::super::{Database, DatabaseHandle};
 
+    #[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+    pub enum Side {
+        Buy,
+        Sell { limit: u64 },
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serializable, Deserializable)]
+    pub struct Order {
+        pub owner: String,
+        pub side: Side,
+        pub amounts: Vec<u64>,
+        pub memo: Option<String>,
+        pub fill: (u64, bool),
+    }
+
     database_schema! {
         pub struct TestSchema {
             pub foo: String,
@@ -21,6 +39,18 @@ mod tests {
             pub foo: String,
             pub bar: String,
         }
+
+        pub struct CollectionSchema {
+            pub allowance: Map<String, Map<String, u64>>,
+            pub history: List<u64>,
+            pub holders: Set<String>,
+            pub after: u64,
+        }
+
+        pub struct OrderSchema {
+            pub orders: Map<u64, Order>,
+            pub last_order: Order,
+        }
     }
 
     #[test]
@@ -60,6 +90,55 @@ mod tests {
         assert_eq!(schema.balance_of.insert("inner_key", &100), Some(42));
     }
 
+    #[test]
+    fn test_map_iteration() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let schema = TestSchema::new();
+        let another = AnotherSchema::new();
+        assert!(schema.balance_of.is_empty());
+
+        schema.balance_of.insert("carol", &3);
+        schema.balance_of.insert("alice", &1);
+        schema.balance_of.insert("bob", &2);
+        schema.balance_of.insert("alina", &4);
+        schema.foo.insert("not in the map");
+        another.foo.insert("not in the map");
+
+        assert_eq!(schema.balance_of.len(), 4);
+        assert_eq!(
+            schema.balance_of.iter().collect::<Vec<_>>(),
+            vec![
+                ("alice".to_owned(), 1),
+                ("alina".to_owned(), 4),
+                ("bob".to_owned(), 2),
+                ("carol".to_owned(), 3),
+            ]
+        );
+        assert_eq!(
+            schema.balance_of.range(Some("alina"), Some("carol")).collect::<Vec<_>>(),
+            vec![("alina".to_owned(), 4), ("bob".to_owned(), 2)]
+        );
+        assert_eq!(
+            schema.balance_of.range(Some("b"), None).collect::<Vec<_>>(),
+            vec![("bob".to_owned(), 2), ("carol".to_owned(), 3)]
+        );
+        assert_eq!(
+            schema.balance_of.iter_prefix("ali").collect::<Vec<_>>(),
+            vec![("alice".to_owned(), 1), ("alina".to_owned(), 4)]
+        );
+
+        // The map may be modified while iterating.
+        for (key, _) in schema.balance_of.iter() {
+            schema.balance_of.remove(&key);
+        }
+        assert!(schema.balance_of.is_empty());
+        assert_eq!(schema.foo.get(), Some("not in the map".to_owned()));
+    }
+
     #[test]
     fn test_namespaces() {
         {
@@ -85,4 +164,128 @@ mod tests {
         assert_eq!(schema1.foo.get(), Some("hello".to_owned()));
         assert_eq!(schema2.foo.get(), Some("world".to_owned()));
     }
+
+    #[test]
+    fn test_list() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let schema = CollectionSchema::new();
+        assert!(schema.history.is_empty());
+        assert_eq!(schema.history.pop(), None);
+
+        for value in 0..300 {
+            schema.history.push(&value);
+        }
+        assert_eq!(schema.history.len(), 300);
+        assert_eq!(schema.history.get(0), Some(0));
+        assert_eq!(schema.history.get(299), Some(299));
+        assert_eq!(schema.history.get(300), None);
+        assert_eq!(schema.history.iter().collect::<Vec<_>>(), (0..300).collect::<Vec<_>>());
+
+        assert_eq!(schema.history.set(1, &1000), Some(1));
+        assert_eq!(schema.history.set(300, &1000), None);
+        assert_eq!(schema.history.get(1), Some(1000));
+
+        assert_eq!(schema.history.pop(), Some(299));
+        assert_eq!(schema.history.len(), 299);
+        assert_eq!(schema.history.get(299), None);
+
+        schema.after.insert(&42);
+        schema.history.clear();
+        assert!(schema.history.is_empty());
+        assert_eq!(schema.history.iter().count(), 0);
+        assert_eq!(schema.after.get(), Some(42));
+    }
+
+    #[test]
+    fn test_set() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let schema = CollectionSchema::new();
+        assert!(schema.holders.is_empty());
+
+        assert!(schema.holders.insert("bob"));
+        assert!(schema.holders.insert("alice"));
+        assert!(!schema.holders.insert("bob"));
+        assert!(schema.holders.contains("alice"));
+        assert!(!schema.holders.contains("carol"));
+        assert_eq!(schema.holders.len(), 2);
+        assert_eq!(
+            schema.holders.iter().collect::<Vec<_>>(),
+            vec!["alice".to_owned(), "bob".to_owned()]
+        );
+
+        assert!(schema.holders.remove("alice"));
+        assert!(!schema.holders.remove("alice"));
+        assert_eq!(schema.holders.iter().collect::<Vec<_>>(), vec!["bob".to_owned()]);
+    }
+
+    #[test]
+    fn test_nested_map() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let schema = CollectionSchema::new();
+        schema.allowance.get("alice").insert("bob", &10);
+        schema.allowance.get("alice").insert("carol", &20);
+        schema.allowance.get("bob").insert("alice", &30);
+        // Keys which are prefixes of other keys must not overlap.
+        schema.allowance.get("ali").insert("ce", &40);
+
+        assert_eq!(schema.allowance.get("alice").get("bob"), Some(10));
+        assert_eq!(schema.allowance.get("bob").get("bob"), None);
+        assert_eq!(schema.allowance.get("alice").len(), 2);
+        assert_eq!(
+            schema.allowance.get("ali").iter().collect::<Vec<_>>(),
+            vec![("ce".to_owned(), 40)]
+        );
+
+        schema.allowance.get("alice").clear();
+        assert!(schema.allowance.get("alice").is_empty());
+        assert_eq!(schema.allowance.get("bob").get("alice"), Some(30));
+
+        schema.allowance.clear();
+        assert!(schema.allowance.get("bob").is_empty());
+        assert!(schema.allowance.get("ali").is_empty());
+    }
+
+    #[test]
+    fn test_derived_values() {
+        {
+            let mut db = DatabaseHandle::instance();
+            db.clear();
+        }
+
+        let buy = Order {
+            owner: "alice".to_owned(),
+            side: Side::Buy,
+            amounts: vec![10, 20],
+            memo: None,
+            fill: (0, false),
+        };
+        let sell = Order {
+            owner: "bob".to_owned(),
+            side: Side::Sell { limit: 42 },
+            amounts: vec![],
+            memo: Some("urgent".to_owned()),
+            fill: (5, true),
+        };
+
+        let schema = OrderSchema::new();
+        schema.orders.insert(&1, &buy);
+        schema.orders.insert(&2, &sell);
+        schema.last_order.insert(&sell);
+
+        assert_eq!(schema.orders.get(&1), Some(buy.clone()));
+        assert_eq!(schema.last_order.get(), Some(sell.clone()));
+        assert_eq!(schema.orders.iter().collect::<Vec<_>>(), vec![(1, buy), (2, sell)]);
+    }
 }
@@ -0,0 +1,130 @@
+//! Interface to the untrusted key-value store used for paged state.
+#[cfg(target_env = "sgx")]
+use sgx_types::*;
+
+#[cfg(not(target_env = "sgx"))]
+use std::collections::HashMap;
+#[cfg(not(target_env = "sgx"))]
+use std::sync::Mutex;
+
+#[cfg(target_env = "sgx")]
+use ekiden_common::error::Error;
+use ekiden_common::error::Result;
+
+/// OCALLs defined by the Ekiden database enclave specification.
+#[cfg(target_env = "sgx")]
+extern "C" {
+    /// Fetch a value from the untrusted key-value store.
+    pub fn untrusted_db_get(
+        key: *const u8,
+        key_length: usize,
+        value: *mut u8,
+        value_capacity: usize,
+        value_length: *mut usize,
+        found: *mut u8,
+    ) -> sgx_status_t;
+
+    /// Store a value into the untrusted key-value store.
+    pub fn untrusted_db_insert(
+        key: *const u8,
+        key_length: usize,
+        value: *const u8,
+        value_length: usize,
+        stored: *mut u8,
+    ) -> sgx_status_t;
+}
+
+/// Initial size of the buffer for values fetched from the untrusted store.
+#[cfg(target_env = "sgx")]
+const VALUE_BUFFER_SIZE: usize = 4 * 1024;
+
+#[cfg(not(target_env = "sgx"))]
+lazy_static! {
+    // Key-value store used in unit tests (on non-SGX).
+    static ref STORE: Mutex<HashMap<Vec<u8>, Vec<u8>>> = Mutex::new(HashMap::new());
+}
+
+/// Fetch a value from the untrusted key-value store.
+///
+/// The returned value is not authenticated in any way.
+#[cfg(target_env = "sgx")]
+pub fn get(key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut value: Vec<u8> = Vec::with_capacity(VALUE_BUFFER_SIZE);
+
+    loop {
+        let mut value_length = 0;
+        let mut found = 0;
+        let status = unsafe {
+            untrusted_db_get(
+                key.as_ptr(),
+                key.len(),
+                value.as_mut_ptr(),
+                value.capacity(),
+                &mut value_length,
+                &mut found,
+            )
+        };
+
+        match status {
+            sgx_status_t::SGX_SUCCESS => {}
+            status => {
+                return Err(Error::new(format!(
+                    "Untrusted database OCALL failed: {:?}",
+                    status
+                )));
+            }
+        }
+
+        if found == 0 {
+            return Ok(None);
+        }
+
+        if value_length > value.capacity() {
+            // Value did not fit, retry with a large enough buffer.
+            value.reserve_exact(value_length);
+            continue;
+        }
+
+        unsafe {
+            value.set_len(value_length);
+        }
+
+        return Ok(Some(value));
+    }
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn get(key: &[u8]) -> Result<Option<Vec<u8>>> {
+    Ok(STORE.lock().unwrap().get(key).cloned())
+}
+
+/// Store a value into the untrusted key-value store.
+#[cfg(target_env = "sgx")]
+pub fn insert(key: &[u8], value: &[u8]) -> Result<()> {
+    let mut stored = 0;
+    let status = unsafe {
+        untrusted_db_insert(
+            key.as_ptr(),
+            key.len(),
+            value.as_ptr(),
+            value.len(),
+            &mut stored,
+        )
+    };
+
+    match status {
+        sgx_status_t::SGX_SUCCESS if stored != 0 => Ok(()),
+        sgx_status_t::SGX_SUCCESS => Err(Error::new("Failed to store value in untrusted database")),
+        status => Err(Error::new(format!(
+            "Untrusted database OCALL failed: {:?}",
+            status
+        ))),
+    }
+}
+
+#[cfg(not(target_env = "sgx"))]
+pub fn insert(key: &[u8], value: &[u8]) -> Result<()> {
+    STORE.lock().unwrap().insert(key.to_vec(), value.to_vec());
+
+    Ok(())
+}
@@ -27,6 +27,9 @@ extern "C" {
         eid: sgx_enclave_id_t,
         state: *const u8,
         state_length: usize,
+        error: *mut u8,
+        error_capacity: usize,
+        error_length: *mut usize,
     ) -> sgx_status_t;
 
     pub fn db_state_get(
@@ -34,5 +37,39 @@ extern "C" {
         state: *mut u8,
         state_capacity: usize,
         state_length: *mut usize,
+        height: u64,
+        base_state_hash: *const u8,
+        base_state_hash_length: usize,
+        signature: *mut u8,
+    ) -> sgx_status_t;
+
+    pub fn db_state_get_diff(
+        eid: sgx_enclave_id_t,
+        diff: *mut u8,
+        diff_capacity: usize,
+        diff_length: *mut usize,
+        height: u64,
+        base_state_hash: *const u8,
+        base_state_hash_length: usize,
+        signature: *mut u8,
+    ) -> sgx_status_t;
+
+    pub fn db_state_checkpoint(
+        eid: sgx_enclave_id_t,
+        checkpoint: *const u8,
+        checkpoint_length: usize,
+        checkpoint_height: u64,
+        diffs: *const u8,
+        diffs_length: usize,
+        state: *mut u8,
+        state_capacity: usize,
+        state_length: *mut usize,
+        signature: *mut u8,
+    ) -> sgx_status_t;
+
+    pub fn db_state_hash(
+        eid: sgx_enclave_id_t,
+        hash: *mut u8,
+        signature: *mut u8,
     ) -> sgx_status_t;
 }
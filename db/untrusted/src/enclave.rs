@@ -2,29 +2,76 @@
 use sgx_types::*;
 
 use ekiden_common::error::{Error, Result};
+use ekiden_common::serializer::Serializable;
+use ekiden_enclave_common::signature::SIGNATURE_LEN;
 use ekiden_enclave_untrusted::Enclave;
 
 use super::ecall_proxy;
 
+/// Length of a state hash.
+pub const STATE_HASH_LEN: usize = 32;
+
 /// Enclave database interface.
 pub trait EnclaveDb {
     /// Maximum response size (in kilobytes).
     const MAX_RESPONSE_SIZE: usize = 1024;
 
+    /// Maximum length of an error message returned by the enclave (in bytes).
+    const MAX_ERROR_LENGTH: usize = 1024;
+
     /// Compute difference between states.
+    ///
+    /// The difference is not signed, so it cannot be submitted to consensus.
     fn db_state_diff(&self, old: &Vec<u8>, new: &Vec<u8>) -> Result<Vec<u8>>;
 
     /// Apply difference between states to an existing state.
     fn db_state_apply(&self, old: &Vec<u8>, diff: &Vec<u8>) -> Result<Vec<u8>>;
 
     /// Set enclave state.
+    ///
+    /// Fails if the enclave cannot import the state or bring it up to the current schema
+    /// version.
     fn db_state_set(&self, state: &Vec<u8>) -> Result<()>;
 
     /// Retrieve enclave state.
     ///
     /// If nothing was modified since the last import, this method will return an empty
-    /// vector.
-    fn db_state_get(&self) -> Result<Vec<u8>>;
+    /// vector. Otherwise the enclave signs the state together with the given height and
+    /// the hash of the state it was computed on. Returns the state and the signature.
+    fn db_state_get(&self, height: u64, base_state_hash: &[u8]) -> Result<(Vec<u8>, Vec<u8>)>;
+
+    /// Retrieve the changes made since the enclave state was last set as a diff.
+    ///
+    /// The diff only contains the keys that were written, so its size does not depend on
+    /// the size of the state. If nothing was modified, this method will return an empty
+    /// vector. Otherwise the enclave signs the diff together with the height of the state
+    /// that it produces and the hash of the state it was computed on. Returns the diff and
+    /// the signature.
+    fn db_state_get_diff(
+        &self,
+        height: u64,
+        base_state_hash: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>)>;
+
+    /// Fold the diffs on top of a checkpoint into a new checkpoint.
+    ///
+    /// The checkpoint and diffs must be the current consensus state of the contract, as
+    /// the enclave binds its signature to their consensus hash. Returns the new checkpoint
+    /// and the signature.
+    fn db_state_checkpoint(
+        &self,
+        checkpoint: &Vec<u8>,
+        checkpoint_height: u64,
+        diffs: &[Vec<u8>],
+    ) -> Result<(Vec<u8>, Vec<u8>)>;
+
+    /// Retrieve a signed hash of the enclave state.
+    ///
+    /// The hash is a keyed hash of the canonical plaintext of the state, so enclaves of
+    /// the same contract with the same state contents return the same hash, while the
+    /// hash reveals nothing about the state. Returns the hash of `STATE_HASH_LEN` bytes
+    /// and the enclave's signature over it.
+    fn db_state_hash(&self) -> Result<(Vec<u8>, Vec<u8>)>;
 }
 
 impl EnclaveDb for Enclave {
@@ -90,22 +137,40 @@ impl EnclaveDb for Enclave {
 
     /// Set enclave state.
     fn db_state_set(&self, state: &Vec<u8>) -> Result<()> {
+        let mut error = vec![0; Self::MAX_ERROR_LENGTH];
+        let mut error_length = 0;
+
         let status = unsafe {
-            ecall_proxy::db_state_set(self.get_id(), state.as_ptr() as *const u8, state.len())
+            ecall_proxy::db_state_set(
+                self.get_id(),
+                state.as_ptr() as *const u8,
+                state.len(),
+                error.as_mut_ptr(),
+                error.len(),
+                &mut error_length,
+            )
         };
 
         if status != sgx_status_t::SGX_SUCCESS {
             return Err(Error::new("Failed to call enclave state set"));
         }
 
+        if error_length > 0 {
+            return Err(Error::new(format!(
+                "Failed to set enclave state: {}",
+                String::from_utf8_lossy(&error[..error_length])
+            )));
+        }
+
         Ok(())
     }
 
     /// Retrieve enclave state.
-    fn db_state_get(&self) -> Result<Vec<u8>> {
+    fn db_state_get(&self, height: u64, base_state_hash: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
         // Reserve space up to the maximum size of serialized response.
         let mut state: Vec<u8> = Vec::with_capacity(Self::MAX_RESPONSE_SIZE * 1024);
         let mut state_length = 0;
+        let mut signature = vec![0; SIGNATURE_LEN];
 
         let status = unsafe {
             ecall_proxy::db_state_get(
@@ -113,6 +178,10 @@ impl EnclaveDb for Enclave {
                 state.as_mut_ptr() as *mut u8,
                 state.capacity(),
                 &mut state_length,
+                height,
+                base_state_hash.as_ptr(),
+                base_state_hash.len(),
+                signature.as_mut_ptr(),
             )
         };
 
@@ -124,6 +193,97 @@ impl EnclaveDb for Enclave {
             state.set_len(state_length);
         }
 
-        Ok(state)
+        Ok((state, signature))
+    }
+
+    /// Retrieve the changes made since the enclave state was last set as a diff.
+    fn db_state_get_diff(
+        &self,
+        height: u64,
+        base_state_hash: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        // Reserve space up to the maximum size of serialized response.
+        let mut diff: Vec<u8> = Vec::with_capacity(Self::MAX_RESPONSE_SIZE * 1024);
+        let mut diff_length = 0;
+        let mut signature = vec![0; SIGNATURE_LEN];
+
+        let status = unsafe {
+            ecall_proxy::db_state_get_diff(
+                self.get_id(),
+                diff.as_mut_ptr() as *mut u8,
+                diff.capacity(),
+                &mut diff_length,
+                height,
+                base_state_hash.as_ptr(),
+                base_state_hash.len(),
+                signature.as_mut_ptr(),
+            )
+        };
+
+        if status != sgx_status_t::SGX_SUCCESS {
+            return Err(Error::new("Failed to call enclave state diff export"));
+        }
+
+        unsafe {
+            diff.set_len(diff_length);
+        }
+
+        Ok((diff, signature))
+    }
+
+    /// Fold the diffs on top of a checkpoint into a new checkpoint.
+    fn db_state_checkpoint(
+        &self,
+        checkpoint: &Vec<u8>,
+        checkpoint_height: u64,
+        diffs: &[Vec<u8>],
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let diffs = diffs.to_vec().write()?;
+
+        // Reserve space up to the maximum size of serialized response.
+        let mut state: Vec<u8> = Vec::with_capacity(Self::MAX_RESPONSE_SIZE * 1024);
+        let mut state_length = 0;
+        let mut signature = vec![0; SIGNATURE_LEN];
+
+        let status = unsafe {
+            ecall_proxy::db_state_checkpoint(
+                self.get_id(),
+                checkpoint.as_ptr() as *const u8,
+                checkpoint.len(),
+                checkpoint_height,
+                diffs.as_ptr() as *const u8,
+                diffs.len(),
+                state.as_mut_ptr() as *mut u8,
+                state.capacity(),
+                &mut state_length,
+                signature.as_mut_ptr(),
+            )
+        };
+
+        if status != sgx_status_t::SGX_SUCCESS {
+            return Err(Error::new("Failed to call enclave state checkpoint"));
+        }
+
+        unsafe {
+            state.set_len(state_length);
+        }
+
+        Ok((state, signature))
+    }
+
+    /// Retrieve a signed hash of the enclave state.
+    fn db_state_hash(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut hash = vec![0; STATE_HASH_LEN];
+        let mut signature = vec![0; SIGNATURE_LEN];
+
+        let status = unsafe {
+            ecall_proxy::db_state_hash(self.get_id(), hash.as_mut_ptr(), signature.as_mut_ptr())
+        };
+
+        if status != sgx_status_t::SGX_SUCCESS {
+            return Err(Error::new("Failed to call enclave state hash"));
+        }
+
+        Ok((hash, signature))
     }
 }
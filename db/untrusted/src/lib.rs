@@ -1,11 +1,19 @@
 extern crate sgx_types;
 
+#[macro_use]
+extern crate lazy_static;
+
 extern crate ekiden_common;
+extern crate ekiden_enclave_common;
 extern crate ekiden_enclave_untrusted;
 
 pub mod enclave;
+pub mod store;
 #[doc(hidden)]
 pub mod ecall_proxy;
+#[doc(hidden)]
+pub mod ocall_proxy;
 
 // Exports.
 pub use enclave::EnclaveDb;
+pub use store::{set_key_value_store, FileKeyValueStore, KeyValueStore, MemoryKeyValueStore};
@@ -0,0 +1,63 @@
+use std;
+
+use super::store::get_key_value_store;
+
+/// Fetch a value from the untrusted key-value store used for paged state.
+#[no_mangle]
+pub extern "C" fn untrusted_db_get(
+    key: *const u8,
+    key_length: usize,
+    value: *mut u8,
+    value_capacity: usize,
+    value_length: *mut usize,
+    found: *mut u8,
+) {
+    let key = unsafe { std::slice::from_raw_parts(key, key_length) };
+
+    // TODO: Handle errors? The enclave treats a missing value as an error.
+    let result = match get_key_value_store().get(key) {
+        Ok(Some(result)) => result,
+        _ => {
+            unsafe {
+                *found = 0;
+            }
+            return;
+        }
+    };
+
+    unsafe {
+        *found = 1;
+        *value_length = result.len();
+    }
+
+    // If the value does not fit, the enclave retries with a large enough buffer.
+    if result.len() <= value_capacity {
+        unsafe {
+            std::ptr::copy_nonoverlapping(result.as_ptr(), value, result.len());
+        }
+    }
+}
+
+/// Store a value into the untrusted key-value store used for paged state.
+///
+/// Sets `stored` to 0 if the value could not be stored.
+#[no_mangle]
+pub extern "C" fn untrusted_db_insert(
+    key: *const u8,
+    key_length: usize,
+    value: *const u8,
+    value_length: usize,
+    stored: *mut u8,
+) {
+    let key = unsafe { std::slice::from_raw_parts(key, key_length) };
+    let value = unsafe { std::slice::from_raw_parts(value, value_length) };
+
+    let result = get_key_value_store().insert(key, value);
+    if let Err(ref error) = result {
+        eprintln!("Failed to store paged state {:?}", error);
+    }
+
+    unsafe {
+        *stored = result.is_ok() as u8;
+    }
+}
@@ -0,0 +1,176 @@
+//! Untrusted key-value store holding paged enclave state.
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use ekiden_common::error::Result;
+
+/// Key-value store holding the encrypted nodes of paged enclave state.
+///
+/// The enclave encrypts and authenticates everything that it stores, so the store
+/// does not need to be trusted. It must however be shared by all compute nodes which
+/// run the same contract, as the consensus state only refers to the root of the
+/// state tree.
+///
+/// Values are never removed. Nodes of earlier versions of the state tree are not
+/// garbage-collected, so the store grows with every state update.
+pub trait KeyValueStore: Send + Sync + 'static {
+    /// Fetch the value of a key.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Set the value of a key.
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()>;
+}
+
+/// Key-value store which keeps all values in (untrusted) memory.
+///
+/// The values are lost when the compute node exits and are not shared with other
+/// compute nodes, so this is only suitable for a single compute node and for tests.
+#[derive(Default)]
+pub struct MemoryKeyValueStore {
+    items: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryKeyValueStore {
+    /// Construct an empty store.
+    pub fn new() -> Self {
+        MemoryKeyValueStore {
+            items: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl KeyValueStore for MemoryKeyValueStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.items.lock().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.items.lock().unwrap().insert(key.to_vec(), value.to_vec());
+
+        Ok(())
+    }
+}
+
+/// Counter used to generate unique names for temporary files.
+static TEMP_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Key-value store which keeps each value in a file in a directory.
+///
+/// The directory may be on a file system which is shared by all compute nodes of a
+/// contract. Values are written to a temporary file which is then renamed into place,
+/// so readers never observe a partially written value.
+pub struct FileKeyValueStore {
+    /// Directory holding the values.
+    path: PathBuf,
+}
+
+impl FileKeyValueStore {
+    /// Open the store in the given directory, creating the directory if it does not
+    /// exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        fs::create_dir_all(&path)?;
+
+        Ok(FileKeyValueStore {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Name of the file holding the value of a key.
+    fn file_name(key: &[u8]) -> String {
+        let mut name = String::new();
+        for &byte in key {
+            write!(&mut name, "{:02x}", byte).unwrap();
+        }
+        name
+    }
+}
+
+impl KeyValueStore for FileKeyValueStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        match File::open(self.path.join(Self::file_name(key))) {
+            Ok(mut file) => file.read_to_end(&mut value)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Some(value))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let name = Self::file_name(key);
+        let temp_path = self.path.join(format!(
+            "{}.{}-{}.tmp",
+            name,
+            process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(value)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, self.path.join(name))?;
+
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// Global key-value store for all the enclaves.
+    ///
+    /// This must be global, because we need to be able to get the current store
+    /// when we are invoked from an OCALL and at that point we only have global
+    /// state available.
+    static ref KEY_VALUE_STORE: RwLock<Arc<KeyValueStore>> =
+        RwLock::new(Arc::new(MemoryKeyValueStore::new()));
+}
+
+/// Replace the global key-value store used for paged enclave state.
+///
+/// By default, a [`MemoryKeyValueStore`] is used. This must be called before any
+/// enclave state is paged out, as values are not copied to the new store.
+pub fn set_key_value_store(store: Arc<KeyValueStore>) {
+    *KEY_VALUE_STORE.write().unwrap() = store;
+}
+
+/// Get the global key-value store used for paged enclave state.
+pub fn get_key_value_store() -> Arc<KeyValueStore> {
+    KEY_VALUE_STORE.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{FileKeyValueStore, KeyValueStore};
+
+    #[test]
+    fn test_file_store() {
+        let path = env::temp_dir().join(format!("ekiden-db-store-{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let store = FileKeyValueStore::open(&path).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), None);
+        store.insert(b"foo", b"hello world").unwrap();
+        store.insert(b"bar", b"").unwrap();
+        store.insert(b"foo", b"changed").unwrap();
+
+        // Values are persisted across instances.
+        let store = FileKeyValueStore::open(&path).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(store.get(b"bar").unwrap(), Some(vec![]));
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! Hashes of the consensus state.
+//!
+//! These are shared between consensus, which commits to its state with them, and
+//! enclaves, which bind signed state updates to the consensus state they were
+//! computed on.
+use byteorder::{LittleEndian, WriteBytesExt};
+use sodalite;
+
+/// Length of a consensus state hash.
+pub const HASH_LEN: usize = 32;
+
+/// Domain separation prefix for Merkle tree leaves.
+pub const MERKLE_LEAF_PREFIX: u8 = 0x00;
+/// Domain separation prefix for internal Merkle tree nodes.
+pub const MERKLE_NODE_PREFIX: u8 = 0x01;
+
+/// Hash function used for the consensus state hash.
+pub fn hash(data: &[u8]) -> Vec<u8> {
+    let mut digest = [0; sodalite::HASH_LEN];
+    sodalite::hash(&mut digest, data);
+
+    digest[..HASH_LEN].to_vec()
+}
+
+/// Compute the root of a binary Merkle tree over the given leaf hashes.
+///
+/// A node without a sibling is promoted to the next level unchanged. There must be
+/// at least one leaf.
+pub fn merkle_root(mut level: Vec<Vec<u8>>) -> Vec<u8> {
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                if pair.len() == 2 {
+                    let mut node = vec![MERKLE_NODE_PREFIX];
+                    node.extend_from_slice(&pair[0]);
+                    node.extend_from_slice(&pair[1]);
+                    hash(&node)
+                } else {
+                    pair[0].clone()
+                }
+            })
+            .collect();
+    }
+
+    level.pop().unwrap()
+}
+
+/// Compute the hash of the state of a contract.
+///
+/// The hash is the root of a binary Merkle tree where the first leaf covers the
+/// checkpoint and its height and each subsequent leaf covers one diff, in order.
+pub fn contract_state_hash(
+    checkpoint_height: u64,
+    checkpoint: &[u8],
+    diffs: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut leaves = Vec::with_capacity(diffs.len() + 1);
+
+    let mut checkpoint_leaf = vec![MERKLE_LEAF_PREFIX];
+    checkpoint_leaf
+        .write_u64::<LittleEndian>(checkpoint_height)
+        .unwrap();
+    checkpoint_leaf.extend_from_slice(checkpoint);
+    leaves.push(hash(&checkpoint_leaf));
+
+    for diff in diffs {
+        let mut diff_leaf = vec![MERKLE_LEAF_PREFIX];
+        diff_leaf.extend_from_slice(&diff);
+        leaves.push(hash(&diff_leaf));
+    }
+
+    merkle_root(leaves)
+}
@@ -2,31 +2,48 @@ use sgx_types;
 
 use sodalite;
 
+use ekiden_common::error::{Error, Result};
+
 /// Used in enclave identity proof.
 const QUOTE_CONTEXT_IDENTITY: super::quote::QuoteContext = *b"EkQ-Iden";
 
 /// Version of the public identity string format.
-const IDENTITY_VERSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+const IDENTITY_VERSION: [u8; 8] = [1, 0, 0, 0, 0, 0, 0, 0];
 
 /// The components of a public identity string.
 #[derive(Clone)]
 pub struct PublicIdentityComponents {
     /// Long term enclave key E used in RPC, public part.
     pub rpc_key_e_pub: sodalite::BoxPublicKey,
+    /// Long term enclave signing key, public part.
+    pub sign_key_pub: sodalite::SignPublicKey,
 }
 
+/// Length of the public identity string.
+const PUBLIC_IDENTITY_LEN: usize = sodalite::BOX_PUBLIC_KEY_LEN + sodalite::SIGN_PUBLIC_KEY_LEN;
+
 /// Pack components into a public identity string.
 pub fn pack_public_identity(components: &PublicIdentityComponents) -> Vec<u8> {
-    components.rpc_key_e_pub.to_vec()
+    let mut public_identity = Vec::with_capacity(PUBLIC_IDENTITY_LEN);
+    public_identity.extend_from_slice(&components.rpc_key_e_pub);
+    public_identity.extend_from_slice(&components.sign_key_pub);
+    public_identity
 }
 
 /// Unpack components from a public identity string.
-pub fn unpack_public_identity(public_identity: &[u8]) -> PublicIdentityComponents {
+pub fn unpack_public_identity(public_identity: &[u8]) -> Result<PublicIdentityComponents> {
+    if public_identity.len() != PUBLIC_IDENTITY_LEN {
+        return Err(Error::new("Malformed public identity string"));
+    }
+
     let mut components = PublicIdentityComponents {
         rpc_key_e_pub: [0; sodalite::BOX_PUBLIC_KEY_LEN],
+        sign_key_pub: [0; sodalite::SIGN_PUBLIC_KEY_LEN],
     };
-    components.rpc_key_e_pub.copy_from_slice(public_identity);
-    components
+    let (rpc_key_e_pub, sign_key_pub) = public_identity.split_at(sodalite::BOX_PUBLIC_KEY_LEN);
+    components.rpc_key_e_pub.copy_from_slice(rpc_key_e_pub);
+    components.sign_key_pub.copy_from_slice(sign_key_pub);
+    Ok(components)
 }
 
 /// Pack fields into a report data struct.
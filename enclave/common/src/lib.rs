@@ -11,8 +11,10 @@ extern crate sodalite;
 #[macro_use]
 extern crate ekiden_common;
 
+pub mod consensus_hash;
 pub mod identity;
 pub mod quote;
+pub mod signature;
 
 // This is pub so that other crates can import our protos.
 pub mod generated;
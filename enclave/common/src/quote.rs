@@ -146,7 +146,7 @@ pub fn verify(identity_proof: &IdentityProof) -> Result<IdentityAuthenticatedInf
     }
 
     Ok(IdentityAuthenticatedInfo {
-        identity: super::identity::unpack_public_identity(public_identity),
+        identity: super::identity::unpack_public_identity(public_identity)?,
         mr_enclave: quote_body.report_body.mr_enclave,
     })
 }
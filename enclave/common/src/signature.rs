@@ -0,0 +1,129 @@
+//! Signatures made with the enclave signing key.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sodalite;
+
+use ekiden_common::error::{Error, Result};
+
+pub const SIGNATURE_CONTEXT_LEN: usize = 8;
+/// The purpose of `SignatureContext` is to prevent signatures from being used in
+/// different contexts. The value is included as a prefix in the signed message.
+pub type SignatureContext = [u8; SIGNATURE_CONTEXT_LEN];
+
+/// Length of a signature.
+pub const SIGNATURE_LEN: usize = sodalite::SIGN_LEN;
+
+/// Used when signing a full state that replaces the consensus state.
+pub const SIGNATURE_CONTEXT_STATE_REPLACE: SignatureContext = *b"EkS-Repl";
+/// Used when signing a state diff that is added to the consensus state.
+pub const SIGNATURE_CONTEXT_STATE_DIFF: SignatureContext = *b"EkS-Diff";
+/// Used when signing a full state that compacts the consensus state into a checkpoint.
+pub const SIGNATURE_CONTEXT_STATE_CHECKPOINT: SignatureContext = *b"EkS-Ckpt";
+/// Used when signing the keyed hash of the current state.
+pub const SIGNATURE_CONTEXT_STATE_HASH: SignatureContext = *b"EkS-Hash";
+
+/// Prefix a message with its signature context.
+fn pack_message(context: &SignatureContext, message: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(SIGNATURE_CONTEXT_LEN + message.len());
+    packed.extend_from_slice(context);
+    packed.extend_from_slice(message);
+    packed
+}
+
+/// Sign a message in the given context.
+pub fn sign(
+    sign_key_priv: &sodalite::SignSecretKey,
+    context: &SignatureContext,
+    message: &[u8],
+) -> Vec<u8> {
+    let message = pack_message(context, message);
+    let mut signed_message = vec![0; SIGNATURE_LEN + message.len()];
+    sodalite::sign_attached(&mut signed_message, &message, sign_key_priv);
+    signed_message.truncate(SIGNATURE_LEN);
+    signed_message
+}
+
+/// Verify a signature over a message in the given context.
+pub fn verify(
+    sign_key_pub: &sodalite::SignPublicKey,
+    context: &SignatureContext,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    if signature.len() != SIGNATURE_LEN {
+        return Err(Error::new("Malformed signature"));
+    }
+
+    let message = pack_message(context, message);
+    let mut signed_message = Vec::with_capacity(SIGNATURE_LEN + message.len());
+    signed_message.extend_from_slice(signature);
+    signed_message.extend_from_slice(&message);
+
+    let mut opened_message = vec![0; signed_message.len()];
+    match sodalite::sign_attached_open(&mut opened_message, &signed_message, sign_key_pub) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::new("Signature verification failed")),
+    }
+}
+
+/// Pack a state update, the height of the state it produces and the hash of the state
+/// it was computed on into a message for signing.
+///
+/// Binding the height and the base state hash prevents a signed state update from being
+/// replayed on top of a different state.
+pub fn pack_state_update(height: u64, base_state_hash: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(12 + base_state_hash.len() + payload.len());
+    message.write_u64::<LittleEndian>(height).unwrap();
+    message
+        .write_u32::<LittleEndian>(base_state_hash.len() as u32)
+        .unwrap();
+    message.extend_from_slice(base_state_hash);
+    message.extend_from_slice(payload);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use sodalite;
+
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let mut sign_key_pub = [0; sodalite::SIGN_PUBLIC_KEY_LEN];
+        let mut sign_key_priv = [0; sodalite::SIGN_SECRET_KEY_LEN];
+        sodalite::sign_keypair_seed(&mut sign_key_pub, &mut sign_key_priv, &[42; 32]);
+
+        let message = pack_state_update(7, b"base", b"payload");
+        let signature = sign(&sign_key_priv, &SIGNATURE_CONTEXT_STATE_DIFF, &message);
+        assert_eq!(signature.len(), SIGNATURE_LEN);
+
+        assert!(verify(&sign_key_pub, &SIGNATURE_CONTEXT_STATE_DIFF, &message, &signature).is_ok());
+
+        // Signature must not verify in a different context.
+        assert!(
+            verify(&sign_key_pub, &SIGNATURE_CONTEXT_STATE_REPLACE, &message, &signature).is_err()
+        );
+
+        // Signature must not verify for a different height.
+        let other_message = pack_state_update(8, b"base", b"payload");
+        assert!(
+            verify(&sign_key_pub, &SIGNATURE_CONTEXT_STATE_DIFF, &other_message, &signature)
+                .is_err()
+        );
+
+        // Signature must not verify for a different base state.
+        let other_message = pack_state_update(7, b"other", b"payload");
+        assert!(
+            verify(&sign_key_pub, &SIGNATURE_CONTEXT_STATE_DIFF, &other_message, &signature)
+                .is_err()
+        );
+
+        // The base state hash is length-prefixed, so it cannot be shifted into the payload.
+        let other_message = pack_state_update(7, b"bas", b"epayload");
+        assert!(
+            verify(&sign_key_pub, &SIGNATURE_CONTEXT_STATE_DIFF, &other_message, &signature)
+                .is_err()
+        );
+    }
+}
@@ -29,6 +29,8 @@ use super::crypto::{SecretSeed, SECRET_SEED_LEN};
 struct IdentityExport {
     /// Seed for RPC `E` key.
     seed: SecretSeed,
+    /// Seed for signing key.
+    sign_seed: SecretSeed,
 }
 
 #[cfg(target_env = "sgx")]
@@ -40,6 +42,33 @@ pub struct Identity {
     pub public: ekiden_enclave_common::identity::PublicIdentityComponents,
     /// Long term enclave key E used in RPC, private part.
     pub rpc_key_e_priv: sodalite::BoxSecretKey,
+    /// Long term enclave signing key, private part.
+    pub sign_key_priv: sodalite::SignSecretKey,
+}
+
+impl Identity {
+    /// Derive an identity from secret seeds.
+    fn from_seeds(seed: &SecretSeed, sign_seed: &SecretSeed) -> Self {
+        let mut identity = Identity {
+            public: ekiden_enclave_common::identity::PublicIdentityComponents {
+                rpc_key_e_pub: [0; sodalite::BOX_PUBLIC_KEY_LEN],
+                sign_key_pub: [0; sodalite::SIGN_PUBLIC_KEY_LEN],
+            },
+            rpc_key_e_priv: [0; sodalite::BOX_SECRET_KEY_LEN],
+            sign_key_priv: [0; sodalite::SIGN_SECRET_KEY_LEN],
+        };
+        sodalite::box_keypair_seed(
+            &mut identity.public.rpc_key_e_pub,
+            &mut identity.rpc_key_e_priv,
+            seed,
+        );
+        sodalite::sign_keypair_seed(
+            &mut identity.public.sign_key_pub,
+            &mut identity.sign_key_priv,
+            sign_seed,
+        );
+        identity
+    }
 }
 
 lazy_static! {
@@ -63,8 +92,11 @@ pub extern "C" fn identity_create(
 ) {
     let mut export = IdentityExport {
         seed: [0; SECRET_SEED_LEN],
+        sign_seed: [0; SECRET_SEED_LEN],
     };
     random::get_random_bytes(&mut export.seed).expect("ekiden_common::random::get_random_bytes");
+    random::get_random_bytes(&mut export.sign_seed)
+        .expect("ekiden_common::random::get_random_bytes");
     let sealed_data = SgxSealedData::<IdentityExport>::seal_data_ex(
         0x01, // KEYPOLICY_MRENCLAVE
         sgx_attributes_t {
@@ -120,17 +152,7 @@ pub extern "C" fn identity_restore(
         .expect("SgxSealedData::unseal_data");
     let export = unsealed_data.get_decrypt_txt();
 
-    let mut identity = Identity {
-        public: ekiden_enclave_common::identity::PublicIdentityComponents {
-            rpc_key_e_pub: [0; sodalite::BOX_PUBLIC_KEY_LEN],
-        },
-        rpc_key_e_priv: [0; sodalite::BOX_SECRET_KEY_LEN],
-    };
-    sodalite::box_keypair_seed(
-        &mut identity.public.rpc_key_e_pub,
-        &mut identity.rpc_key_e_priv,
-        &export.seed,
-    );
+    let identity = Identity::from_seeds(&export.seed, &export.sign_seed);
 
     {
         let mut guard = IDENTITY.lock().unwrap();
@@ -166,19 +188,10 @@ pub fn nosgx_init_dummy() {
 
     let mut seed: SecretSeed = [0; SECRET_SEED_LEN];
     random::get_random_bytes(&mut seed).expect("ekiden_common::random::get_random_bytes");
+    let mut sign_seed: SecretSeed = [0; SECRET_SEED_LEN];
+    random::get_random_bytes(&mut sign_seed).expect("ekiden_common::random::get_random_bytes");
 
-    let mut identity = Identity {
-        public: ekiden_enclave_common::identity::PublicIdentityComponents {
-            rpc_key_e_pub: [0; sodalite::BOX_PUBLIC_KEY_LEN],
-        },
-        rpc_key_e_priv: [0; sodalite::BOX_SECRET_KEY_LEN],
-    };
-    sodalite::box_keypair_seed(
-        &mut identity.public.rpc_key_e_pub,
-        &mut identity.rpc_key_e_priv,
-        &seed,
-    );
-    *guard = Some(identity);
+    *guard = Some(Identity::from_seeds(&seed, &sign_seed));
 
     let mut av_report = ekiden_enclave_common::api::AvReport::new();
     av_report.set_body(b"{}".to_vec());
@@ -232,6 +245,16 @@ pub fn get_identity() -> Identity {
         .clone()
 }
 
+/// Sign a message with the identity signing key.
+pub fn sign(
+    context: &ekiden_enclave_common::signature::SignatureContext,
+    message: &[u8],
+) -> Vec<u8> {
+    let guard = IDENTITY.lock().unwrap();
+    let identity = guard.as_ref().expect("IDENTITY not initialized");
+    ekiden_enclave_common::signature::sign(&identity.sign_key_priv, context, message)
+}
+
 /// Get the identity proof.
 pub fn get_proof() -> ekiden_enclave_common::api::IdentityProof {
     let mut identity_proof = ekiden_enclave_common::api::IdentityProof::new();
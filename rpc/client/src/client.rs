@@ -18,6 +18,7 @@ use ekiden_common::error::Error;
 use ekiden_common::error::Result;
 use ekiden_enclave_common::quote::MrEnclave;
 use ekiden_rpc_common::api;
+use ekiden_rpc_common::chunking::{self, ChunkAssembler};
 
 use super::backend::ContractClientBackend;
 use super::future::ClientFuture;
@@ -98,9 +99,91 @@ impl<Backend: ContractClientBackend + 'static> ContractClientContext<Backend> {
     }
 
     /// Call a contract method.
+    ///
+    /// Large request and response payloads are transferred in chunks.
     fn call_raw(
+        context: Arc<Mutex<Self>>,
+        mut plain_request: api::PlainClientRequest,
+    ) -> ClientFuture<Vec<u8>> {
+        if !chunking::is_chunked(plain_request.get_payload()) {
+            return Self::call_raw_attempt(context, plain_request, true);
+        }
+
+        // Send all but the last chunk separately and the last chunk with the request.
+        let mut chunks = match chunking::split(&plain_request.take_payload()) {
+            Ok(chunks) => chunks,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let last_chunk = chunks.pop().expect("Chunked payload has at least two chunks");
+        plain_request.set_chunk(last_chunk);
+
+        // Context moved into the closure (renamed for clarity).
+        let shared_context = context;
+
+        let result = Self::send_request_chunks(shared_context.clone(), chunks).and_then(
+            move |_| {
+                // The chunks are stored in the session, so the request cannot be made
+                // again over a new secure channel.
+                Self::call_raw_attempt(shared_context, plain_request, false)
+            },
+        );
+
+        Box::new(result)
+    }
+
+    /// Send chunks of a request payload in order.
+    fn send_request_chunks(
+        context: Arc<Mutex<Self>>,
+        mut chunks: Vec<api::PayloadChunk>,
+    ) -> ClientFuture<()> {
+        if chunks.is_empty() {
+            return Box::new(future::ok(()));
+        }
+
+        let chunk = chunks.remove(0);
+        let result = Self::call::<api::PayloadChunk, api::RequestChunkResponse>(
+            context.clone(),
+            api::METHOD_REQUEST_CHUNK,
+            chunk,
+        ).and_then(move |_| Self::send_request_chunks(context, chunks));
+
+        Box::new(result)
+    }
+
+    /// Add a chunk of a response payload and fetch the next chunk, until the whole
+    /// payload has been received.
+    fn add_response_chunk(
+        context: Arc<Mutex<Self>>,
+        mut chunks: ChunkAssembler,
+        chunk: &api::PayloadChunk,
+    ) -> ClientFuture<Vec<u8>> {
+        match chunks.add(chunk) {
+            Ok(Some(payload)) => return Box::new(future::ok(payload)),
+            Ok(None) => {}
+            Err(error) => return Box::new(future::err(error)),
+        }
+
+        let mut request = api::ResponseChunkRequest::new();
+        request.set_index(chunk.get_index() + 1);
+
+        let result = Self::call::<api::ResponseChunkRequest, api::PayloadChunk>(
+            context.clone(),
+            api::METHOD_RESPONSE_CHUNK,
+            request,
+        ).and_then(move |chunk| Self::add_response_chunk(context, chunks, &chunk));
+
+        Box::new(result)
+    }
+
+    /// Make a single attempt to call a contract method.
+    ///
+    /// If `reconnect` is set and the contract no longer has our secure channel session
+    /// (e.g., because it expired or was evicted), a new secure channel is established
+    /// and the call is made again.
+    fn call_raw_attempt(
         context: Arc<Mutex<Self>>,
         plain_request: api::PlainClientRequest,
+        reconnect: bool,
     ) -> ClientFuture<Vec<u8>> {
         // Ensure secure channel is initialized before making the request.
         let init_sc = Self::init_secure_channel(context.clone());
@@ -112,6 +195,18 @@ impl<Backend: ContractClientBackend + 'static> ContractClientContext<Backend> {
             // Clone method for use in later future.
             let cloned_method = plain_request.get_method().to_owned();
 
+            // Keep the request in case it needs to be made again over a new secure channel.
+            // Closing the channel over a new secure channel would be pointless, and chunked
+            // transfers cannot be continued in a new session.
+            let retry_request = if reconnect && cloned_method != api::METHOD_CHANNEL_CLOSE
+                && cloned_method != api::METHOD_REQUEST_CHUNK
+                && cloned_method != api::METHOD_RESPONSE_CHUNK
+            {
+                Some(plain_request.clone())
+            } else {
+                None
+            };
+
             // Prepare the backend call future. This is done in a new scope so that the held
             // lock is released early and we can move shared_context into the next future.
             let backend_call = {
@@ -172,6 +267,17 @@ impl<Backend: ContractClientBackend + 'static> ContractClientContext<Backend> {
                                     if cloned_method != api::METHOD_CHANNEL_INIT {
                                         context.secure_channel.close();
 
+                                        // The contract could not open the request, so it was not
+                                        // processed and can safely be made again over a new
+                                        // secure channel.
+                                        if let Some(retry_request) = retry_request {
+                                            return Self::call_raw_attempt(
+                                                shared_context.clone(),
+                                                retry_request,
+                                                false,
+                                            );
+                                        }
+
                                         // Channel will reset on the next request.
                                         return Box::new(future::err(Error::new(
                                             "Secure channel closed",
@@ -205,6 +311,15 @@ impl<Backend: ContractClientBackend + 'static> ContractClientContext<Backend> {
                         }
                     };
 
+                    // Fetch the remaining chunks of a chunked response.
+                    if plain_response.has_chunk() {
+                        return Self::add_response_chunk(
+                            shared_context.clone(),
+                            ChunkAssembler::new(),
+                            plain_response.get_chunk(),
+                        );
+                    }
+
                     Box::new(future::ok(plain_response.take_payload()))
                 },
             );
@@ -245,101 +360,186 @@ impl<Backend: ContractClientBackend + 'static> ContractClientContext<Backend> {
     /// Initialize a secure channel with the contract.
     ///
     /// If the channel has already been initialized the future returned by this method
-    /// will immediately resolve.
+    /// will immediately resolve. If the contract issued a session ticket for a previous
+    /// session, the session is resumed instead of making a full handshake.
     fn init_secure_channel(context: Arc<Mutex<Self>>) -> ClientFuture<()> {
         // Context moved into the closure (renamed for clarity).
         let shared_context = context;
 
-        let result = future::lazy(move || {
-            // Return is futures::future::Either. A is immediate return. B is request.
-
+        let result = future::lazy(move || -> ClientFuture<()> {
             let request = {
                 let mut context = shared_context.lock().unwrap();
 
                 // If secure channel is already initialized, we don't need to do anything.
                 if !context.secure_channel.is_closed() {
-                    return future::Either::A(future::ok(()));
+                    return Box::new(future::ok(()));
                 }
 
                 // Reset secure channel.
                 match context.secure_channel.reset() {
                     Ok(()) => {}
-                    Err(error) => return future::Either::A(future::err(error)),
+                    Err(error) => return Box::new(future::err(error)),
                 };
 
-                let mut request = api::ChannelInitRequest::new();
-                request.set_short_term_public_key(
-                    context.secure_channel.get_client_public_key().to_vec(),
-                );
-                request
+                context.secure_channel.get_resumption_request()
             };
 
-            // Call remote channel init.
-            future::Either::B(
-                Self::call::<api::ChannelInitRequest, api::ChannelInitResponse>(
-                    shared_context.clone(),
-                    api::METHOD_CHANNEL_INIT,
-                    request,
-                ).and_then(move |response: api::ChannelInitResponse| {
-                    // Return is futures::future::Either. A is immediate return. B is request.
+            match request {
+                Some(request) => Self::resume_secure_channel(shared_context, request),
+                None => Self::handshake_secure_channel(shared_context),
+            }
+        });
 
-                    let request = {
-                        let mut context = shared_context.lock().unwrap();
-                        let client_authentication = context.client_authentication;
-
-                        // Verify contract identity and set up a secure channel.
-                        let iai = match context.secure_channel.setup(
-                            response.get_authenticated_short_term_public_key(),
-                            client_authentication,
-                        ) {
-                            Ok(iai) => iai,
-                            Err(e) => return future::Either::A(future::err(e)),
-                        };
+        Box::new(result)
+    }
 
-                        // Verify MRENCLAVE.
-                        if &iai.mr_enclave != &context.mr_enclave {
-                            return future::Either::A(future::err(Error::new(
-                                "Secure channel initialization failed: MRENCLAVE mismatch",
-                            )));
-                        }
+    /// Resume a secure channel session from a session ticket.
+    ///
+    /// If the session cannot be resumed (e.g., because the ticket is not accepted), a
+    /// new session is established with a full handshake instead.
+    fn resume_secure_channel(
+        context: Arc<Mutex<Self>>,
+        request: api::ChannelResumeRequest,
+    ) -> ClientFuture<()> {
+        // Context moved into the closure (renamed for clarity).
+        let shared_context = context;
 
-                        // TODO: Other access control policy on enclave identity will go here.
+        let result = Self::call::<api::ChannelResumeRequest, api::ChannelResumeResponse>(
+            shared_context.clone(),
+            api::METHOD_CHANNEL_RESUME,
+            request,
+        ).then(move |response| -> ClientFuture<()> {
+            let resumed = {
+                let mut context = shared_context.lock().unwrap();
 
-                        // If we don't need to authenticate, we're done.
-                        if !client_authentication {
-                            return future::Either::A(future::ok(()));
-                        }
+                let result = match response {
+                    Ok(response) => context.secure_channel.resume(&response),
+                    Err(error) => Err(error),
+                };
 
-                        let mut request = api::ChannelAuthRequest::new();
-                        let credentials = match context.backend.get_credentials() {
-                                Some(credentials) => credentials,
-                                None => return future::Either::A(future::err(Error::new("Channel requires client authentication and backend has no credentials"))),
-                            };
-                        let bastpk = match context.secure_channel.get_authentication(
-                            &credentials.long_term_private_key,
-                            credentials.identity_proof,
-                        ) {
-                            Ok(bastpk) => bastpk,
-                            Err(e) => return future::Either::A(future::err(e)),
+                match result {
+                    Ok(()) => true,
+                    Err(_) => {
+                        // Start over with new short-term keys.
+                        context.secure_channel.clear_ticket();
+                        context.secure_channel.close();
+                        match context.secure_channel.reset() {
+                            Ok(()) => {}
+                            Err(error) => return Box::new(future::err(error)),
                         };
-                        request.set_boxed_authenticated_short_term_public_key(bastpk);
-                        request
+
+                        false
+                    }
+                }
+            };
+
+            if resumed {
+                Box::new(future::ok(()))
+            } else {
+                Self::handshake_secure_channel(shared_context)
+            }
+        });
+
+        Box::new(result)
+    }
+
+    /// Establish a new secure channel session with a full handshake.
+    ///
+    /// The secure channel must have been reset.
+    fn handshake_secure_channel(context: Arc<Mutex<Self>>) -> ClientFuture<()> {
+        // Context moved into the closure (renamed for clarity).
+        let shared_context = context;
+
+        let request = {
+            let context = shared_context.lock().unwrap();
+
+            let mut request = api::ChannelInitRequest::new();
+            request.set_short_term_public_key(
+                context.secure_channel.get_client_public_key().to_vec(),
+            );
+            if let Some(ratchet) = context.secure_channel.get_ratchet_parameters() {
+                request.set_ratchet(ratchet.clone());
+            }
+            request
+        };
+
+        // Call remote channel init.
+        let result = Self::call::<api::ChannelInitRequest, api::ChannelInitResponse>(
+            shared_context.clone(),
+            api::METHOD_CHANNEL_INIT,
+            request,
+        ).and_then(move |response: api::ChannelInitResponse| {
+            // Return is futures::future::Either. A is immediate return. B is request.
+
+            let request = {
+                let mut context = shared_context.lock().unwrap();
+                let client_authentication = context.client_authentication;
+
+                // Verify contract identity and set up a secure channel.
+                let ratchet = if response.has_ratchet() {
+                    Some(response.get_ratchet())
+                } else {
+                    None
+                };
+                let iai = match context.secure_channel.setup(
+                    response.get_authenticated_short_term_public_key(),
+                    ratchet,
+                    client_authentication,
+                ) {
+                    Ok(iai) => iai,
+                    Err(e) => return future::Either::A(future::err(e)),
+                };
+
+                // Verify MRENCLAVE.
+                if &iai.mr_enclave != &context.mr_enclave {
+                    return future::Either::A(future::err(Error::new(
+                        "Secure channel initialization failed: MRENCLAVE mismatch",
+                    )));
+                }
+
+                // TODO: Other access control policy on enclave identity will go here.
+
+                if response.has_ticket() {
+                    context.secure_channel.set_ticket(response.get_ticket());
+                }
+
+                // If we don't need to authenticate, we're done.
+                if !client_authentication {
+                    return future::Either::A(future::ok(()));
+                }
+
+                let mut request = api::ChannelAuthRequest::new();
+                let credentials = match context.backend.get_credentials() {
+                        Some(credentials) => credentials,
+                        None => return future::Either::A(future::err(Error::new("Channel requires client authentication and backend has no credentials"))),
                     };
+                let bastpk = match context.secure_channel.get_authentication(
+                    &credentials.long_term_private_key,
+                    credentials.identity_proof,
+                ) {
+                    Ok(bastpk) => bastpk,
+                    Err(e) => return future::Either::A(future::err(e)),
+                };
+                request.set_boxed_authenticated_short_term_public_key(bastpk);
+                request
+            };
 
-                    // Call remote channel auth.
-                    future::Either::B(
-                        Self::call::<api::ChannelAuthRequest, api::ChannelAuthResponse>(
-                            shared_context.clone(),
-                            api::METHOD_CHANNEL_AUTH,
-                            request,
-                        ).and_then(
-                            move |_response: api::ChannelAuthResponse| {
-                                let mut context = shared_context.lock().unwrap();
-
-                                context.secure_channel.authentication_sent()
-                            },
-                        ),
-                    )
+            // Call remote channel auth.
+            future::Either::B(
+                Self::call::<api::ChannelAuthRequest, api::ChannelAuthResponse>(
+                    shared_context.clone(),
+                    api::METHOD_CHANNEL_AUTH,
+                    request,
+                ).and_then(move |response: api::ChannelAuthResponse| {
+                    let mut context = shared_context.lock().unwrap();
+
+                    // The ticket issued after authentication also resumes the
+                    // authentication.
+                    if response.has_ticket() {
+                        context.secure_channel.set_ticket(response.get_ticket());
+                    }
+
+                    context.secure_channel.authentication_sent()
                 }),
             )
         });
@@ -482,6 +682,20 @@ impl<Backend: ContractClientBackend + 'static> ContractClient<Backend> {
         Box::new(result)
     }
 
+    /// Request forward-secure key ratcheting for the secure channel.
+    ///
+    /// A new key epoch is started after `message_interval` requests or after
+    /// `time_interval` seconds, where zero means no limit. This takes effect when the
+    /// secure channel is next initialized. Contracts which do not support key ratcheting
+    /// ignore the request.
+    pub fn set_key_ratchet(&self, message_interval: u64, time_interval: u64) {
+        let mut context = self.context.lock().unwrap();
+
+        context
+            .secure_channel
+            .set_ratchet_parameters(message_interval, time_interval);
+    }
+
     /// Initialize a secure channel with the contract.
     ///
     /// If this method is not called, secure channel is automatically initialized
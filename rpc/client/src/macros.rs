@@ -29,7 +29,8 @@ macro_rules! create_client_rpc {
         }
 
         $(
-            rpc $method_name: ident ( $request_type: ty ) -> $response_type: ty ;
+            rpc $method_name: ident ( $request_type: ty ) -> $response_type: ty
+                $( where $( $policy: expr ),+ )* ;
         )*
     ) => {
         mod $output_module {
@@ -57,6 +58,15 @@ macro_rules! create_client_rpc {
                     }
                 }
 
+                /// Request forward-secure key ratcheting for the secure channel.
+                ///
+                /// A new key epoch is started after `message_interval` requests or after
+                /// `time_interval` seconds, where zero means no limit. This takes effect
+                /// when the secure channel is next initialized.
+                pub fn set_key_ratchet(&self, message_interval: u64, time_interval: u64) {
+                    self.client.set_key_ratchet(message_interval, time_interval)
+                }
+
                 /// Initialize a secure channel with the contract.
                 ///
                 /// If this method is not called, secure channel is automatically initialized
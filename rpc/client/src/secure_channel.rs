@@ -1,3 +1,7 @@
+#[cfg(not(target_env = "sgx"))]
+use std::time::Instant;
+use std::time::Duration;
+
 use sodalite;
 
 use protobuf;
@@ -7,10 +11,13 @@ use ekiden_common::error::{Error, Result};
 use ekiden_common::random;
 use ekiden_enclave_common;
 use ekiden_rpc_common::api;
-use ekiden_rpc_common::secure_channel::{create_box, open_box, MonotonicNonceGenerator,
-                                        NonceGenerator, RandomNonceGenerator, SessionState,
-                                        NONCE_CONTEXT_AUTHIN, NONCE_CONTEXT_AUTHOUT,
-                                        NONCE_CONTEXT_INIT, NONCE_CONTEXT_REQUEST,
+use ekiden_rpc_common::secure_channel::{clear_key, create_box, create_box_with_key,
+                                        derive_resumed_shared_key, derive_resumption_secret,
+                                        open_box, open_box_with_key, KeyRatchet,
+                                        MonotonicNonceGenerator, NonceGenerator,
+                                        RandomNonceGenerator, SessionState, NONCE_CONTEXT_AUTHIN,
+                                        NONCE_CONTEXT_AUTHOUT, NONCE_CONTEXT_INIT,
+                                        NONCE_CONTEXT_LEN, NONCE_CONTEXT_REQUEST,
                                         NONCE_CONTEXT_RESPONSE};
 
 // Secret seed used for generating private and public keys.
@@ -23,7 +30,7 @@ type SecretSeed = [u8; SECRET_SEED_LEN];
 /// contract.
 #[derive(Default)]
 pub struct SecureChannelContext {
-    /// Client short-term private key.
+    /// Client short-term private key (cleared once the session is established).
     client_private_key: sodalite::BoxSecretKey,
     /// Client short-term public key.
     client_public_key: sodalite::BoxPublicKey,
@@ -31,7 +38,7 @@ pub struct SecureChannelContext {
     contract_long_term_public_key: sodalite::BoxPublicKey,
     /// Contract contract short-term public key.
     contract_short_term_public_key: sodalite::BoxPublicKey,
-    /// Cached shared key.
+    /// Shared key (cleared once a key ratchet has been derived from it).
     shared_key: Option<sodalite::SecretboxKey>,
     /// Session state.
     state: SessionState,
@@ -39,13 +46,27 @@ pub struct SecureChannelContext {
     long_term_nonce_generator: RandomNonceGenerator,
     /// Short-term nonce generator.
     short_term_nonce_generator: MonotonicNonceGenerator,
+    /// Key ratcheting parameters requested from the contract (if any).
+    ratchet_parameters: Option<api::RatchetParameters>,
+    /// Key ratchet (if negotiated).
+    ratchet: Option<KeyRatchet>,
+    /// Maximum duration of a key epoch (if any).
+    ratchet_time_interval: Option<Duration>,
+    /// Time at which the current key epoch started.
+    #[cfg(not(target_env = "sgx"))]
+    ratchet_epoch_started: Option<Instant>,
+    /// Last session ticket issued by the contract (if any).
+    ticket: Option<api::CryptoBox>,
+    /// Resumption secret of the session the ticket was issued for.
+    resumption_secret: Option<sodalite::SecretboxKey>,
 }
 
 impl SecureChannelContext {
     /// Reset secure channel context.
     ///
     /// Calling this function will generate new short-term keys for the client
-    /// and clear any contract public keys.
+    /// and clear any contract public keys. The session ticket is retained, so the
+    /// session can be resumed.
     pub fn reset(&mut self) -> Result<()> {
         // Generate new short-term key pair for the client.
         let mut seed: SecretSeed = [0u8; SECRET_SEED_LEN];
@@ -62,20 +83,50 @@ impl SecureChannelContext {
         self.contract_short_term_public_key = [0; sodalite::BOX_PUBLIC_KEY_LEN];
 
         // Clear session keys.
+        if let Some(ref mut shared_key) = self.shared_key {
+            clear_key(shared_key);
+        }
         self.shared_key = None;
 
         // Reset session nonce.
         self.short_term_nonce_generator.reset();
 
+        // Clear key ratchet.
+        self.ratchet = None;
+        self.ratchet_time_interval = None;
+
         self.state.transition_to(SessionState::Init)?;
 
         Ok(())
     }
 
+    /// Request key ratcheting when the secure channel is next initialized.
+    ///
+    /// A new key epoch is started after `message_interval` requests or after
+    /// `time_interval` seconds, where zero means no limit. Time-based ratcheting is
+    /// not available when the client runs inside an enclave.
+    pub fn set_ratchet_parameters(&mut self, message_interval: u64, time_interval: u64) {
+        let mut parameters = api::RatchetParameters::new();
+        parameters.set_message_interval(message_interval);
+        parameters.set_time_interval(time_interval);
+
+        self.ratchet_parameters = Some(parameters);
+    }
+
+    /// Get key ratcheting parameters to request from the contract (if any).
+    pub fn get_ratchet_parameters(&self) -> Option<&api::RatchetParameters> {
+        self.ratchet_parameters.as_ref()
+    }
+
     /// Setup secure channel.
+    ///
+    /// The key ratcheting parameters are those accepted by the contract. Contracts which
+    /// do not support key ratcheting do not return any parameters, in which case the
+    /// session's shared key is used for all requests and responses.
     pub fn setup(
         &mut self,
         contract_astpk: &api::AuthenticatedShortTermPublicKey,
+        ratchet: Option<&api::RatchetParameters>,
         client_authentication_required: bool,
     ) -> Result<ekiden_enclave_common::quote::IdentityAuthenticatedInfo> {
         let iai = ekiden_enclave_common::quote::verify(contract_astpk.get_identity_proof())?;
@@ -103,18 +154,163 @@ impl SecureChannelContext {
             self.state.transition_to(SessionState::Established)?;
         }
 
-        // Cache shared channel key.
-        let mut key = self.shared_key
-            .get_or_insert([0u8; sodalite::SECRETBOX_KEY_LEN]);
+        // Derive shared channel key.
+        let mut key = [0u8; sodalite::SECRETBOX_KEY_LEN];
         sodalite::box_beforenm(
             &mut key,
             &self.contract_short_term_public_key,
             &self.client_private_key,
         );
 
+        // Any previous ticket is for a different session.
+        self.ticket = None;
+        self.resumption_secret = Some(derive_resumption_secret(&key));
+
+        self.set_shared_key(ratchet, key)?;
+
         Ok(iai)
     }
 
+    /// Store a session ticket issued by the contract, which can be used to resume the
+    /// current session.
+    pub fn set_ticket(&mut self, ticket: &api::CryptoBox) {
+        self.ticket = Some(ticket.clone());
+    }
+
+    /// Forget the session ticket, so that the next session is established with a full
+    /// handshake.
+    pub fn clear_ticket(&mut self) {
+        self.ticket = None;
+        self.resumption_secret = None;
+    }
+
+    /// Create a request for resuming the session from the session ticket (if any).
+    ///
+    /// The secure channel must have been reset, so that the resumed session uses new
+    /// short-term keys.
+    pub fn get_resumption_request(&self) -> Option<api::ChannelResumeRequest> {
+        let ticket = match (&self.ticket, &self.resumption_secret) {
+            (&Some(ref ticket), &Some(_)) => ticket.clone(),
+            _ => return None,
+        };
+
+        let mut request = api::ChannelResumeRequest::new();
+        request.set_ticket(ticket);
+        request.set_short_term_public_key(self.client_public_key.to_vec());
+
+        Some(request)
+    }
+
+    /// Resume secure channel.
+    ///
+    /// The contract is not authenticated again, as only the contract which issued the
+    /// ticket can derive the shared key of the resumed session.
+    pub fn resume(&mut self, response: &api::ChannelResumeResponse) -> Result<()> {
+        let resumption_secret = match self.resumption_secret {
+            Some(resumption_secret) => resumption_secret,
+            None => return Err(Error::new("No session ticket")),
+        };
+
+        if response.get_short_term_public_key().len() != sodalite::BOX_PUBLIC_KEY_LEN {
+            return Err(Error::new("Bad short-term contract key"));
+        }
+        self.contract_short_term_public_key
+            .copy_from_slice(response.get_short_term_public_key());
+
+        let key = derive_resumed_shared_key(
+            &resumption_secret,
+            &self.contract_short_term_public_key,
+            &self.client_private_key,
+        );
+
+        // Keep the new ticket for resuming the resumed session.
+        self.ticket = if response.has_ticket() {
+            Some(response.get_ticket().clone())
+        } else {
+            None
+        };
+        self.resumption_secret = Some(derive_resumption_secret(&key));
+
+        let ratchet = if response.has_ratchet() {
+            Some(response.get_ratchet())
+        } else {
+            None
+        };
+        self.set_shared_key(ratchet, key)?;
+
+        self.state.transition_to(SessionState::Established)?;
+
+        Ok(())
+    }
+
+    /// Set the shared key of a new session.
+    ///
+    /// If the contract accepted key ratcheting, requests and responses are encrypted
+    /// with ratcheted keys and the shared key is cleared once the key ratchet has been
+    /// derived from it. The short-term private key is cleared as well, as it is not
+    /// needed once the shared key has been derived.
+    fn set_shared_key(
+        &mut self,
+        ratchet: Option<&api::RatchetParameters>,
+        mut shared_key: sodalite::SecretboxKey,
+    ) -> Result<()> {
+        clear_key(&mut self.client_private_key);
+
+        let ratchet = match ratchet {
+            Some(ratchet) => ratchet,
+            None => {
+                self.shared_key = Some(shared_key);
+                return Ok(());
+            }
+        };
+
+        if self.ratchet_parameters.is_none() {
+            clear_key(&mut shared_key);
+            return Err(Error::new("Contract enabled key ratcheting without request"));
+        }
+
+        self.ratchet = Some(KeyRatchet::new(
+            &shared_key,
+            ratchet.get_message_interval(),
+            true,
+        ));
+        clear_key(&mut shared_key);
+        if ratchet.get_time_interval() > 0 {
+            self.ratchet_time_interval = Some(Duration::from_secs(ratchet.get_time_interval()));
+        }
+        self.restart_ratchet_epoch();
+
+        Ok(())
+    }
+
+    /// Check if the current key epoch has been used for longer than allowed.
+    #[cfg(not(target_env = "sgx"))]
+    fn is_ratchet_epoch_expired(&self) -> bool {
+        match (self.ratchet_time_interval, self.ratchet_epoch_started) {
+            (Some(interval), Some(started)) => started.elapsed() >= interval,
+            _ => false,
+        }
+    }
+
+    /// Check if the current key epoch has been used for longer than allowed.
+    ///
+    /// There is no trusted time source inside an enclave, so only message-based
+    /// ratcheting is used.
+    #[cfg(target_env = "sgx")]
+    fn is_ratchet_epoch_expired(&self) -> bool {
+        false
+    }
+
+    /// Record the start of a new key epoch.
+    #[cfg(not(target_env = "sgx"))]
+    fn restart_ratchet_epoch(&mut self) {
+        self.ratchet_epoch_started = Some(Instant::now());
+    }
+
+    /// Record the start of a new key epoch.
+    #[cfg(target_env = "sgx")]
+    fn restart_ratchet_epoch(&mut self) {}
+
     /// Generate a client authentication box.
     pub fn get_authentication(
         &mut self,
@@ -136,18 +332,35 @@ impl SecureChannelContext {
         astpk.set_identity_proof(identity_proof);
         astpk.set_boxed_short_term_public_key(box_inner);
         let astpk_bytes = astpk.write_to_bytes()?;
-        let mut box_outer = create_box(
-            &astpk_bytes,
-            &NONCE_CONTEXT_AUTHOUT,
-            &mut self.short_term_nonce_generator,
-            &self.contract_short_term_public_key,
-            &self.client_private_key,
-            &mut self.shared_key,
-        )?;
+        let mut box_outer = self.create_session_box(&astpk_bytes, &NONCE_CONTEXT_AUTHOUT, false)?;
         box_outer.set_public_key(self.client_public_key.to_vec());
         Ok(box_outer)
     }
 
+    /// Create cryptographic box for the contract, with the ratchet or the shared key.
+    fn create_session_box(
+        &mut self,
+        payload: &[u8],
+        nonce_context: &[u8; NONCE_CONTEXT_LEN],
+        new_epoch: bool,
+    ) -> Result<api::CryptoBox> {
+        match (&mut self.ratchet, &self.shared_key) {
+            (&mut Some(ref mut ratchet), _) => ratchet.create_box(
+                payload,
+                nonce_context,
+                &mut self.short_term_nonce_generator,
+                new_epoch,
+            ),
+            (&mut None, &Some(ref shared_key)) => create_box_with_key(
+                payload,
+                nonce_context,
+                &mut self.short_term_nonce_generator,
+                shared_key,
+            ),
+            _ => Err(Error::new("Secure channel has no keys")),
+        }
+    }
+
     /// Call this after sending the client authentication box.
     /// There's no response message to pass to this method.
     /// It transitions the channel to Established state.
@@ -187,14 +400,20 @@ impl SecureChannelContext {
         &mut self,
         request: &api::PlainClientRequest,
     ) -> Result<api::CryptoBox> {
-        let mut crypto_box = create_box(
-            &request.write_to_bytes()?,
-            &NONCE_CONTEXT_REQUEST,
-            &mut self.short_term_nonce_generator,
-            &self.contract_short_term_public_key,
-            &self.client_private_key,
-            &mut self.shared_key,
-        )?;
+        let new_epoch = self.is_ratchet_epoch_expired();
+        let epoch = self.ratchet
+            .as_ref()
+            .map(|ratchet| ratchet.get_send_epoch());
+        let request = request.write_to_bytes()?;
+        let mut crypto_box =
+            self.create_session_box(&request, &NONCE_CONTEXT_REQUEST, new_epoch)?;
+
+        let next_epoch = self.ratchet
+            .as_ref()
+            .map(|ratchet| ratchet.get_send_epoch());
+        if next_epoch != epoch {
+            self.restart_ratchet_epoch();
+        }
 
         // Set public key so the contract knows which client this is.
         crypto_box.set_public_key(self.client_public_key.to_vec());
@@ -207,14 +426,20 @@ impl SecureChannelContext {
         &mut self,
         response: &api::CryptoBox,
     ) -> Result<api::PlainClientResponse> {
-        let plain_response = open_box(
-            &response,
-            &NONCE_CONTEXT_RESPONSE,
-            &mut self.short_term_nonce_generator,
-            &self.contract_short_term_public_key,
-            &self.client_private_key,
-            &mut self.shared_key,
-        )?;
+        let plain_response = match (&mut self.ratchet, &self.shared_key) {
+            (&mut Some(ref mut ratchet), _) => ratchet.open_box(
+                &response,
+                &NONCE_CONTEXT_RESPONSE,
+                &mut self.short_term_nonce_generator,
+            )?,
+            (&mut None, &Some(ref shared_key)) => open_box_with_key(
+                &response,
+                &NONCE_CONTEXT_RESPONSE,
+                &mut self.short_term_nonce_generator,
+                shared_key,
+            )?,
+            _ => return Err(Error::new("Secure channel has no keys")),
+        };
 
         Ok(protobuf::parse_from_bytes(&plain_response)?)
     }
@@ -0,0 +1,226 @@
+//! Chunking of large request and response payloads.
+//!
+//! Payloads which are too large to be sent in a single message are split into chunks,
+//! which are sent in separate messages over the same secure channel session. This way
+//! the buffers used for passing messages in and out of the enclave only need to hold a
+//! single chunk.
+use std::mem;
+
+use ekiden_common::error::{Error, Result};
+
+use super::api;
+
+/// Maximum size of a payload which is sent in a single message. Larger payloads
+/// are chunked.
+pub const MAX_PAYLOAD_SIZE: usize = 256 * 1024;
+/// Size of payload chunks.
+pub const PAYLOAD_CHUNK_SIZE: usize = 128 * 1024;
+/// Maximum size of a chunked payload.
+pub const MAX_CHUNKED_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Check if a payload must be chunked.
+pub fn is_chunked(payload: &[u8]) -> bool {
+    payload.len() > MAX_PAYLOAD_SIZE
+}
+
+/// Split a payload into chunks.
+///
+/// An empty payload is split into a single empty chunk, so that every payload has a
+/// last chunk.
+pub fn split(payload: &[u8]) -> Result<Vec<api::PayloadChunk>> {
+    if payload.len() > MAX_CHUNKED_PAYLOAD_SIZE {
+        return Err(Error::new("Payload too large"));
+    }
+
+    if payload.is_empty() {
+        let mut chunk = api::PayloadChunk::new();
+        chunk.set_count(1);
+        return Ok(vec![chunk]);
+    }
+
+    let count = payload.chunks(PAYLOAD_CHUNK_SIZE).len() as u32;
+    let chunks = payload
+        .chunks(PAYLOAD_CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut chunk = api::PayloadChunk::new();
+            chunk.set_index(index as u32);
+            chunk.set_count(count);
+            chunk.set_data(data.to_vec());
+            chunk
+        })
+        .collect();
+
+    Ok(chunks)
+}
+
+/// Assembles a payload from its chunks, which must be added in order.
+#[derive(Default)]
+pub struct ChunkAssembler {
+    /// Payload assembled so far.
+    payload: Vec<u8>,
+    /// Index of the next expected chunk.
+    next_index: u32,
+    /// Total number of chunks (zero if no payload is being assembled).
+    count: u32,
+}
+
+impl ChunkAssembler {
+    /// Create a new chunk assembler.
+    pub fn new() -> Self {
+        ChunkAssembler::default()
+    }
+
+    /// Add the next chunk of the payload.
+    ///
+    /// The first chunk of a payload discards any incomplete previous payload. Returns
+    /// the assembled payload once its last chunk has been added.
+    pub fn add(&mut self, chunk: &api::PayloadChunk) -> Result<Option<Vec<u8>>> {
+        if chunk.get_index() == 0 {
+            self.payload.clear();
+            self.next_index = 0;
+            self.count = chunk.get_count();
+        }
+
+        if self.count == 0 || chunk.get_index() != self.next_index
+            || chunk.get_count() != self.count
+        {
+            self.reset();
+            return Err(Error::new("Unexpected payload chunk"));
+        }
+
+        if self.payload.len() + chunk.get_data().len() > MAX_CHUNKED_PAYLOAD_SIZE {
+            self.reset();
+            return Err(Error::new("Payload too large"));
+        }
+
+        self.payload.extend_from_slice(chunk.get_data());
+        self.next_index += 1;
+
+        if self.next_index < self.count {
+            return Ok(None);
+        }
+
+        let payload = mem::replace(&mut self.payload, vec![]);
+        self.reset();
+
+        Ok(Some(payload))
+    }
+
+    /// Number of bytes of the incomplete payload.
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Check if no bytes of an incomplete payload are held.
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    /// Discard any incomplete payload.
+    pub fn reset(&mut self) {
+        self.payload = vec![];
+        self.next_index = 0;
+        self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::api;
+    use super::*;
+
+    /// Split a payload and assemble it again.
+    fn round_trip(payload: &[u8]) -> Vec<u8> {
+        let mut assembler = ChunkAssembler::new();
+        let chunks = split(payload).unwrap();
+        let (last, chunks) = chunks.split_last().unwrap();
+        for chunk in chunks {
+            assert_eq!(assembler.add(chunk).unwrap(), None);
+        }
+
+        assembler.add(last).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let payload: Vec<u8> = (0..3 * PAYLOAD_CHUNK_SIZE + 1)
+            .map(|index| index as u8)
+            .collect();
+
+        let chunks = split(&payload).unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.get_count() == 4));
+        assert_eq!(round_trip(&payload), payload);
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let chunks = split(&[]).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].get_count(), 1);
+        assert_eq!(round_trip(&[]), Vec::<u8>::new());
+
+        // A chunk of a payload without chunks is rejected.
+        let mut assembler = ChunkAssembler::new();
+        let chunk = api::PayloadChunk::new();
+        assert_eq!(
+            assembler.add(&chunk).unwrap_err().message,
+            "Unexpected payload chunk"
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_chunk() {
+        let chunks = split(&vec![1; 3 * PAYLOAD_CHUNK_SIZE]).unwrap();
+        let mut assembler = ChunkAssembler::new();
+
+        assembler.add(&chunks[0]).unwrap();
+        assert_eq!(
+            assembler.add(&chunks[2]).unwrap_err().message,
+            "Unexpected payload chunk"
+        );
+        assert!(assembler.is_empty());
+
+        // The incomplete payload was discarded, so the client has to start over.
+        assert!(assembler.add(&chunks[1]).is_err());
+        for chunk in &chunks[..2] {
+            assert_eq!(assembler.add(chunk).unwrap(), None);
+        }
+        assert_eq!(assembler.add(&chunks[2]).unwrap().unwrap().len(), 3 * PAYLOAD_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_count_mismatch() {
+        let mut chunks = split(&vec![1; 2 * PAYLOAD_CHUNK_SIZE]).unwrap();
+        let mut assembler = ChunkAssembler::new();
+
+        assembler.add(&chunks[0]).unwrap();
+        chunks[1].set_count(3);
+        assert_eq!(
+            assembler.add(&chunks[1]).unwrap_err().message,
+            "Unexpected payload chunk"
+        );
+        assert!(assembler.is_empty());
+    }
+
+    #[test]
+    fn test_oversize_payload() {
+        let payload = vec![0; MAX_CHUNKED_PAYLOAD_SIZE + 1];
+        assert_eq!(split(&payload).unwrap_err().message, "Payload too large");
+
+        // Chunks which add up to more than the maximum size are rejected as well.
+        let mut assembler = ChunkAssembler::new();
+        let mut chunk = api::PayloadChunk::new();
+        chunk.set_count(2);
+        chunk.set_data(payload[..MAX_CHUNKED_PAYLOAD_SIZE / 2 + 1].to_vec());
+        assert_eq!(assembler.add(&chunk).unwrap(), None);
+
+        chunk.set_index(1);
+        assert_eq!(
+            assembler.add(&chunk).unwrap_err().message,
+            "Payload too large"
+        );
+        assert!(assembler.is_empty());
+    }
+}
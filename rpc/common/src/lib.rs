@@ -6,6 +6,7 @@ extern crate sodalite;
 extern crate ekiden_common;
 extern crate ekiden_enclave_common;
 
+pub mod chunking;
 pub mod reflection;
 pub mod secure_channel;
 pub mod client;
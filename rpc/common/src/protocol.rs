@@ -2,5 +2,11 @@
 pub const METHOD_CHANNEL_INIT: &'static str = "_channel_init";
 /// Secure channel client authentication request.
 pub const METHOD_CHANNEL_AUTH: &'static str = "_channel_auth";
+/// Secure channel resumption request.
+pub const METHOD_CHANNEL_RESUME: &'static str = "_channel_resume";
 /// Secure channel teardown request.
 pub const METHOD_CHANNEL_CLOSE: &'static str = "_channel_close";
+/// Chunk of a request payload.
+pub const METHOD_REQUEST_CHUNK: &'static str = "_request_chunk";
+/// Chunk of a response payload.
+pub const METHOD_RESPONSE_CHUNK: &'static str = "_response_chunk";
@@ -1,3 +1,18 @@
+use ekiden_enclave_common::quote::MrEnclave;
+
+/// Authorization policy of an RPC API method, which restricts the clients that may
+/// call the method.
+#[derive(Debug, Clone)]
+pub enum AuthorizationPolicy {
+    /// The client must be attested.
+    ClientAttestation,
+    /// The client must be attested with one of the given MRENCLAVEs.
+    ClientMrEnclave(Vec<MrEnclave>),
+    /// The client must be attested with a long-term public key which is in the admin
+    /// set of the contract.
+    Admin,
+}
+
 /// Descriptor of an RPC API method.
 pub struct ApiMethodDescriptor {
     /// Method name.
@@ -5,4 +20,6 @@ pub struct ApiMethodDescriptor {
     /// Whether the method call requires the client to be attested and therefore
     /// the method handler can assume client's MRENCLAVE is available.
     pub client_attestation_required: bool,
+    /// Authorization policies of the method, which must all be satisfied by the client.
+    pub policies: Vec<AuthorizationPolicy>,
 }
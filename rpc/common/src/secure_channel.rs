@@ -1,4 +1,7 @@
 //! Common structures for secure channels.
+use std::mem;
+use std::ptr;
+
 use byteorder::{ByteOrder, LittleEndian};
 
 use sodalite;
@@ -21,6 +24,26 @@ pub const NONCE_CONTEXT_AUTHOUT: NonceContext = *b"EkidenS--AuthOut";
 pub const NONCE_CONTEXT_REQUEST: NonceContext = *b"EkidenS--Request";
 /// Nonce for use in response context.
 pub const NONCE_CONTEXT_RESPONSE: NonceContext = *b"EkidenS-Response";
+/// Nonce for use in session ticket context.
+pub const NONCE_CONTEXT_TICKET: NonceContext = *b"EkidenS---Ticket";
+
+/// Number of low bits of a nonce counter which count the messages sent under the same
+/// key epoch, when key ratcheting is used. The remaining bits hold the key epoch.
+const RATCHET_EPOCH_SHIFT: u32 = 32;
+/// Maximum number of messages which may be sent under the same key epoch.
+pub const RATCHET_MAX_MESSAGE_INTERVAL: u64 = 1 << RATCHET_EPOCH_SHIFT;
+/// Maximum number of key epochs that a received message may advance the key chain by.
+const RATCHET_MAX_SKIP: u64 = 1024;
+/// Domain separation prefix for deriving the initial chain key of a direction.
+const RATCHET_CONTEXT_INIT: &'static [u8] = b"EkidenS-RatchetI";
+/// Domain separation prefix for deriving the chain key of the next epoch.
+const RATCHET_CONTEXT_CHAIN: &'static [u8] = b"EkidenS-RatchetC";
+/// Domain separation prefix for deriving the message key of an epoch.
+const RATCHET_CONTEXT_KEY: &'static [u8] = b"EkidenS-RatchetK";
+/// Domain separation prefix for deriving the resumption secret of a session.
+const RESUME_CONTEXT_SECRET: &'static [u8] = b"EkidenS-ResumeSc";
+/// Domain separation prefix for deriving the shared key of a resumed session.
+const RESUME_CONTEXT_SESSION: &'static [u8] = b"EkidenS-ResumeSn";
 
 /// Nonce generator.
 pub trait NonceGenerator {
@@ -31,8 +54,11 @@ pub trait NonceGenerator {
     fn get_nonce(&mut self, context: &NonceContext) -> Result<sodalite::BoxNonce>;
 
     /// Unpack nonce from a cryptographic box.
+    ///
+    /// The nonce is only checked, as the box may not be authentic. It must be recorded
+    /// with `accept_nonce` once the box has been opened.
     fn unpack_nonce(
-        &mut self,
+        &self,
         crypto_box: &api::CryptoBox,
         context: &NonceContext,
     ) -> Result<sodalite::BoxNonce> {
@@ -46,6 +72,11 @@ pub trait NonceGenerator {
 
         Ok(nonce)
     }
+
+    /// Record the nonce of an authentic box.
+    fn accept_nonce(&mut self, _nonce: &sodalite::BoxNonce) {
+        // Nothing to record by default.
+    }
 }
 
 /// Random nonce generator.
@@ -95,6 +126,21 @@ impl MonotonicNonceGenerator {
             last_received_nonce: None,
         }
     }
+
+    /// Counter of the next nonce to be sent.
+    pub fn get_next_counter(&self) -> u64 {
+        self.next_send_nonce
+    }
+
+    /// Skip sent nonces up to the given counter.
+    ///
+    /// Nonces are never reused, so this does nothing if the counter has already been
+    /// passed.
+    pub fn skip_to(&mut self, counter: u64) {
+        if counter > self.next_send_nonce {
+            self.next_send_nonce = counter;
+        }
+    }
 }
 
 impl NonceGenerator for MonotonicNonceGenerator {
@@ -120,7 +166,7 @@ impl NonceGenerator for MonotonicNonceGenerator {
     }
 
     fn unpack_nonce(
-        &mut self,
+        &self,
         crypto_box: &api::CryptoBox,
         context: &NonceContext,
     ) -> Result<sodalite::BoxNonce> {
@@ -145,10 +191,13 @@ impl NonceGenerator for MonotonicNonceGenerator {
             None => {}
         }
 
-        self.last_received_nonce = Some(counter_value);
-
         Ok(nonce)
     }
+
+    /// Record the nonce of an authentic box, so that it cannot be replayed.
+    fn accept_nonce(&mut self, nonce: &sodalite::BoxNonce) {
+        self.last_received_nonce = Some(LittleEndian::read_u64(&nonce[NONCE_CONTEXT_LEN..]));
+    }
 }
 
 impl Default for MonotonicNonceGenerator {
@@ -157,6 +206,18 @@ impl Default for MonotonicNonceGenerator {
     }
 }
 
+/// Decode the counter of a nonce generated by [`MonotonicNonceGenerator`].
+///
+/// [`MonotonicNonceGenerator`]: self::MonotonicNonceGenerator
+pub fn get_nonce_counter(crypto_box: &api::CryptoBox) -> Result<u64> {
+    let nonce = crypto_box.get_nonce();
+    if nonce.len() != sodalite::BOX_NONCE_LEN {
+        return Err(Error::new("Invalid nonce"));
+    }
+
+    Ok(LittleEndian::read_u64(&nonce[NONCE_CONTEXT_LEN..]))
+}
+
 /// Current state of the secure channel session.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SessionState {
@@ -209,6 +270,21 @@ impl Default for SessionState {
     }
 }
 
+/// Compute the shared key of a key pair if it is not cached yet.
+fn get_shared_key(
+    public_key: &sodalite::BoxPublicKey,
+    private_key: &sodalite::BoxSecretKey,
+    shared_key: &mut Option<sodalite::SecretboxKey>,
+) -> sodalite::SecretboxKey {
+    if shared_key.is_none() {
+        // Compute shared key so we can speed up subsequent box operations.
+        let mut key = shared_key.get_or_insert([0u8; sodalite::SECRETBOX_KEY_LEN]);
+        sodalite::box_beforenm(&mut key, &public_key, &private_key);
+    }
+
+    shared_key.unwrap()
+}
+
 /// Create cryptographic box (encrypted and authenticated).
 pub fn create_box<NG: NonceGenerator>(
     payload: &[u8],
@@ -217,6 +293,18 @@ pub fn create_box<NG: NonceGenerator>(
     public_key: &sodalite::BoxPublicKey,
     private_key: &sodalite::BoxSecretKey,
     shared_key: &mut Option<sodalite::SecretboxKey>,
+) -> Result<api::CryptoBox> {
+    let key = get_shared_key(public_key, private_key, shared_key);
+
+    create_box_with_key(payload, nonce_context, nonce_generator, &key)
+}
+
+/// Create cryptographic box (encrypted and authenticated) with a shared key.
+pub fn create_box_with_key<NG: NonceGenerator>(
+    payload: &[u8],
+    nonce_context: &NonceContext,
+    nonce_generator: &mut NG,
+    shared_key: &sodalite::SecretboxKey,
 ) -> Result<api::CryptoBox> {
     let mut crypto_box = api::CryptoBox::new();
     let mut key_with_payload = vec![0u8; payload.len() + 32];
@@ -227,18 +315,7 @@ pub fn create_box<NG: NonceGenerator>(
     // room for it. The box_ method also requires that it is zero-initialized.
     key_with_payload[32..].copy_from_slice(payload);
 
-    if shared_key.is_none() {
-        // Compute shared key so we can speed up subsequent box operations.
-        let mut key = shared_key.get_or_insert([0u8; sodalite::SECRETBOX_KEY_LEN]);
-        sodalite::box_beforenm(&mut key, &public_key, &private_key);
-    }
-
-    match sodalite::box_afternm(
-        &mut encrypted,
-        &key_with_payload,
-        &nonce,
-        &shared_key.unwrap(),
-    ) {
+    match sodalite::box_afternm(&mut encrypted, &key_with_payload, &nonce, shared_key) {
         Ok(_) => {}
         _ => return Err(Error::new("Box operation failed")),
     };
@@ -257,23 +334,29 @@ pub fn open_box<NG: NonceGenerator>(
     public_key: &sodalite::BoxPublicKey,
     private_key: &sodalite::BoxSecretKey,
     shared_key: &mut Option<sodalite::SecretboxKey>,
+) -> Result<Vec<u8>> {
+    let key = get_shared_key(public_key, private_key, shared_key);
+
+    open_box_with_key(crypto_box, nonce_context, nonce_generator, &key)
+}
+
+/// Open cryptographic box with a shared key.
+pub fn open_box_with_key<NG: NonceGenerator>(
+    crypto_box: &api::CryptoBox,
+    nonce_context: &NonceContext,
+    nonce_generator: &mut NG,
+    shared_key: &sodalite::SecretboxKey,
 ) -> Result<Vec<u8>> {
     // Reserve space for payload.
     let mut payload = vec![0u8; crypto_box.get_payload().len()];
+    let nonce = nonce_generator.unpack_nonce(&crypto_box, &nonce_context)?;
 
-    if shared_key.is_none() {
-        // Compute shared key so we can speed up subsequent box operations.
-        let mut key = shared_key.get_or_insert([0u8; sodalite::SECRETBOX_KEY_LEN]);
-        sodalite::box_beforenm(&mut key, &public_key, &private_key);
-    }
-
-    match sodalite::box_open_afternm(
-        &mut payload,
-        &crypto_box.get_payload(),
-        &nonce_generator.unpack_nonce(&crypto_box, &nonce_context)?,
-        &shared_key.unwrap(),
-    ) {
+    match sodalite::box_open_afternm(&mut payload, &crypto_box.get_payload(), &nonce, shared_key) {
         Ok(_) => {
+            // Only record the nonce once the box is known to be authentic, so forged
+            // boxes cannot block later messages.
+            nonce_generator.accept_nonce(&nonce);
+
             // Trim first all-zero 32 bytes that were used to allocate space for the shared
             // secret key.
             Ok(payload[32..].to_vec())
@@ -281,3 +364,401 @@ pub fn open_box<NG: NonceGenerator>(
         _ => Err(Error::new("Failed to open box")),
     }
 }
+
+/// Overwrite key material with zeros.
+///
+/// The writes are volatile, so they are not optimized away even if the key is not used
+/// afterwards.
+pub fn clear_key(key: &mut [u8]) {
+    for byte in key.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// Derive a key from secret data, separated by the given context.
+fn derive_key(context: &[u8], secret: &[u8]) -> sodalite::SecretboxKey {
+    let mut data = Vec::with_capacity(context.len() + secret.len());
+    data.extend_from_slice(context);
+    data.extend_from_slice(secret);
+
+    let mut digest = [0; sodalite::HASH_LEN];
+    sodalite::hash(&mut digest, &data);
+
+    let mut result = [0; sodalite::SECRETBOX_KEY_LEN];
+    result.copy_from_slice(&digest[..sodalite::SECRETBOX_KEY_LEN]);
+    result
+}
+
+/// Derive the resumption secret of a session from its shared key.
+///
+/// The resumption secret is sealed into the session tickets issued by the contract.
+pub fn derive_resumption_secret(shared_key: &sodalite::SecretboxKey) -> sodalite::SecretboxKey {
+    derive_key(RESUME_CONTEXT_SECRET, shared_key)
+}
+
+/// Derive the shared key of a resumed session.
+///
+/// The key depends on both the resumption secret of the previous session and the new
+/// short-term keys, so it can only be derived by the parties of the previous session
+/// and it is fresh for each resumption.
+pub fn derive_resumed_shared_key(
+    resumption_secret: &sodalite::SecretboxKey,
+    public_key: &sodalite::BoxPublicKey,
+    private_key: &sodalite::BoxSecretKey,
+) -> sodalite::SecretboxKey {
+    let mut shared_key = [0u8; sodalite::SECRETBOX_KEY_LEN];
+    sodalite::box_beforenm(&mut shared_key, public_key, private_key);
+
+    let mut secret = resumption_secret.to_vec();
+    secret.extend_from_slice(&shared_key);
+
+    derive_key(RESUME_CONTEXT_SESSION, &secret)
+}
+
+/// Key chain for the messages sent in one direction of a secure channel session.
+///
+/// The chain key of each epoch is derived from the chain key of the previous epoch
+/// by a one-way function, and the key which messages are encrypted with is derived
+/// from the chain key. Keys of previous epochs are cleared when the chain advances, so
+/// a leaked message key only exposes the messages of its epoch. The session's shared
+/// key must be cleared once the chains have been created, as all chain keys can be
+/// derived from it.
+#[derive(Clone)]
+pub struct KeyChain {
+    /// Current key epoch.
+    epoch: u64,
+    /// Chain key of the current epoch.
+    chain_key: sodalite::SecretboxKey,
+    /// Message key of the current epoch.
+    key: sodalite::SecretboxKey,
+}
+
+impl KeyChain {
+    /// Create a new key chain from the session's shared key, for messages which use
+    /// the given nonce context.
+    pub fn new(shared_key: &sodalite::SecretboxKey, nonce_context: &NonceContext) -> Self {
+        let mut context = RATCHET_CONTEXT_INIT.to_vec();
+        context.extend_from_slice(nonce_context);
+
+        let mut chain_key = derive_key(&context, shared_key);
+        let chain = KeyChain {
+            epoch: 0,
+            chain_key: chain_key,
+            key: derive_key(RATCHET_CONTEXT_KEY, &chain_key),
+        };
+        clear_key(&mut chain_key);
+
+        chain
+    }
+
+    /// Current key epoch.
+    pub fn get_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Key chain advanced to the given epoch.
+    ///
+    /// The chain cannot go back to previous epochs.
+    pub fn advance(&self, epoch: u64) -> Result<KeyChain> {
+        if epoch < self.epoch {
+            return Err(Error::new("Key epoch has already passed"));
+        }
+        if epoch - self.epoch > RATCHET_MAX_SKIP {
+            return Err(Error::new("Key epoch is too far ahead"));
+        }
+
+        let mut chain_key = self.chain_key;
+        for _ in self.epoch..epoch {
+            let mut next_chain_key = derive_key(RATCHET_CONTEXT_CHAIN, &chain_key);
+            mem::swap(&mut chain_key, &mut next_chain_key);
+            clear_key(&mut next_chain_key);
+        }
+
+        let key = derive_key(RATCHET_CONTEXT_KEY, &chain_key);
+        let chain = KeyChain {
+            epoch: epoch,
+            chain_key: chain_key,
+            key: key,
+        };
+        clear_key(&mut chain_key);
+
+        Ok(chain)
+    }
+}
+
+impl Drop for KeyChain {
+    fn drop(&mut self) {
+        clear_key(&mut self.chain_key);
+        clear_key(&mut self.key);
+    }
+}
+
+/// Forward-secure key ratchet of a secure channel session.
+///
+/// Requests and responses are encrypted with keys of separate [`KeyChain`]s instead
+/// of the session's shared key. The client authentication box is sent as a request.
+/// The key epoch of a message is stored in the high bits of its nonce counter, so the
+/// receiver can derive its key. The sender moves to a new epoch after a configured
+/// number of messages or when explicitly requested (e.g., after some time has passed),
+/// by skipping the nonce counter to the next epoch.
+///
+/// [`KeyChain`]: self::KeyChain
+pub struct KeyRatchet {
+    /// Maximum number of messages sent under each key epoch (zero if unlimited).
+    message_interval: u64,
+    /// Chain for messages sent by this side.
+    send_chain: KeyChain,
+    /// Chain for messages received by this side.
+    receive_chain: KeyChain,
+}
+
+impl KeyRatchet {
+    /// Create a new key ratchet.
+    ///
+    /// The chains for sending and receiving are selected based on whether this side of
+    /// the session is the client. The caller must clear the shared key afterwards.
+    pub fn new(shared_key: &sodalite::SecretboxKey, message_interval: u64, client: bool) -> Self {
+        let request_chain = KeyChain::new(shared_key, &NONCE_CONTEXT_REQUEST);
+        let response_chain = KeyChain::new(shared_key, &NONCE_CONTEXT_RESPONSE);
+        let (send_chain, receive_chain) = if client {
+            (request_chain, response_chain)
+        } else {
+            (response_chain, request_chain)
+        };
+
+        KeyRatchet {
+            message_interval: message_interval,
+            send_chain: send_chain,
+            receive_chain: receive_chain,
+        }
+    }
+
+    /// Current key epoch of sent messages.
+    pub fn get_send_epoch(&self) -> u64 {
+        self.send_chain.get_epoch()
+    }
+
+    /// Current key epoch of received messages.
+    pub fn get_receive_epoch(&self) -> u64 {
+        self.receive_chain.get_epoch()
+    }
+
+    /// Move sent messages to at least the given key epoch.
+    pub fn advance_send_epoch(
+        &mut self,
+        epoch: u64,
+        nonce_generator: &mut MonotonicNonceGenerator,
+    ) -> Result<()> {
+        if epoch > self.send_chain.get_epoch() {
+            self.send_chain = self.send_chain.advance(epoch)?;
+            nonce_generator.skip_to(epoch << RATCHET_EPOCH_SHIFT);
+        }
+
+        Ok(())
+    }
+
+    /// Create cryptographic box with the key of the current send epoch.
+    ///
+    /// If `new_epoch` is true or the message limit of the current epoch has been
+    /// reached, the box is created under the next epoch.
+    pub fn create_box(
+        &mut self,
+        payload: &[u8],
+        nonce_context: &NonceContext,
+        nonce_generator: &mut MonotonicNonceGenerator,
+        new_epoch: bool,
+    ) -> Result<api::CryptoBox> {
+        let counter = nonce_generator.get_next_counter();
+        let mut epoch = counter >> RATCHET_EPOCH_SHIFT;
+        let index = counter & (RATCHET_MAX_MESSAGE_INTERVAL - 1);
+        if new_epoch || (self.message_interval > 0 && index >= self.message_interval) {
+            epoch += 1;
+        }
+        self.advance_send_epoch(epoch, nonce_generator)?;
+
+        create_box_with_key(
+            payload,
+            nonce_context,
+            nonce_generator,
+            &self.send_chain.key,
+        )
+    }
+
+    /// Open cryptographic box with the key of its epoch.
+    ///
+    /// The receive chain is only advanced if the box is authentic.
+    pub fn open_box(
+        &mut self,
+        crypto_box: &api::CryptoBox,
+        nonce_context: &NonceContext,
+        nonce_generator: &mut MonotonicNonceGenerator,
+    ) -> Result<Vec<u8>> {
+        let epoch = get_nonce_counter(crypto_box)? >> RATCHET_EPOCH_SHIFT;
+        let chain = self.receive_chain.advance(epoch)?;
+        let payload = open_box_with_key(crypto_box, nonce_context, nonce_generator, &chain.key)?;
+
+        self.receive_chain = chain;
+
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client and contract key ratchets of the same session.
+    fn ratchets(message_interval: u64) -> (KeyRatchet, KeyRatchet) {
+        let shared_key = [7u8; sodalite::SECRETBOX_KEY_LEN];
+
+        (
+            KeyRatchet::new(&shared_key, message_interval, true),
+            KeyRatchet::new(&shared_key, message_interval, false),
+        )
+    }
+
+    /// Send a request from the client to the contract.
+    fn send(
+        client: &mut KeyRatchet,
+        client_nonces: &mut MonotonicNonceGenerator,
+        new_epoch: bool,
+    ) -> api::CryptoBox {
+        client
+            .create_box(b"request", &NONCE_CONTEXT_REQUEST, client_nonces, new_epoch)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ratchet_steps() {
+        let (mut client, mut contract) = ratchets(2);
+        let mut client_nonces = MonotonicNonceGenerator::new();
+        let mut contract_nonces = MonotonicNonceGenerator::new();
+
+        // A new epoch is started after the message interval.
+        for &epoch in [0, 0, 1, 1, 2].iter() {
+            let request = send(&mut client, &mut client_nonces, false);
+            assert_eq!(client.get_send_epoch(), epoch);
+
+            let payload = contract
+                .open_box(&request, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+                .unwrap();
+            assert_eq!(payload, b"request".to_vec());
+            assert_eq!(contract.get_receive_epoch(), epoch);
+        }
+
+        // A new epoch may be requested before the interval is reached.
+        send(&mut client, &mut client_nonces, true);
+        assert_eq!(client.get_send_epoch(), 3);
+
+        // The contract follows the epochs of the client in its responses.
+        contract
+            .advance_send_epoch(2, &mut contract_nonces)
+            .unwrap();
+        let response = contract
+            .create_box(b"response", &NONCE_CONTEXT_RESPONSE, &mut contract_nonces, false)
+            .unwrap();
+        let payload = client
+            .open_box(&response, &NONCE_CONTEXT_RESPONSE, &mut client_nonces)
+            .unwrap();
+        assert_eq!(payload, b"response".to_vec());
+        assert_eq!(client.get_receive_epoch(), 2);
+
+        // Epochs too far ahead are rejected.
+        assert!(client.send_chain.advance(3 + RATCHET_MAX_SKIP + 1).is_err());
+        assert!(client.send_chain.advance(2).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let (mut client, mut contract) = ratchets(2);
+        let mut client_nonces = MonotonicNonceGenerator::new();
+        let mut contract_nonces = MonotonicNonceGenerator::new();
+
+        let first = send(&mut client, &mut client_nonces, false);
+        let second = send(&mut client, &mut client_nonces, false);
+        let third = send(&mut client, &mut client_nonces, false);
+
+        contract
+            .open_box(&first, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+            .unwrap();
+        contract
+            .open_box(&third, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+            .unwrap();
+
+        // Replayed and reordered messages are rejected, also from earlier epochs.
+        assert!(
+            contract
+                .open_box(&third, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+                .is_err()
+        );
+        assert!(
+            contract
+                .open_box(&second, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+                .is_err()
+        );
+
+        // Messages are rejected in a different nonce context.
+        let fourth = send(&mut client, &mut client_nonces, false);
+        assert!(
+            contract
+                .open_box(&fourth, &NONCE_CONTEXT_RESPONSE, &mut contract_nonces)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_forged_nonce() {
+        let (mut client, mut contract) = ratchets(0);
+        let mut client_nonces = MonotonicNonceGenerator::new();
+        let mut contract_nonces = MonotonicNonceGenerator::new();
+
+        let request = send(&mut client, &mut client_nonces, false);
+
+        // A box with a forged nonce from a later epoch does not open, and it neither
+        // advances the key chain nor the received nonce.
+        let mut forged = request.clone();
+        let mut nonce = forged.get_nonce().to_vec();
+        LittleEndian::write_u64(&mut nonce[NONCE_CONTEXT_LEN..], 5 << RATCHET_EPOCH_SHIFT);
+        forged.set_nonce(nonce);
+        assert!(
+            contract
+                .open_box(&forged, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+                .is_err()
+        );
+        assert_eq!(contract.get_receive_epoch(), 0);
+
+        // The same holds for boxes opened with the shared key.
+        let shared_key = [7u8; sodalite::SECRETBOX_KEY_LEN];
+        let mut sender_nonces = MonotonicNonceGenerator::new();
+        let mut receiver_nonces = MonotonicNonceGenerator::new();
+        let message = create_box_with_key(
+            b"message",
+            &NONCE_CONTEXT_REQUEST,
+            &mut sender_nonces,
+            &shared_key,
+        ).unwrap();
+        let mut forged = message.clone();
+        let mut nonce = forged.get_nonce().to_vec();
+        LittleEndian::write_u64(&mut nonce[NONCE_CONTEXT_LEN..], 1000);
+        forged.set_nonce(nonce);
+        assert!(
+            open_box_with_key(
+                &forged,
+                &NONCE_CONTEXT_REQUEST,
+                &mut receiver_nonces,
+                &shared_key,
+            ).is_err()
+        );
+
+        // The authentic messages are still accepted.
+        contract
+            .open_box(&request, &NONCE_CONTEXT_REQUEST, &mut contract_nonces)
+            .unwrap();
+        open_box_with_key(
+            &message,
+            &NONCE_CONTEXT_REQUEST,
+            &mut receiver_nonces,
+            &shared_key,
+        ).unwrap();
+    }
+}
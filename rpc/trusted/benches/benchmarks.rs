@@ -33,6 +33,7 @@ fn register_empty_method() {
         ApiMethodDescriptor {
             name: "benchmark_empty".to_owned(),
             client_attestation_required: false,
+            policies: vec![],
         },
         |_request: &Request<Empty>| -> Result<Empty> { Ok(Empty::new()) },
     ));
@@ -71,6 +72,7 @@ fn init_secure_channel(
         api::METHOD_CHANNEL_INIT.to_owned(),
         None,
         None,
+        None,
     );
 
     let dispatcher = Dispatcher::get();
@@ -194,6 +196,7 @@ fn benchmark_dispatch_empty_request(b: &mut Bencher) {
         "benchmark_empty".to_owned(),
         None,
         None,
+        None,
     );
 
     b.iter(|| {
@@ -1,5 +1,5 @@
 //! RPC method dispatcher.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 #[cfg(not(target_env = "sgx"))]
 use std::sync::{Mutex, MutexGuard};
 #[cfg(target_env = "sgx")]
@@ -7,16 +7,16 @@ use std::sync::SgxMutex as Mutex;
 #[cfg(target_env = "sgx")]
 use std::sync::SgxMutexGuard as MutexGuard;
 
-use ekiden_common::error::Result;
+use ekiden_common::error::{Error, Result};
 use ekiden_common::profile_block;
 use ekiden_common::serializer::{Deserializable, Serializable};
 use ekiden_enclave_trusted::utils::{read_enclave_request, write_enclave_response};
 use ekiden_rpc_common::api;
-use ekiden_rpc_common::reflection::ApiMethodDescriptor;
+use ekiden_rpc_common::reflection::{ApiMethodDescriptor, AuthorizationPolicy};
 
 use super::{request, response};
 use super::error::DispatchError;
-use super::secure_channel::open_request_box;
+use super::secure_channel::{open_request_box, start_batch};
 
 /// List of methods that allow plain requests. All other requests must be done over
 /// a secure channel.
@@ -25,6 +25,19 @@ const PLAIN_METHODS: &'static [&'static str] = &[
     // Authentication uses its own boxes very similar to RPC encryption, but with its own nonce
     // contexts.
     api::METHOD_CHANNEL_AUTH,
+    // Resumption keys the new session from the ticket, in a single round trip.
+    api::METHOD_CHANNEL_RESUME,
+];
+
+/// List of secure channel methods. They only change the secure channel sessions, so
+/// they are not dispatched again when a batch is retried.
+const SECURE_CHANNEL_METHODS: &'static [&'static str] = &[
+    api::METHOD_CHANNEL_INIT,
+    api::METHOD_CHANNEL_AUTH,
+    api::METHOD_CHANNEL_RESUME,
+    api::METHOD_CHANNEL_CLOSE,
+    api::METHOD_REQUEST_CHUNK,
+    api::METHOD_RESPONSE_CHUNK,
 ];
 
 /// Handler for an API method.
@@ -44,10 +57,47 @@ where
     }
 }
 
+/// Transaction around the invocation of an API method.
+///
+/// The dispatcher runs each request in its own transaction, which is committed if the
+/// request succeeds and rolled back otherwise. This way a failed request does not leave
+/// any partial changes behind.
+pub trait RequestTransaction: Send + Sync {
+    /// Start a new transaction.
+    fn begin(&self);
+
+    /// Keep the changes made since the transaction was started.
+    fn commit(&self) -> Result<()>;
+
+    /// Discard the changes made since the transaction was started.
+    fn rollback(&self) -> Result<()>;
+}
+
+/// Set of clients which are allowed to call methods with the admin policy.
+///
+/// Clients are identified by their long-term public keys. The set may for example be
+/// kept in the contract's database, so that it can be changed by admin methods.
+pub trait AdminSet: Send + Sync {
+    /// Check if the client with the given long-term public key is an admin.
+    fn contains(&self, public_key: &[u8]) -> bool;
+}
+
+impl AdminSet for HashSet<Vec<u8>> {
+    fn contains(&self, public_key: &[u8]) -> bool {
+        HashSet::contains(self, public_key)
+    }
+}
+
 /// Dispatcher for an API method.
 pub trait ApiMethodHandlerDispatch {
     /// Dispatches the given raw request.
-    fn dispatch(&self, request: &request::Request<Vec<u8>>) -> response::Response;
+    ///
+    /// The admin set is used to authorize methods with the admin policy.
+    fn dispatch(
+        &self,
+        request: &request::Request<Vec<u8>>,
+        admin_set: Option<&AdminSet>,
+    ) -> response::Response;
 }
 
 struct ApiMethodHandlerDispatchImpl<Request, Response> {
@@ -55,13 +105,51 @@ struct ApiMethodHandlerDispatchImpl<Request, Response> {
     handler: Box<ApiMethodHandler<Request, Response> + Sync + Send>,
 }
 
+impl<Request, Response> ApiMethodHandlerDispatchImpl<Request, Response> {
+    /// Check that the client making the request satisfies all authorization policies
+    /// of the method.
+    fn authorize(
+        &self,
+        request: &request::Request<Vec<u8>>,
+        admin_set: Option<&AdminSet>,
+    ) -> ::std::result::Result<(), &'static str> {
+        for policy in &self.descriptor.policies {
+            match *policy {
+                AuthorizationPolicy::ClientAttestation => {
+                    if request.get_client_mr_enclave().is_none() {
+                        return Err("Method requires client attestation");
+                    }
+                }
+                AuthorizationPolicy::ClientMrEnclave(ref allowed) => {
+                    match request.get_client_mr_enclave() {
+                        Some(mr_enclave) if allowed.contains(mr_enclave) => {}
+                        _ => return Err("Client enclave is not allowed to call method"),
+                    }
+                }
+                AuthorizationPolicy::Admin => {
+                    match (request.get_client_long_term_public_key(), admin_set) {
+                        (Some(public_key), Some(admin_set)) if admin_set.contains(public_key) => {}
+                        _ => return Err("Method requires an admin client"),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl<Request, Response> ApiMethodHandlerDispatch for ApiMethodHandlerDispatchImpl<Request, Response>
 where
     Request: Deserializable + Send + 'static,
     Response: Serializable + Send + 'static,
 {
     /// Dispatches the given raw request.
-    fn dispatch(&self, request: &request::Request<Vec<u8>>) -> response::Response {
+    fn dispatch(
+        &self,
+        request: &request::Request<Vec<u8>>,
+        admin_set: Option<&AdminSet>,
+    ) -> response::Response {
         // If the method requires client attestation ensure that it has been provided.
         if self.descriptor.client_attestation_required && request.get_client_mr_enclave().is_none()
         {
@@ -72,6 +160,15 @@ where
             );
         }
 
+        // Ensure that the client is authorized to call the method.
+        if let Err(message) = self.authorize(&request, admin_set) {
+            return response::Response::error(
+                &request,
+                api::PlainClientResponse_Code::ERROR_UNAUTHORIZED,
+                message,
+            );
+        }
+
         // Deserialize request.
         let request_message = match Deserializable::read(&request) {
             Ok(message) => request.copy_metadata_to(message),
@@ -140,14 +237,29 @@ impl EnclaveMethod {
         &self.name
     }
 
-    pub fn dispatch(&self, request: &request::Request<Vec<u8>>) -> response::Response {
-        self.dispatcher.dispatch(&request)
+    pub fn dispatch(
+        &self,
+        request: &request::Request<Vec<u8>>,
+        admin_set: Option<&AdminSet>,
+    ) -> response::Response {
+        self.dispatcher.dispatch(&request, admin_set)
     }
 }
 
+/// Request of the last batch, kept so that the batch can be retried.
+enum BatchEntry {
+    /// Request which is dispatched again when the batch is retried.
+    Request(request::Request<Vec<u8>>),
+    /// Response which is sent again when the batch is retried.
+    Response(api::ClientResponse),
+}
+
 lazy_static! {
     // Global RPC dispatcher object.
     static ref DISPATCHER: Mutex<Dispatcher> = Mutex::new(Dispatcher::new());
+
+    // Requests of the last batch.
+    static ref LAST_BATCH: Mutex<Vec<BatchEntry>> = Mutex::new(vec![]);
 }
 
 /// RPC method dispatcher.
@@ -157,6 +269,10 @@ lazy_static! {
 pub struct Dispatcher {
     /// Registered RPC methods.
     methods: HashMap<String, EnclaveMethod>,
+    /// Transaction around each request.
+    transaction: Option<Box<RequestTransaction>>,
+    /// Clients which are allowed to call methods with the admin policy.
+    admin_set: Option<Box<AdminSet>>,
 }
 
 impl Dispatcher {
@@ -164,6 +280,8 @@ impl Dispatcher {
     pub fn new() -> Self {
         let mut dispatcher = Dispatcher {
             methods: HashMap::new(),
+            transaction: None,
+            admin_set: None,
         };
 
         // Register internal methods.
@@ -171,6 +289,7 @@ impl Dispatcher {
             ApiMethodDescriptor {
                 name: api::METHOD_CHANNEL_INIT.to_owned(),
                 client_attestation_required: false,
+                policies: vec![],
             },
             |request: &request::Request<api::ChannelInitRequest>| {
                 super::secure_channel::channel_init(request)
@@ -181,12 +300,76 @@ impl Dispatcher {
             ApiMethodDescriptor {
                 name: api::METHOD_CHANNEL_AUTH.to_owned(),
                 client_attestation_required: false,
+                policies: vec![],
             },
             |request: &request::Request<api::ChannelAuthRequest>| {
                 super::secure_channel::channel_auth(request)
             },
         ));
 
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: api::METHOD_CHANNEL_RESUME.to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            |request: &request::Request<api::ChannelResumeRequest>| {
+                super::secure_channel::channel_resume(request)
+            },
+        ));
+
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: api::METHOD_CHANNEL_CLOSE.to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            |request: &request::Request<api::ChannelCloseRequest>| {
+                let public_key = match request.get_client_public_key() {
+                    Some(public_key) => public_key,
+                    None => return Err(Error::new("Method must be called over a secure channel")),
+                };
+
+                super::secure_channel::channel_close(&public_key)?;
+
+                Ok(api::ChannelCloseResponse::new())
+            },
+        ));
+
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: api::METHOD_REQUEST_CHUNK.to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            |request: &request::Request<api::PayloadChunk>| {
+                let public_key = match request.get_client_public_key() {
+                    Some(public_key) => public_key,
+                    None => return Err(Error::new("Method must be called over a secure channel")),
+                };
+
+                super::secure_channel::add_request_chunk(&public_key, request)?;
+
+                Ok(api::RequestChunkResponse::new())
+            },
+        ));
+
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: api::METHOD_RESPONSE_CHUNK.to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            |request: &request::Request<api::ResponseChunkRequest>| {
+                let public_key = match request.get_client_public_key() {
+                    Some(public_key) => public_key,
+                    None => return Err(Error::new("Method must be called over a secure channel")),
+                };
+
+                super::secure_channel::get_response_chunk(&public_key, request.get_index())
+            },
+        ));
+
         dispatcher
     }
 
@@ -203,6 +386,24 @@ impl Dispatcher {
         self.methods.insert(method.get_name().clone(), method);
     }
 
+    /// Set the transaction which each request is run in.
+    pub fn set_transaction<T>(&mut self, transaction: T)
+    where
+        T: RequestTransaction + 'static,
+    {
+        self.transaction = Some(Box::new(transaction));
+    }
+
+    /// Set the clients which are allowed to call methods with the admin policy.
+    ///
+    /// If no admin set is configured, methods with the admin policy cannot be called.
+    pub fn set_admin_set<A>(&mut self, admin_set: A)
+    where
+        A: AdminSet + 'static,
+    {
+        self.admin_set = Some(Box::new(admin_set));
+    }
+
     /// Dispatches a raw RPC request.
     pub fn dispatch(&self, request: request::Request<Vec<u8>>) -> response::Response {
         // If an error occurred during request processing, forward it.
@@ -215,8 +416,30 @@ impl Dispatcher {
             .get_method()
             .expect("Non-errored request without method passed to dispatcher");
 
+        let admin_set = self.admin_set.as_ref().map(|admin_set| &**admin_set);
+
         match self.methods.get(method) {
-            Some(method_dispatch) => method_dispatch.dispatch(&request),
+            Some(method_dispatch) => match self.transaction {
+                Some(ref transaction) => {
+                    transaction.begin();
+                    let response = method_dispatch.dispatch(&request, admin_set);
+                    let result = if response.is_success() {
+                        transaction.commit()
+                    } else {
+                        transaction.rollback()
+                    };
+
+                    match result {
+                        Ok(()) => response,
+                        Err(error) => response::Response::error(
+                            &request,
+                            api::PlainClientResponse_Code::ERROR,
+                            &format!("Unable to finish request transaction: {}", error.message),
+                        ),
+                    }
+                }
+                None => method_dispatch.dispatch(&request, admin_set),
+            },
             None => response::Response::error(
                 &request,
                 api::PlainClientResponse_Code::ERROR_METHOD_NOT_FOUND,
@@ -226,6 +449,108 @@ impl Dispatcher {
     }
 }
 
+/// Parse and open the client requests of a batch.
+fn parse_requests(client_requests: Vec<api::ClientRequest>) -> Vec<request::Request<Vec<u8>>> {
+    profile_block!("parse_request");
+
+    let mut requests = vec![];
+
+    for mut client_request in client_requests.into_iter() {
+        if client_request.has_encrypted_request() {
+            // Encrypted request.
+            let plain_request = match open_request_box(&client_request.get_encrypted_request()) {
+                Ok(plain_request) => plain_request,
+                Err(error) => request::Request::error(DispatchError::new(
+                    api::PlainClientResponse_Code::ERROR_SECURE_CHANNEL,
+                    &format!("Unable to open secure channel request: {}", error.message),
+                )),
+            };
+
+            requests.push(plain_request);
+        } else {
+            // Plain request.
+            let mut plain_request = client_request.take_plain_request();
+            let plain_request = match PLAIN_METHODS
+                .iter()
+                .find(|&method| method == &plain_request.get_method())
+            {
+                Some(_) => request::Request::new(
+                    plain_request.take_payload(),
+                    plain_request.take_method(),
+                    None,
+                    None,
+                    None,
+                ),
+                None => {
+                    // Method requires a secure channel.
+                    request::Request::error(DispatchError::new(
+                        api::PlainClientResponse_Code::ERROR_METHOD_SECURE,
+                        "Method call must be made over a secure channel",
+                    ))
+                }
+            };
+
+            requests.push(plain_request);
+        }
+    }
+
+    requests
+}
+
+/// Dispatch a new batch of requests, keeping it so that it can be retried.
+fn dispatch_batch(
+    dispatcher: &Dispatcher,
+    requests: Vec<request::Request<Vec<u8>>>,
+) -> Vec<api::ClientResponse> {
+    profile_block!("process_requests");
+
+    let mut last_batch = LAST_BATCH.lock().unwrap();
+    last_batch.clear();
+
+    let mut responses = vec![];
+    for request in requests {
+        let retriable = request.get_error().is_none() && match request.get_method() {
+            Some(method) => !SECURE_CHANNEL_METHODS.iter().any(|&name| name == method),
+            None => false,
+        };
+
+        let response = if retriable {
+            let response = dispatcher.dispatch(request.clone()).take_message();
+            last_batch.push(BatchEntry::Request(request));
+            response
+        } else {
+            let response = dispatcher.dispatch(request).take_message();
+            last_batch.push(BatchEntry::Response(response.clone()));
+            response
+        };
+
+        responses.push(response);
+    }
+
+    responses
+}
+
+/// Dispatch the requests of the last batch again, on the current state.
+///
+/// A batch is retried if its state update was rejected because another compute node
+/// updated the state first. The requests are not opened again, as the secure channel
+/// sessions have already received them. Secure channel requests only change the
+/// sessions, so their responses are sent again instead.
+fn retry_batch(dispatcher: &Dispatcher) -> Vec<api::ClientResponse> {
+    profile_block!("retry_requests");
+
+    let last_batch = LAST_BATCH.lock().unwrap();
+    last_batch
+        .iter()
+        .map(|entry| match *entry {
+            BatchEntry::Request(ref request) => {
+                dispatcher.dispatch(request.clone()).take_message()
+            }
+            BatchEntry::Response(ref response) => response.clone(),
+        })
+        .collect()
+}
+
 /// RPC dispatch ECALL entry point.
 ///
 /// This method gets executed every time there are some requests are to
@@ -238,68 +563,17 @@ pub extern "C" fn rpc_call(
     response_capacity: usize,
     response_length: *mut usize,
 ) {
-    // Parse requests.
-    let requests = {
-        profile_block!("parse_request");
-
-        let mut enclave_request: api::EnclaveRequest =
-            read_enclave_request(request_data, request_length);
-        let client_requests = enclave_request.take_client_request();
-        let mut requests = vec![];
-
-        for mut client_request in client_requests.into_iter() {
-            if client_request.has_encrypted_request() {
-                // Encrypted request.
-                let plain_request = match open_request_box(&client_request.get_encrypted_request())
-                {
-                    Ok(plain_request) => plain_request,
-                    _ => request::Request::error(DispatchError::new(
-                        api::PlainClientResponse_Code::ERROR_SECURE_CHANNEL,
-                        "Unable to open secure channel request",
-                    )),
-                };
-
-                requests.push(plain_request);
-            } else {
-                // Plain request.
-                let mut plain_request = client_request.take_plain_request();
-                let plain_request = match PLAIN_METHODS
-                    .iter()
-                    .find(|&method| method == &plain_request.get_method())
-                {
-                    Some(_) => request::Request::new(
-                        plain_request.take_payload(),
-                        plain_request.take_method(),
-                        None,
-                        None,
-                    ),
-                    None => {
-                        // Method requires a secure channel.
-                        request::Request::error(DispatchError::new(
-                            api::PlainClientResponse_Code::ERROR_METHOD_SECURE,
-                            "Method call must be made over a secure channel",
-                        ))
-                    }
-                };
-
-                requests.push(plain_request);
-            }
-        }
-
-        requests
-    };
-
-    // Process requests.
-    let responses = {
-        profile_block!("process_requests");
+    let mut enclave_request: api::EnclaveRequest =
+        read_enclave_request(request_data, request_length);
 
-        let dispatcher = Dispatcher::get();
-        let mut responses = vec![];
-        for request in requests {
-            responses.push(dispatcher.dispatch(request));
-        }
+    let responses = if enclave_request.get_retry() {
+        retry_batch(&Dispatcher::get())
+    } else {
+        // Expire idle secure channel sessions.
+        start_batch();
 
-        responses
+        let requests = parse_requests(enclave_request.take_client_request().into_vec());
+        dispatch_batch(&Dispatcher::get(), requests)
     };
 
     // Generate response.
@@ -310,8 +584,8 @@ pub extern "C" fn rpc_call(
         let mut enclave_response = api::EnclaveResponse::new();
         {
             let client_responses = enclave_response.mut_client_response();
-            for mut response in responses {
-                client_responses.push(response.take_message());
+            for response in responses {
+                client_responses.push(response);
             }
         }
 
@@ -324,3 +598,212 @@ pub extern "C" fn rpc_call(
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use ekiden_common::error::Result;
+    use ekiden_common::serializer::Deserializable;
+    use ekiden_enclave_common::quote::MrEnclave;
+    use ekiden_rpc_common::api;
+    use ekiden_rpc_common::reflection::{ApiMethodDescriptor, AuthorizationPolicy};
+
+    use super::{dispatch_batch, retry_batch, Dispatcher, EnclaveMethod};
+    use super::super::request::Request;
+
+    /// Number of calls to the counting method.
+    static CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Method which does nothing.
+    fn noop(_request: &Request<()>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Method which returns the number of calls to it.
+    fn count(_request: &Request<()>) -> Result<u64> {
+        Ok(CALLS.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+    }
+
+    /// Create a dispatcher with a method for each policy and the given admins.
+    fn dispatcher(admins: Option<HashSet<Vec<u8>>>) -> Dispatcher {
+        let mut dispatcher = Dispatcher::new();
+        if let Some(admins) = admins {
+            dispatcher.set_admin_set(admins);
+        }
+
+        let methods = vec![
+            ("attested", vec![AuthorizationPolicy::ClientAttestation]),
+            (
+                "enclave",
+                vec![AuthorizationPolicy::ClientMrEnclave(vec![MrEnclave([1; 32])])],
+            ),
+            ("admin", vec![AuthorizationPolicy::Admin]),
+            (
+                "enclave_admin",
+                vec![
+                    AuthorizationPolicy::ClientMrEnclave(vec![MrEnclave([1; 32])]),
+                    AuthorizationPolicy::Admin,
+                ],
+            ),
+        ];
+        for (name, policies) in methods {
+            dispatcher.add_method(EnclaveMethod::new(
+                ApiMethodDescriptor {
+                    name: name.to_owned(),
+                    client_attestation_required: false,
+                    policies: policies,
+                },
+                noop,
+            ));
+        }
+
+        dispatcher
+    }
+
+    /// Dispatch a request from a client with the given attestation.
+    fn dispatch(
+        dispatcher: &Dispatcher,
+        method: &str,
+        client: Option<(&[u8], MrEnclave)>,
+    ) -> api::PlainClientResponse_Code {
+        let (long_term_public_key, mr_enclave) = match client {
+            Some((public_key, mr_enclave)) => (Some(public_key.to_vec()), Some(mr_enclave)),
+            None => (None, None),
+        };
+        // Without a client short-term key, the response is not sent over a secure
+        // channel, so no session is needed.
+        let request = Request::new(
+            vec![],
+            method.to_owned(),
+            None,
+            long_term_public_key,
+            mr_enclave,
+        );
+
+        let mut response = dispatcher.dispatch(request);
+        response.take_message().get_plain_response().get_code()
+    }
+
+    #[test]
+    fn test_policy_client_attestation() {
+        let dispatcher = dispatcher(None);
+
+        assert_eq!(
+            dispatch(&dispatcher, "attested", None),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(&dispatcher, "attested", Some((b"alice", MrEnclave([2; 32])))),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+    }
+
+    #[test]
+    fn test_policy_client_mr_enclave() {
+        let dispatcher = dispatcher(None);
+
+        assert_eq!(
+            dispatch(&dispatcher, "enclave", None),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(&dispatcher, "enclave", Some((b"alice", MrEnclave([2; 32])))),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(&dispatcher, "enclave", Some((b"alice", MrEnclave([1; 32])))),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+    }
+
+    #[test]
+    fn test_policy_admin() {
+        // Without an admin set, nobody is an admin.
+        let dispatcher_without_admins = dispatcher(None);
+        assert_eq!(
+            dispatch(
+                &dispatcher_without_admins,
+                "admin",
+                Some((b"alice", MrEnclave([1; 32])))
+            ),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+
+        let mut admins = HashSet::new();
+        admins.insert(b"alice".to_vec());
+        let dispatcher = dispatcher(Some(admins));
+
+        assert_eq!(
+            dispatch(&dispatcher, "admin", None),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(&dispatcher, "admin", Some((b"bob", MrEnclave([1; 32])))),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(&dispatcher, "admin", Some((b"alice", MrEnclave([2; 32])))),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+
+        // All policies of a method must be satisfied.
+        assert_eq!(
+            dispatch(
+                &dispatcher,
+                "enclave_admin",
+                Some((b"alice", MrEnclave([2; 32])))
+            ),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(
+                &dispatcher,
+                "enclave_admin",
+                Some((b"bob", MrEnclave([1; 32])))
+            ),
+            api::PlainClientResponse_Code::ERROR_UNAUTHORIZED
+        );
+        assert_eq!(
+            dispatch(
+                &dispatcher,
+                "enclave_admin",
+                Some((b"alice", MrEnclave([1; 32])))
+            ),
+            api::PlainClientResponse_Code::SUCCESS
+        );
+    }
+
+    #[test]
+    fn test_retry_batch() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: "count".to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            count,
+        ));
+        let count_of = |response: &api::ClientResponse| {
+            u64::read(&response.get_plain_response().get_payload().to_vec()).unwrap()
+        };
+
+        let requests = vec![
+            Request::new(vec![], "count".to_owned(), None, None, None),
+            Request::new(vec![], api::METHOD_CHANNEL_CLOSE.to_owned(), None, None, None),
+        ];
+        let responses = dispatch_batch(&dispatcher, requests);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(count_of(&responses[0]), 1);
+
+        // Contract requests are dispatched again, while the responses to secure channel
+        // requests are sent again.
+        let retried = retry_batch(&dispatcher);
+        assert_eq!(retried.len(), 2);
+        assert_eq!(count_of(&retried[0]), 2);
+        assert_eq!(retried[1], responses[1]);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+}
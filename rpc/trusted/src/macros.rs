@@ -1,5 +1,8 @@
 /// Registers defined RPC methods into the enclave RPC dispatcher.
 ///
+/// Authorization policies of each method (see `AuthorizationPolicy`) are given in
+/// its `where` clause, e.g., `rpc set_owner(SetOwnerRequest) -> SetOwnerResponse where Admin;`.
+///
 /// # Examples
 ///
 /// This macro should be invoked using a concrete API generated by `rpc_api` as
@@ -19,7 +22,8 @@ macro_rules! create_enclave_rpc {
         }
 
         $(
-            rpc $method_name:ident ( $request_type:ty ) -> $response_type:ty ;
+            rpc $method_name:ident ( $request_type:ty ) -> $response_type:ty
+                $( where $( $policy:expr ),+ )* ;
         )*
     ) => {
         #[cfg(target_env = "sgx")]
@@ -27,17 +31,28 @@ macro_rules! create_enclave_rpc {
             ENCLAVE_RPC_INIT, enclave_rpc_init = {
                 use ekiden_core::error::Result;
                 use ekiden_core::rpc::reflection::ApiMethodDescriptor;
+                #[allow(unused_imports)]
+                use ekiden_core::rpc::reflection::AuthorizationPolicy::*;
+                use ekiden_trusted::db::{DatabaseAdminSet, DatabaseTransaction};
                 use ekiden_trusted::rpc::dispatcher::{Dispatcher, EnclaveMethod};
                 use ekiden_trusted::rpc::request::Request;
 
                 // Register generated methods using the dispatcher.
                 let mut dispatcher = Dispatcher::get();
+
+                // Discard database changes of failed requests.
+                dispatcher.set_transaction(DatabaseTransaction);
+
+                // Keep the admin set in the contract state, so it is the same on every
+                // compute node.
+                dispatcher.set_admin_set(DatabaseAdminSet);
                 $(
                     dispatcher.add_method(
                         EnclaveMethod::new(
                             ApiMethodDescriptor {
                                 name: stringify!($method_name).to_owned(),
                                 client_attestation_required: $client_attestation_required,
+                                policies: vec![ $( $( $policy, )+ )* ],
                             },
                             |request: &Request<$request_type>| -> Result<$response_type> {
                                 $method_name(request)
@@ -14,6 +14,8 @@ pub struct Request<T> {
     method: Option<String>,
     /// Client short-term public key (if request is authenticated).
     public_key: Option<Vec<u8>>,
+    /// Client long-term public key (if channel is mutually authenticated).
+    long_term_public_key: Option<Vec<u8>>,
     /// Client MRENCLAVE (if channel is mutually authenticated).
     mr_enclave: Option<MrEnclave>,
     /// Optional error occurred during request processing.
@@ -26,12 +28,14 @@ impl<T> Request<T> {
         message: T,
         method: String,
         public_key: Option<Vec<u8>>,
+        long_term_public_key: Option<Vec<u8>>,
         mr_enclave: Option<MrEnclave>,
     ) -> Self {
         Request {
             message: Some(message),
             method: Some(method),
             public_key: public_key,
+            long_term_public_key: long_term_public_key,
             mr_enclave: mr_enclave,
             error: None,
         }
@@ -43,6 +47,7 @@ impl<T> Request<T> {
             message: None,
             method: None,
             public_key: None,
+            long_term_public_key: None,
             mr_enclave: None,
             error: Some(error),
         }
@@ -61,11 +66,28 @@ impl<T> Request<T> {
             message: Some(message),
             method: self.method.clone(),
             public_key: self.public_key.clone(),
+            long_term_public_key: self.long_term_public_key.clone(),
             mr_enclave: self.mr_enclave.clone(),
             error: None,
         }
     }
 
+    /// Create new request with dispatch error, keeping the metadata of the current
+    /// request.
+    ///
+    /// This way the error response is sent over the same secure channel as the
+    /// request.
+    pub fn copy_metadata_to_error<M>(&self, error: DispatchError) -> Request<M> {
+        Request {
+            message: None,
+            method: self.method.clone(),
+            public_key: self.public_key.clone(),
+            long_term_public_key: self.long_term_public_key.clone(),
+            mr_enclave: self.mr_enclave.clone(),
+            error: Some(error),
+        }
+    }
+
     /// Get short-term public key of the client making this request.
     ///
     /// If the request was made over a non-secure channel, this will be [`None`].
@@ -75,6 +97,17 @@ impl<T> Request<T> {
         self.public_key.as_ref()
     }
 
+    /// Get long-term public key of the client making this request.
+    ///
+    /// Unlike the short-term public key, the long-term public key identifies the
+    /// client across sessions. If the request was made over a channel without client
+    /// attestation, this will be [`None`].
+    ///
+    /// [`None`]: std::option::Option
+    pub fn get_client_long_term_public_key(&self) -> Option<&Vec<u8>> {
+        self.long_term_public_key.as_ref()
+    }
+
     /// Get MRENCLAVE of the client making this request.
     ///
     /// If the request was made over a channel without client attestation, this
@@ -12,11 +12,14 @@ use super::secure_channel::create_response_box;
 pub struct Response {
     /// Response message.
     message: api::ClientResponse,
+    /// True if the request was successful.
+    success: bool,
 }
 
 impl Response {
     /// Create new response.
     pub fn new<Rq>(request: &Request<Rq>, response: api::PlainClientResponse) -> Self {
+        let mut success = response.get_code() == api::PlainClientResponse_Code::SUCCESS;
         let mut message = api::ClientResponse::new();
         if let Some(ref public_key) = request.get_client_public_key() {
             // Encrypted response.
@@ -26,6 +29,7 @@ impl Response {
                     // Failed to create a cryptographic box for the response. This could
                     // be due to the session being incorrect or due to other issues. In
                     // this case, we should generate a plain error message.
+                    success = false;
                     message.set_plain_response(Self::generate_error(
                         api::PlainClientResponse_Code::ERROR_SECURE_CHANNEL,
                         "Failed to generate secure channel response",
@@ -37,7 +41,7 @@ impl Response {
             message.set_plain_response(response);
         }
 
-        Response { message }
+        Response { message, success }
     }
 
     /// Create success response.
@@ -60,7 +64,7 @@ impl Response {
     }
 
     /// Generate error response.
-    fn generate_error(
+    pub(crate) fn generate_error(
         error: api::PlainClientResponse_Code,
         message: &str,
     ) -> api::PlainClientResponse {
@@ -77,6 +81,11 @@ impl Response {
         response
     }
 
+    /// Returns true if the request was successful.
+    pub fn is_success(&self) -> bool {
+        self.success
+    }
+
     /// Take response message.
     ///
     /// After calling this method, a default message will be left in its place.
@@ -3,7 +3,8 @@ use protobuf;
 use protobuf::Message;
 use sodalite;
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 #[cfg(not(target_env = "sgx"))]
 use std::sync::{Mutex, MutexGuard};
 #[cfg(target_env = "sgx")]
@@ -14,14 +15,33 @@ use std::sync::SgxMutexGuard as MutexGuard;
 use ekiden_common::error::{Error, Result};
 use ekiden_common::random;
 use ekiden_enclave_common;
-use ekiden_enclave_common::quote::MrEnclave;
+use ekiden_enclave_common::quote::{MrEnclave, MRENCLAVE_LEN};
 use ekiden_enclave_trusted;
 use ekiden_enclave_trusted::crypto::{SecretSeed, SECRET_SEED_LEN};
 use ekiden_rpc_common::api;
-use ekiden_rpc_common::secure_channel::{self, MonotonicNonceGenerator, RandomNonceGenerator,
-                                        SessionState};
+use ekiden_rpc_common::chunking::{self, ChunkAssembler};
+use ekiden_rpc_common::secure_channel::{self, KeyRatchet, MonotonicNonceGenerator,
+                                        RandomNonceGenerator, SessionState,
+                                        NONCE_CONTEXT_TICKET, RATCHET_MAX_MESSAGE_INTERVAL};
 
+use super::error::DispatchError;
 use super::request::Request;
+use super::response::Response;
+
+/// Default maximum number of concurrent client sessions.
+pub const DEFAULT_MAX_SESSIONS: usize = 1024;
+/// Default number of batches after which an idle client session expires.
+pub const DEFAULT_SESSION_IDLE_BATCHES: u64 = 1000;
+/// Default number of batches after which the ticket key is rotated.
+pub const DEFAULT_TICKET_KEY_LIFETIME_BATCHES: u64 = 1000;
+/// Default maximum number of bytes of chunked payloads held by a single session.
+pub const DEFAULT_MAX_SESSION_CHUNK_BYTES: usize = chunking::MAX_CHUNKED_PAYLOAD_SIZE;
+/// Default maximum number of bytes of chunked payloads held by all sessions.
+pub const DEFAULT_MAX_CHUNK_BYTES: usize = 4 * chunking::MAX_CHUNKED_PAYLOAD_SIZE;
+/// Number of ticket key epochs after the epoch of its key in which a ticket is accepted.
+const TICKET_LIFETIME_EPOCHS: u64 = 1;
+/// Name of the shared key which session tickets are encrypted with.
+const SHARED_TICKET_KEY_NAME: &'static str = "secure_channel_ticket";
 
 /// Single secure channel session between client and contract.
 #[derive(Default)]
@@ -30,10 +50,10 @@ pub struct ClientSession {
     client_public_key: sodalite::BoxPublicKey,
     /// Contract short-term public key.
     contract_public_key: sodalite::BoxPublicKey,
-    /// Contract short-term private key.
-    contract_private_key: sodalite::BoxSecretKey,
-    /// Cached shared key.
+    /// Shared key (cleared once a key ratchet has been derived from it).
     shared_key: Option<sodalite::SecretboxKey>,
+    /// Secret from which sessions resumed from this session are keyed.
+    resumption_secret: Option<sodalite::SecretboxKey>,
     /// Short-term nonce generator.
     nonce_generator: MonotonicNonceGenerator,
     /// Session state.
@@ -42,6 +62,190 @@ pub struct ClientSession {
     client_long_term_public_key: Option<sodalite::BoxPublicKey>,
     /// Client MRENCLAVE (if authenticated).
     client_mr_enclave: Option<MrEnclave>,
+    /// Key ratcheting parameters (if negotiated).
+    ratchet_parameters: Option<api::RatchetParameters>,
+    /// Key ratchet (if negotiated).
+    ratchet: Option<KeyRatchet>,
+    /// Batch in which the session was last used.
+    last_used: u64,
+    /// Chunks of the request payload being received.
+    request_chunks: ChunkAssembler,
+    /// Remaining chunks of the last response payload.
+    response_chunks: VecDeque<api::PayloadChunk>,
+}
+
+/// Source of the keys which session tickets are encrypted with.
+///
+/// Ticket keys have epochs, so they can be rotated. A ticket records the epoch of the
+/// key it is encrypted with, and it expires once the current epoch is more than
+/// `TICKET_LIFETIME_EPOCHS` epochs later.
+pub trait TicketKeySource: Send + Sync {
+    /// Get the current key epoch and its key.
+    fn get_current_key(&mut self) -> Result<(u64, Vec<u8>)>;
+
+    /// Get the key of the given epoch.
+    fn get_key(&mut self, epoch: u64) -> Result<Vec<u8>>;
+
+    /// Start a new key epoch.
+    ///
+    /// Called once the ticket key lifetime has passed. Sources whose keys are rotated
+    /// by other means do nothing.
+    fn rotate(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Ticket key source which generates random keys.
+///
+/// The keys are only known to this enclave instance, so tickets can only be redeemed
+/// with the same instance, and they are lost when the enclave is restarted. Only the
+/// keys of the current and previous epoch are kept.
+#[derive(Default)]
+pub struct RandomTicketKeySource {
+    /// Current key epoch and its key (once generated).
+    current: Option<(u64, sodalite::SecretboxKey)>,
+    /// Previous key epoch and its key (if any).
+    previous: Option<(u64, sodalite::SecretboxKey)>,
+}
+
+impl RandomTicketKeySource {
+    /// Create a new random ticket key source.
+    pub fn new() -> Self {
+        RandomTicketKeySource::default()
+    }
+
+    /// Generate the key of a new epoch.
+    fn generate(&mut self, epoch: u64) -> Result<(u64, sodalite::SecretboxKey)> {
+        let mut key = [0u8; sodalite::SECRETBOX_KEY_LEN];
+        random::get_random_bytes(&mut key)?;
+
+        if let Some(mut previous) = self.previous.take() {
+            secure_channel::clear_key(&mut previous.1);
+        }
+        self.previous = self.current.take();
+        self.current = Some((epoch, key));
+
+        Ok((epoch, key))
+    }
+}
+
+impl TicketKeySource for RandomTicketKeySource {
+    fn get_current_key(&mut self) -> Result<(u64, Vec<u8>)> {
+        let (epoch, key) = match self.current {
+            Some(current) => current,
+            None => self.generate(0)?,
+        };
+
+        Ok((epoch, key.to_vec()))
+    }
+
+    fn get_key(&mut self, epoch: u64) -> Result<Vec<u8>> {
+        match (self.current, self.previous) {
+            (Some((current_epoch, key)), _) if current_epoch == epoch => Ok(key.to_vec()),
+            (_, Some((previous_epoch, key))) if previous_epoch == epoch => Ok(key.to_vec()),
+            _ => Err(Error::new("Ticket key not available")),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        // Keys are generated when the first ticket is issued.
+        let epoch = match self.current {
+            Some((epoch, _)) => epoch + 1,
+            None => return Ok(()),
+        };
+
+        self.generate(epoch)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for RandomTicketKeySource {
+    fn drop(&mut self) {
+        if let Some((_, ref mut key)) = self.current {
+            secure_channel::clear_key(key);
+        }
+        if let Some((_, ref mut key)) = self.previous {
+            secure_channel::clear_key(key);
+        }
+    }
+}
+
+/// Store of named keys with epochs, which is shared by all instances of a contract
+/// (e.g., the key manager).
+pub trait EpochKeyStore: Send + Sync {
+    /// Get the newest epoch of a named key and its key.
+    fn get_current_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)>;
+
+    /// Get the key of a given epoch of a named key.
+    fn get_key(&mut self, name: &str, size: usize, epoch: u64) -> Result<Vec<u8>>;
+
+    /// Generate a key for a new epoch of a named key, which becomes its newest epoch.
+    fn rotate_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)>;
+
+    /// Forget cached newest epochs, so that epochs created by other instances are
+    /// picked up.
+    fn refresh_current_epochs(&mut self) {
+        // Nothing cached by default.
+    }
+}
+
+/// Ticket key source which uses the epochs of a key shared by all instances of the
+/// contract.
+///
+/// All instances obtain the same keys, so sessions can be resumed on any compute node.
+/// Each instance rotates the key once its lifetime has passed, unless another instance
+/// rotated it since the last time this instance checked. This way the key is rotated
+/// about once per lifetime, rather than once per instance.
+pub struct SharedTicketKeySource<S: EpochKeyStore> {
+    /// Store of the shared key.
+    store: S,
+    /// Newest epoch seen by this instance when it last used or rotated the key.
+    seen_epoch: Option<u64>,
+}
+
+impl<S: EpochKeyStore> SharedTicketKeySource<S> {
+    /// Create a new shared ticket key source.
+    pub fn new(store: S) -> Self {
+        SharedTicketKeySource {
+            store,
+            seen_epoch: None,
+        }
+    }
+}
+
+impl<S: EpochKeyStore> TicketKeySource for SharedTicketKeySource<S> {
+    fn get_current_key(&mut self) -> Result<(u64, Vec<u8>)> {
+        let (epoch, key) = self.store
+            .get_current_key(SHARED_TICKET_KEY_NAME, sodalite::SECRETBOX_KEY_LEN)?;
+        self.seen_epoch.get_or_insert(epoch);
+
+        Ok((epoch, key))
+    }
+
+    fn get_key(&mut self, epoch: u64) -> Result<Vec<u8>> {
+        self.store
+            .get_key(SHARED_TICKET_KEY_NAME, sodalite::SECRETBOX_KEY_LEN, epoch)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.store.refresh_current_epochs();
+        let (epoch, _) = self.store
+            .get_current_key(SHARED_TICKET_KEY_NAME, sodalite::SECRETBOX_KEY_LEN)?;
+
+        // Only rotate if no other instance did since this instance last checked. An
+        // instance which has not used the key yet has no tickets to expire.
+        if self.seen_epoch != Some(epoch) {
+            self.seen_epoch = Some(epoch);
+            return Ok(());
+        }
+
+        let (epoch, _) = self.store
+            .rotate_key(SHARED_TICKET_KEY_NAME, sodalite::SECRETBOX_KEY_LEN)?;
+        self.seen_epoch = Some(epoch);
+
+        Ok(())
+    }
 }
 
 /// Secure channel context.
@@ -50,6 +254,22 @@ pub struct SecureChannelContext {
     sessions: HashMap<sodalite::BoxPublicKey, ClientSession>,
     /// Long-term nonce generator.
     nonce_generator: RandomNonceGenerator,
+    /// Source of the keys which session tickets are encrypted with.
+    ticket_key_source: Box<TicketKeySource>,
+    /// Number of batches after which the ticket key is rotated (zero if the ticket key
+    /// is never rotated by the context).
+    ticket_key_lifetime: u64,
+    /// Number of the current batch of requests.
+    batch: u64,
+    /// Maximum number of concurrent client sessions.
+    max_sessions: usize,
+    /// Number of batches after which an idle session expires (zero if sessions
+    /// never expire).
+    idle_batches: u64,
+    /// Maximum number of bytes of chunked payloads held by a single session.
+    max_session_chunk_bytes: usize,
+    /// Maximum number of bytes of chunked payloads held by all sessions.
+    max_chunk_bytes: usize,
 }
 
 impl SecureChannelContext {
@@ -58,6 +278,13 @@ impl SecureChannelContext {
         SecureChannelContext {
             sessions: HashMap::new(),
             nonce_generator: RandomNonceGenerator::new(),
+            ticket_key_source: Box::new(RandomTicketKeySource::new()),
+            ticket_key_lifetime: DEFAULT_TICKET_KEY_LIFETIME_BATCHES,
+            batch: 0,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            idle_batches: DEFAULT_SESSION_IDLE_BATCHES,
+            max_session_chunk_bytes: DEFAULT_MAX_SESSION_CHUNK_BYTES,
+            max_chunk_bytes: DEFAULT_MAX_CHUNK_BYTES,
         }
     }
 
@@ -83,11 +310,15 @@ impl SecureChannelContext {
 
     /// Create a new client session.
     ///
+    /// If key ratcheting parameters are given, requests and responses of the session
+    /// are encrypted with ratcheted keys.
+    ///
     /// Returns a cryptographic box, encrypted to the client short-term key and
     /// authenticated by the contract long-term key.
     pub fn create_session(
         &mut self,
         public_key: &[u8],
+        ratchet: Option<&api::RatchetParameters>,
     ) -> Result<api::AuthenticatedShortTermPublicKey> {
         let key = SecureChannelContext::get_session_key(&public_key)?;
 
@@ -97,6 +328,9 @@ impl SecureChannelContext {
 
         let mut session = ClientSession::new(key.clone())?;
         session.transition_to(SessionState::Established)?;
+        if let Some(ratchet) = ratchet {
+            session.enable_ratchet(ratchet);
+        }
 
         let box_inner = secure_channel::create_box(
             session.get_contract_public_key(),
@@ -110,11 +344,109 @@ impl SecureChannelContext {
         astpk.set_identity_proof(ekiden_enclave_trusted::identity::get_proof());
         astpk.set_boxed_short_term_public_key(box_inner);
 
-        // TODO: What about session table overflows?
+        self.insert_session(key, session)?;
+
+        Ok(astpk)
+    }
+
+    /// Set limits on client sessions.
+    ///
+    /// At most `max_sessions` sessions are kept. Sessions which have not been used for
+    /// `idle_batches` batches of requests expire, unless `idle_batches` is zero. Enclaves
+    /// have no trusted time source, so idle time is measured in batches.
+    ///
+    /// Batches are started by the host, so the host controls how quickly sessions
+    /// expire: it can expire all idle sessions by starting empty batches, or keep them
+    /// by not starting any. The limits only bound the memory used by sessions; they do
+    /// not protect sessions against a malicious host, which can deny service anyway.
+    pub fn set_session_limits(&mut self, max_sessions: usize, idle_batches: u64) {
+        assert!(max_sessions > 0, "At least one session must be allowed");
+
+        self.max_sessions = max_sessions;
+        self.idle_batches = idle_batches;
+    }
+
+    /// Set limits on chunked payloads.
+    ///
+    /// Chunks of incomplete requests and of responses not yet fetched by the client are
+    /// held in enclave memory. A session holds at most `max_session_chunk_bytes` bytes
+    /// of chunks, and all sessions together hold at most `max_chunk_bytes` bytes.
+    pub fn set_chunk_limits(&mut self, max_session_chunk_bytes: usize, max_chunk_bytes: usize) {
+        self.max_session_chunk_bytes = max_session_chunk_bytes;
+        self.max_chunk_bytes = max_chunk_bytes;
+    }
+
+    /// Number of bytes of chunked payloads which the given session may hold.
+    ///
+    /// The bytes held by the other sessions are summed up on each call. This is only
+    /// done for chunked payloads, and the number of sessions is bounded by
+    /// `max_sessions`.
+    fn get_chunk_budget(&self, public_key: &[u8]) -> Result<usize> {
+        let key = SecureChannelContext::get_session_key(&public_key)?;
+        let used: usize = self.sessions
+            .iter()
+            .filter(|&(other_key, _)| *other_key != key)
+            .map(|(_, session)| session.get_chunk_bytes())
+            .sum();
+
+        Ok(cmp::min(
+            self.max_session_chunk_bytes,
+            self.max_chunk_bytes.saturating_sub(used),
+        ))
+    }
+
+    /// Start processing a new batch of requests, expiring idle sessions and rotating the
+    /// ticket key once its lifetime has passed.
+    pub fn start_batch(&mut self) {
+        self.batch += 1;
+
+        if self.ticket_key_lifetime > 0 && self.batch % self.ticket_key_lifetime == 0 {
+            // Tickets are optional, so a failed rotation only means that the current
+            // key remains in use until the next rotation.
+            let _ = self.ticket_key_source.rotate();
+        }
+
+        if self.idle_batches == 0 {
+            return;
+        }
+
+        let batch = self.batch;
+        let idle_batches = self.idle_batches;
+        self.sessions.retain(|_, session| batch - session.last_used <= idle_batches);
+    }
+
+    /// Insert a new client session, evicting the least recently used session if the
+    /// maximum number of sessions has been reached.
+    ///
+    /// Sessions used in the current batch are never evicted, as their requests may not
+    /// have been responded to yet.
+    ///
+    /// Finding the session to evict takes time linear in the number of sessions. This is
+    /// only done when a session is established at the limit, and the number of sessions
+    /// is bounded by `max_sessions`, so no separate recency index is kept.
+    fn insert_session(
+        &mut self,
+        key: sodalite::BoxPublicKey,
+        mut session: ClientSession,
+    ) -> Result<()> {
+        if self.sessions.len() >= self.max_sessions {
+            let batch = self.batch;
+            let evicted = match self.sessions
+                .iter()
+                .filter(|&(_, session)| session.last_used < batch)
+                .min_by_key(|&(_, session)| session.last_used)
+            {
+                Some((key, _)) => key.clone(),
+                None => return Err(Error::new("Too many client sessions")),
+            };
+
+            self.sessions.remove(&evicted);
+        }
 
+        session.last_used = self.batch;
         self.sessions.insert(key, session);
 
-        Ok(astpk)
+        Ok(())
     }
 
     /// Lookup existing client session.
@@ -127,7 +459,7 @@ impl SecureChannelContext {
 
         match sessions.get_mut(&key) {
             Some(session) => Ok(session),
-            None => Err(Error::new("Client session not found")),
+            None => Err(Error::new("Client session not found or expired")),
         }
     }
 
@@ -135,15 +467,9 @@ impl SecureChannelContext {
     pub fn authenticate_client(&mut self, box_outer: &api::CryptoBox) -> Result<()> {
         let session =
             SecureChannelContext::get_session(&mut self.sessions, box_outer.get_public_key())?;
+        session.last_used = self.batch;
 
-        let astpk_bytes = secure_channel::open_box(
-            &box_outer,
-            &secure_channel::NONCE_CONTEXT_AUTHOUT,
-            &mut session.nonce_generator,
-            &session.client_public_key,
-            &session.contract_private_key,
-            &mut session.shared_key,
-        )?;
+        let astpk_bytes = session.open_box(&box_outer, &secure_channel::NONCE_CONTEXT_AUTHOUT)?;
         let astpk: api::AuthenticatedShortTermPublicKey = protobuf::parse_from_bytes(&astpk_bytes)?;
 
         let iai = ekiden_enclave_common::quote::verify(astpk.get_identity_proof())?;
@@ -168,6 +494,137 @@ impl SecureChannelContext {
         Ok(())
     }
 
+    /// Set the source of the keys which session tickets are encrypted with.
+    ///
+    /// By default, a [`RandomTicketKeySource`] is used, so tickets can only be redeemed
+    /// with the same enclave instance. A source which provides the same keys to all
+    /// instances of the contract (e.g., the key manager) allows sessions to be resumed
+    /// on other compute nodes.
+    ///
+    /// [`RandomTicketKeySource`]: self::RandomTicketKeySource
+    pub fn set_ticket_key_source<S>(&mut self, source: S)
+    where
+        S: TicketKeySource + 'static,
+    {
+        self.ticket_key_source = Box::new(source);
+    }
+
+    /// Set the number of batches after which the ticket key is rotated.
+    ///
+    /// Tickets expire when the ticket key is rotated for the second time after they were
+    /// issued. If `lifetime` is zero, the ticket key is never rotated by the context.
+    pub fn set_ticket_key_lifetime(&mut self, lifetime: u64) {
+        self.ticket_key_lifetime = lifetime;
+    }
+
+    /// Convert a key obtained from the ticket key source.
+    fn to_ticket_key(key: &[u8]) -> Result<sodalite::SecretboxKey> {
+        if key.len() != sodalite::SECRETBOX_KEY_LEN {
+            return Err(Error::new("Bad ticket key"));
+        }
+
+        let mut ticket_key = [0u8; sodalite::SECRETBOX_KEY_LEN];
+        ticket_key.copy_from_slice(&key);
+
+        Ok(ticket_key)
+    }
+
+    /// Issue a session ticket, which allows the client to resume an existing session.
+    ///
+    /// The ticket is encrypted and authenticated with the current ticket key, so it can
+    /// only be read by the contract.
+    pub fn issue_ticket(&mut self, public_key: &[u8]) -> Result<api::CryptoBox> {
+        let (key_epoch, ticket_key) = self.ticket_key_source.get_current_key()?;
+        let mut ticket_key = SecureChannelContext::to_ticket_key(&ticket_key)?;
+
+        let mut ticket = SecureChannelContext::get_session(&mut self.sessions, public_key)?
+            .get_ticket()?;
+        ticket.set_key_epoch(key_epoch);
+        ticket.set_expiry(key_epoch.saturating_add(TICKET_LIFETIME_EPOCHS));
+
+        let result = secure_channel::create_box_with_key(
+            &ticket.write_to_bytes()?,
+            &NONCE_CONTEXT_TICKET,
+            &mut self.nonce_generator,
+            &ticket_key,
+        );
+        secure_channel::clear_key(&mut ticket_key);
+
+        result
+    }
+
+    /// Open a session ticket with the ticket key of the current or an earlier epoch.
+    ///
+    /// Tickets encrypted with the key of an expired epoch are rejected.
+    fn open_ticket(&mut self, ticket: &api::CryptoBox) -> Result<api::SessionTicket> {
+        let (current_epoch, _) = self.ticket_key_source.get_current_key()?;
+
+        let first_epoch = current_epoch.saturating_sub(TICKET_LIFETIME_EPOCHS);
+        for epoch in (first_epoch..current_epoch + 1).rev() {
+            let mut ticket_key = match self.ticket_key_source.get_key(epoch) {
+                Ok(ticket_key) => SecureChannelContext::to_ticket_key(&ticket_key)?,
+                Err(_) => continue,
+            };
+
+            let payload = secure_channel::open_box_with_key(
+                ticket,
+                &NONCE_CONTEXT_TICKET,
+                &mut self.nonce_generator,
+                &ticket_key,
+            );
+            secure_channel::clear_key(&mut ticket_key);
+
+            let payload = match payload {
+                Ok(payload) => payload,
+                Err(_) => continue,
+            };
+
+            // The expiry is sealed into the ticket, so it is checked as well.
+            let ticket: api::SessionTicket = protobuf::parse_from_bytes(&payload)?;
+            if ticket.get_key_epoch() != epoch {
+                return Err(Error::new("Malformed session ticket"));
+            }
+            if ticket.get_expiry() < current_epoch {
+                return Err(Error::new("Session ticket expired"));
+            }
+
+            return Ok(ticket);
+        }
+
+        Err(Error::new("Invalid or expired session ticket"))
+    }
+
+    /// Resume a session from a session ticket.
+    ///
+    /// The new session is keyed with the given client short-term key and a new contract
+    /// short-term key, and it retains the client authentication and key ratcheting
+    /// parameters of the session the ticket was issued for.
+    pub fn resume_session(
+        &mut self,
+        ticket: &api::CryptoBox,
+        public_key: &[u8],
+    ) -> Result<api::ChannelResumeResponse> {
+        let ticket = self.open_ticket(ticket)?;
+
+        let key = SecureChannelContext::get_session_key(&public_key)?;
+
+        if self.sessions.contains_key(&key) {
+            return Err(Error::new("Session already exists"));
+        }
+
+        let session = ClientSession::resume(key.clone(), &ticket)?;
+
+        let mut response = api::ChannelResumeResponse::new();
+        response.set_short_term_public_key(session.get_contract_public_key().to_vec());
+        if let Some(ref ratchet) = session.ratchet_parameters {
+            response.set_ratchet(ratchet.clone());
+        }
+
+        self.insert_session(key, session)?;
+
+        Ok(response)
+    }
+
     /// Close an existing session.
     pub fn close_session(&mut self, public_key: &[u8]) -> Result<()> {
         let key = SecureChannelContext::get_session_key(&public_key)?;
@@ -181,6 +638,18 @@ impl SecureChannelContext {
 impl ClientSession {
     /// Create a new client session.
     pub fn new(public_key: sodalite::BoxPublicKey) -> Result<Self> {
+        ClientSession::create(public_key, None)
+    }
+
+    /// Create a new client session with a new contract short-term key pair.
+    ///
+    /// The shared key is derived from the key pair and the resumption secret of the
+    /// previous session (if any). The short-term private key is cleared once the shared
+    /// key has been derived, as it is not needed afterwards.
+    fn create(
+        public_key: sodalite::BoxPublicKey,
+        resumption_secret: Option<&sodalite::SecretboxKey>,
+    ) -> Result<Self> {
         let mut session = ClientSession::default();
         session.transition_to(SessionState::Init)?;
         session.client_public_key = public_key;
@@ -192,27 +661,127 @@ impl ClientSession {
             Err(_) => return Err(Error::new("Keypair generation failed")),
         }
 
-        sodalite::box_keypair_seed(
-            &mut session.contract_public_key,
-            &mut session.contract_private_key,
-            &seed,
-        );
+        let mut private_key = [0u8; sodalite::BOX_SECRET_KEY_LEN];
+        sodalite::box_keypair_seed(&mut session.contract_public_key, &mut private_key, &seed);
+        secure_channel::clear_key(&mut seed);
 
-        // Cache shared channel key.
-        {
-            let mut key = session
-                .shared_key
-                .get_or_insert([0u8; sodalite::SECRETBOX_KEY_LEN]);
-            sodalite::box_beforenm(
-                &mut key,
+        let shared_key = match resumption_secret {
+            Some(resumption_secret) => secure_channel::derive_resumed_shared_key(
+                resumption_secret,
                 &session.client_public_key,
-                &session.contract_private_key,
-            );
+                &private_key,
+            ),
+            None => {
+                let mut shared_key = [0u8; sodalite::SECRETBOX_KEY_LEN];
+                sodalite::box_beforenm(&mut shared_key, &session.client_public_key, &private_key);
+                shared_key
+            }
+        };
+        secure_channel::clear_key(&mut private_key);
+
+        session.resumption_secret = Some(secure_channel::derive_resumption_secret(&shared_key));
+        session.shared_key = Some(shared_key);
+
+        Ok(session)
+    }
+
+    /// Create a client session from a session ticket.
+    fn resume(public_key: sodalite::BoxPublicKey, ticket: &api::SessionTicket) -> Result<Self> {
+        if ticket.get_resumption_secret().len() != sodalite::SECRETBOX_KEY_LEN {
+            return Err(Error::new("Malformed session ticket"));
+        }
+        let mut resumption_secret = [0u8; sodalite::SECRETBOX_KEY_LEN];
+        resumption_secret.copy_from_slice(ticket.get_resumption_secret());
+
+        let mut session = ClientSession::create(public_key, Some(&resumption_secret))?;
+        secure_channel::clear_key(&mut resumption_secret);
+        session.transition_to(SessionState::Established)?;
+
+        if !ticket.get_client_long_term_public_key().is_empty() {
+            if ticket.get_client_long_term_public_key().len() != sodalite::BOX_PUBLIC_KEY_LEN
+                || ticket.get_client_mr_enclave().len() != MRENCLAVE_LEN
+            {
+                return Err(Error::new("Malformed session ticket"));
+            }
+
+            let mut client_long_term_public_key = [0u8; sodalite::BOX_PUBLIC_KEY_LEN];
+            client_long_term_public_key.copy_from_slice(ticket.get_client_long_term_public_key());
+            let mut client_mr_enclave = MrEnclave::default();
+            client_mr_enclave.0.copy_from_slice(ticket.get_client_mr_enclave());
+
+            session.client_long_term_public_key = Some(client_long_term_public_key);
+            session.client_mr_enclave = Some(client_mr_enclave);
+        }
+
+        if ticket.has_ratchet() {
+            session.enable_ratchet(ticket.get_ratchet());
         }
 
         Ok(session)
     }
 
+    /// Session state to be sealed into a session ticket.
+    fn get_ticket(&self) -> Result<api::SessionTicket> {
+        let resumption_secret = match self.resumption_secret {
+            Some(ref resumption_secret) => resumption_secret,
+            None => return Err(Error::new("Session cannot be resumed")),
+        };
+
+        let mut ticket = api::SessionTicket::new();
+        ticket.set_resumption_secret(resumption_secret.to_vec());
+        if let Some(ref client_long_term_public_key) = self.client_long_term_public_key {
+            ticket.set_client_long_term_public_key(client_long_term_public_key.to_vec());
+        }
+        if let Some(ref client_mr_enclave) = self.client_mr_enclave {
+            ticket.set_client_mr_enclave(client_mr_enclave.0.to_vec());
+        }
+        if let Some(ref ratchet) = self.ratchet_parameters {
+            ticket.set_ratchet(ratchet.clone());
+        }
+
+        Ok(ticket)
+    }
+
+    /// Encrypt requests and responses with ratcheted keys.
+    ///
+    /// The shared key is cleared once the key ratchet has been derived from it, so a
+    /// leaked session state does not expose messages of previous key epochs.
+    fn enable_ratchet(&mut self, parameters: &api::RatchetParameters) {
+        let mut shared_key = match self.shared_key.take() {
+            Some(shared_key) => shared_key,
+            None => return,
+        };
+
+        self.ratchet = Some(KeyRatchet::new(
+            &shared_key,
+            parameters.get_message_interval(),
+            false,
+        ));
+        self.ratchet_parameters = Some(parameters.clone());
+
+        secure_channel::clear_key(&mut shared_key);
+    }
+
+    /// Open cryptographic box sent by the client, with the ratchet or the shared key.
+    fn open_box(
+        &mut self,
+        crypto_box: &api::CryptoBox,
+        nonce_context: &[u8; secure_channel::NONCE_CONTEXT_LEN],
+    ) -> Result<Vec<u8>> {
+        match (&mut self.ratchet, &self.shared_key) {
+            (&mut Some(ref mut ratchet), _) => {
+                ratchet.open_box(crypto_box, nonce_context, &mut self.nonce_generator)
+            }
+            (&mut None, &Some(ref shared_key)) => secure_channel::open_box_with_key(
+                crypto_box,
+                nonce_context,
+                &mut self.nonce_generator,
+                shared_key,
+            ),
+            _ => Err(Error::new("Session has no keys")),
+        }
+    }
+
     /// Get client short-term public key.
     pub fn get_client_public_key(&self) -> &sodalite::BoxPublicKey {
         &self.client_public_key
@@ -223,16 +792,53 @@ impl ClientSession {
         &self.contract_public_key
     }
 
+    /// Number of bytes of chunked payloads held by the session.
+    fn get_chunk_bytes(&self) -> usize {
+        let response_bytes: usize = self.response_chunks
+            .iter()
+            .map(|chunk| chunk.get_data().len())
+            .sum();
+
+        self.request_chunks.len() + response_bytes
+    }
+
+    /// Add a chunk of a request payload, if the session may hold `budget` bytes of
+    /// chunked payloads.
+    fn add_chunk(
+        &mut self,
+        chunk: &api::PayloadChunk,
+        budget: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        // The first chunk of a payload discards any incomplete previous payload.
+        let mut held = self.get_chunk_bytes();
+        if chunk.get_index() == 0 {
+            held -= self.request_chunks.len();
+        }
+
+        if held + chunk.get_data().len() > budget {
+            self.request_chunks.reset();
+            return Err(Error::new("Chunk budget exceeded"));
+        }
+
+        self.request_chunks.add(chunk)
+    }
+
     /// Open cryptographic box with RPC request.
-    pub fn open_request_box(&mut self, request: &api::CryptoBox) -> Result<Request<Vec<u8>>> {
-        let plain_request = secure_channel::open_box(
-            &request,
-            &secure_channel::NONCE_CONTEXT_REQUEST,
-            &mut self.nonce_generator,
-            &self.client_public_key,
-            &self.contract_private_key,
-            &mut self.shared_key,
-        )?;
+    ///
+    /// The session may hold `budget` bytes of chunked payloads.
+    pub fn open_request_box(
+        &mut self,
+        request: &api::CryptoBox,
+        budget: usize,
+    ) -> Result<Request<Vec<u8>>> {
+        let plain_request = self.open_box(&request, &secure_channel::NONCE_CONTEXT_REQUEST)?;
+
+        // Follow the key epochs of the client, which may also start new epochs based on
+        // time.
+        if let Some(ref mut ratchet) = self.ratchet {
+            let epoch = ratchet.get_receive_epoch();
+            ratchet.advance_send_epoch(epoch, &mut self.nonce_generator)?;
+        }
 
         let mut plain_request: api::PlainClientRequest =
             protobuf::parse_from_bytes(&plain_request)?;
@@ -245,27 +851,107 @@ impl ClientSession {
             }
         }
 
-        Ok(Request::new(
+        let request = Request::new(
             plain_request.take_payload(),
             plain_request.take_method(),
             Some(self.client_public_key.to_vec()),
+            self.client_long_term_public_key.map(|key| key.to_vec()),
             self.client_mr_enclave.clone(),
-        ))
+        );
+
+        if !plain_request.has_chunk() {
+            return Ok(request);
+        }
+
+        // Assemble the payload of a chunked request. Errors are reported over the
+        // secure channel, as they are not secure channel errors.
+        match self.add_chunk(plain_request.get_chunk(), budget) {
+            Ok(Some(payload)) => Ok(request.copy_metadata_to(payload)),
+            Ok(None) => Ok(request.copy_metadata_to_error(DispatchError::new(
+                api::PlainClientResponse_Code::ERROR_BAD_REQUEST,
+                "Incomplete chunked request",
+            ))),
+            Err(error) => Ok(request.copy_metadata_to_error(DispatchError::new(
+                api::PlainClientResponse_Code::ERROR_BAD_REQUEST,
+                &error.message,
+            ))),
+        }
+    }
+
+    /// Add a chunk of a request payload, other than the last chunk.
+    ///
+    /// The session may hold `budget` bytes of chunked payloads.
+    pub fn add_request_chunk(&mut self, chunk: &api::PayloadChunk, budget: usize) -> Result<()> {
+        if chunk.get_index().saturating_add(1) >= chunk.get_count() {
+            return Err(Error::new("Last chunk must be sent with the request"));
+        }
+
+        self.add_chunk(chunk, budget)?;
+
+        Ok(())
+    }
+
+    /// Get the next chunk of the last response payload.
+    pub fn get_response_chunk(&mut self, index: u32) -> Result<api::PayloadChunk> {
+        match self.response_chunks.front() {
+            Some(chunk) if chunk.get_index() == index => {}
+            _ => return Err(Error::new("Response chunk not available")),
+        }
+
+        self.response_chunks
+            .pop_front()
+            .ok_or_else(|| Error::new("Response chunk not available"))
     }
 
     /// Create cryptographic box with RPC response.
+    ///
+    /// The session may hold `budget` bytes of chunked payloads. A chunked response
+    /// discards any chunks of the previous response which the client has not fetched.
     pub fn create_response_box(
         &mut self,
         response: &api::PlainClientResponse,
+        budget: usize,
     ) -> Result<api::CryptoBox> {
-        Ok(secure_channel::create_box(
-            &response.write_to_bytes()?,
-            &secure_channel::NONCE_CONTEXT_RESPONSE,
-            &mut self.nonce_generator,
-            &self.client_public_key,
-            &self.contract_private_key,
-            &mut self.shared_key,
-        )?)
+        let response = if chunking::is_chunked(response.get_payload()) {
+            // Send the first chunk and keep the rest until the client fetches them.
+            let mut chunks: VecDeque<_> = chunking::split(response.get_payload())?
+                .into_iter()
+                .collect();
+            let mut chunked_response = api::PlainClientResponse::new();
+            chunked_response.set_code(response.get_code());
+            chunked_response.set_chunk(chunks.pop_front().unwrap_or_default());
+            self.response_chunks = chunks;
+
+            if self.get_chunk_bytes() > budget {
+                // The error is reported over the secure channel, as the request itself
+                // has been processed.
+                self.response_chunks.clear();
+                chunked_response = Response::generate_error(
+                    api::PlainClientResponse_Code::ERROR,
+                    "Chunk budget exceeded",
+                );
+            }
+
+            chunked_response.write_to_bytes()?
+        } else {
+            response.write_to_bytes()?
+        };
+
+        match (&mut self.ratchet, &self.shared_key) {
+            (&mut Some(ref mut ratchet), _) => ratchet.create_box(
+                &response,
+                &secure_channel::NONCE_CONTEXT_RESPONSE,
+                &mut self.nonce_generator,
+                false,
+            ),
+            (&mut None, &Some(ref shared_key)) => secure_channel::create_box_with_key(
+                &response,
+                &secure_channel::NONCE_CONTEXT_RESPONSE,
+                &mut self.nonce_generator,
+                shared_key,
+            ),
+            _ => Err(Error::new("Session has no keys")),
+        }
     }
 
     /// Transition secure channel to a new state.
@@ -274,6 +960,17 @@ impl ClientSession {
     }
 }
 
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        if let Some(ref mut shared_key) = self.shared_key {
+            secure_channel::clear_key(shared_key);
+        }
+        if let Some(ref mut resumption_secret) = self.resumption_secret {
+            secure_channel::clear_key(resumption_secret);
+        }
+    }
+}
+
 lazy_static! {
     // Global secure channel context.
     static ref SECURE_CHANNEL_CTX: Mutex<SecureChannelContext> =
@@ -284,11 +981,32 @@ lazy_static! {
 pub fn channel_init(request: &api::ChannelInitRequest) -> Result<api::ChannelInitResponse> {
     let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
 
+    // Accept key ratcheting if requested by the client. Clients which do not support
+    // key ratcheting never request it.
+    let ratchet = if request.has_ratchet() {
+        let mut ratchet = request.get_ratchet().clone();
+        let message_interval = ratchet.get_message_interval();
+        ratchet.set_message_interval(message_interval.min(RATCHET_MAX_MESSAGE_INTERVAL));
+
+        Some(ratchet)
+    } else {
+        None
+    };
+
     // Create new session.
-    let astpk = channel.create_session(request.get_short_term_public_key())?;
+    let astpk = channel.create_session(request.get_short_term_public_key(), ratchet.as_ref())?;
 
     let mut response = api::ChannelInitResponse::new();
     response.set_authenticated_short_term_public_key(astpk);
+    if let Some(ratchet) = ratchet {
+        response.set_ratchet(ratchet);
+    }
+
+    // Session tickets are optional, so the channel is still established if no ticket
+    // can be issued (e.g., if the ticket key is not available).
+    if let Ok(ticket) = channel.issue_ticket(request.get_short_term_public_key()) {
+        response.set_ticket(ticket);
+    }
 
     Ok(response)
 }
@@ -300,26 +1018,77 @@ pub fn channel_auth(request: &api::ChannelAuthRequest) -> Result<api::ChannelAut
 
     channel.authenticate_client(box_outer)?;
 
-    Ok(api::ChannelAuthResponse::new())
+    // Issue a new ticket, which includes the client authentication.
+    let mut response = api::ChannelAuthResponse::new();
+    if let Ok(ticket) = channel.issue_ticket(box_outer.get_public_key()) {
+        response.set_ticket(ticket);
+    }
+
+    Ok(response)
+}
+
+/// Resume secure channel from a session ticket.
+pub fn channel_resume(request: &api::ChannelResumeRequest) -> Result<api::ChannelResumeResponse> {
+    let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+    let public_key = request.get_short_term_public_key();
+
+    let mut response = channel.resume_session(request.get_ticket(), public_key)?;
+
+    // Issue a ticket for resuming the new session.
+    if let Ok(ticket) = channel.issue_ticket(public_key) {
+        response.set_ticket(ticket);
+    }
+
+    Ok(response)
 }
 
 /// Close secure channel.
+///
+/// The session is closed once the response to the close request has been created.
 pub fn channel_close(public_key: &[u8]) -> Result<()> {
     let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
 
-    channel.close_session(&public_key)?;
+    SecureChannelContext::get_session(&mut channel.sessions, &public_key)?
+        .transition_to(SessionState::Closed)?;
 
     Ok(())
 }
 
+/// Add a chunk of a request payload, other than the last chunk.
+pub fn add_request_chunk(public_key: &[u8], chunk: &api::PayloadChunk) -> Result<()> {
+    let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+    let budget = channel.get_chunk_budget(&public_key)?;
+
+    SecureChannelContext::get_session(&mut channel.sessions, &public_key)?
+        .add_request_chunk(&chunk, budget)
+}
+
+/// Get the next chunk of the last response payload.
+pub fn get_response_chunk(public_key: &[u8], index: u32) -> Result<api::PayloadChunk> {
+    let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+
+    SecureChannelContext::get_session(&mut channel.sessions, &public_key)?
+        .get_response_chunk(index)
+}
+
+/// Start processing a new batch of requests, expiring idle sessions.
+pub fn start_batch() {
+    let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+
+    channel.start_batch();
+}
+
 /// Open cryptographic box with RPC request.
 pub fn open_request_box(request: &api::CryptoBox) -> Result<Request<Vec<u8>>> {
     let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+    let batch = channel.batch;
+    let budget = channel.get_chunk_budget(&request.get_public_key())?;
+
+    let session =
+        SecureChannelContext::get_session(&mut channel.sessions, &request.get_public_key())?;
+    session.last_used = batch;
 
-    Ok(
-        SecureChannelContext::get_session(&mut channel.sessions, &request.get_public_key())?
-            .open_request_box(&request)?,
-    )
+    Ok(session.open_request_box(&request, budget)?)
 }
 
 /// Create cryptographic box with RPC response.
@@ -328,9 +1097,481 @@ pub fn create_response_box(
     response: &api::PlainClientResponse,
 ) -> Result<api::CryptoBox> {
     let mut channel = SECURE_CHANNEL_CTX.lock().unwrap();
+    let budget = channel.get_chunk_budget(&public_key)?;
+
+    let (response_box, closed) = {
+        let session = SecureChannelContext::get_session(&mut channel.sessions, &public_key)?;
+        let response_box = session.create_response_box(&response, budget)?;
+
+        (response_box, session.state == SessionState::Closed)
+    };
+
+    // Remove sessions closed by this request, now that the client got the response.
+    if closed {
+        channel.close_session(&public_key)?;
+    }
+
+    Ok(response_box)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf;
+    use protobuf::Message;
+    use sodalite;
+
+    use std::sync::{Arc, Mutex};
+
+    use ekiden_common::error::{Error, Result};
+    use ekiden_common::serializer::{Deserializable, Serializable};
+    use ekiden_rpc_common::api;
+    use ekiden_rpc_common::chunking::{self, ChunkAssembler, PAYLOAD_CHUNK_SIZE};
+    use ekiden_rpc_common::reflection::ApiMethodDescriptor;
+    use ekiden_rpc_common::secure_channel::{self, MonotonicNonceGenerator, SessionState};
+
+    use super::{open_request_box, ClientSession, EpochKeyStore, SecureChannelContext,
+                SharedTicketKeySource};
+    use super::super::dispatcher::{Dispatcher, EnclaveMethod};
+    use super::super::request::Request;
+
+    /// Generate a client short-term key pair.
+    fn client_key(seed: u8) -> (sodalite::BoxPublicKey, sodalite::BoxSecretKey) {
+        let mut public_key = [0u8; sodalite::BOX_PUBLIC_KEY_LEN];
+        let mut private_key = [0u8; sodalite::BOX_SECRET_KEY_LEN];
+        sodalite::box_keypair_seed(&mut public_key, &mut private_key, &[seed; 32]);
+
+        (public_key, private_key)
+    }
+
+    /// Create a context with a session for the given client key.
+    fn context_with_session(public_key: sodalite::BoxPublicKey) -> SecureChannelContext {
+        let mut context = SecureChannelContext::new();
+        let session = ClientSession::new(public_key).unwrap();
+        context.insert_session(public_key, session).unwrap();
+
+        context
+    }
+
+    /// Client side of a session in the global secure channel context.
+    struct Client {
+        public_key: sodalite::BoxPublicKey,
+        shared_key: sodalite::SecretboxKey,
+        nonce_generator: MonotonicNonceGenerator,
+    }
+
+    impl Client {
+        /// Establish a session in the global secure channel context.
+        fn connect(seed: u8) -> Self {
+            let (public_key, private_key) = client_key(seed);
+            let mut session = ClientSession::new(public_key).unwrap();
+            session.transition_to(SessionState::Established).unwrap();
+
+            let mut shared_key = [0u8; sodalite::SECRETBOX_KEY_LEN];
+            sodalite::box_beforenm(
+                &mut shared_key,
+                session.get_contract_public_key(),
+                &private_key,
+            );
+            SecureChannelContext::get()
+                .insert_session(public_key, session)
+                .unwrap();
+
+            Client {
+                public_key,
+                shared_key,
+                nonce_generator: MonotonicNonceGenerator::new(),
+            }
+        }
+
+        /// Call a method over the secure channel.
+        fn call(
+            &mut self,
+            dispatcher: &Dispatcher,
+            method: &str,
+            payload: Vec<u8>,
+            chunk: Option<api::PayloadChunk>,
+        ) -> api::PlainClientResponse {
+            let mut plain_request = api::PlainClientRequest::new();
+            plain_request.set_method(method.to_owned());
+            plain_request.set_payload(payload);
+            if let Some(chunk) = chunk {
+                plain_request.set_chunk(chunk);
+            }
+
+            let mut request_box = secure_channel::create_box_with_key(
+                &plain_request.write_to_bytes().unwrap(),
+                &secure_channel::NONCE_CONTEXT_REQUEST,
+                &mut self.nonce_generator,
+                &self.shared_key,
+            ).unwrap();
+            request_box.set_public_key(self.public_key.to_vec());
+
+            let request = open_request_box(&request_box).unwrap();
+            let mut response = dispatcher.dispatch(request);
+            let plain_response = secure_channel::open_box_with_key(
+                response.take_message().get_encrypted_response(),
+                &secure_channel::NONCE_CONTEXT_RESPONSE,
+                &mut self.nonce_generator,
+                &self.shared_key,
+            ).unwrap();
+
+            protobuf::parse_from_bytes(&plain_response).unwrap()
+        }
+    }
+
+    /// Method which returns its request payload.
+    fn echo(request: &Request<Vec<u8>>) -> Result<Vec<u8>> {
+        Ok((**request).clone())
+    }
+
+    /// Shared key store which keeps the epochs of a single key in memory.
+    #[derive(Clone, Default)]
+    struct MemoryKeyStore {
+        keys: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl EpochKeyStore for MemoryKeyStore {
+        fn get_current_key(&mut self, name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+            if self.keys.lock().unwrap().is_empty() {
+                return self.rotate_key(name, size);
+            }
+
+            let keys = self.keys.lock().unwrap();
+            Ok(((keys.len() - 1) as u64, keys[keys.len() - 1].clone()))
+        }
+
+        fn get_key(&mut self, _name: &str, _size: usize, epoch: u64) -> Result<Vec<u8>> {
+            match self.keys.lock().unwrap().get(epoch as usize) {
+                Some(key) => Ok(key.clone()),
+                None => Err(Error::new("Key not available")),
+            }
+        }
+
+        fn rotate_key(&mut self, _name: &str, size: usize) -> Result<(u64, Vec<u8>)> {
+            let mut keys = self.keys.lock().unwrap();
+            let epoch = keys.len() as u64;
+            keys.push(vec![epoch as u8 + 1; size]);
+
+            Ok((epoch, keys[epoch as usize].clone()))
+        }
+    }
+
+    /// Resume a session with a new client key.
+    fn resume(
+        context: &mut SecureChannelContext,
+        ticket: &api::CryptoBox,
+        seed: u8,
+    ) -> Result<(), String> {
+        let (public_key, _) = client_key(seed);
+
+        context
+            .resume_session(ticket, &public_key)
+            .map(|_| ())
+            .map_err(|error| error.message)
+    }
+
+    #[test]
+    fn test_ticket_resumption() {
+        let (public_key, _) = client_key(1);
+        let mut context = context_with_session(public_key);
+        let ticket = context.issue_ticket(&public_key).unwrap();
+
+        // Only the holder of the resumption secret can derive the new shared key.
+        let (new_public_key, new_private_key) = client_key(2);
+        let response = context.resume_session(&ticket, &new_public_key).unwrap();
+
+        let mut contract_public_key = [0u8; sodalite::BOX_PUBLIC_KEY_LEN];
+        contract_public_key.copy_from_slice(response.get_short_term_public_key());
+        let resumption_secret = context.sessions[&public_key].resumption_secret.unwrap();
+        let shared_key = secure_channel::derive_resumed_shared_key(
+            &resumption_secret,
+            &contract_public_key,
+            &new_private_key,
+        );
+        assert_eq!(context.sessions[&new_public_key].shared_key, Some(shared_key));
+
+        // The same client key cannot be used twice.
+        assert!(resume(&mut context, &ticket, 2).is_err());
+    }
+
+    #[test]
+    fn test_ticket_expiry() {
+        let (public_key, _) = client_key(1);
+        let mut context = context_with_session(public_key);
+        context.set_ticket_key_lifetime(2);
+        let ticket = context.issue_ticket(&public_key).unwrap();
+
+        // Tickets remain valid for one rotation of the ticket key.
+        context.start_batch();
+        context.start_batch();
+        assert!(resume(&mut context, &ticket, 2).is_ok());
+
+        context.start_batch();
+        context.start_batch();
+        assert!(resume(&mut context, &ticket, 3).is_err());
+
+        // New tickets are issued with the new ticket key.
+        let ticket = context.issue_ticket(&public_key).unwrap();
+        assert!(resume(&mut context, &ticket, 4).is_ok());
+    }
+
+    #[test]
+    fn test_shared_ticket_key_rotation() {
+        let store = MemoryKeyStore::default();
+        let (public_key, _) = client_key(1);
+        let mut context = context_with_session(public_key);
+        context.set_ticket_key_source(SharedTicketKeySource::new(store.clone()));
+        context.set_ticket_key_lifetime(2);
+        let mut other_context = SecureChannelContext::new();
+        other_context.set_ticket_key_source(SharedTicketKeySource::new(store.clone()));
+        other_context.set_ticket_key_lifetime(2);
+        let ticket = context.issue_ticket(&public_key).unwrap();
+
+        // All instances rotate the shared key once its lifetime has passed, but it is
+        // only rotated once.
+        let start_batches = |context: &mut SecureChannelContext,
+                             other_context: &mut SecureChannelContext| {
+            for _ in 0..2 {
+                context.start_batch();
+                other_context.start_batch();
+            }
+        };
+        start_batches(&mut context, &mut other_context);
+        assert_eq!(store.keys.lock().unwrap().len(), 2);
+
+        // Tickets can be redeemed with any instance, until the shared key has been
+        // rotated twice.
+        assert!(resume(&mut other_context, &ticket, 2).is_ok());
+
+        start_batches(&mut context, &mut other_context);
+        assert_eq!(store.keys.lock().unwrap().len(), 3);
+        assert!(resume(&mut context, &ticket, 3).is_err());
+        assert!(resume(&mut other_context, &ticket, 4).is_err());
+    }
 
-    Ok(
-        SecureChannelContext::get_session(&mut channel.sessions, &public_key)?
-            .create_response_box(&response)?,
-    )
+    #[test]
+    fn test_ticket_forged() {
+        let (public_key, _) = client_key(1);
+        let mut context = context_with_session(public_key);
+        let ticket = context.issue_ticket(&public_key).unwrap();
+
+        let mut forged = ticket.clone();
+        let mut payload = forged.get_payload().to_vec();
+        payload[40] ^= 1;
+        forged.set_payload(payload);
+        assert!(resume(&mut context, &forged, 2).is_err());
+
+        // Tickets of another enclave instance are rejected.
+        let mut other_context = context_with_session(public_key);
+        assert!(resume(&mut other_context, &ticket, 2).is_err());
+    }
+
+    #[test]
+    fn test_ticket_without_resumption_secret() {
+        let (public_key, _) = client_key(1);
+        let mut context = context_with_session(public_key);
+        context
+            .sessions
+            .get_mut(&public_key)
+            .unwrap()
+            .resumption_secret = None;
+
+        assert_eq!(
+            context.issue_ticket(&public_key).unwrap_err().message,
+            "Session cannot be resumed"
+        );
+    }
+
+    #[test]
+    fn test_session_eviction() {
+        let mut context = SecureChannelContext::new();
+        context.set_session_limits(2, 0);
+
+        let keys: Vec<_> = (1..6).map(|seed| client_key(seed).0).collect();
+        let insert = |context: &mut SecureChannelContext, index: usize| {
+            let session = ClientSession::new(keys[index]).unwrap();
+            context.insert_session(keys[index], session)
+        };
+
+        insert(&mut context, 0).unwrap();
+        context.start_batch();
+        insert(&mut context, 1).unwrap();
+        context.start_batch();
+
+        // The least recently used session is evicted first, even if it was
+        // established earlier.
+        context.sessions.get_mut(&keys[0]).unwrap().last_used = context.batch;
+        insert(&mut context, 2).unwrap();
+        assert!(context.sessions.contains_key(&keys[0]));
+        assert!(!context.sessions.contains_key(&keys[1]));
+
+        // Sessions used in the current batch are never evicted.
+        assert_eq!(
+            insert(&mut context, 3).unwrap_err().message,
+            "Too many client sessions"
+        );
+
+        context.start_batch();
+        insert(&mut context, 3).unwrap();
+        assert_eq!(context.sessions.len(), 2);
+        assert!(context.sessions.contains_key(&keys[3]));
+    }
+
+    #[test]
+    fn test_session_idle_expiry() {
+        let (public_key, _) = client_key(1);
+        let (other_public_key, _) = client_key(2);
+        let mut context = context_with_session(public_key);
+        context.set_session_limits(10, 2);
+        let session = ClientSession::new(other_public_key).unwrap();
+        context.insert_session(other_public_key, session).unwrap();
+
+        // Sessions expire once they have not been used for more than the idle batches.
+        context.start_batch();
+        context.start_batch();
+        context.sessions.get_mut(&other_public_key).unwrap().last_used = context.batch;
+        assert_eq!(context.sessions.len(), 2);
+
+        context.start_batch();
+        assert!(!context.sessions.contains_key(&public_key));
+        assert!(context.sessions.contains_key(&other_public_key));
+
+        // Without idle expiry, sessions are kept.
+        context.set_session_limits(10, 0);
+        for _ in 0..10 {
+            context.start_batch();
+        }
+        assert!(context.sessions.contains_key(&other_public_key));
+    }
+
+    #[test]
+    fn test_chunk_budget() {
+        let (public_key, _) = client_key(1);
+        let (other_public_key, _) = client_key(2);
+        let mut context = context_with_session(public_key);
+        let session = ClientSession::new(other_public_key).unwrap();
+        context.insert_session(other_public_key, session).unwrap();
+        context.set_chunk_limits(3 * PAYLOAD_CHUNK_SIZE, 4 * PAYLOAD_CHUNK_SIZE);
+
+        let chunks = chunking::split(&vec![1; 5 * PAYLOAD_CHUNK_SIZE]).unwrap();
+        let add = |context: &mut SecureChannelContext,
+                   key: &sodalite::BoxPublicKey,
+                   chunk: &api::PayloadChunk| {
+            let budget = context.get_chunk_budget(key).unwrap();
+            context
+                .sessions
+                .get_mut(key)
+                .unwrap()
+                .add_request_chunk(chunk, budget)
+                .map_err(|error| error.message)
+        };
+
+        // A single session holds at most its own budget, and exceeding it discards the
+        // incomplete request.
+        for chunk in &chunks[..3] {
+            add(&mut context, &public_key, chunk).unwrap();
+        }
+        assert_eq!(
+            add(&mut context, &public_key, &chunks[3]).unwrap_err(),
+            "Chunk budget exceeded"
+        );
+        assert!(context.sessions[&public_key].request_chunks.is_empty());
+
+        // All sessions together hold at most the total budget.
+        for chunk in &chunks[..3] {
+            add(&mut context, &public_key, chunk).unwrap();
+        }
+        add(&mut context, &other_public_key, &chunks[0]).unwrap();
+        assert_eq!(
+            add(&mut context, &other_public_key, &chunks[1]).unwrap_err(),
+            "Chunk budget exceeded"
+        );
+
+        // Chunks of responses which exceed the budget are not kept.
+        let mut response = api::PlainClientResponse::new();
+        response.set_payload(vec![1; 2 * PAYLOAD_CHUNK_SIZE + 1]);
+        let budget = context.get_chunk_budget(&other_public_key).unwrap();
+        {
+            let session = context.sessions.get_mut(&other_public_key).unwrap();
+            session.create_response_box(&response, budget).unwrap();
+            assert!(session.response_chunks.is_empty());
+        }
+
+        context
+            .sessions
+            .get_mut(&public_key)
+            .unwrap()
+            .request_chunks
+            .reset();
+        let budget = context.get_chunk_budget(&other_public_key).unwrap();
+        let session = context.sessions.get_mut(&other_public_key).unwrap();
+        session.create_response_box(&response, budget).unwrap();
+        assert_eq!(session.response_chunks.len(), 2);
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.add_method(EnclaveMethod::new(
+            ApiMethodDescriptor {
+                name: "echo".to_owned(),
+                client_attestation_required: false,
+                policies: vec![],
+            },
+            echo,
+        ));
+        let mut client = Client::connect(3);
+
+        // Send all but the last chunk separately and the last chunk with the request.
+        let payload: Vec<u8> = (0..3 * PAYLOAD_CHUNK_SIZE)
+            .map(|index| index as u8)
+            .collect();
+        let mut chunks = chunking::split(&payload.write().unwrap()).unwrap();
+        let last_chunk = chunks.pop().unwrap();
+        for chunk in chunks {
+            let response = client.call(
+                &dispatcher,
+                api::METHOD_REQUEST_CHUNK,
+                chunk.write().unwrap(),
+                None,
+            );
+            assert_eq!(response.get_code(), api::PlainClientResponse_Code::SUCCESS);
+        }
+
+        let response = client.call(&dispatcher, "echo", vec![], Some(last_chunk));
+        assert_eq!(response.get_code(), api::PlainClientResponse_Code::SUCCESS);
+        assert!(response.has_chunk());
+
+        // Fetch the remaining chunks of the response.
+        let mut assembler = ChunkAssembler::new();
+        let mut chunk = response.get_chunk().clone();
+        let response_payload = loop {
+            if let Some(payload) = assembler.add(&chunk).unwrap() {
+                break payload;
+            }
+
+            let mut request = api::ResponseChunkRequest::new();
+            request.set_index(chunk.get_index() + 1);
+            let response = client.call(
+                &dispatcher,
+                api::METHOD_RESPONSE_CHUNK,
+                request.write().unwrap(),
+                None,
+            );
+            assert_eq!(response.get_code(), api::PlainClientResponse_Code::SUCCESS);
+            chunk = api::PayloadChunk::read(&response.get_payload().to_vec()).unwrap();
+        };
+        assert_eq!(Vec::<u8>::read(&response_payload).unwrap(), payload);
+
+        // Chunks are only available once.
+        let mut request = api::ResponseChunkRequest::new();
+        request.set_index(1);
+        let response = client.call(
+            &dispatcher,
+            api::METHOD_RESPONSE_CHUNK,
+            request.write().unwrap(),
+            None,
+        );
+        assert_eq!(response.get_code(), api::PlainClientResponse_Code::ERROR);
+    }
 }
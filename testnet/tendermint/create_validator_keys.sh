@@ -1,5 +1,7 @@
 #!/bin/sh -eu
 TM_VERSION=$1
+# App hash of the genesis state, as printed by `ekiden-consensus import --genesis`.
+APP_HASH=${2:-}
 IMAGE_TAG=tendermint/tendermint:$TM_VERSION
 VALIDATOR_POWER=10
 
@@ -8,7 +10,7 @@ cat >genesis.json <<EOF
   "genesis_time": "2018-03-03T00:00:00.000Z",
   "chain_id": "ekidentm-test",
   "validators": [],
-  "app_hash": ""
+  "app_hash": "$APP_HASH"
 }
 EOF
 